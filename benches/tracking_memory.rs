@@ -0,0 +1,94 @@
+//! Memory used per tracked transaction by an `Account`.
+//!
+//! Compares the compact columnar tracking of deposits used by `Account` with the layout it
+//! replaced, a `HashMap` from transaction id to amount and dispute status. Memory is measured
+//! with a global allocator that counts the bytes currently allocated.
+//!
+//! Run it with `cargo bench --bench tracking_memory`.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use payment_settle_accounts::{Account, Amount, Transaction, TransactionType};
+use rust_decimal::Decimal;
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Layout used to track deposits before the compact tracking.
+#[allow(dead_code)]
+enum LegacyStatus {
+    Deposit,
+    BeingDisputed,
+}
+
+#[allow(dead_code)]
+struct LegacyTrack {
+    amount: Decimal,
+    status: LegacyStatus,
+}
+
+fn deposit(transaction_id: u32) -> Transaction {
+    Transaction::builder()
+        .ty(TransactionType::Deposit)
+        .client_id(1)
+        .transaction_id(transaction_id)
+        .amount(Amount::new(Decimal::new(transaction_id as i64, 4)).unwrap())
+        .build()
+}
+
+fn measure<T>(build: impl FnOnce() -> T) -> (usize, T) {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    let value = build();
+    (ALLOCATED.load(Ordering::SeqCst) - before, value)
+}
+
+fn main() {
+    println!("transactions,legacy bytes/tx,compact bytes/tx");
+    for transactions in [1_000_u32, 100_000, 1_000_000] {
+        let (legacy, map) = measure(|| {
+            let mut map = HashMap::new();
+            for transaction_id in 0..transactions {
+                map.insert(
+                    transaction_id,
+                    LegacyTrack {
+                        amount: Decimal::new(transaction_id as i64, 4),
+                        status: LegacyStatus::Deposit,
+                    },
+                );
+            }
+            map
+        });
+        drop(map);
+        let (compact, account) = measure(|| {
+            let mut account = Account::new(1);
+            for transaction_id in 0..transactions {
+                account.process(&deposit(transaction_id)).unwrap();
+            }
+            account
+        });
+        drop(account);
+        println!(
+            "{},{:.2},{:.2}",
+            transactions,
+            legacy as f64 / transactions as f64,
+            compact as f64 / transactions as f64
+        );
+    }
+}
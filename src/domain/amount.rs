@@ -0,0 +1,92 @@
+//! Validated amount of a transaction.
+use std::fmt;
+
+#[cfg(test)]
+use fake::{Dummy, Fake, Faker};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::TransactionError;
+
+/// Amount of a deposit or withdrawal, which is never negative.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+#[serde(try_from = "Decimal", into = "Decimal")]
+pub struct Amount(Decimal);
+
+impl Amount {
+    /// Creates an amount, or returns an error if `value` is negative.
+    pub fn new(value: Decimal) -> Result<Self, TransactionError> {
+        if value.is_sign_negative() && !value.is_zero() {
+            return Err(TransactionError::InvalidTransactionAmount(format!(
+                "Transaction amount {} is negative",
+                value
+            )));
+        }
+        Ok(Self(value))
+    }
+
+    /// Returns the value of the amount.
+    pub fn value(&self) -> Decimal {
+        self.0
+    }
+}
+
+impl TryFrom<Decimal> for Amount {
+    type Error = TransactionError;
+
+    fn try_from(value: Decimal) -> Result<Self, Self::Error> {
+        Self::new(value)
+    }
+}
+
+/// Unsigned integers are never negative, so they are always valid amounts.
+impl From<u32> for Amount {
+    fn from(value: u32) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<Amount> for Decimal {
+    fn from(amount: Amount) -> Self {
+        amount.0
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+impl Dummy<Faker> for Amount {
+    fn dummy_with_rng<R: fake::Rng + ?Sized>(config: &Faker, rng: &mut R) -> Self {
+        Self(config.fake_with_rng::<Decimal, R>(rng).abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert_eq!(Amount::new(dec!(1.5)).unwrap().value(), dec!(1.5));
+        assert_eq!(Amount::new(dec!(-0)).unwrap().value(), dec!(0));
+        assert!(matches!(
+            Amount::new(dec!(-1)),
+            Err(TransactionError::InvalidTransactionAmount(_))
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_negative() {
+        assert!(serde_json::from_str::<Amount>("\"-1.5\"").is_err());
+        assert_eq!(
+            serde_json::from_str::<Amount>("\"1.5\"").unwrap(),
+            Amount::new(dec!(1.5)).unwrap()
+        );
+    }
+}
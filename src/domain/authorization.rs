@@ -0,0 +1,69 @@
+//! Authorization holds of an account.
+//!
+//! An authorization moves funds from available to held until it is captured, voided or it
+//! expires. Holds of authorizations are kept apart from the holds of disputes, so the summary can
+//! tell them apart.
+#[cfg(test)]
+use fake::Dummy;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::exchange::AppliedRate;
+use crate::{Currency, Timestamp};
+
+/// Open authorization hold kept by an `Account` until it is captured, voided or expired.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(test, derive(Dummy))]
+pub(crate) struct Authorization {
+    amount: Decimal,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    applied_rate: Option<AppliedRate>,
+}
+
+impl Authorization {
+    pub(crate) fn new(amount: Decimal, timestamp: Option<Timestamp>) -> Self {
+        Self {
+            amount,
+            timestamp,
+            currency: None,
+            applied_rate: None,
+        }
+    }
+
+    /// Returns the same authorization held in `currency`, or in the base currency if none.
+    pub(crate) fn with_currency(self, currency: Option<Currency>) -> Self {
+        Self { currency, ..self }
+    }
+
+    /// Returns the same authorization converted to the base currency with `applied_rate`.
+    pub(crate) fn with_applied_rate(self, applied_rate: Option<AppliedRate>) -> Self {
+        Self {
+            applied_rate,
+            ..self
+        }
+    }
+
+    /// Returns the amount held by the authorization.
+    pub(crate) fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    /// Returns the time of the authorization, if the input has it.
+    pub(crate) fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    /// Returns the currency of the authorization, or none for the base currency.
+    pub(crate) fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Returns the rate applied to convert the authorization to the base currency, if any.
+    pub(crate) fn applied_rate(&self) -> Option<AppliedRate> {
+        self.applied_rate
+    }
+}
@@ -0,0 +1,102 @@
+//! Currencies of the balances of an account.
+//!
+//! An account keeps its balances in a base currency, the one of the transactions without a
+//! currency, and a separate pair of available and held funds for every other currency it
+//! receives. Funds in different currencies are never mixed.
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(test)]
+use fake::{Dummy, Faker};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::TransactionError;
+
+/// Currency code of three letters, such as `EUR`, `GBP` or `USD`.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct Currency([u8; 3]);
+
+impl FromStr for Currency {
+    type Err = TransactionError;
+
+    /// Parses a currency code of three letters, in upper or lower case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [a, b, c] if s.chars().all(|c| c.is_ascii_alphabetic()) => Ok(Currency([
+                a.to_ascii_uppercase(),
+                b.to_ascii_uppercase(),
+                c.to_ascii_uppercase(),
+            ])),
+            _ => Err(TransactionError::InvalidCurrency(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The code is always made of ASCII letters.
+        self.0
+            .iter()
+            .try_for_each(|c| write!(f, "{}", char::from(*c)))
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+impl Dummy<Faker> for Currency {
+    fn dummy_with_rng<R: fake::Rng + ?Sized>(_: &Faker, rng: &mut R) -> Self {
+        let codes = [*b"EUR", *b"GBP", *b"USD"];
+        Currency(codes[rng.gen_range(0..codes.len())])
+    }
+}
+
+/// Available and held funds of an account in one currency, and the fees charged in it. The held
+/// funds are the ones held by disputes; the ones held by authorizations are kept with each
+/// authorization.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+#[cfg_attr(test, derive(Dummy))]
+pub(crate) struct Balance {
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+    #[serde(default)]
+    pub(crate) fees: Decimal,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_currency_from_str() {
+        let currency: Currency = "eur".parse().unwrap();
+
+        assert_eq!(currency.to_string(), "EUR");
+        assert_eq!(currency, "EUR".parse().unwrap());
+        for invalid in ["", "EU", "EURO", "E1R", "€"] {
+            assert!(matches!(
+                invalid.parse::<Currency>(),
+                Err(TransactionError::InvalidCurrency(_))
+            ));
+        }
+    }
+}
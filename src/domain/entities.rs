@@ -1,19 +1,33 @@
 //! Contains the entities used in the application.
 
 use core::fmt;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
 
 #[cfg(test)]
 use fake::Dummy;
 
 use ::serde::{Deserialize, Serialize};
+use log::info;
 use rust_decimal::Decimal;
 use typed_builder::TypedBuilder;
 
+use super::amount::Amount;
+use super::authorization::Authorization;
+use super::currency::{Balance, Currency};
+use super::exchange::AppliedRate;
+use super::fees::FeeCharge;
+use super::ledger::{JournalEntry, LedgerAccount};
+use super::limits::RecentTransaction;
+use super::policy::AccountPolicy;
+use super::precision::{PrecisionPolicy, DEFAULT_SCALE};
+use super::retention::{PruneReport, RetentionPolicy};
+use super::tracker::{TxTrack, TxTracker};
 use crate::TransactionError;
 
 /// Represents the type of a transaction.
-#[derive(Deserialize, PartialEq, Debug, Clone)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
 #[cfg_attr(test, derive(Dummy))]
 pub enum TransactionType {
     /// Represents a deposit transaction.
@@ -31,6 +45,68 @@ pub enum TransactionType {
     /// Represents a chargeback transaction.
     #[serde(rename = "chargeback")]
     Chargeback,
+    /// Holds funds of the available balance until the authorization is captured or voided.
+    #[serde(rename = "authorize")]
+    Authorize,
+    /// Debits the funds held by an authorization, releasing what is not captured.
+    #[serde(rename = "capture")]
+    Capture,
+    /// Releases the funds held by an authorization.
+    #[serde(rename = "void")]
+    Void,
+    /// Moves funds of the available balance to the account of another client.
+    #[serde(rename = "transfer")]
+    Transfer,
+    /// Administrative operation that unlocks an account locked by a chargeback.
+    #[serde(rename = "unlock")]
+    Unlock,
+    /// Administrative operation that freezes an account, rejecting all its transactions.
+    #[serde(rename = "freeze")]
+    Freeze,
+    /// Administrative operation that unfreezes a frozen account.
+    #[serde(rename = "unfreeze")]
+    Unfreeze,
+    /// Administrative operation that closes an account permanently.
+    #[serde(rename = "close")]
+    Close,
+}
+
+impl FromStr for TransactionType {
+    type Err = TransactionError;
+
+    /// Parses a transaction type with the same name used in the input.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::Chargeback),
+            "authorize" => Ok(TransactionType::Authorize),
+            "capture" => Ok(TransactionType::Capture),
+            "void" => Ok(TransactionType::Void),
+            "transfer" => Ok(TransactionType::Transfer),
+            "unlock" => Ok(TransactionType::Unlock),
+            "freeze" => Ok(TransactionType::Freeze),
+            "unfreeze" => Ok(TransactionType::Unfreeze),
+            "close" => Ok(TransactionType::Close),
+            _ => Err(TransactionError::InvalidTransactionType(s.into())),
+        }
+    }
+}
+
+impl TransactionType {
+    /// Returns whether the type is an administrative operation, which must carry an operator
+    /// and a reason and is recorded in the audit trail.
+    pub fn is_administrative(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Unlock
+                | TransactionType::Freeze
+                | TransactionType::Unfreeze
+                | TransactionType::Close
+        )
+    }
 }
 
 /// Represents a client ID.
@@ -39,8 +115,19 @@ pub type ClientId = u16;
 /// Represents a transaction ID.
 pub type TxId = u32;
 
+/// Time of a transaction, in seconds since the Unix epoch.
+pub type Timestamp = u64;
+
 /// Represents a transaction object.
-#[derive(Deserialize, PartialEq, TypedBuilder, Clone, Debug)]
+///
+/// Transactions read from an input are built from a `TransactionRecord`, which validates that
+/// deposits, withdrawals, authorizations and transfers have a non-negative amount, and that
+/// transfers have a destination client other than the source one. Disputes, resolves and
+/// chargebacks can have an amount to apply them to part of the disputed deposit, or none to apply
+/// them to the whole of it, and captures can have an amount to capture part of an authorization.
+/// Transactions without a currency are in the base currency of the accounts.
+#[derive(Deserialize, Serialize, PartialEq, TypedBuilder, Clone, Debug)]
+#[serde(try_from = "TransactionRecord")]
 #[cfg_attr(test, derive(Dummy))]
 pub struct Transaction {
     #[serde(rename = "type")]
@@ -52,9 +139,160 @@ pub struct Transaction {
     #[serde(rename = "tx")]
     transaction_id: TxId,
 
-    #[builder(default, setter(strip_option), setter(into))]
+    /// The builder takes an `Amount`, so a negative amount is rejected by `Amount::new` before
+    /// building the transaction.
+    #[builder(default, setter(strip_option, into))]
+    #[serde(rename = "amount")]
+    amount: Option<Amount>,
+
+    #[builder(default, setter(strip_option, into))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    operator: Option<String>,
+
+    #[builder(default, setter(strip_option, into))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<ClientId>,
+
+    #[builder(default, setter(strip_option))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+}
+
+/// Raw record of a transaction as found in the input, before being validated.
+#[derive(Deserialize, Serialize, PartialEq, Clone, Debug)]
+pub struct TransactionRecord {
+    #[serde(rename = "type")]
+    pub ty: TransactionType,
+
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+
+    #[serde(rename = "tx")]
+    pub transaction_id: TxId,
+
     #[serde(rename = "amount")]
-    amount: Option<Decimal>,
+    pub amount: Option<Decimal>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<ClientId>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = TransactionError;
+
+    /// Validates a raw record: deposits, withdrawals, authorizations and transfers must have a
+    /// non-negative amount, disputes, resolves and chargebacks may have a positive one and
+    /// captures a non-negative one, and voids
+    /// and administrative operations must not have any. Administrative operations must also have
+    /// an operator and a reason and no currency, and only transfers have a destination, which
+    /// must be another client.
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        let present = |value: &Option<String>| value.as_ref().is_some_and(|v| !v.is_empty());
+        if record.ty.is_administrative() && !(present(&record.operator) && present(&record.reason))
+        {
+            return Err(TransactionError::InvalidAdministrativeRecord(format!(
+                "Operator and reason are required for {:?} transaction {}",
+                record.ty, record.transaction_id
+            )));
+        }
+        if let (true, Some(currency)) = (record.ty.is_administrative(), record.currency) {
+            return Err(TransactionError::InvalidCurrency(format!(
+                "Unexpected currency {} for {:?} transaction {}",
+                currency, record.ty, record.transaction_id
+            )));
+        }
+        match (&record.ty, record.destination) {
+            (TransactionType::Transfer, Some(destination)) if destination != record.client_id => {}
+            (TransactionType::Transfer, _) => {
+                return Err(TransactionError::InvalidClient(format!(
+                    "Transfer {} needs a destination other than client {}",
+                    record.transaction_id, record.client_id
+                )));
+            }
+            (_, Some(destination)) => {
+                return Err(TransactionError::InvalidClient(format!(
+                    "Unexpected destination {} for {:?} transaction {}",
+                    destination, record.ty, record.transaction_id
+                )));
+            }
+            (_, None) => {}
+        }
+        let amount = match (&record.ty, record.amount) {
+            (
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Authorize
+                | TransactionType::Transfer,
+                Some(amount),
+            ) => Some(Amount::new(amount)?),
+            (
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Authorize
+                | TransactionType::Transfer,
+                None,
+            ) => {
+                return Err(TransactionError::InvalidTransactionAmount(format!(
+                    "Amount is missing for {:?} transaction {}",
+                    record.ty, record.transaction_id
+                )));
+            }
+            (
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback,
+                Some(amount),
+            ) if amount <= Decimal::ZERO => {
+                return Err(TransactionError::InvalidTransactionAmount(format!(
+                    "Amount {} of {:?} transaction {} is not positive",
+                    amount, record.ty, record.transaction_id
+                )));
+            }
+            (
+                TransactionType::Dispute
+                | TransactionType::Resolve
+                | TransactionType::Chargeback
+                | TransactionType::Capture,
+                amount,
+            ) => amount.map(Amount::new).transpose()?,
+            (_, Some(amount)) => {
+                return Err(TransactionError::InvalidTransactionAmount(format!(
+                    "Unexpected amount {} for {:?} transaction {}",
+                    amount, record.ty, record.transaction_id
+                )));
+            }
+            (_, None) => None,
+        };
+        Ok(Self {
+            ty: record.ty,
+            client_id: record.client_id,
+            transaction_id: record.transaction_id,
+            amount,
+            operator: record.operator,
+            reason: record.reason,
+            timestamp: record.timestamp,
+            destination: record.destination,
+            currency: record.currency,
+        })
+    }
 }
 
 impl Transaction {
@@ -75,75 +313,129 @@ impl Transaction {
 
     /// Returns the amount of the transaction.
     pub fn amount(&self) -> Option<Decimal> {
-        self.amount
+        self.amount.map(|amount| amount.value())
     }
 
-    /// Returns the amount of the transaction or an error if it is missing.
-    pub fn amount_or_err(&self, msg: &str) -> Result<Decimal, TransactionError> {
-        let amount = self
-            .amount()
-            .ok_or_else(|| TransactionError::InvalidTransactionAmount(msg.into()))?;
-        if amount < Decimal::ZERO {
-            return Err(TransactionError::InvalidTransactionAmount(
-                "Transaction amount is negative".into(),
-            ));
-        }
-        Ok(amount)
+    /// Returns the operator of an administrative operation.
+    pub fn operator(&self) -> Option<&str> {
+        self.operator.as_deref()
     }
-}
 
-#[derive(PartialEq, Clone, Debug)]
-#[cfg_attr(test, derive(Dummy))]
-enum TxStatus {
-    Depoit,
-    BeingDisputed,
-}
+    /// Returns the reason of an administrative operation.
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
 
-#[derive(PartialEq, Clone, Debug)]
-#[cfg_attr(test, derive(Dummy))]
-struct TxTrack {
-    amount: Decimal,
-    status: TxStatus,
-}
+    /// Returns the time of the transaction, if the input has it.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
 
-impl TxTrack {
-    fn new(amount: Decimal) -> Self {
-        Self {
-            amount,
-            status: TxStatus::Depoit,
-        }
+    /// Returns the client receiving the funds of a transfer.
+    pub fn destination(&self) -> Option<ClientId> {
+        self.destination
+    }
+
+    /// Returns the currency of the transaction, or none for the base currency.
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Returns the amount of the transaction or an error if it is missing.
+    pub fn amount_or_err(&self, msg: &str) -> Result<Decimal, TransactionError> {
+        self.amount()
+            .ok_or_else(|| TransactionError::InvalidTransactionAmount(msg.into()))
     }
 
-    fn being_disputed(&mut self) -> bool {
-        self.status == TxStatus::BeingDisputed
+    /// Returns the transaction with its amount adjusted to the precision `policy`, or an error
+    /// if the policy rejects it or the amount of a partial dispute, resolve or chargeback is
+    /// rounded down to zero.
+    pub fn with_precision(mut self, policy: &PrecisionPolicy) -> Result<Self, TransactionError> {
+        self.amount = self
+            .amount
+            .map(|amount| Amount::new(policy.apply(amount.value())?))
+            .transpose()?;
+        match (&self.ty, self.amount()) {
+            (
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback,
+                Some(amount),
+            ) if amount.is_zero() => Err(TransactionError::InvalidTransactionAmount(format!(
+                "Amount of {:?} transaction {} is zero at {} decimal places",
+                self.ty,
+                self.transaction_id,
+                policy.scale()
+            ))),
+            _ => Ok(self),
+        }
     }
 
-    fn amount(&self) -> Decimal {
-        self.amount
+    /// Returns the transaction with the `base` currency of the input left implicit, or an error
+    /// if it has a currency and the input has no base currency to tell the balances apart.
+    pub fn with_base_currency(mut self, base: Option<Currency>) -> Result<Self, TransactionError> {
+        match (self.currency, base) {
+            (Some(currency), Some(base)) if currency == base => self.currency = None,
+            (Some(currency), None) => {
+                return Err(TransactionError::InvalidCurrency(format!(
+                    "Currency {} of transaction {} needs a base currency",
+                    currency, self.transaction_id
+                )));
+            }
+            _ => {}
+        }
+        Ok(self)
     }
 }
 
 /// Represents the result of a transaction.
-#[derive(PartialEq, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 #[cfg_attr(test, derive(Dummy))]
 pub struct Account {
     client_id: ClientId,
     available: Decimal,
     held: Decimal,
     locked: bool,
-    previous_deposits: HashMap<TxId, TxTrack>,
+    #[serde(default)]
+    frozen: bool,
+    #[serde(default)]
+    closed: bool,
+    previous_deposits: TxTracker,
+    #[serde(default)]
+    authorizations: BTreeMap<TxId, Authorization>,
+    /// Balances in currencies other than the base one, which is kept in `available` and `held`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    balances: BTreeMap<Currency, Balance>,
+    /// Fees charged to the account in the base currency.
+    #[serde(default)]
+    fees: Decimal,
+    /// Transactions still counting for the withdrawal limits over time.
+    #[serde(
+        default,
+        alias = "recent_withdrawals",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    recent_transactions: Vec<RecentTransaction>,
+    /// Sorted ids of the deposits pruned from the history and of the authorizations captured,
+    /// voided or expired, still rejected as duplicates.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    retired_ids: Vec<TxId>,
+    /// Rates applied to convert the withdrawals and transfers in other currencies to the base
+    /// currency.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    applied_rates: BTreeMap<TxId, AppliedRate>,
 }
 
 impl fmt::Debug for Account {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "client: {}, available: {}, held: {}, total: {}, locked: {}",
+            "client: {}, available: {}, held: {}, total: {}, locked: {}, frozen: {}, closed: {}",
             self.client_id,
             self.available,
-            self.held,
-            self.available + self.held,
+            self.held(),
+            self.total(),
             self.locked,
+            self.frozen,
+            self.closed,
         )
     }
 }
@@ -156,7 +448,15 @@ impl Account {
             available: Decimal::ZERO,
             held: Decimal::ZERO,
             locked: false,
-            previous_deposits: HashMap::new(),
+            frozen: false,
+            closed: false,
+            previous_deposits: TxTracker::new(),
+            authorizations: BTreeMap::new(),
+            balances: BTreeMap::new(),
+            fees: Decimal::ZERO,
+            recent_transactions: Vec::new(),
+            retired_ids: Vec::new(),
+            applied_rates: BTreeMap::new(),
         }
     }
 
@@ -171,51 +471,281 @@ impl Account {
             available,
             held,
             locked,
-            previous_deposits: HashMap::new(),
+            frozen: false,
+            closed: false,
+            previous_deposits: TxTracker::new(),
+            authorizations: BTreeMap::new(),
+            balances: BTreeMap::new(),
+            fees: Decimal::ZERO,
+            recent_transactions: Vec::new(),
+            retired_ids: Vec::new(),
+            applied_rates: BTreeMap::new(),
         }
     }
 
     /// Processes a transaction and updates the transaction result accordingly.
     pub fn process(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
-        if self.locked {
+        self.process_with_policy(transaction, &AccountPolicy::default())
+            .map(|_| ())
+    }
+
+    /// Processes a transaction applying the limits of `policy`. All the balance arithmetic is
+    /// checked, so a transaction that would overflow a balance is rejected with
+    /// `TransactionError::Overflow` and leaves the account unchanged.
+    ///
+    /// Returns the fee charged by the fee schedule of `policy`, if any, which the caller must
+    /// credit to the house account with `collect_fee`: propagating the error with `?` and
+    /// discarding the fee leaves the funds charged to the client out of every account.
+    ///
+    /// Transfers involve two accounts and are rejected here, they are applied with
+    /// `transfer_with_journal`.
+    #[must_use = "the fee charged must be credited to the house account with `collect_fee`"]
+    pub(crate) fn process_with_policy(
+        &mut self,
+        transaction: &Transaction,
+        policy: &AccountPolicy,
+    ) -> Result<Option<FeeCharge>, TransactionError> {
+        self.process_with_journal(transaction, policy, &mut JournalEntry::new())
+    }
+
+    /// Processes a transaction like `process_with_policy`, adding the postings of the changes to
+    /// the balances to `entry`. The new balances of the account are derived from those postings.
+    /// Authorizations released because they expired are posted even if the transaction is
+    /// rejected.
+    ///
+    /// The fee charged, if any, is posted to the house account in `entry` but must still be
+    /// credited to it with `collect_fee`.
+    #[must_use = "the fee charged must be credited to the house account with `collect_fee`"]
+    pub fn process_with_journal(
+        &mut self,
+        transaction: &Transaction,
+        policy: &AccountPolicy,
+        entry: &mut JournalEntry,
+    ) -> Result<Option<FeeCharge>, TransactionError> {
+        if *transaction.ty() == TransactionType::Transfer {
+            return Err(TransactionError::IncompleteTransfer(transaction.clone()));
+        }
+        self.apply(transaction, policy, entry)
+    }
+
+    /// Applies a transfer from this account to `destination` following `policy`, adding the
+    /// postings of the changes to the balances of both accounts to `entry`. Either both accounts
+    /// are updated or, if the transfer is rejected, none of them: the destination is validated
+    /// before debiting the source, so crediting it afterwards cannot fail.
+    pub fn transfer_with_journal(
+        &mut self,
+        destination: &mut Account,
+        transaction: &Transaction,
+        policy: &AccountPolicy,
+        entry: &mut JournalEntry,
+    ) -> Result<(), TransactionError> {
+        if *transaction.ty() != TransactionType::Transfer
+            || transaction.destination() != Some(destination.client_id)
+        {
+            return Err(TransactionError::IncompleteTransfer(transaction.clone()));
+        }
+        destination.release_expired(transaction.timestamp(), policy, entry);
+        // Both accounts receive the amount already converted to the base currency.
+        let (_, rate) = self.currency_of(transaction, policy)?;
+        let converted = Self::converted(transaction, rate.as_ref(), None, policy)?;
+        let transaction = &*converted;
+        let credited = destination.credit(transaction, policy)?;
+        // Transfers are free, so no fee is charged here.
+        self.apply(transaction, policy, entry)?;
+        destination.set_balance(transaction.currency(), credited);
+        Ok(())
+    }
+
+    /// Returns the balance of the account in the currency of a transfer after receiving its
+    /// funds, or the reason the account cannot receive them.
+    fn credit(
+        &self,
+        transaction: &Transaction,
+        policy: &AccountPolicy,
+    ) -> Result<Balance, TransactionError> {
+        if self.closed {
+            return Err(TransactionError::AccountClosed(transaction.clone()));
+        }
+        if self.locked && !policy.allowed_when_locked(transaction.ty()) {
             return Err(TransactionError::AccountLocked(transaction.clone()));
         }
+        if self.frozen {
+            return Err(TransactionError::AccountFrozen(transaction.clone()));
+        }
+        let amount = transaction.amount_or_err("Transfer amount is missing")?;
+        let currency = transaction.currency();
+        let mut posted = JournalEntry::new();
+        posted.post(
+            LedgerAccount::Available(self.client_id),
+            LedgerAccount::Available(transaction.client_id()),
+            currency,
+            amount,
+        );
+        let balance = checked(self.posted_balance(currency, &posted), transaction)?;
+        let held = balance.held + self.held_by_authorizations_in(currency);
+        let total = checked(balance.available.checked_add(held), transaction)?;
+        if policy.exceeds_balance(total) {
+            return Err(TransactionError::BalanceExceedsLimit(transaction.clone()));
+        }
+        Ok(balance)
+    }
+
+    fn apply(
+        &mut self,
+        transaction: &Transaction,
+        policy: &AccountPolicy,
+        entry: &mut JournalEntry,
+    ) -> Result<Option<FeeCharge>, TransactionError> {
+        self.release_expired(transaction.timestamp(), policy, entry);
+        if self.closed {
+            return Err(TransactionError::AccountClosed(transaction.clone()));
+        }
+        if self.locked && !policy.allowed_when_locked(transaction.ty()) {
+            return Err(TransactionError::AccountLocked(transaction.clone()));
+        }
+        if self.frozen && !transaction.ty().is_administrative() {
+            return Err(TransactionError::AccountFrozen(transaction.clone()));
+        }
+        let (currency, rate) = self.currency_of(transaction, policy)?;
+        let remaining = self.remaining_of(transaction);
+        let converted = Self::converted(transaction, rate.as_ref(), remaining, policy)?;
+        let transaction = &*converted;
+        let mut balance = self.balance(currency);
+        let mut fee = Decimal::ZERO;
+        // Transaction to count for the limits of the client, once it is accepted.
+        let mut limited = None;
+        // Postings of the transaction, only added to `entry` once it is accepted.
+        let mut posted = JournalEntry::new();
+        let available_funds = LedgerAccount::Available(self.client_id);
+        let held_funds = LedgerAccount::Held(self.client_id);
+        let authorized_funds = LedgerAccount::Authorized(self.client_id);
         match transaction.ty() {
             TransactionType::Deposit => {
                 let amount = transaction.amount_or_err("Deposit amount is missing")?;
                 if self.exists(transaction) {
                     return Err(TransactionError::DuplicateTransaction(transaction.clone()));
                 }
-                self.available += amount;
-                self.previous_deposits
-                    .insert(transaction.transaction_id(), TxTrack::new(amount));
+                if policy.exceeds_transaction_amount(amount) {
+                    return Err(TransactionError::TransactionAmountExceedsLimit(
+                        transaction.clone(),
+                    ));
+                }
+                if let Some(limits) = policy.withdrawal_limits(self.client_id) {
+                    let deposit =
+                        RecentTransaction::deposit(self.transaction_time(transaction), currency);
+                    limits.check_count(
+                        transaction,
+                        deposit.timestamp,
+                        &self.recent_transactions,
+                    )?;
+                    limited = Some((limits, deposit));
+                }
+                fee = self.fee(transaction, currency, amount, policy)?;
+                posted.post(available_funds, LedgerAccount::External, currency, amount);
+                self.post_fee(&mut posted, currency, fee, policy);
+                balance = self.charged_balance(currency, &posted, fee, transaction)?;
+                if fee > Decimal::ZERO && !policy.allows_available(balance.available) {
+                    return Err(TransactionError::InsufficientFundsForFee(
+                        transaction.clone(),
+                    ));
+                }
+                let held = balance.held + self.held_by_authorizations_in(currency);
+                let total = checked(balance.available.checked_add(held), transaction)?;
+                if policy.exceeds_balance(total) {
+                    return Err(TransactionError::BalanceExceedsLimit(transaction.clone()));
+                }
+                self.previous_deposits.insert(
+                    transaction.transaction_id(),
+                    TxTrack::new(amount)
+                        .with_timestamp(transaction.timestamp())
+                        .with_currency(currency)
+                        .with_applied_rate(rate),
+                );
             }
-            TransactionType::Withdrawal => {
+            TransactionType::Withdrawal | TransactionType::Transfer => {
                 let amount = transaction.amount_or_err("Withdrawal amount is missing")?;
                 if self.exists(transaction) {
                     return Err(TransactionError::DuplicateTransaction(transaction.clone()));
                 }
-                if self.available >= amount {
-                    self.available -= amount;
-                } else {
+                if policy.exceeds_transaction_amount(amount) {
+                    return Err(TransactionError::TransactionAmountExceedsLimit(
+                        transaction.clone(),
+                    ));
+                }
+                if let Some(limits) = policy.withdrawal_limits(self.client_id) {
+                    let withdrawal = RecentTransaction::withdrawal(
+                        self.transaction_time(transaction),
+                        currency,
+                        amount,
+                    );
+                    limits.check(
+                        transaction,
+                        currency,
+                        amount,
+                        withdrawal.timestamp,
+                        &self.recent_transactions,
+                    )?;
+                    limited = Some((limits, withdrawal));
+                }
+                if balance.available < amount {
                     return Err(TransactionError::InsufficientFunds(transaction.clone()));
                 }
+                fee = self.fee(transaction, currency, amount, policy)?;
+                let credited = match transaction.destination() {
+                    Some(destination) => LedgerAccount::Available(destination),
+                    None => LedgerAccount::External,
+                };
+                posted.post(credited, available_funds, currency, amount);
+                self.post_fee(&mut posted, currency, fee, policy);
+                balance = self.charged_balance(currency, &posted, fee, transaction)?;
+                if fee > Decimal::ZERO && !policy.allows_available(balance.available) {
+                    return Err(TransactionError::InsufficientFundsForFee(
+                        transaction.clone(),
+                    ));
+                }
             }
             TransactionType::Dispute => {
-                if let Some(ref mut tx) = self
-                    .previous_deposits
-                    .get_mut(&transaction.transaction_id())
-                {
-                    if tx.being_disputed() {
+                if let Some(tx) = self.previous_deposits.get(transaction.transaction_id()) {
+                    if tx.being_disputed() && tx.undisputed() <= Decimal::ZERO {
                         return Err(TransactionError::TransactionBeingDisputed(
                             transaction.clone(),
                         ));
                     }
-                    let amount = tx.amount();
-                    if self.available >= amount {
-                        self.available -= amount;
-                        self.held += amount;
-                        tx.status = TxStatus::BeingDisputed;
+                    if tx.charged_back() && tx.undisputed() <= Decimal::ZERO {
+                        return Err(TransactionError::TransactionChargedBack(
+                            transaction.clone(),
+                        ));
+                    }
+                    let history = tx.history();
+                    if history.chargebacks() > 0 && !policy.dispute_after_chargeback() {
+                        return Err(TransactionError::DisputeAfterChargeback(
+                            transaction.clone(),
+                        ));
+                    }
+                    if policy.dispute_window_expired(tx.timestamp(), transaction.timestamp()) {
+                        return Err(TransactionError::DisputeWindowExpired(transaction.clone()));
+                    }
+                    if !tx.being_disputed() {
+                        if let Some(max) = policy.exceeds_disputes(history.disputes()) {
+                            return Err(TransactionError::DisputeLimitExceeded(
+                                max,
+                                transaction.clone(),
+                            ));
+                        }
+                    }
+                    let amount = transaction.amount().unwrap_or(tx.undisputed());
+                    if amount > tx.undisputed() {
+                        return Err(TransactionError::InconsistenceBalance(
+                            "Attempt to dispute more than the undisputed amount".into(),
+                            transaction.clone(),
+                        ));
+                    }
+                    posted.post(held_funds, available_funds, currency, amount);
+                    let disputed = checked(self.posted_balance(currency, &posted), transaction)?;
+                    if policy.allows_available(disputed.available) {
+                        balance = disputed;
+                        self.previous_deposits
+                            .insert(transaction.transaction_id(), tx.dispute(amount));
                     } else {
                         return Err(TransactionError::InconsistenceBalance(
                             "Attempt to dispute more than available".into(),
@@ -229,20 +759,24 @@ impl Account {
                 }
             }
             TransactionType::Resolve => {
-                if let Some(tx) = self
-                    .previous_deposits
-                    .get_mut(&transaction.transaction_id())
-                {
+                if let Some(tx) = self.previous_deposits.get(transaction.transaction_id()) {
                     if !tx.being_disputed() {
                         return Err(TransactionError::CannotResolveWithoutDispute(
                             transaction.clone(),
                         ));
                     }
-                    let amount = tx.amount();
-                    if self.held >= amount {
-                        self.available += amount;
-                        self.held -= amount;
-                        tx.status = TxStatus::Depoit;
+                    let amount = transaction.amount().unwrap_or(tx.disputed());
+                    if amount > tx.disputed() {
+                        return Err(TransactionError::InconsistenceBalance(
+                            "Attempt to resolve more than disputed".into(),
+                            transaction.clone(),
+                        ));
+                    }
+                    if balance.held >= amount {
+                        posted.post(available_funds, held_funds, currency, amount);
+                        balance = checked(self.posted_balance(currency, &posted), transaction)?;
+                        self.previous_deposits
+                            .insert(transaction.transaction_id(), tx.resolve(amount));
                     } else {
                         return Err(TransactionError::InconsistenceBalance(
                             "Attempt to resolve more than held".into(),
@@ -252,19 +786,32 @@ impl Account {
                 }
             }
             TransactionType::Chargeback => {
-                if let Some(ref mut tx) = self
-                    .previous_deposits
-                    .get_mut(&transaction.transaction_id())
-                {
+                if let Some(tx) = self.previous_deposits.get(transaction.transaction_id()) {
                     if !tx.being_disputed() {
                         return Err(TransactionError::CannotChargebackWithoutDispute(
                             transaction.clone(),
                         ));
                     }
-                    let amount = tx.amount();
-                    if self.held >= amount {
-                        self.held -= amount;
-                        self.locked = true;
+                    let amount = transaction.amount().unwrap_or(tx.disputed());
+                    if amount > tx.disputed() {
+                        return Err(TransactionError::InconsistenceBalance(
+                            "Attempt to chargeback more than disputed".into(),
+                            transaction.clone(),
+                        ));
+                    }
+                    if balance.held >= amount {
+                        // The chargeback cannot be refused, so the fee is reduced to what the
+                        // available funds can pay instead.
+                        fee = policy.chargeable(
+                            balance.available,
+                            self.fee(transaction, currency, amount, policy)?,
+                        );
+                        posted.post(LedgerAccount::External, held_funds, currency, amount);
+                        self.post_fee(&mut posted, currency, fee, policy);
+                        balance = self.charged_balance(currency, &posted, fee, transaction)?;
+                        self.locked |= policy.lock_on_chargeback();
+                        self.previous_deposits
+                            .insert(transaction.transaction_id(), tx.chargeback(amount));
                     } else {
                         return Err(TransactionError::InconsistenceBalance(
                             "Attempt to chargeback more than held".into(),
@@ -273,15 +820,585 @@ impl Account {
                     }
                 }
             }
+            TransactionType::Unlock => {
+                if !self.locked {
+                    return Err(TransactionError::InvalidAdministrativeOperation(
+                        "Account is not locked".into(),
+                        transaction.clone(),
+                    ));
+                }
+                self.locked = false;
+            }
+            TransactionType::Freeze => {
+                if self.frozen {
+                    return Err(TransactionError::InvalidAdministrativeOperation(
+                        "Account is already frozen".into(),
+                        transaction.clone(),
+                    ));
+                }
+                self.frozen = true;
+            }
+            TransactionType::Unfreeze => {
+                if !self.frozen {
+                    return Err(TransactionError::InvalidAdministrativeOperation(
+                        "Account is not frozen".into(),
+                        transaction.clone(),
+                    ));
+                }
+                self.frozen = false;
+            }
+            TransactionType::Authorize => {
+                let amount = transaction.amount_or_err("Authorization amount is missing")?;
+                if self.exists(transaction) {
+                    return Err(TransactionError::DuplicateTransaction(transaction.clone()));
+                }
+                if policy.exceeds_transaction_amount(amount) {
+                    return Err(TransactionError::TransactionAmountExceedsLimit(
+                        transaction.clone(),
+                    ));
+                }
+                if balance.available < amount {
+                    return Err(TransactionError::InsufficientFunds(transaction.clone()));
+                }
+                posted.post(authorized_funds, available_funds, currency, amount);
+                balance = checked(self.posted_balance(currency, &posted), transaction)?;
+                self.authorizations.insert(
+                    transaction.transaction_id(),
+                    Authorization::new(amount, transaction.timestamp())
+                        .with_currency(currency)
+                        .with_applied_rate(rate),
+                );
+            }
+            TransactionType::Capture => {
+                let authorization = self
+                    .authorizations
+                    .get(&transaction.transaction_id())
+                    .copied()
+                    .ok_or_else(|| TransactionError::UnknownAuthorization(transaction.clone()))?;
+                let captured = transaction.amount().unwrap_or(authorization.amount());
+                if captured > authorization.amount() {
+                    return Err(TransactionError::InconsistenceBalance(
+                        "Attempt to capture more than authorized".into(),
+                        transaction.clone(),
+                    ));
+                }
+                let released = authorization.amount() - captured;
+                posted.post(
+                    LedgerAccount::External,
+                    authorized_funds,
+                    currency,
+                    captured,
+                );
+                posted.post(available_funds, authorized_funds, currency, released);
+                balance = checked(self.posted_balance(currency, &posted), transaction)?;
+                self.authorizations.remove(&transaction.transaction_id());
+                self.retire_id(transaction.transaction_id());
+            }
+            TransactionType::Void => {
+                let authorization = self
+                    .authorizations
+                    .get(&transaction.transaction_id())
+                    .copied()
+                    .ok_or_else(|| TransactionError::UnknownAuthorization(transaction.clone()))?;
+                posted.post(
+                    available_funds,
+                    authorized_funds,
+                    currency,
+                    authorization.amount(),
+                );
+                balance = checked(self.posted_balance(currency, &posted), transaction)?;
+                self.authorizations.remove(&transaction.transaction_id());
+                self.retire_id(transaction.transaction_id());
+            }
+            TransactionType::Close => {
+                let balances = self.currency_balances().collect::<Vec<_>>();
+                if balances.iter().any(|(currency, balance)| {
+                    balance.held + self.held_by_authorizations_in(*currency) > Decimal::ZERO
+                }) {
+                    return Err(TransactionError::InvalidAdministrativeOperation(
+                        "Account has held funds under dispute or authorization".into(),
+                        transaction.clone(),
+                    ));
+                }
+                if balances
+                    .iter()
+                    .any(|(_, balance)| balance.available < Decimal::ZERO)
+                {
+                    return Err(TransactionError::InvalidAdministrativeOperation(
+                        "Account has a negative balance".into(),
+                        transaction.clone(),
+                    ));
+                }
+                // The funds of a closed account could never be withdrawn.
+                if balances
+                    .iter()
+                    .any(|(_, balance)| balance.available > Decimal::ZERO)
+                {
+                    return Err(TransactionError::InvalidAdministrativeOperation(
+                        "Account has available funds".into(),
+                        transaction.clone(),
+                    ));
+                }
+                self.closed = true;
+            }
+        }
+        let charge = if fee > Decimal::ZERO {
+            info!(
+                "Charging fee {} to client {} for {:?} transaction {}",
+                fee,
+                self.client_id,
+                transaction.ty(),
+                transaction.transaction_id()
+            );
+            Some(FeeCharge {
+                currency,
+                amount: fee,
+            })
+        } else {
+            None
+        };
+        if balance != self.balance(currency) {
+            self.set_balance(currency, balance);
+        }
+        if let Some((limits, recent)) = limited {
+            limits.record(&mut self.recent_transactions, recent);
+        }
+        if let (TransactionType::Withdrawal | TransactionType::Transfer, Some(rate)) =
+            (transaction.ty(), rate)
+        {
+            self.applied_rates
+                .insert(transaction.transaction_id(), rate);
+        }
+        entry.extend(posted);
+        Ok(charge)
+    }
+
+    /// Returns the fee the fee schedule of `policy` charges for `amount` of `transaction` on the
+    /// balances in `currency`, or zero for the house account, which is never charged. Flat fees
+    /// are in the base currency, so the transactions in other currencies they would apply to are
+    /// rejected.
+    fn fee(
+        &self,
+        transaction: &Transaction,
+        currency: Option<Currency>,
+        amount: Decimal,
+        policy: &AccountPolicy,
+    ) -> Result<Decimal, TransactionError> {
+        match policy.fees() {
+            Some(fees) if fees.house() != self.client_id => {
+                if currency.is_some() && fees.requires_base_currency(transaction.ty()) {
+                    return Err(TransactionError::FlatFeeInOtherCurrency(
+                        transaction.clone(),
+                    ));
+                }
+                checked(
+                    fees.fee(
+                        transaction.ty(),
+                        currency,
+                        amount,
+                        policy.precision().scale(),
+                    ),
+                    transaction,
+                )
+            }
+            _ => Ok(Decimal::ZERO),
+        }
+    }
+
+    /// Posts the `fee` charged in `currency` from the available funds of the account to the ones
+    /// of the house account of `policy`. Zero fees are not posted.
+    fn post_fee(
+        &self,
+        posted: &mut JournalEntry,
+        currency: Option<Currency>,
+        fee: Decimal,
+        policy: &AccountPolicy,
+    ) {
+        if let Some(fees) = policy.fees() {
+            posted.post(
+                LedgerAccount::Available(fees.house()),
+                LedgerAccount::Available(self.client_id),
+                currency,
+                fee,
+            );
         }
-        Ok(())
+    }
+
+    /// Returns the balance of the account in `currency` derived from the postings of `posted` to
+    /// its available and held funds, or none if a sum overflows. The funds held by authorizations
+    /// are kept with each authorization instead.
+    fn posted_balance(&self, currency: Option<Currency>, posted: &JournalEntry) -> Option<Balance> {
+        let mut balance = self.balance(currency);
+        for posting in posted
+            .postings()
+            .iter()
+            .filter(|posting| posting.currency == currency)
+        {
+            let funds = match posting.account {
+                LedgerAccount::Available(client) if client == self.client_id => {
+                    &mut balance.available
+                }
+                LedgerAccount::Held(client) if client == self.client_id => &mut balance.held,
+                _ => continue,
+            };
+            *funds = funds.checked_add(posting.amount)?;
+        }
+        Some(balance)
+    }
+
+    /// Returns the balance of the account in `currency` derived from the postings of `posted`,
+    /// with the `fee` charged by `transaction` added to the fees of the account. Both sums are
+    /// checked before anything of the account is changed.
+    fn charged_balance(
+        &self,
+        currency: Option<Currency>,
+        posted: &JournalEntry,
+        fee: Decimal,
+        transaction: &Transaction,
+    ) -> Result<Balance, TransactionError> {
+        let mut balance = checked(self.posted_balance(currency, posted), transaction)?;
+        balance.fees = checked(balance.fees.checked_add(fee), transaction)?;
+        Ok(balance)
+    }
+
+    /// Credits a fee charged to another account to the available funds of this house account.
+    /// The fees are never larger than the amounts of the transactions charged, so the addition
+    /// saturates rather than rejecting a transaction already applied to the other account.
+    pub fn collect_fee(&mut self, charge: &FeeCharge) {
+        let mut balance = self.balance(charge.currency);
+        balance.available = balance.available.saturating_add(charge.amount);
+        self.set_balance(charge.currency, balance);
+    }
+
+    /// Returns the currency of the balances `transaction` applies to, or none for the base
+    /// currency, and the rate its amount is converted to the base currency with, if any.
+    /// Disputes, resolves and chargebacks apply to the currency of the deposit and captures and
+    /// voids to the one of the authorization, at the rate they were converted with, so a
+    /// currency given by the transaction must match the original one. Other transactions in a
+    /// currency are converted at the rate effective at their time when `policy` converts them.
+    fn currency_of(
+        &self,
+        transaction: &Transaction,
+        policy: &AccountPolicy,
+    ) -> Result<(Option<Currency>, Option<AppliedRate>), TransactionError> {
+        let tx_id = transaction.transaction_id();
+        let original = match transaction.ty() {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                self.previous_deposits
+                    .get(tx_id)
+                    .map(|track| (track.currency(), track.applied_rate()))
+            }
+            TransactionType::Capture | TransactionType::Void => self
+                .authorizations
+                .get(&tx_id)
+                .map(|authorization| (authorization.currency(), authorization.applied_rate())),
+            _ => {
+                return match (transaction.currency(), policy.conversion()) {
+                    (Some(currency), Some(conversion)) => conversion
+                        .rate(currency, transaction.timestamp())
+                        .map(|rate| (None, Some(rate)))
+                        .ok_or_else(|| TransactionError::MissingExchangeRate(transaction.clone())),
+                    (currency, _) => Ok((currency, None)),
+                }
+            }
+        };
+        match (original, transaction.currency()) {
+            (Some((currency, rate)), Some(given))
+                if rate.map_or(currency, |rate| Some(rate.currency)) != Some(given) =>
+            {
+                Err(TransactionError::CurrencyMismatch(transaction.clone()))
+            }
+            (Some(original), _) => Ok(original),
+            (None, currency) => Ok((currency, None)),
+        }
+    }
+
+    /// Returns the converted amount left of the deposit or the authorization `transaction`
+    /// takes a part of, if any.
+    fn remaining_of(&self, transaction: &Transaction) -> Option<Decimal> {
+        let tx_id = transaction.transaction_id();
+        match transaction.ty() {
+            TransactionType::Dispute => self.previous_deposits.get(tx_id).map(|tx| tx.undisputed()),
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                self.previous_deposits.get(tx_id).map(|tx| tx.disputed())
+            }
+            TransactionType::Capture => self
+                .authorizations
+                .get(&tx_id)
+                .map(|authorization| authorization.amount()),
+            _ => None,
+        }
+    }
+
+    /// Returns `transaction` with its amount converted to the base currency with `rate`, or
+    /// unchanged without a rate. The amount of a part of a deposit or an authorization is
+    /// converted out of the `remaining` amount of it.
+    fn converted<'a>(
+        transaction: &'a Transaction,
+        rate: Option<&AppliedRate>,
+        remaining: Option<Decimal>,
+        policy: &AccountPolicy,
+    ) -> Result<Cow<'a, Transaction>, TransactionError> {
+        let Some(rate) = rate else {
+            return Ok(Cow::Borrowed(transaction));
+        };
+        let amount = transaction
+            .amount
+            .map(|amount| Amount::new(policy.convert(amount.value(), rate, remaining)?))
+            .transpose()?;
+        info!(
+            "Converting {:?} transaction {} of client {} from {} at rate {}",
+            transaction.ty(),
+            transaction.transaction_id(),
+            transaction.client_id(),
+            rate.currency,
+            rate.rate
+        );
+        Ok(Cow::Owned(Transaction {
+            amount,
+            currency: None,
+            ..transaction.clone()
+        }))
+    }
+
+    /// Returns the balances of the account in `currency`, or in the base currency if none.
+    pub(crate) fn balance(&self, currency: Option<Currency>) -> Balance {
+        match currency {
+            Some(currency) => self.balances.get(&currency).copied().unwrap_or_default(),
+            None => Balance {
+                available: self.available,
+                held: self.held,
+                fees: self.fees,
+            },
+        }
+    }
+
+    fn set_balance(&mut self, currency: Option<Currency>, balance: Balance) {
+        match currency {
+            Some(currency) => {
+                self.balances.insert(currency, balance);
+            }
+            None => {
+                self.available = balance.available;
+                self.held = balance.held;
+                self.fees = balance.fees;
+            }
+        }
+    }
+
+    /// Returns the balances of the account in every currency, starting with the base one.
+    pub(crate) fn currency_balances(
+        &self,
+    ) -> impl Iterator<Item = (Option<Currency>, Balance)> + '_ {
+        std::iter::once((None, self.balance(None))).chain(
+            self.balances
+                .iter()
+                .map(|(currency, balance)| (Some(*currency), *balance)),
+        )
+    }
+
+    /// Returns the time of a transaction for the withdrawal limits: its timestamp, or the time of
+    /// the latest transaction of the account counting for them if it has none.
+    fn transaction_time(&self, transaction: &Transaction) -> Timestamp {
+        transaction
+            .timestamp()
+            .or_else(|| {
+                self.recent_transactions
+                    .iter()
+                    .map(|recent| recent.timestamp)
+                    .max()
+            })
+            .unwrap_or_default()
     }
 
     // Verify if the transaction was already processed with same id and type
     fn exists(&self, transaction: &Transaction) -> bool {
         self.previous_deposits
-            .get(&transaction.transaction_id())
-            .is_some()
+            .contains(transaction.transaction_id())
+            || self
+                .authorizations
+                .contains_key(&transaction.transaction_id())
+            || self
+                .retired_ids
+                .binary_search(&transaction.transaction_id())
+                .is_ok()
+    }
+
+    /// Keeps the id of a transaction no longer tracked to reject its duplicates.
+    fn retire_id(&mut self, tx_id: TxId) {
+        if let Err(position) = self.retired_ids.binary_search(&tx_id) {
+            self.retired_ids.insert(position, tx_id);
+        }
+    }
+
+    /// Returns the ids of the transactions no longer tracked, still rejected as duplicates.
+    pub(crate) fn retired_ids(&self) -> &[TxId] {
+        &self.retired_ids
+    }
+
+    /// Returns the rates applied to convert the withdrawals and transfers in other currencies.
+    pub(crate) fn applied_rates(&self) -> &BTreeMap<TxId, AppliedRate> {
+        &self.applied_rates
+    }
+
+    /// Restores the id of a transaction no longer tracked, for an account rebuilt from storage.
+    pub(crate) fn restore_retired(&mut self, tx_id: TxId) {
+        self.retire_id(tx_id);
+    }
+
+    /// Keeps `ids` of transactions no longer tracked to reject their duplicates.
+    fn retire(&mut self, ids: impl IntoIterator<Item = TxId>) {
+        self.retired_ids.extend(ids);
+        self.retired_ids.sort_unstable();
+        self.retired_ids.dedup();
+        self.retired_ids.shrink_to_fit();
+    }
+
+    /// Releases the authorizations that expired at `now` according to `policy`, posting the
+    /// funds released to `entry`.
+    fn release_expired(
+        &mut self,
+        now: Option<Timestamp>,
+        policy: &AccountPolicy,
+        entry: &mut JournalEntry,
+    ) {
+        let expired = self
+            .authorizations
+            .iter()
+            .filter(|(_, authorization)| {
+                policy.authorization_expired(authorization.timestamp(), now)
+            })
+            .map(|(tx_id, _)| *tx_id)
+            .collect::<Vec<_>>();
+        for tx_id in expired {
+            if let Some(authorization) = self.authorizations.remove(&tx_id) {
+                self.retire_id(tx_id);
+                let mut released = JournalEntry::new();
+                released.post(
+                    LedgerAccount::Available(self.client_id),
+                    LedgerAccount::Authorized(self.client_id),
+                    authorization.currency(),
+                    authorization.amount(),
+                );
+                // The amount was taken from the available funds, so adding it back cannot
+                // overflow.
+                if let Some(balance) = self.posted_balance(authorization.currency(), &released) {
+                    self.set_balance(authorization.currency(), balance);
+                }
+                entry.extend(released);
+                info!(
+                    "Authorization {} of client {} expired, releasing {}",
+                    tx_id,
+                    self.client_id,
+                    authorization.amount()
+                );
+            }
+        }
+    }
+
+    /// Returns the open authorizations of the account.
+    pub(crate) fn authorizations(&self) -> impl Iterator<Item = (TxId, Authorization)> + '_ {
+        self.authorizations
+            .iter()
+            .map(|(tx_id, authorization)| (*tx_id, *authorization))
+    }
+
+    /// Adds an open authorization, used when an account is rebuilt from storage.
+    pub(crate) fn restore_authorization(&mut self, tx_id: TxId, authorization: Authorization) {
+        self.authorizations.insert(tx_id, authorization);
+    }
+
+    /// Returns the tracking record of a previous deposit, if any.
+    pub(crate) fn tracked(&self, tx_id: TxId) -> Option<TxTrack> {
+        self.previous_deposits.get(tx_id)
+    }
+
+    /// Adds a tracking record of a previous deposit, used when an account is rebuilt from storage.
+    pub(crate) fn track(&mut self, tx_id: TxId, track: TxTrack) {
+        self.previous_deposits.insert(tx_id, track);
+    }
+
+    /// Returns the transactions still counting for the withdrawal limits.
+    pub(crate) fn recent_transactions(&self) -> &[RecentTransaction] {
+        &self.recent_transactions
+    }
+
+    /// Adds transactions counting for the withdrawal limits, used when an account is rebuilt
+    /// from storage.
+    pub(crate) fn restore_recent(&mut self, recent: RecentTransaction) {
+        self.recent_transactions.push(recent);
+    }
+
+    /// Returns the number of deposits tracked in memory.
+    pub(crate) fn tracked_len(&self) -> usize {
+        self.previous_deposits.len()
+    }
+
+    /// Returns the amounts under dispute of the deposits tracked in memory, by currency.
+    pub(crate) fn open_disputes(&self) -> BTreeMap<Option<Currency>, Decimal> {
+        let mut disputes = BTreeMap::new();
+        for (_, track) in self.previous_deposits.iter() {
+            if track.being_disputed() {
+                *disputes.entry(track.currency()).or_default() += track.disputed();
+            }
+        }
+        disputes
+    }
+
+    /// Removes the tracked deposits of `ids` that are not being disputed and returns them, so
+    /// they can be kept somewhere else while they are not needed.
+    pub(crate) fn evict_undisputed(&mut self, ids: &BTreeSet<TxId>) -> Vec<(TxId, TxTrack)> {
+        self.previous_deposits.evict_undisputed(ids)
+    }
+
+    /// Returns the ids of the deposits tracked in memory.
+    pub(crate) fn tracked_ids(&self) -> impl Iterator<Item = TxId> + '_ {
+        self.previous_deposits.iter().map(|(tx_id, _)| tx_id)
+    }
+
+    /// Returns the highest transaction id tracked by the account.
+    pub(crate) fn last_tracked(&self) -> Option<TxId> {
+        self.previous_deposits.last_id()
+    }
+
+    /// Drops the tracked deposits that `policy` considers no longer needed, where `latest` is
+    /// the latest transaction id seen, and reports how much was dropped.
+    pub(crate) fn prune(&mut self, policy: &RetentionPolicy, latest: TxId) -> PruneReport {
+        let before = self.history_size();
+        let mut pruned = Vec::new();
+        let entries = if self.locked && policy.locked_accounts() {
+            pruned.extend(self.tracked_ids());
+            self.previous_deposits = TxTracker::new();
+            pruned.len()
+        } else {
+            let window_start = policy.window_start(latest);
+            self.previous_deposits.retain(|tx_id, track| {
+                // Deposits partially charged back can still be disputed for the rest.
+                let keep = if track.charged_back() && track.undisputed() <= Decimal::ZERO {
+                    !policy.charged_back()
+                } else {
+                    track.being_disputed() || !matches!(window_start, Some(start) if tx_id < start)
+                };
+                if !keep {
+                    pruned.push(tx_id);
+                }
+                keep
+            })
+        };
+        if !pruned.is_empty() {
+            self.retire(pruned);
+        }
+        PruneReport {
+            accounts: usize::from(entries > 0),
+            entries,
+            bytes: before.saturating_sub(self.history_size()),
+        }
+    }
+
+    // Heap size of the tracked deposits and of the ids kept after pruning them.
+    fn history_size(&self) -> usize {
+        self.previous_deposits.heap_size()
+            + self.retired_ids.capacity() * std::mem::size_of::<TxId>()
     }
 
     /// Returns the client ID associated with the transaction result.
@@ -289,31 +1406,240 @@ impl Account {
         self.client_id
     }
 
-    /// Returns the available amount in the transaction result.
+    /// Returns the available amount in the transaction result, in the base currency.
     pub fn available(&self) -> Decimal {
         self.available
     }
 
-    /// Returns the held amount in the transaction result.
+    /// Returns the held amount in the transaction result, both by disputes and authorizations,
+    /// in the base currency.
     pub fn held(&self) -> Decimal {
+        self.held + self.held_by_authorizations()
+    }
+
+    /// Returns the amount held by open disputes.
+    pub fn held_by_disputes(&self) -> Decimal {
         self.held
     }
 
-    /// Returns the total amount in the transaction result.
-    pub fn total(&self) -> Decimal {
-        self.held + self.available
+    /// Returns the amount held by open authorizations.
+    pub fn held_by_authorizations(&self) -> Decimal {
+        self.held_by_authorizations_in(None)
+    }
+
+    pub(crate) fn held_by_authorizations_in(&self, currency: Option<Currency>) -> Decimal {
+        self.authorizations
+            .values()
+            .filter(|authorization| authorization.currency() == currency)
+            .map(|authorization| authorization.amount())
+            .sum()
+    }
+
+    /// Returns the total amount in the transaction result, in the base currency.
+    pub fn total(&self) -> Decimal {
+        self.held() + self.available
+    }
+
+    /// Returns the fees charged to the account, in the base currency.
+    pub fn fees(&self) -> Decimal {
+        self.fees
+    }
+
+    /// Checks if the transaction result is locked.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Checks if the account was frozen by an administrative operation.
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    /// Checks if the account was closed by an administrative operation.
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Returns the balances of the account in currencies other than the base one.
+    pub(crate) fn balances(&self) -> impl Iterator<Item = (Currency, Balance)> + '_ {
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (*currency, *balance))
+    }
+
+    /// Adds the balance in a currency other than the base one, used when an account is rebuilt
+    /// from storage.
+    pub(crate) fn restore_balance(&mut self, currency: Currency, balance: Balance) {
+        self.balances.insert(currency, balance);
+    }
+
+    /// Returns a summary of the balances of the account in every currency, with `scale` decimal
+    /// places. The base currency is left out when the account only has funds in other
+    /// currencies.
+    pub fn summaries(&self, scale: u32) -> Vec<TransactionResultSummary> {
+        self.currency_balances()
+            .filter(|(currency, balance)| {
+                currency.is_some()
+                    || self.balances.is_empty()
+                    || *balance != Balance::default()
+                    || self.held_by_authorizations_in(None) != Decimal::ZERO
+            })
+            .map(|(currency, balance)| {
+                let held_by_authorizations = self.held_by_authorizations_in(currency);
+                let held = balance.held + held_by_authorizations;
+                TransactionResultSummary {
+                    client: self.client_id,
+                    currency,
+                    available: balance.available,
+                    held,
+                    total: held + balance.available,
+                    locked: self.locked,
+                    held_by_authorizations,
+                    fees: balance.fees,
+                    frozen: self.frozen,
+                    closed: self.closed,
+                }
+                .with_scale(scale)
+            })
+            .collect()
+    }
+
+    /// Restores the administrative status of an account rebuilt from storage.
+    pub(crate) fn with_status(mut self, frozen: bool, closed: bool) -> Self {
+        self.frozen = frozen;
+        self.closed = closed;
+        self
+    }
+
+    /// Returns the entry posting the opening balances of the account from the external funds.
+    pub(crate) fn opening_entry(&self) -> JournalEntry {
+        let mut entry = JournalEntry::new();
+        for (currency, balance) in self.currency_balances() {
+            entry.post(
+                LedgerAccount::Available(self.client_id),
+                LedgerAccount::External,
+                currency,
+                balance.available,
+            );
+            entry.post(
+                LedgerAccount::Held(self.client_id),
+                LedgerAccount::External,
+                currency,
+                balance.held,
+            );
+            entry.post(
+                LedgerAccount::Authorized(self.client_id),
+                LedgerAccount::External,
+                currency,
+                self.held_by_authorizations_in(currency),
+            );
+        }
+        entry
+    }
+
+    /// Restores the fees charged to an account rebuilt from storage.
+    pub(crate) fn with_fees(mut self, fees: Decimal) -> Self {
+        self.fees = fees;
+        self
+    }
+}
+
+impl TransactionResultSummary {
+    /// Returns whether the available funds of the account are below zero.
+    pub fn negative(&self) -> bool {
+        self.available < Decimal::ZERO
+    }
+
+    /// Returns the currency of the balances, or none for the base currency.
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Returns the summary with the balances in the base currency labelled as `base`.
+    pub fn with_base_currency(mut self, base: Currency) -> Self {
+        self.currency.get_or_insert(base);
+        self
     }
 
-    /// Checks if the transaction result is locked.
-    pub fn locked(&self) -> bool {
-        self.locked
+    /// Returns the summary with its balances rounded to `scale` decimal places and padded with
+    /// zeros up to that scale, so all the balances are formatted with the same number of decimal
+    /// places.
+    pub fn with_scale(self, scale: u32) -> Self {
+        let fixed_scale = |amount: Decimal| {
+            let mut amount = amount.round_dp(scale);
+            amount.rescale(scale);
+            amount
+        };
+        Self {
+            available: fixed_scale(self.available),
+            held: fixed_scale(self.held),
+            total: fixed_scale(self.total),
+            held_by_authorizations: fixed_scale(self.held_by_authorizations),
+            fees: fixed_scale(self.fees),
+            ..self
+        }
     }
 }
 
+/// Summary of an account with additional details, written when a detailed summary is requested.
+/// The held funds are broken down by reason: disputes and authorizations, and the fees charged
+/// to the account and whether it is frozen or closed are included.
 #[derive(Debug, Serialize, PartialEq)]
+pub struct DetailedSummary {
+    client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(with = "rust_decimal::serde::str")]
+    available: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    held: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    held_disputes: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    held_authorizations: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    total: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    fees: Decimal,
+    locked: bool,
+    frozen: bool,
+    closed: bool,
+    negative: bool,
+}
+
+impl From<TransactionResultSummary> for DetailedSummary {
+    fn from(summary: TransactionResultSummary) -> Self {
+        Self {
+            negative: summary.negative(),
+            client: summary.client,
+            currency: summary.currency,
+            available: summary.available,
+            held: summary.held,
+            held_disputes: summary.held - summary.held_by_authorizations,
+            held_authorizations: summary.held_by_authorizations,
+            total: summary.total,
+            fees: summary.fees,
+            locked: summary.locked,
+            frozen: summary.frozen,
+            closed: summary.closed,
+        }
+    }
+}
+
+/// Returns the result of a checked operation on the balances of an account, or an overflow error
+/// for `transaction` if it did not fit.
+fn checked<T>(result: Option<T>, transaction: &Transaction) -> Result<T, TransactionError> {
+    result.ok_or_else(|| TransactionError::Overflow(transaction.clone()))
+}
+
+/// Summary of the balances of an account. It is also the format used to import the opening
+/// balances of the accounts before processing any transaction.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
 #[cfg_attr(test, derive(Dummy))]
 pub struct TransactionResultSummary {
     client: ClientId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
     #[serde(with = "rust_decimal::serde::str")]
     available: Decimal,
     #[serde(with = "rust_decimal::serde::str")]
@@ -321,18 +1647,64 @@ pub struct TransactionResultSummary {
     #[serde(with = "rust_decimal::serde::str")]
     total: Decimal,
     locked: bool,
+    /// Part of `held` held by authorizations, only kept for the detailed summary.
+    #[serde(skip)]
+    held_by_authorizations: Decimal,
+    /// Fees charged to the account, only kept for the detailed summary.
+    #[serde(skip)]
+    fees: Decimal,
+    /// Whether the account is frozen, only kept for the detailed summary.
+    #[serde(skip)]
+    frozen: bool,
+    /// Whether the account is closed, only kept for the detailed summary.
+    #[serde(skip)]
+    closed: bool,
 }
 
 impl From<Account> for TransactionResultSummary {
-    /// Converts a `TransactionResult` into a `TransactionResultCSV`.
+    /// Converts a `TransactionResult` into a `TransactionResultCSV`, with `DEFAULT_SCALE`
+    /// decimal places.
     fn from(result: Account) -> Self {
         Self {
             client: result.client_id(),
-            available: result.available().round_dp(4),
-            held: result.held().round_dp(4),
-            total: result.total().round_dp(4),
+            currency: None,
+            available: result.available(),
+            held: result.held(),
+            total: result.total(),
             locked: result.locked(),
+            held_by_authorizations: result.held_by_authorizations(),
+            fees: result.fees(),
+            frozen: result.frozen(),
+            closed: result.closed(),
+        }
+        .with_scale(DEFAULT_SCALE)
+    }
+}
+
+impl TryFrom<TransactionResultSummary> for Account {
+    type Error = TransactionError;
+
+    /// Converts an opening balance into an `Account`, validating that its total is the sum of
+    /// the available and held amounts. Opening balances are in the base currency.
+    fn try_from(summary: TransactionResultSummary) -> Result<Self, Self::Error> {
+        if let Some(currency) = summary.currency {
+            return Err(TransactionError::InvalidOpeningBalance(format!(
+                "client {}: opening balances must be in the base currency, not {}",
+                summary.client, currency
+            )));
         }
+        if summary.available.checked_add(summary.held) != Some(summary.total) {
+            return Err(TransactionError::InvalidOpeningBalance(format!(
+                "client {}: total {} is not available {} plus held {}",
+                summary.client, summary.total, summary.available, summary.held
+            )));
+        }
+        Ok(Account::create_with(
+            summary.client,
+            summary.available,
+            summary.held,
+            summary.locked,
+        ))
     }
 }
 
@@ -341,6 +1713,10 @@ mod tests {
     use rust_decimal_macros::dec;
 
     use super::*;
+    use crate::{
+        CurrencyConversion, ExcessPrecision, ExchangeRate, Fee, FeeSchedule, Ledger, LedgerAccount,
+        NegativeBalance, Posting, RoundingMode, WithdrawalLimitSchedule, WithdrawalLimits,
+    };
 
     #[test]
     fn test_process_deposit() {
@@ -398,7 +1774,7 @@ mod tests {
 
         let withdrawal = Transaction::builder()
             .ty(TransactionType::Withdrawal)
-            .amount(dec!(12.1))
+            .amount(Amount::new(dec!(12.1)).unwrap())
             .transaction_id(2)
             .client_id(1)
             .build();
@@ -416,7 +1792,7 @@ mod tests {
     fn test_process_dispute_with_valid_deposit() {
         let deposit = Transaction::builder()
             .ty(TransactionType::Deposit)
-            .amount(dec!(12.0))
+            .amount(Amount::new(dec!(12.0)).unwrap())
             .transaction_id(1)
             .client_id(1)
             .build();
@@ -441,7 +1817,7 @@ mod tests {
     fn test_process_dispute_with_invalid_deposit() {
         let deposit = Transaction::builder()
             .ty(TransactionType::Deposit)
-            .amount(dec!(12.0))
+            .amount(Amount::new(dec!(12.0)).unwrap())
             .transaction_id(1)
             .client_id(1)
             .build();
@@ -466,7 +1842,7 @@ mod tests {
     fn test_process_resolve_with_valid_dispute() {
         let deposit = Transaction::builder()
             .ty(TransactionType::Deposit)
-            .amount(dec!(12.0))
+            .amount(Amount::new(dec!(12.0)).unwrap())
             .transaction_id(1)
             .client_id(1)
             .build();
@@ -651,4 +2027,1435 @@ mod tests {
         assert_eq!(transaction_result.available(), 0.into());
         assert_eq!(transaction_result.held(), 12.into());
     }
+
+    #[test]
+    fn test_account_from_opening_balance() {
+        let summary: TransactionResultSummary =
+            Account::create_with(1, dec!(1.5), dec!(2), true).into();
+
+        let account = Account::try_from(summary).unwrap();
+
+        assert_eq!(account, Account::create_with(1, dec!(1.5), dec!(2), true));
+    }
+
+    #[test]
+    fn test_account_from_inconsistent_opening_balance() {
+        let summary = TransactionResultSummary {
+            client: 1,
+            currency: None,
+            available: dec!(1.5),
+            held: dec!(2),
+            total: dec!(3),
+            locked: false,
+            held_by_authorizations: dec!(0),
+            fees: dec!(0),
+            frozen: false,
+            closed: false,
+        };
+
+        let result = Account::try_from(summary);
+
+        assert!(matches!(
+            result,
+            Err(TransactionError::InvalidOpeningBalance(_))
+        ));
+    }
+
+    fn transaction(ty: TransactionType, transaction_id: TxId) -> Transaction {
+        Transaction::builder()
+            .ty(ty)
+            .transaction_id(transaction_id)
+            .client_id(1)
+            .build()
+    }
+
+    fn deposit(transaction_id: TxId) -> Transaction {
+        Transaction::builder()
+            .ty(TransactionType::Deposit)
+            .amount(1)
+            .transaction_id(transaction_id)
+            .client_id(1)
+            .build()
+    }
+
+    #[test]
+    fn test_dispute_charged_back_deposit() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+        account
+            .process(&transaction(TransactionType::Dispute, 1))
+            .unwrap();
+        account
+            .process(&transaction(TransactionType::Chargeback, 1))
+            .unwrap();
+        account.locked = false;
+
+        let result = account.process(&transaction(TransactionType::Dispute, 1));
+
+        assert!(matches!(
+            result,
+            Err(TransactionError::TransactionChargedBack(_))
+        ));
+    }
+
+    #[test]
+    fn test_prune_outside_dispute_window() {
+        let mut account = Account::new(1);
+        for tx_id in 1..=10 {
+            account.process(&deposit(tx_id)).unwrap();
+        }
+        account
+            .process(&transaction(TransactionType::Dispute, 2))
+            .unwrap();
+        let policy = RetentionPolicy::builder().dispute_window(5).build();
+
+        let report = account.prune(&policy, 10);
+
+        assert_eq!(report.accounts, 1);
+        assert_eq!(report.entries, 3);
+        assert!(report.bytes > 0);
+        assert!(account.tracked(2).is_some());
+        assert!(account.tracked(4).is_none());
+        assert!(account.tracked(5).is_some());
+        assert!(matches!(
+            account.process(&transaction(TransactionType::Dispute, 1)),
+            Err(TransactionError::CannotDisputeWithoutDeposit(_))
+        ));
+        assert!(matches!(
+            account.process(&deposit(1)),
+            Err(TransactionError::DuplicateTransaction(_))
+        ));
+    }
+
+    #[test]
+    fn test_prune_locked_account() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+        account.process(&deposit(2)).unwrap();
+        account
+            .process(&transaction(TransactionType::Dispute, 1))
+            .unwrap();
+        account
+            .process(&transaction(TransactionType::Chargeback, 1))
+            .unwrap();
+
+        let charged_back = RetentionPolicy::builder().charged_back(true).build();
+        assert_eq!(account.prune(&charged_back, 2).entries, 1);
+        assert_eq!(account.tracked_len(), 1);
+
+        let locked = RetentionPolicy::builder().locked_accounts(true).build();
+        assert_eq!(account.prune(&locked, 2).entries, 1);
+        assert_eq!(account.tracked_len(), 0);
+        assert_eq!(account.prune(&locked, 2), PruneReport::default());
+
+        // Once unlocked, the pruned deposits can neither be disputed nor replayed.
+        account.process(&admin(TransactionType::Unlock)).unwrap();
+        assert!(matches!(
+            account.process(&transaction(TransactionType::Dispute, 2)),
+            Err(TransactionError::CannotDisputeWithoutDeposit(_))
+        ));
+        assert!(matches!(
+            account.process(&deposit(2)),
+            Err(TransactionError::DuplicateTransaction(_))
+        ));
+    }
+
+    #[test]
+    fn test_deposit_overflow() {
+        let mut account = Account::create_with(1, Decimal::MAX, dec!(0), false);
+
+        let result = account.process(&deposit(1));
+
+        assert!(matches!(result, Err(TransactionError::Overflow(_))));
+        assert_eq!(account.available(), Decimal::MAX);
+        assert_eq!(account.tracked_len(), 0);
+    }
+
+    #[test]
+    fn test_deposit_overflow_of_total() {
+        let mut account = Account::create_with(1, Decimal::MAX - dec!(1), dec!(1), false);
+
+        let result = account.process(&deposit(1));
+
+        assert!(matches!(result, Err(TransactionError::Overflow(_))));
+        assert_eq!(account.available(), Decimal::MAX - dec!(1));
+    }
+
+    #[test]
+    fn test_process_with_policy_limits() {
+        let policy = AccountPolicy::builder()
+            .max_transaction_amount(dec!(5))
+            .max_balance(dec!(8))
+            .build();
+        let mut account = Account::new(1);
+        let deposit = |transaction_id, amount: Decimal| {
+            Transaction::builder()
+                .ty(TransactionType::Deposit)
+                .amount(Amount::new(amount).unwrap())
+                .transaction_id(transaction_id)
+                .client_id(1)
+                .build()
+        };
+
+        assert!(matches!(
+            account.process_with_policy(&deposit(1, dec!(6)), &policy),
+            Err(TransactionError::TransactionAmountExceedsLimit(_))
+        ));
+        account
+            .process_with_policy(&deposit(2, dec!(5)), &policy)
+            .unwrap();
+        assert!(matches!(
+            account.process_with_policy(&deposit(3, dec!(4)), &policy),
+            Err(TransactionError::BalanceExceedsLimit(_))
+        ));
+        account
+            .process_with_policy(&deposit(4, dec!(3)), &policy)
+            .unwrap();
+        assert_eq!(account.available(), dec!(8));
+    }
+
+    #[test]
+    fn test_account_from_overflowing_opening_balance() {
+        let summary = TransactionResultSummary {
+            client: 1,
+            currency: None,
+            available: Decimal::MAX,
+            held: dec!(1),
+            total: Decimal::MAX,
+            locked: false,
+            held_by_authorizations: dec!(0),
+            fees: dec!(0),
+            frozen: false,
+            closed: false,
+        };
+
+        let result = Account::try_from(summary);
+
+        assert!(matches!(
+            result,
+            Err(TransactionError::InvalidOpeningBalance(_))
+        ));
+    }
+
+    #[test]
+    fn test_summary_with_fixed_scale() {
+        let account = Account::create_with(1, dec!(1.5), dec!(0.123456), false);
+
+        let summary = account.summaries(6).remove(0);
+        assert_eq!(summary.available.to_string(), "1.500000");
+        assert_eq!(summary.total.to_string(), "1.623456");
+        let summary = TransactionResultSummary::from(account);
+        assert_eq!(summary.available.to_string(), "1.5000");
+        assert_eq!(summary.held.to_string(), "0.1235");
+        assert_eq!(summary.total.to_string(), "1.6235");
+    }
+
+    fn record(ty: TransactionType, amount: Option<Decimal>) -> TransactionRecord {
+        TransactionRecord {
+            ty,
+            client_id: 1,
+            transaction_id: 1,
+            amount,
+            operator: None,
+            reason: None,
+            timestamp: None,
+            destination: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn test_transaction_from_record() {
+        let deposit = Transaction::try_from(record(TransactionType::Deposit, Some(dec!(1.5))));
+        let dispute = Transaction::try_from(record(TransactionType::Dispute, None));
+        let partial = Transaction::try_from(record(TransactionType::Dispute, Some(dec!(0.5))));
+
+        assert_eq!(deposit.unwrap().amount(), Some(dec!(1.5)));
+        assert_eq!(dispute.unwrap().amount(), None);
+        assert_eq!(partial.unwrap().amount(), Some(dec!(0.5)));
+    }
+
+    #[test]
+    fn test_transaction_from_invalid_record() {
+        let invalid = [
+            record(TransactionType::Deposit, Some(dec!(-1.5))),
+            record(TransactionType::Withdrawal, None),
+            record(TransactionType::Chargeback, Some(dec!(-1))),
+            record(TransactionType::Dispute, Some(dec!(0))),
+            record(TransactionType::Resolve, Some(dec!(-0.5))),
+        ];
+
+        for record in invalid {
+            assert!(matches!(
+                Transaction::try_from(record),
+                Err(TransactionError::InvalidTransactionAmount(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_partial_amount_rounded_to_zero() {
+        let partial = |ty| Transaction::try_from(record(ty, Some(dec!(0.00001)))).unwrap();
+        let truncate = PrecisionPolicy::builder()
+            .excess(ExcessPrecision::Truncate)
+            .build();
+
+        for policy in [PrecisionPolicy::default(), truncate] {
+            for ty in [
+                TransactionType::Dispute,
+                TransactionType::Resolve,
+                TransactionType::Chargeback,
+            ] {
+                assert!(matches!(
+                    partial(ty).with_precision(&policy),
+                    Err(TransactionError::InvalidTransactionAmount(_))
+                ));
+            }
+            assert_eq!(
+                partial(TransactionType::Deposit)
+                    .with_precision(&policy)
+                    .unwrap()
+                    .amount(),
+                Some(dec!(0))
+            );
+        }
+    }
+
+    #[test]
+    fn test_transfer_record_requires_destination() {
+        let transfer = |destination| TransactionRecord {
+            destination,
+            ..record(TransactionType::Transfer, Some(dec!(1)))
+        };
+        let deposit = TransactionRecord {
+            destination: Some(2),
+            ..record(TransactionType::Deposit, Some(dec!(1)))
+        };
+
+        assert_eq!(
+            Transaction::try_from(transfer(Some(2)))
+                .unwrap()
+                .destination(),
+            Some(2)
+        );
+        for record in [transfer(None), transfer(Some(1)), deposit] {
+            assert!(matches!(
+                Transaction::try_from(record),
+                Err(TransactionError::InvalidClient(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_deserialize_validates_transaction() {
+        let json = r#"{"type":"dispute","client":1,"tx":1,"amount":"-2"}"#;
+
+        assert!(serde_json::from_str::<Transaction>(json).is_err());
+    }
+
+    fn admin(ty: TransactionType) -> Transaction {
+        Transaction::builder()
+            .ty(ty)
+            .transaction_id(100)
+            .client_id(1)
+            .operator("alice")
+            .reason("Support ticket")
+            .build()
+    }
+
+    #[test]
+    fn test_unlock_account() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+        account
+            .process(&transaction(TransactionType::Dispute, 1))
+            .unwrap();
+        account
+            .process(&transaction(TransactionType::Chargeback, 1))
+            .unwrap();
+
+        account.process(&admin(TransactionType::Unlock)).unwrap();
+
+        assert!(!account.locked());
+        assert!(account.process(&deposit(2)).is_ok());
+        assert!(matches!(
+            account.process(&admin(TransactionType::Unlock)),
+            Err(TransactionError::InvalidAdministrativeOperation(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_freeze_and_unfreeze_account() {
+        let mut account = Account::new(1);
+
+        account.process(&admin(TransactionType::Freeze)).unwrap();
+        assert!(matches!(
+            account.process(&deposit(1)),
+            Err(TransactionError::AccountFrozen(_))
+        ));
+        account.process(&admin(TransactionType::Unfreeze)).unwrap();
+
+        assert!(!account.frozen());
+        assert!(account.process(&deposit(1)).is_ok());
+    }
+
+    #[test]
+    fn test_close_account() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+        account
+            .process(&transaction(TransactionType::Dispute, 1))
+            .unwrap();
+        assert!(matches!(
+            account.process(&admin(TransactionType::Close)),
+            Err(TransactionError::InvalidAdministrativeOperation(_, _))
+        ));
+        account
+            .process(&transaction(TransactionType::Resolve, 1))
+            .unwrap();
+        assert!(matches!(
+            account.process(&admin(TransactionType::Close)),
+            Err(TransactionError::InvalidAdministrativeOperation(_, _))
+        ));
+        account
+            .process(
+                &Transaction::builder()
+                    .ty(TransactionType::Withdrawal)
+                    .amount(1)
+                    .transaction_id(2)
+                    .client_id(1)
+                    .build(),
+            )
+            .unwrap();
+
+        account.process(&admin(TransactionType::Close)).unwrap();
+
+        assert!(account.closed());
+        assert!(matches!(
+            account.process(&admin(TransactionType::Unfreeze)),
+            Err(TransactionError::AccountClosed(_))
+        ));
+    }
+
+    #[test]
+    fn test_administrative_record_requires_operator_and_reason() {
+        let record = TransactionRecord {
+            ty: TransactionType::Unlock,
+            client_id: 1,
+            transaction_id: 1,
+            amount: None,
+            operator: Some("alice".into()),
+            reason: None,
+            timestamp: None,
+            destination: None,
+            currency: None,
+        };
+
+        assert!(matches!(
+            Transaction::try_from(record),
+            Err(TransactionError::InvalidAdministrativeRecord(_))
+        ));
+    }
+
+    #[test]
+    fn test_locked_account_finishes_other_disputes() {
+        let policy = AccountPolicy::builder()
+            .allowed_when_locked(vec![TransactionType::Resolve, TransactionType::Chargeback])
+            .build();
+        let mut account = Account::new(1);
+        for tx_id in 1..=3 {
+            account
+                .process_with_policy(&deposit(tx_id), &policy)
+                .unwrap();
+            account
+                .process_with_policy(&transaction(TransactionType::Dispute, tx_id), &policy)
+                .unwrap();
+        }
+
+        account
+            .process_with_policy(&transaction(TransactionType::Chargeback, 1), &policy)
+            .unwrap();
+        account
+            .process_with_policy(&transaction(TransactionType::Resolve, 2), &policy)
+            .unwrap();
+        account
+            .process_with_policy(&transaction(TransactionType::Chargeback, 3), &policy)
+            .unwrap();
+
+        assert!(account.locked());
+        assert_eq!(account.held(), dec!(0));
+        assert_eq!(account.available(), dec!(1));
+        assert!(matches!(
+            account.process_with_policy(&deposit(4), &policy),
+            Err(TransactionError::AccountLocked(_))
+        ));
+    }
+
+    #[test]
+    fn test_chargeback_without_lock() {
+        let policy = AccountPolicy::builder().lock_on_chargeback(false).build();
+        let mut account = Account::new(1);
+        account.process_with_policy(&deposit(1), &policy).unwrap();
+        account
+            .process_with_policy(&transaction(TransactionType::Dispute, 1), &policy)
+            .unwrap();
+
+        account
+            .process_with_policy(&transaction(TransactionType::Chargeback, 1), &policy)
+            .unwrap();
+
+        assert!(!account.locked());
+        assert!(account.process_with_policy(&deposit(2), &policy).is_ok());
+    }
+
+    #[test]
+    fn test_transaction_type_from_str() {
+        assert_eq!(
+            "chargeback".parse::<TransactionType>().unwrap(),
+            TransactionType::Chargeback
+        );
+        assert!(matches!(
+            "refund".parse::<TransactionType>(),
+            Err(TransactionError::InvalidTransactionType(_))
+        ));
+    }
+
+    #[test]
+    fn test_dispute_with_negative_balance() {
+        let withdrawal = Transaction::builder()
+            .ty(TransactionType::Withdrawal)
+            .amount(Amount::new(dec!(0.75)).unwrap())
+            .transaction_id(2)
+            .client_id(1)
+            .build();
+        let run = |negative_balance| {
+            let policy = AccountPolicy::builder()
+                .negative_balance(negative_balance)
+                .build();
+            let mut account = Account::new(1);
+            account.process_with_policy(&deposit(1), &policy).unwrap();
+            account.process_with_policy(&withdrawal, &policy).unwrap();
+            let result =
+                account.process_with_policy(&transaction(TransactionType::Dispute, 1), &policy);
+            (result, account)
+        };
+
+        let (result, account) = run(NegativeBalance::Reject);
+        assert!(matches!(
+            result,
+            Err(TransactionError::InconsistenceBalance(_, _))
+        ));
+        assert_eq!(account.available(), dec!(0.25));
+
+        let (result, account) = run(NegativeBalance::Limit(dec!(0.5)));
+        assert!(result.is_err());
+        assert_eq!(account.held(), dec!(0));
+
+        let (result, account) = run(NegativeBalance::Limit(dec!(0.75)));
+        assert!(result.is_ok());
+        assert_eq!(account.available(), dec!(-0.75));
+        assert_eq!(account.held(), dec!(1));
+
+        let (result, account) = run(NegativeBalance::Unlimited);
+        assert!(result.is_ok());
+        let summary: TransactionResultSummary = account.into();
+        assert!(summary.negative());
+        assert!(DetailedSummary::from(summary).negative);
+    }
+
+    fn partial(ty: TransactionType, amount: Decimal) -> Transaction {
+        Transaction::builder()
+            .ty(ty)
+            .amount(Amount::new(amount).unwrap())
+            .transaction_id(1)
+            .client_id(1)
+            .build()
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+
+        account
+            .process(&partial(TransactionType::Dispute, dec!(0.3)))
+            .unwrap();
+        account
+            .process(&partial(TransactionType::Dispute, dec!(0.5)))
+            .unwrap();
+        assert_eq!(account.available(), dec!(0.2));
+        assert_eq!(account.held(), dec!(0.8));
+
+        // Only 0.2 of the deposit is left to dispute.
+        assert!(matches!(
+            account.process(&partial(TransactionType::Dispute, dec!(0.3))),
+            Err(TransactionError::InconsistenceBalance(_, _))
+        ));
+        // Without an amount, the rest of the deposit is disputed.
+        account
+            .process(&transaction(TransactionType::Dispute, 1))
+            .unwrap();
+        assert_eq!(account.held(), dec!(1));
+        assert!(matches!(
+            account.process(&transaction(TransactionType::Dispute, 1)),
+            Err(TransactionError::TransactionBeingDisputed(_))
+        ));
+    }
+
+    #[test]
+    fn test_partial_resolve_and_chargeback() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+        account
+            .process(&transaction(TransactionType::Dispute, 1))
+            .unwrap();
+
+        account
+            .process(&partial(TransactionType::Resolve, dec!(0.25)))
+            .unwrap();
+        assert_eq!(account.available(), dec!(0.25));
+        assert_eq!(account.held(), dec!(0.75));
+        assert!(matches!(
+            account.process(&partial(TransactionType::Chargeback, dec!(1))),
+            Err(TransactionError::InconsistenceBalance(_, _))
+        ));
+
+        account
+            .process(&partial(TransactionType::Chargeback, dec!(0.5)))
+            .unwrap();
+        assert_eq!(account.held(), dec!(0.25));
+        assert!(account.locked());
+        assert!(account.tracked(1).unwrap().being_disputed());
+
+        let policy = AccountPolicy::builder()
+            .allowed_when_locked(vec![TransactionType::Resolve])
+            .build();
+        account
+            .process_with_policy(&transaction(TransactionType::Resolve, 1), &policy)
+            .unwrap();
+        assert_eq!(account.available(), dec!(0.5));
+        assert_eq!(account.held(), dec!(0));
+        let track = account.tracked(1).unwrap();
+        assert!(!track.being_disputed());
+        assert_eq!(track.disputed(), dec!(0));
+        assert_eq!(track.undisputed(), dec!(0.5));
+    }
+
+    #[test]
+    fn test_dispute_again_after_resolve() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+        for _ in 0..3 {
+            account
+                .process(&transaction(TransactionType::Dispute, 1))
+                .unwrap();
+            account
+                .process(&transaction(TransactionType::Resolve, 1))
+                .unwrap();
+        }
+        assert_eq!(account.tracked(1).unwrap().history().disputes(), 3);
+
+        let policy = AccountPolicy::builder().max_disputes(1).build();
+        let mut account = Account::new(1);
+        account.process_with_policy(&deposit(1), &policy).unwrap();
+        account
+            .process_with_policy(&partial(TransactionType::Dispute, dec!(0.5)), &policy)
+            .unwrap();
+        // Disputing the rest of the amount is part of the same dispute.
+        account
+            .process_with_policy(&transaction(TransactionType::Dispute, 1), &policy)
+            .unwrap();
+        account
+            .process_with_policy(&transaction(TransactionType::Resolve, 1), &policy)
+            .unwrap();
+        assert!(matches!(
+            account.process_with_policy(&transaction(TransactionType::Dispute, 1), &policy),
+            Err(TransactionError::DisputeLimitExceeded(1, _))
+        ));
+        assert_eq!(account.available(), dec!(1));
+    }
+
+    #[test]
+    fn test_dispute_after_partial_chargeback() {
+        let run = |policy: &AccountPolicy| {
+            let mut account = Account::new(1);
+            account.process_with_policy(&deposit(1), policy).unwrap();
+            account
+                .process_with_policy(&partial(TransactionType::Dispute, dec!(0.4)), policy)
+                .unwrap();
+            account
+                .process_with_policy(&transaction(TransactionType::Chargeback, 1), policy)
+                .unwrap();
+            account.process_with_policy(&transaction(TransactionType::Dispute, 1), policy)
+        };
+
+        let allowed = AccountPolicy::builder().lock_on_chargeback(false).build();
+        assert!(run(&allowed).is_ok());
+        let rejected = AccountPolicy::builder()
+            .lock_on_chargeback(false)
+            .dispute_after_chargeback(false)
+            .build();
+        assert!(matches!(
+            run(&rejected),
+            Err(TransactionError::DisputeAfterChargeback(_))
+        ));
+    }
+
+    #[test]
+    fn test_dispute_window() {
+        let policy = AccountPolicy::builder().dispute_window(86400).build();
+        let at = |ty, transaction_id, timestamp| {
+            Transaction::builder()
+                .ty(ty)
+                .amount(1)
+                .transaction_id(transaction_id)
+                .client_id(1)
+                .timestamp(timestamp)
+                .build()
+        };
+        let dispute = |transaction_id, timestamp| {
+            Transaction::builder()
+                .ty(TransactionType::Dispute)
+                .transaction_id(transaction_id)
+                .client_id(1)
+                .timestamp(timestamp)
+                .build()
+        };
+        let mut account = Account::new(1);
+        account
+            .process_with_policy(&at(TransactionType::Deposit, 1, 0), &policy)
+            .unwrap();
+        account
+            .process_with_policy(&at(TransactionType::Deposit, 2, 0), &policy)
+            .unwrap();
+        account.process_with_policy(&deposit(3), &policy).unwrap();
+
+        assert!(account
+            .process_with_policy(&dispute(1, 86400), &policy)
+            .is_ok());
+        assert!(matches!(
+            account.process_with_policy(&dispute(2, 86401), &policy),
+            Err(TransactionError::DisputeWindowExpired(_))
+        ));
+        // Deposits without a timestamp can always be disputed.
+        assert!(account
+            .process_with_policy(&dispute(3, 86401), &policy)
+            .is_ok());
+        assert_eq!(account.held(), dec!(2));
+    }
+
+    fn authorization(
+        ty: TransactionType,
+        amount: Option<Decimal>,
+        timestamp: Timestamp,
+    ) -> Transaction {
+        let transaction = Transaction::builder()
+            .ty(ty)
+            .transaction_id(10)
+            .client_id(1)
+            .timestamp(timestamp);
+        match amount {
+            Some(amount) => transaction.amount(Amount::new(amount).unwrap()).build(),
+            None => transaction.build(),
+        }
+    }
+
+    #[test]
+    fn test_authorize_and_capture() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+        account
+            .process(&transaction(TransactionType::Dispute, 1))
+            .unwrap();
+        account.process(&deposit(2)).unwrap();
+
+        account
+            .process(&authorization(
+                TransactionType::Authorize,
+                Some(dec!(0.75)),
+                0,
+            ))
+            .unwrap();
+        assert_eq!(account.available(), dec!(0.25));
+        assert_eq!(account.held(), dec!(1.75));
+        assert_eq!(account.held_by_disputes(), dec!(1));
+        assert_eq!(account.held_by_authorizations(), dec!(0.75));
+        assert!(matches!(
+            account.process(&authorization(TransactionType::Authorize, Some(dec!(1)), 0)),
+            Err(TransactionError::DuplicateTransaction(_))
+        ));
+
+        // The part that is not captured is released.
+        account
+            .process(&authorization(TransactionType::Capture, Some(dec!(0.5)), 0))
+            .unwrap();
+        assert_eq!(account.available(), dec!(0.5));
+        assert_eq!(account.held(), dec!(1));
+        assert_eq!(account.total(), dec!(1.5));
+        assert!(matches!(
+            account.process(&authorization(TransactionType::Void, None, 0)),
+            Err(TransactionError::UnknownAuthorization(_))
+        ));
+
+        let summary = DetailedSummary::from(TransactionResultSummary::from(account));
+        assert_eq!(summary.held_disputes, dec!(1));
+        assert_eq!(summary.held_authorizations, dec!(0));
+    }
+
+    #[test]
+    fn test_authorization_void_and_expiry() {
+        let policy = AccountPolicy::builder().authorization_expiry(3600).build();
+        let mut account = Account::new(1);
+        account.process_with_policy(&deposit(1), &policy).unwrap();
+        assert!(matches!(
+            account.process_with_policy(
+                &authorization(TransactionType::Authorize, Some(dec!(2)), 0),
+                &policy
+            ),
+            Err(TransactionError::InsufficientFunds(_))
+        ));
+
+        account
+            .process_with_policy(
+                &authorization(TransactionType::Authorize, Some(dec!(1)), 0),
+                &policy,
+            )
+            .unwrap();
+        account
+            .process_with_policy(&authorization(TransactionType::Void, None, 10), &policy)
+            .unwrap();
+        assert_eq!(account.available(), dec!(1));
+        // The id of a voided authorization cannot be reused.
+        assert!(matches!(
+            account.process_with_policy(
+                &authorization(TransactionType::Authorize, Some(dec!(1)), 20),
+                &policy
+            ),
+            Err(TransactionError::DuplicateTransaction(_))
+        ));
+
+        let renumbered = |transaction: Transaction| Transaction {
+            transaction_id: 11,
+            ..transaction
+        };
+        account
+            .process_with_policy(
+                &renumbered(authorization(
+                    TransactionType::Authorize,
+                    Some(dec!(1)),
+                    100,
+                )),
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(account.held(), dec!(1));
+        // The capture comes after the expiry, which released the funds first.
+        assert!(matches!(
+            account.process_with_policy(
+                &renumbered(authorization(TransactionType::Capture, None, 3701)),
+                &policy
+            ),
+            Err(TransactionError::UnknownAuthorization(_))
+        ));
+        assert_eq!(account.available(), dec!(1));
+        assert_eq!(account.held(), dec!(0));
+        assert!(matches!(
+            account.process_with_policy(
+                &renumbered(authorization(
+                    TransactionType::Authorize,
+                    Some(dec!(1)),
+                    3702,
+                )),
+                &policy
+            ),
+            Err(TransactionError::DuplicateTransaction(_))
+        ));
+    }
+
+    fn transfer(amount: Decimal) -> Transaction {
+        Transaction::builder()
+            .ty(TransactionType::Transfer)
+            .transaction_id(20)
+            .client_id(1)
+            .destination(2)
+            .amount(Amount::new(amount).unwrap())
+            .build()
+    }
+
+    #[test]
+    fn test_transfer_between_accounts() {
+        let mut source = Account::new(1);
+        let mut destination = Account::new(2);
+        source.process(&deposit(1)).unwrap();
+
+        source
+            .transfer_with_journal(
+                &mut destination,
+                &transfer(dec!(0.4)),
+                &AccountPolicy::default(),
+                &mut JournalEntry::new(),
+            )
+            .unwrap();
+        assert_eq!(source.available(), dec!(0.6));
+        assert_eq!(destination.available(), dec!(0.4));
+
+        assert!(matches!(
+            source.transfer_with_journal(
+                &mut destination,
+                &transfer(dec!(1)),
+                &AccountPolicy::default(),
+                &mut JournalEntry::new(),
+            ),
+            Err(TransactionError::InsufficientFunds(_))
+        ));
+        assert!(matches!(
+            source.process(&transfer(dec!(0.1))),
+            Err(TransactionError::IncompleteTransfer(_))
+        ));
+        assert_eq!(source.available(), dec!(0.6));
+        assert_eq!(destination.available(), dec!(0.4));
+    }
+
+    #[test]
+    fn test_transfer_to_locked_account_leaves_source_unchanged() {
+        let mut source = Account::new(1);
+        let mut destination = Account::create_with(2, dec!(0), dec!(0), true);
+        source.process(&deposit(1)).unwrap();
+
+        assert!(matches!(
+            source.transfer_with_journal(
+                &mut destination,
+                &transfer(dec!(0.5)),
+                &AccountPolicy::default(),
+                &mut JournalEntry::new(),
+            ),
+            Err(TransactionError::AccountLocked(_))
+        ));
+        assert_eq!(source.available(), dec!(1));
+        assert_eq!(destination.available(), dec!(0));
+    }
+
+    fn in_currency(ty: TransactionType, transaction_id: TxId, currency: &str) -> Transaction {
+        let has_amount = matches!(ty, TransactionType::Deposit | TransactionType::Withdrawal);
+        let transaction = Transaction::builder()
+            .ty(ty)
+            .transaction_id(transaction_id)
+            .client_id(1)
+            .currency(currency.parse().unwrap());
+        if has_amount {
+            transaction.amount(Amount::new(dec!(2)).unwrap()).build()
+        } else {
+            transaction.build()
+        }
+    }
+
+    #[test]
+    fn test_balances_per_currency() {
+        let mut account = Account::new(1);
+        account.process(&deposit(1)).unwrap();
+        account
+            .process(&in_currency(TransactionType::Deposit, 2, "EUR"))
+            .unwrap();
+        account
+            .process(&in_currency(TransactionType::Deposit, 3, "GBP"))
+            .unwrap();
+        account
+            .process(&in_currency(TransactionType::Dispute, 3, "GBP"))
+            .unwrap();
+        assert!(matches!(
+            account.process(&in_currency(TransactionType::Withdrawal, 4, "USD")),
+            Err(TransactionError::InsufficientFunds(_))
+        ));
+        assert_eq!(account.available(), dec!(1));
+        assert_eq!(account.held(), dec!(0));
+
+        let summaries = account.summaries(DEFAULT_SCALE);
+        let balances = summaries
+            .iter()
+            .map(|summary| (summary.currency(), summary.available, summary.held))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            balances,
+            vec![
+                (None, dec!(1), dec!(0)),
+                (Some("EUR".parse().unwrap()), dec!(2), dec!(0)),
+                (Some("GBP".parse().unwrap()), dec!(0), dec!(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dispute_in_other_currency_than_deposit() {
+        let mut account = Account::new(1);
+        account
+            .process(&in_currency(TransactionType::Deposit, 1, "EUR"))
+            .unwrap();
+
+        for ty in [TransactionType::Dispute, TransactionType::Resolve] {
+            assert!(matches!(
+                account.process(&in_currency(ty, 1, "USD")),
+                Err(TransactionError::CurrencyMismatch(_))
+            ));
+        }
+        // Without a currency, the dispute applies to the currency of the deposit.
+        account
+            .process(&transaction(TransactionType::Dispute, 1))
+            .unwrap();
+        assert!(matches!(
+            account.process(&in_currency(TransactionType::Chargeback, 1, "GBP")),
+            Err(TransactionError::CurrencyMismatch(_))
+        ));
+        account
+            .process(&in_currency(TransactionType::Resolve, 1, "EUR"))
+            .unwrap();
+        assert_eq!(
+            account.summaries(DEFAULT_SCALE)[0].currency(),
+            Some("EUR".parse().unwrap())
+        );
+        assert_eq!(account.summaries(DEFAULT_SCALE)[0].available, dec!(2));
+    }
+
+    #[test]
+    fn test_transaction_with_base_currency() {
+        let eur = "EUR".parse().unwrap();
+        let transaction = in_currency(TransactionType::Deposit, 1, "EUR");
+
+        assert_eq!(
+            transaction
+                .clone()
+                .with_base_currency(Some(eur))
+                .unwrap()
+                .currency(),
+            None
+        );
+        assert!(matches!(
+            transaction.with_base_currency(None),
+            Err(TransactionError::InvalidCurrency(_))
+        ));
+        assert_eq!(
+            deposit(1).with_base_currency(None).unwrap().currency(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_conversion_at_the_rate_of_the_deposit() {
+        let rate = |rate, valid_from, valid_until| ExchangeRate {
+            from: "GBP".parse().unwrap(),
+            to: "EUR".parse().unwrap(),
+            rate,
+            valid_from,
+            valid_until,
+        };
+        let conversion = CurrencyConversion::new(
+            "EUR".parse().unwrap(),
+            vec![
+                rate(dec!(1.2), None, Some(100)),
+                rate(dec!(1.1), Some(100), None),
+            ],
+            PrecisionPolicy::default(),
+        )
+        .unwrap();
+        let policy = AccountPolicy::builder().conversion(conversion).build();
+        let at = |transaction: Transaction, timestamp| Transaction {
+            timestamp: Some(timestamp),
+            ..transaction
+        };
+        let mut account = Account::new(1);
+
+        account
+            .process_with_policy(
+                &at(in_currency(TransactionType::Deposit, 1, "GBP"), 50),
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(account.available(), dec!(2.4));
+        assert!(account.balances().next().is_none());
+        assert!(matches!(
+            account.process_with_policy(&in_currency(TransactionType::Deposit, 2, "USD"), &policy),
+            Err(TransactionError::MissingExchangeRate(_))
+        ));
+
+        // The partial dispute comes after the rate changed, but it is converted at the rate of
+        // the deposit.
+        let dispute = Transaction::builder()
+            .ty(TransactionType::Dispute)
+            .transaction_id(1)
+            .client_id(1)
+            .amount(1)
+            .currency("GBP".parse().unwrap())
+            .timestamp(150)
+            .build();
+        account.process_with_policy(&dispute, &policy).unwrap();
+        assert_eq!(account.available(), dec!(1.2));
+        assert_eq!(account.held(), dec!(1.2));
+        assert!(matches!(
+            account
+                .process_with_policy(&in_currency(TransactionType::Chargeback, 1, "EUR"), &policy),
+            Err(TransactionError::CurrencyMismatch(_))
+        ));
+        account
+            .process_with_policy(&transaction(TransactionType::Chargeback, 1), &policy)
+            .unwrap();
+        assert_eq!(account.total(), dec!(1.2));
+    }
+
+    #[test]
+    fn test_converted_parts_and_withdrawals() {
+        let conversion = CurrencyConversion::new(
+            "EUR".parse().unwrap(),
+            vec![ExchangeRate {
+                from: "GBP".parse().unwrap(),
+                to: "EUR".parse().unwrap(),
+                rate: dec!(1.00005),
+                valid_from: None,
+                valid_until: None,
+            }],
+            PrecisionPolicy::builder()
+                .excess(ExcessPrecision::Round(RoundingMode::HalfUp))
+                .build(),
+        )
+        .unwrap();
+        let policy = AccountPolicy::builder().conversion(conversion).build();
+        let mut account = Account::new(1);
+        account
+            .process_with_policy(&in_currency(TransactionType::Deposit, 1, "GBP"), &policy)
+            .unwrap();
+        account
+            .process_with_policy(&payment(TransactionType::Deposit, 2, dec!(5)), &policy)
+            .unwrap();
+        account
+            .process_with_policy(&in_currency(TransactionType::Withdrawal, 3, "GBP"), &policy)
+            .unwrap();
+        assert_eq!(account.available(), dec!(5));
+        assert_eq!(
+            account.applied_rates().get(&3).map(|rate| rate.rate),
+            Some(dec!(1.00005))
+        );
+        assert!(account.applied_rates().get(&1).is_none());
+
+        // Each half of the deposit rounds up on its own, so the second one only gets what is
+        // left of it.
+        let half = Transaction::builder()
+            .ty(TransactionType::Dispute)
+            .transaction_id(1)
+            .client_id(1)
+            .amount(1)
+            .currency("GBP".parse().unwrap())
+            .build();
+        account.process_with_policy(&half, &policy).unwrap();
+        assert_eq!(account.held(), dec!(1.0001));
+        account.process_with_policy(&half, &policy).unwrap();
+        assert_eq!(account.held(), dec!(2.0001));
+        assert_eq!(account.available(), dec!(2.9999));
+    }
+
+    fn fee_policy(schedule: FeeSchedule) -> AccountPolicy {
+        AccountPolicy::builder().fees(schedule).build()
+    }
+
+    fn payment(ty: TransactionType, transaction_id: TxId, amount: Decimal) -> Transaction {
+        Transaction::builder()
+            .ty(ty)
+            .amount(Amount::new(amount).unwrap())
+            .transaction_id(transaction_id)
+            .client_id(1)
+            .build()
+    }
+
+    #[test]
+    fn test_deposit_and_withdrawal_fees() {
+        let policy = fee_policy(
+            FeeSchedule::builder()
+                .house(0)
+                .deposit("1%".parse::<Fee>().unwrap())
+                .withdrawal("0.5".parse::<Fee>().unwrap())
+                .build(),
+        );
+        let mut account = Account::new(1);
+
+        let charge = account
+            .process_with_policy(&payment(TransactionType::Deposit, 1, dec!(100)), &policy)
+            .unwrap()
+            .unwrap();
+        assert_eq!(charge.currency(), None);
+        assert_eq!(charge.amount(), dec!(1));
+        assert_eq!(account.available(), dec!(99));
+
+        // The withdrawal is covered, but not its fee.
+        assert!(matches!(
+            account.process_with_policy(
+                &payment(TransactionType::Withdrawal, 2, dec!(98.6)),
+                &policy
+            ),
+            Err(TransactionError::InsufficientFundsForFee(_))
+        ));
+        assert_eq!(account.available(), dec!(99));
+        account
+            .process_with_policy(&payment(TransactionType::Withdrawal, 3, dec!(98)), &policy)
+            .unwrap();
+        assert_eq!(account.available(), dec!(0.5));
+        assert_eq!(account.fees(), dec!(1.5));
+
+        // The house account is not charged.
+        let mut house = Account::new(0);
+        house.collect_fee(&charge);
+        let deposit = Transaction {
+            client_id: 0,
+            ..payment(TransactionType::Deposit, 4, dec!(100))
+        };
+        assert_eq!(house.process_with_policy(&deposit, &policy).unwrap(), None);
+        assert_eq!(house.available(), dec!(101));
+        assert_eq!(house.fees(), dec!(0));
+    }
+
+    #[test]
+    fn test_flat_fees_in_other_currencies() {
+        let policy = AccountPolicy::builder()
+            .fees(
+                FeeSchedule::builder()
+                    .house(0)
+                    .deposit("1%".parse::<Fee>().unwrap())
+                    .withdrawal("0.5+1%".parse::<Fee>().unwrap())
+                    .build(),
+            )
+            .precision(PrecisionPolicy::builder().scale(2).build())
+            .build();
+        let mut account = Account::new(1);
+
+        let charge = account
+            .process_with_policy(&in_currency(TransactionType::Deposit, 1, "EUR"), &policy)
+            .unwrap()
+            .unwrap();
+        assert_eq!(charge.currency(), "EUR".parse().ok());
+        assert_eq!(charge.amount(), dec!(0.02));
+        account
+            .process_with_policy(&in_currency(TransactionType::Deposit, 2, "EUR"), &policy)
+            .unwrap();
+        assert!(matches!(
+            account
+                .process_with_policy(&in_currency(TransactionType::Withdrawal, 3, "EUR"), &policy),
+            Err(TransactionError::FlatFeeInOtherCurrency(_))
+        ));
+        assert_eq!(account.balance("EUR".parse().ok()).available, dec!(3.96));
+    }
+
+    #[test]
+    fn test_chargeback_fee_limited_by_available() {
+        let schedule = FeeSchedule::builder()
+            .house(0)
+            .chargeback("2".parse::<Fee>().unwrap())
+            .build();
+        let available_after_chargeback = |negative_balance| {
+            let policy = AccountPolicy::builder()
+                .fees(schedule.clone())
+                .negative_balance(negative_balance)
+                .build();
+            let mut account = Account::new(1);
+            account
+                .process_with_policy(&payment(TransactionType::Deposit, 1, dec!(10)), &policy)
+                .unwrap();
+            account
+                .process_with_policy(&payment(TransactionType::Deposit, 2, dec!(1)), &policy)
+                .unwrap();
+            account
+                .process_with_policy(&transaction(TransactionType::Dispute, 1), &policy)
+                .unwrap();
+            let charge = account
+                .process_with_policy(&transaction(TransactionType::Chargeback, 1), &policy)
+                .unwrap();
+            assert_eq!(charge.map(|charge| charge.amount()), Some(account.fees()));
+            account.available()
+        };
+
+        // After the dispute, only 1 is available to pay the fee of 2.
+        assert_eq!(available_after_chargeback(NegativeBalance::Reject), dec!(0));
+        assert_eq!(
+            available_after_chargeback(NegativeBalance::Limit(dec!(0.5))),
+            dec!(-0.5)
+        );
+        assert_eq!(
+            available_after_chargeback(NegativeBalance::Unlimited),
+            dec!(-1)
+        );
+    }
+
+    #[test]
+    fn test_journal_entries_follow_the_balances() {
+        let policy = AccountPolicy::builder()
+            .authorization_expiry(3600)
+            .fees(
+                FeeSchedule::builder()
+                    .house(0)
+                    .withdrawal("0.1".parse::<Fee>().unwrap())
+                    .build(),
+            )
+            .build();
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(1);
+        let mut destination = Account::new(2);
+        let mut house = Account::new(0);
+        let transactions = [
+            payment(TransactionType::Deposit, 1, dec!(10)),
+            payment(TransactionType::Withdrawal, 2, dec!(3)),
+            transaction(TransactionType::Dispute, 1),
+            transaction(TransactionType::Resolve, 1),
+            authorization(TransactionType::Authorize, Some(dec!(2)), 0),
+            authorization(TransactionType::Capture, Some(dec!(1.5)), 10),
+            Transaction {
+                transaction_id: 31,
+                ..authorization(TransactionType::Authorize, Some(dec!(1)), 100)
+            },
+            // Rejected, but it releases the expired authorization first.
+            Transaction {
+                timestamp: Some(3701),
+                ..payment(TransactionType::Withdrawal, 3, dec!(100))
+            },
+            transfer(dec!(1)),
+        ];
+        for transaction in transactions.iter() {
+            let mut entry = JournalEntry::new();
+            let result = match transaction.ty() {
+                TransactionType::Transfer => account
+                    .transfer_with_journal(&mut destination, transaction, &policy, &mut entry)
+                    .map(|_| None),
+                _ => account.process_with_journal(transaction, &policy, &mut entry),
+            };
+            if let Ok(Some(charge)) = result {
+                house.collect_fee(&charge);
+            }
+            ledger.record(&entry).unwrap();
+            for account in [&account, &destination, &house] {
+                ledger.verify(account).unwrap();
+            }
+        }
+
+        assert_eq!(account.available(), dec!(4.4));
+        assert_eq!(ledger.balance(LedgerAccount::External, None), dec!(-5.5));
+        ledger.trial_balance().unwrap();
+    }
+
+    #[test]
+    fn test_fee_overflow_leaves_account_unchanged() {
+        let policy = fee_policy(
+            FeeSchedule::builder()
+                .house(0)
+                .chargeback("1".parse::<Fee>().unwrap())
+                .build(),
+        );
+        let mut account = Account::new(1);
+        // The second deposit leaves funds to pay the chargeback fee with.
+        for transaction in [
+            payment(TransactionType::Deposit, 1, dec!(10)),
+            payment(TransactionType::Deposit, 2, dec!(5)),
+            transaction(TransactionType::Dispute, 1),
+        ] {
+            account.process_with_policy(&transaction, &policy).unwrap();
+        }
+        account.fees = Decimal::MAX;
+        let before = account.clone();
+
+        let result =
+            account.process_with_policy(&transaction(TransactionType::Chargeback, 1), &policy);
+
+        assert!(matches!(result, Err(TransactionError::Overflow(_))));
+        assert_eq!(account, before);
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn test_balances_derived_from_postings() {
+        let mut account = Account::new(1);
+        account
+            .process(&payment(TransactionType::Deposit, 1, dec!(10)))
+            .unwrap();
+        let mut entry = JournalEntry::new();
+
+        let charge = account
+            .process_with_journal(
+                &authorization(TransactionType::Authorize, Some(dec!(4)), 0),
+                &AccountPolicy::default(),
+                &mut entry,
+            )
+            .unwrap();
+
+        assert_eq!(charge, None);
+        assert_eq!(
+            entry.postings(),
+            [
+                Posting {
+                    account: LedgerAccount::Authorized(1),
+                    currency: None,
+                    amount: dec!(4),
+                },
+                Posting {
+                    account: LedgerAccount::Available(1),
+                    currency: None,
+                    amount: dec!(-4),
+                },
+            ]
+        );
+        assert_eq!(account.available(), dec!(6));
+        assert_eq!(account.held(), dec!(4));
+        assert_eq!(account.held_by_authorizations(), dec!(4));
+    }
+
+    #[test]
+    fn test_withdrawal_limits_checked_before_available() {
+        let limits = WithdrawalLimitSchedule::builder()
+            .default(
+                WithdrawalLimits::builder()
+                    .max_amount(dec!(50))
+                    .daily_amount(dec!(60))
+                    .build(),
+            )
+            .clients(BTreeMap::from([(
+                1,
+                WithdrawalLimits::builder().max_transactions(4).build(),
+            )]))
+            .build();
+        let policy = AccountPolicy::builder().withdrawal_limits(limits).build();
+        let mut account = Account::new(1);
+        let mut process = |ty, transaction_id, amount| {
+            account.process_with_policy(&payment(ty, transaction_id, amount), &policy)
+        };
+
+        process(TransactionType::Deposit, 1, dec!(30)).unwrap();
+        // Rejected withdrawals do not count for the limits.
+        assert!(matches!(
+            process(TransactionType::Withdrawal, 2, dec!(40)),
+            Err(TransactionError::InsufficientFunds(_))
+        ));
+        process(TransactionType::Deposit, 3, dec!(100)).unwrap();
+        assert!(matches!(
+            process(TransactionType::Withdrawal, 4, dec!(51)),
+            Err(TransactionError::WithdrawalAmountExceedsLimit(_, _))
+        ));
+        process(TransactionType::Withdrawal, 5, dec!(40)).unwrap();
+        assert!(matches!(
+            process(TransactionType::Withdrawal, 6, dec!(30)),
+            Err(TransactionError::DailyWithdrawalLimitExceeded(_, _))
+        ));
+        process(TransactionType::Withdrawal, 7, dec!(20)).unwrap();
+        // The deposits count for the number of transactions too.
+        assert!(matches!(
+            process(TransactionType::Withdrawal, 8, dec!(0)),
+            Err(TransactionError::TransactionCountExceeded(4, _))
+        ));
+        assert!(matches!(
+            process(TransactionType::Deposit, 9, dec!(1)),
+            Err(TransactionError::TransactionCountExceeded(4, _))
+        ));
+        assert_eq!(account.available(), dec!(70));
+        // Without timestamps, all the transactions are kept as one made at the same time.
+        assert_eq!(
+            account.recent_transactions(),
+            [RecentTransaction {
+                timestamp: 0,
+                currency: None,
+                amount: dec!(60),
+                count: 4,
+            }]
+        );
+    }
 }
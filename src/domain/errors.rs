@@ -1,9 +1,10 @@
 // Error type for the transaction processing
 use std::sync::PoisonError;
 
+use rust_decimal::Decimal;
 use thiserror::Error;
 
-use crate::Transaction;
+use crate::{ClientId, Transaction};
 
 /// Error type for the transaction processing based on thiserror crate
 #[derive(Error, Debug)]
@@ -16,16 +17,64 @@ pub enum TransactionError {
     InvalidTransactionType(String),
     #[error("Invalid transaction amount [{0}]")]
     InvalidTransactionAmount(String),
+    #[error("Invalid currency [{0}]")]
+    InvalidCurrency(String),
+    #[error("Invalid exchange rate [{0}]")]
+    InvalidExchangeRate(String),
+    #[error("Invalid fee [{0}]")]
+    InvalidFee(String),
+    #[error("Invalid withdrawal limits [{0}]")]
+    InvalidWithdrawalLimits(String),
+    #[error("Invalid client list [{0}]")]
+    InvalidClientList(String),
     #[error("Inconsistence Balance amount for transaction [{0} - {1:?}]")]
     InconsistenceBalance(String, Transaction),
     #[error("Error parsing CSV file.\n\n---------------\nOriginal cause:\n---------------\n{0}\n")]
     CSVError(#[from] csv::Error),
     #[error("Error synchronizing transactions\n\n---------------\nOriginal cause:\n---------------\n{0}\n")]
     SyncError(String),
+    #[error("Error accessing transaction storage\n\n---------------\nOriginal cause:\n---------------\n{0}\n")]
+    StorageError(String),
+    #[error("Invalid snapshot file [{0}]")]
+    InvalidSnapshot(String),
+    #[error("Invalid opening balance [{0}]")]
+    InvalidOpeningBalance(String),
+    #[error("Account already exists for client [{0}]")]
+    AccountAlreadyExists(ClientId),
+    #[error("Arithmetic overflow in the balances for transaction [{0:?}]")]
+    Overflow(Transaction),
+    #[error("Transaction amount exceeds the maximum allowed [{0:?}]")]
+    TransactionAmountExceedsLimit(Transaction),
+    #[error("Account balance would exceed the maximum allowed [{0:?}]")]
+    BalanceExceedsLimit(Transaction),
     #[error("Infusfficient funds for withdrawal transaction [{0:?}]")]
     InsufficientFunds(Transaction),
+    #[error("Withdrawal exceeds the maximum of {0} for a single withdrawal [{1:?}]")]
+    WithdrawalAmountExceedsLimit(Decimal, Transaction),
+    #[error("Withdrawal exceeds the maximum of {0} withdrawn in a day [{1:?}]")]
+    DailyWithdrawalLimitExceeded(Decimal, Transaction),
+    #[error("Withdrawal exceeds the maximum of {0} withdrawn within the window [{1:?}]")]
+    RollingWithdrawalLimitExceeded(Decimal, Transaction),
+    #[error("Transaction exceeds the maximum of {0} transactions within the window [{1:?}]")]
+    TransactionCountExceeded(u32, Transaction),
+    #[error("Insufficient funds for the fee of transaction [{0:?}]")]
+    InsufficientFundsForFee(Transaction),
+    #[error("Flat fees are only charged in the base currency [{0:?}]")]
+    FlatFeeInOtherCurrency(Transaction),
+    #[error("Client {0} is in the blocklist [{1:?}]")]
+    ClientBlocked(ClientId, Transaction),
+    #[error("Client {0} is not in the allowlist [{1:?}]")]
+    ClientNotAllowed(ClientId, Transaction),
     #[error("Account locked for dispute transaction [{0:?}]")]
     AccountLocked(Transaction),
+    #[error("Account frozen by an administrative operation for transaction [{0:?}]")]
+    AccountFrozen(Transaction),
+    #[error("Account closed for transaction [{0:?}]")]
+    AccountClosed(Transaction),
+    #[error("Invalid administrative record [{0}]")]
+    InvalidAdministrativeRecord(String),
+    #[error("Invalid administrative operation [{0} - {1:?}]")]
+    InvalidAdministrativeOperation(String, Transaction),
     #[error("Transaction already processed with same id [{0:?}]")]
     DuplicateTransaction(Transaction),
     #[error("Transaction cannot be disputed without a previous deposit [{0:?}]")]
@@ -34,10 +83,60 @@ pub enum TransactionError {
     CannotResolveWithoutDispute(Transaction),
     #[error("Transaction cannot be disputed again because it is under dispute now [{0:?}]")]
     TransactionBeingDisputed(Transaction),
+    #[error("Transaction cannot be disputed again because it was charged back [{0:?}]")]
+    TransactionChargedBack(Transaction),
+    #[error("Transaction cannot be disputed more than {0} times [{1:?}]")]
+    DisputeLimitExceeded(u32, Transaction),
+    #[error("Transaction cannot be disputed after a chargeback [{0:?}]")]
+    DisputeAfterChargeback(Transaction),
+    #[error("Transaction cannot be disputed after the dispute window of the deposit [{0:?}]")]
+    DisputeWindowExpired(Transaction),
+    #[error("Authorization not found, or already captured, voided or expired [{0:?}]")]
+    UnknownAuthorization(Transaction),
+    #[error("Currency does not match the one of the original transaction [{0:?}]")]
+    CurrencyMismatch(Transaction),
+    #[error("No exchange rate to the base currency for transaction [{0:?}]")]
+    MissingExchangeRate(Transaction),
+    #[error("Ledger out of balance [{0}]")]
+    LedgerImbalance(String),
+    #[error("Transfer must be applied to its source and destination accounts [{0:?}]")]
+    IncompleteTransfer(Transaction),
     #[error("Transaction cannot be charged back without a dispute [{0:?}]")]
     CannotChargebackWithoutDispute(Transaction),
 }
 
+impl TransactionError {
+    /// Checks if the error rejects a single input record that is well formed but invalid, so the
+    /// rest of the input can still be processed.
+    pub fn is_invalid_record(&self) -> bool {
+        matches!(
+            self,
+            TransactionError::InvalidTransactionAmount(_)
+                | TransactionError::InvalidCurrency(_)
+                | TransactionError::InvalidClient(_)
+                | TransactionError::InvalidAdministrativeRecord(_)
+        )
+    }
+}
+
+impl From<rusqlite::Error> for TransactionError {
+    fn from(value: rusqlite::Error) -> Self {
+        TransactionError::StorageError(value.to_string())
+    }
+}
+
+impl From<std::io::Error> for TransactionError {
+    fn from(value: std::io::Error) -> Self {
+        TransactionError::StorageError(value.to_string())
+    }
+}
+
+impl From<serde_json::Error> for TransactionError {
+    fn from(value: serde_json::Error) -> Self {
+        TransactionError::StorageError(value.to_string())
+    }
+}
+
 impl<T> From<PoisonError<T>> for TransactionError {
     fn from(value: PoisonError<T>) -> Self {
         TransactionError::SyncError(value.to_string())
@@ -0,0 +1,301 @@
+//! Conversion of the transactions in other currencies to the base currency of the accounts.
+//!
+//! The exchange rates are loaded at startup and each one is effective for a range of time. A
+//! transaction in another currency is converted with the rate effective at its timestamp, and
+//! the rate applied is kept with every transaction converted. The deposits and authorizations
+//! keep it so their later disputes and captures are converted back at the same rate even if it
+//! changed since.
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+use fake::Dummy;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+
+use crate::{Currency, PrecisionPolicy, Timestamp, TransactionError};
+
+/// Exchange rate from a currency to another one, effective from `valid_from` until right before
+/// `valid_until`. A missing bound leaves the range open on that side.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub struct ExchangeRate {
+    pub from: Currency,
+    pub to: Currency,
+    pub rate: Decimal,
+    #[serde(default)]
+    pub valid_from: Option<Timestamp>,
+    #[serde(default)]
+    pub valid_until: Option<Timestamp>,
+}
+
+impl ExchangeRate {
+    /// Returns whether the rate is effective at `at`. Transactions without a timestamp use the
+    /// rate without an end, the one currently effective.
+    fn effective_at(&self, at: Option<Timestamp>) -> bool {
+        match at {
+            Some(at) => {
+                self.valid_from.is_none_or(|from| from <= at)
+                    && self.valid_until.is_none_or(|until| at < until)
+            }
+            None => self.valid_until.is_none(),
+        }
+    }
+}
+
+/// Rate applied to convert a deposit or an authorization from `currency` to the base currency.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(test, derive(Dummy))]
+pub(crate) struct AppliedRate {
+    pub(crate) currency: Currency,
+    pub(crate) rate: Decimal,
+}
+
+impl AppliedRate {
+    /// Converts `amount` to the base currency, adjusting the result to the `precision` policy.
+    pub(crate) fn convert(
+        &self,
+        amount: Decimal,
+        precision: &PrecisionPolicy,
+    ) -> Result<Decimal, TransactionError> {
+        let converted = amount.checked_mul(self.rate).ok_or_else(|| {
+            TransactionError::InvalidTransactionAmount(format!(
+                "Amount {} {} is too large to convert",
+                amount, self.currency
+            ))
+        })?;
+        precision.apply(converted)
+    }
+
+    /// Converts `amount`, a part of a converted deposit or authorization of which `remaining`
+    /// is left in the base currency. Each part is rounded on its own, so the part that takes
+    /// all that is left in the original currency gets the `remaining` amount instead, and the
+    /// parts never add up to more or less than the whole converted.
+    pub(crate) fn convert_part(
+        &self,
+        amount: Decimal,
+        remaining: Decimal,
+        precision: &PrecisionPolicy,
+    ) -> Result<Decimal, TransactionError> {
+        // Rounded up, since the rounding of the previous parts may have left it slightly below.
+        let left = remaining.checked_div(self.rate).map(|left| {
+            left.round_dp_with_strategy(precision.scale(), RoundingStrategy::AwayFromZero)
+        });
+        if left == Some(amount) {
+            return Ok(remaining);
+        }
+        self.convert(amount, precision)
+    }
+}
+
+/// Conversion of the transactions in other currencies to the `base` currency of the accounts,
+/// with the rates from each currency to the base one. Converted amounts are adjusted with the
+/// `precision` policy, which rounds them to four decimal places by default.
+///
+/// # Examples
+///
+/// ```
+/// use payment_settle_accounts::{CurrencyConversion, ExchangeRate, PrecisionPolicy};
+/// use rust_decimal_macros::dec;
+///
+/// let rate = ExchangeRate {
+///     from: "GBP".parse().unwrap(),
+///     to: "EUR".parse().unwrap(),
+///     rate: dec!(1.15),
+///     valid_from: None,
+///     valid_until: None,
+/// };
+/// let conversion = CurrencyConversion::new(
+///     "EUR".parse().unwrap(),
+///     vec![rate],
+///     PrecisionPolicy::default(),
+/// )
+/// .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CurrencyConversion {
+    base: Currency,
+    rates: BTreeMap<Currency, Vec<ExchangeRate>>,
+    precision: PrecisionPolicy,
+}
+
+impl CurrencyConversion {
+    /// Creates the conversion to `base` with the given `rates`, ignoring the ones to other
+    /// currencies. Every rate must be positive, with a non-empty range of time, and the ranges
+    /// of the rates of a currency cannot overlap.
+    pub fn new(
+        base: Currency,
+        rates: Vec<ExchangeRate>,
+        precision: PrecisionPolicy,
+    ) -> Result<Self, TransactionError> {
+        let mut by_currency = BTreeMap::<Currency, Vec<ExchangeRate>>::new();
+        for rate in rates.into_iter().filter(|rate| rate.to == base) {
+            if rate.rate <= Decimal::ZERO || rate.from == base {
+                return Err(TransactionError::InvalidExchangeRate(format!(
+                    "rate {} from {} to {}",
+                    rate.rate, rate.from, rate.to
+                )));
+            }
+            if let (Some(from), Some(until)) = (rate.valid_from, rate.valid_until) {
+                if from >= until {
+                    return Err(TransactionError::InvalidExchangeRate(format!(
+                        "rate from {} to {} valid from {} until {}",
+                        rate.from, rate.to, from, until
+                    )));
+                }
+            }
+            by_currency.entry(rate.from).or_default().push(rate);
+        }
+        for (currency, rates) in by_currency.iter_mut() {
+            // Open starts sort first, as `None` is lower than any timestamp.
+            rates.sort_by_key(|rate| rate.valid_from);
+            let overlaps =
+                rates
+                    .windows(2)
+                    .any(|pair| match (pair[0].valid_until, pair[1].valid_from) {
+                        (Some(until), Some(from)) => until > from,
+                        _ => true,
+                    });
+            if overlaps {
+                return Err(TransactionError::InvalidExchangeRate(format!(
+                    "overlapping rates from {} to {}",
+                    currency, base
+                )));
+            }
+        }
+        Ok(Self {
+            base,
+            rates: by_currency,
+            precision,
+        })
+    }
+
+    /// Returns the currency the transactions are converted to.
+    pub fn base(&self) -> Currency {
+        self.base
+    }
+
+    /// Returns the precision policy applied to the converted amounts.
+    pub(crate) fn precision(&self) -> &PrecisionPolicy {
+        &self.precision
+    }
+
+    /// Returns the rate from `currency` to the base currency effective at `at`, if any.
+    pub(crate) fn rate(&self, currency: Currency, at: Option<Timestamp>) -> Option<AppliedRate> {
+        self.rates
+            .get(&currency)?
+            .iter()
+            .find(|rate| rate.effective_at(at))
+            .map(|rate| AppliedRate {
+                currency,
+                rate: rate.rate,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{ExcessPrecision, RoundingMode};
+
+    fn rate(
+        from: &str,
+        rate: Decimal,
+        valid: (Option<Timestamp>, Option<Timestamp>),
+    ) -> ExchangeRate {
+        ExchangeRate {
+            from: from.parse().unwrap(),
+            to: "EUR".parse().unwrap(),
+            rate,
+            valid_from: valid.0,
+            valid_until: valid.1,
+        }
+    }
+
+    fn conversion(rates: Vec<ExchangeRate>) -> Result<CurrencyConversion, TransactionError> {
+        CurrencyConversion::new("EUR".parse().unwrap(), rates, PrecisionPolicy::default())
+    }
+
+    #[test]
+    fn test_rate_effective_at() {
+        let gbp = "GBP".parse().unwrap();
+        let conversion = conversion(vec![
+            rate("GBP", dec!(1.2), (None, Some(100))),
+            rate("GBP", dec!(1.1), (Some(100), None)),
+        ])
+        .unwrap();
+
+        assert_eq!(conversion.rate(gbp, Some(99)).unwrap().rate, dec!(1.2));
+        assert_eq!(conversion.rate(gbp, Some(100)).unwrap().rate, dec!(1.1));
+        assert_eq!(conversion.rate(gbp, None).unwrap().rate, dec!(1.1));
+        assert!(conversion.rate("USD".parse().unwrap(), Some(1)).is_none());
+    }
+
+    #[test]
+    fn test_invalid_rates() {
+        for rates in [
+            vec![rate("GBP", dec!(0), (None, None))],
+            vec![rate("EUR", dec!(1), (None, None))],
+            vec![rate("GBP", dec!(1), (Some(10), Some(10)))],
+            vec![
+                rate("GBP", dec!(1.2), (None, Some(100))),
+                rate("GBP", dec!(1.1), (Some(99), None)),
+            ],
+            vec![
+                rate("GBP", dec!(1.2), (None, None)),
+                rate("GBP", dec!(1.1), (Some(100), None)),
+            ],
+        ] {
+            assert!(matches!(
+                conversion(rates),
+                Err(TransactionError::InvalidExchangeRate(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_convert_with_rounding() {
+        let applied = AppliedRate {
+            currency: "GBP".parse().unwrap(),
+            rate: dec!(1.15),
+        };
+        let precision = |excess| PrecisionPolicy::builder().excess(excess).build();
+
+        let half_up = precision(ExcessPrecision::Round(RoundingMode::HalfUp));
+        assert_eq!(applied.convert(dec!(2), &half_up).unwrap(), dec!(2.30));
+        assert_eq!(
+            applied.convert(dec!(0.0005), &half_up).unwrap(),
+            dec!(0.0006)
+        );
+        let truncate = precision(ExcessPrecision::Truncate);
+        assert_eq!(
+            applied.convert(dec!(0.0005), &truncate).unwrap(),
+            dec!(0.0005)
+        );
+        assert!(applied
+            .convert(dec!(0.0001), &precision(ExcessPrecision::Reject))
+            .is_err());
+    }
+
+    #[test]
+    fn test_convert_parts() {
+        let applied = AppliedRate {
+            currency: "GBP".parse().unwrap(),
+            rate: dec!(1.00005),
+        };
+        let half_up = PrecisionPolicy::builder()
+            .excess(ExcessPrecision::Round(RoundingMode::HalfUp))
+            .build();
+        let deposited = applied.convert(dec!(2), &half_up).unwrap();
+        assert_eq!(deposited, dec!(2.0001));
+
+        // Both halves round up on their own, but the second one only gets what is left.
+        let first = applied.convert_part(dec!(1), deposited, &half_up).unwrap();
+        assert_eq!(first, dec!(1.0001));
+        let second = applied
+            .convert_part(dec!(1), deposited - first, &half_up)
+            .unwrap();
+        assert_eq!(second, dec!(1.0000));
+        assert_eq!(first + second, deposited);
+    }
+}
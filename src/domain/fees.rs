@@ -0,0 +1,251 @@
+//! Fees charged to the accounts for their deposits, withdrawals and chargebacks.
+//!
+//! Every fee is a flat amount plus a percentage of the amount of the transaction. The fee is
+//! taken from the available funds of the account in the currency of the transaction, after
+//! converting it to the base currency if needed, and credited to the house account. Flat amounts
+//! are in the base currency, so they are not charged on the balances kept in other currencies.
+use std::str::FromStr;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use typed_builder::TypedBuilder;
+
+use crate::{ClientId, Currency, TransactionError, TransactionType};
+
+/// Fee of a transaction: a `flat` amount plus a `percentage` of the amount of the transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Fee {
+    flat: Decimal,
+    percentage: Decimal,
+}
+
+impl Fee {
+    /// Creates a fee of `flat` plus `percentage` percent. Both must be non-negative.
+    pub fn new(flat: Decimal, percentage: Decimal) -> Result<Self, TransactionError> {
+        if flat.is_sign_negative() || percentage.is_sign_negative() {
+            return Err(TransactionError::InvalidFee(format!(
+                "{} plus {}%",
+                flat, percentage
+            )));
+        }
+        Ok(Self { flat, percentage })
+    }
+
+    /// Returns the fee for a transaction of `amount`, rounded half away from zero to `scale`
+    /// decimal places, or none if it does not fit in a decimal.
+    fn charged(&self, amount: Decimal, scale: u32) -> Option<Decimal> {
+        let variable = amount
+            .checked_mul(self.percentage)?
+            .checked_div(Decimal::ONE_HUNDRED)?;
+        Some(
+            self.flat
+                .checked_add(variable)?
+                .round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero),
+        )
+    }
+}
+
+impl FromStr for Fee {
+    type Err = TransactionError;
+
+    /// Parses a flat fee like `0.5`, a percentage like `1.5%` or both like `0.5+1.5%`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TransactionError::InvalidFee(s.to_string());
+        let parse = |value: &str| value.trim().parse::<Decimal>().map_err(|_| invalid());
+        let (flat, percentage) = match s.split_once('+') {
+            Some((flat, percentage)) => (Some(flat), Some(percentage)),
+            None if s.trim_end().ends_with('%') => (None, Some(s)),
+            None => (Some(s), None),
+        };
+        let percentage = match percentage {
+            Some(percentage) => parse(percentage.trim().strip_suffix('%').ok_or_else(invalid)?)?,
+            None => Decimal::ZERO,
+        };
+        let flat = flat.map(parse).transpose()?.unwrap_or_default();
+        Fee::new(flat, percentage)
+    }
+}
+
+/// Fees charged for deposits, withdrawals and chargebacks, credited to the `house` account. The
+/// house account itself is never charged. Transaction types without a fee are free.
+///
+/// # Examples
+///
+/// ```
+/// use payment_settle_accounts::{Fee, FeeSchedule};
+///
+/// let fees = FeeSchedule::builder()
+///     .house(0)
+///     .withdrawal("0.5+1%".parse::<Fee>().unwrap())
+///     .chargeback("15".parse::<Fee>().unwrap())
+///     .build();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, TypedBuilder)]
+pub struct FeeSchedule {
+    /// Account receiving the fees.
+    house: ClientId,
+    #[builder(default, setter(into))]
+    deposit: Option<Fee>,
+    #[builder(default, setter(into))]
+    withdrawal: Option<Fee>,
+    #[builder(default, setter(into))]
+    chargeback: Option<Fee>,
+}
+
+impl FeeSchedule {
+    /// Returns the account receiving the fees.
+    pub fn house(&self) -> ClientId {
+        self.house
+    }
+
+    /// Returns the fee for a transaction of type `ty` and `amount` applied to the balances in
+    /// `currency`, or in the base currency if none, rounded to `scale` decimal places. It is zero
+    /// if the type has no fee, or none if it does not fit in a decimal. Only the percentage is
+    /// charged on the balances in other currencies.
+    pub(crate) fn fee(
+        &self,
+        ty: &TransactionType,
+        currency: Option<Currency>,
+        amount: Decimal,
+        scale: u32,
+    ) -> Option<Decimal> {
+        self.of(ty).map_or(Some(Decimal::ZERO), |fee| {
+            let fee = match currency {
+                Some(_) => Fee {
+                    flat: Decimal::ZERO,
+                    ..fee
+                },
+                None => fee,
+            };
+            fee.charged(amount, scale)
+        })
+    }
+
+    /// Returns whether transactions of type `ty` must be in the base currency, because they are
+    /// charged a flat fee, or for deposits, because their chargebacks are.
+    pub(crate) fn requires_base_currency(&self, ty: &TransactionType) -> bool {
+        let flat = |ty| self.of(&ty).is_some_and(|fee| fee.flat > Decimal::ZERO);
+        match ty {
+            TransactionType::Deposit => {
+                flat(TransactionType::Deposit) || flat(TransactionType::Chargeback)
+            }
+            TransactionType::Withdrawal => flat(TransactionType::Withdrawal),
+            _ => false,
+        }
+    }
+
+    /// Returns whether transactions of type `ty` can be charged a fee.
+    pub(crate) fn charges(&self, ty: &TransactionType) -> bool {
+        self.of(ty).is_some()
+    }
+
+    fn of(&self, ty: &TransactionType) -> Option<Fee> {
+        match ty {
+            TransactionType::Deposit => self.deposit,
+            TransactionType::Withdrawal => self.withdrawal,
+            TransactionType::Chargeback => self.chargeback,
+            _ => None,
+        }
+    }
+}
+
+/// Fee charged to an account by a transaction, to be credited to the house account with
+/// `Account::collect_fee`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeCharge {
+    pub(crate) currency: Option<Currency>,
+    pub(crate) amount: Decimal,
+}
+
+impl FeeCharge {
+    /// Returns the currency of the fee, or none for the base currency.
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Returns the amount of the fee.
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_fee_from_str() {
+        assert_eq!(
+            "0.5+1.5%".parse::<Fee>().unwrap(),
+            Fee::new(dec!(0.5), dec!(1.5)).unwrap()
+        );
+        assert_eq!(
+            "2%".parse::<Fee>().unwrap(),
+            Fee::new(dec!(0), dec!(2)).unwrap()
+        );
+        assert_eq!(
+            "3".parse::<Fee>().unwrap(),
+            Fee::new(dec!(3), dec!(0)).unwrap()
+        );
+        for invalid in ["-1", "1+2", "1+-2%", "free", "%"] {
+            assert!(invalid.parse::<Fee>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_fee_of_transaction_type() {
+        let fees = FeeSchedule::builder()
+            .house(0)
+            .deposit(Fee::new(dec!(0), dec!(0.125)).unwrap())
+            .withdrawal(Fee::new(dec!(0.5), dec!(1)).unwrap())
+            .build();
+
+        assert_eq!(
+            fees.fee(&TransactionType::Deposit, None, dec!(10), 4),
+            Some(dec!(0.0125))
+        );
+        assert_eq!(
+            fees.fee(&TransactionType::Deposit, None, dec!(0.2), 4),
+            Some(dec!(0.0003))
+        );
+        assert_eq!(
+            fees.fee(&TransactionType::Withdrawal, None, dec!(20), 4),
+            Some(dec!(0.7))
+        );
+        assert_eq!(
+            fees.fee(&TransactionType::Chargeback, None, dec!(20), 4),
+            Some(dec!(0))
+        );
+        let fee = Fee::new(dec!(0), dec!(200)).unwrap();
+        assert_eq!(fee.charged(Decimal::MAX, 4), None);
+    }
+
+    #[test]
+    fn test_fee_with_scale_and_currency() {
+        let fees = FeeSchedule::builder()
+            .house(0)
+            .deposit(Fee::new(dec!(0), dec!(0.125)).unwrap())
+            .withdrawal(Fee::new(dec!(0.5), dec!(1)).unwrap())
+            .build();
+        let jpy = "JPY".parse().ok();
+
+        assert_eq!(
+            fees.fee(&TransactionType::Deposit, None, dec!(10), 2),
+            Some(dec!(0.01))
+        );
+        assert_eq!(
+            fees.fee(&TransactionType::Withdrawal, jpy, dec!(20), 4),
+            Some(dec!(0.2))
+        );
+        assert!(!fees.requires_base_currency(&TransactionType::Deposit));
+        assert!(fees.requires_base_currency(&TransactionType::Withdrawal));
+
+        let fees = FeeSchedule::builder()
+            .house(0)
+            .chargeback(Fee::new(dec!(15), dec!(0)).unwrap())
+            .build();
+        assert!(fees.requires_base_currency(&TransactionType::Deposit));
+        assert!(!fees.requires_base_currency(&TransactionType::Chargeback));
+    }
+}
@@ -0,0 +1,520 @@
+//! System-wide invariants checked over all the accounts of an engine.
+//!
+//! The `InvariantChecker` follows the journal entries of the transactions processed and adds up
+//! the funds that entered and left the accounts by transaction type. Once the run is over, it
+//! checks that the totals of the accounts add up to the opening balances plus the deposits minus
+//! the withdrawals, chargebacks and captures, that the funds held by disputes match the deposits
+//! still under dispute, that no account holds negative funds, and that locked accounts were not
+//! changed after they were locked.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::{
+    Account, AccountPolicy, ClientId, Currency, JournalEntry, LedgerAccount, Transaction,
+    TransactionType,
+};
+
+/// Invariant of the accounts of an engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Invariant {
+    /// The totals of the accounts are the opening balances plus the deposits minus the
+    /// withdrawals, chargebacks and captures.
+    Conservation,
+    /// The funds held by disputes are the amounts of the deposits under dispute.
+    HeldByDisputes,
+    /// No account holds negative funds.
+    NonNegativeHeld,
+    /// Locked accounts are not changed by the transactions processed after they were locked.
+    LockedUnchanged,
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Invariant::Conservation => write!(f, "conservation of funds"),
+            Invariant::HeldByDisputes => write!(f, "held funds match the open disputes"),
+            Invariant::NonNegativeHeld => write!(f, "non-negative held funds"),
+            Invariant::LockedUnchanged => write!(f, "locked accounts unchanged"),
+        }
+    }
+}
+
+/// Violation of an invariant by the accounts of some clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    invariant: Invariant,
+    clients: Vec<ClientId>,
+    detail: String,
+}
+
+impl Violation {
+    fn new(invariant: Invariant, clients: Vec<ClientId>, detail: String) -> Self {
+        Self {
+            invariant,
+            clients,
+            detail,
+        }
+    }
+
+    /// Returns the invariant violated.
+    pub fn invariant(&self) -> Invariant {
+        self.invariant
+    }
+
+    /// Returns the clients whose accounts violate the invariant.
+    pub fn clients(&self) -> &[ClientId] {
+        &self.clients
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let clients = self
+            .clients
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            f,
+            "Invariant {} violated by clients [{}]: {}",
+            self.invariant, clients, self.detail
+        )
+    }
+}
+
+/// Funds that entered and left the accounts in a currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Flows {
+    opening: Decimal,
+    deposits: Decimal,
+    withdrawals: Decimal,
+    chargebacks: Decimal,
+    captures: Decimal,
+}
+
+impl Flows {
+    /// Returns the funds the accounts should have in total.
+    fn expected(&self) -> Decimal {
+        self.opening
+            .saturating_add(self.deposits)
+            .saturating_sub(self.withdrawals)
+            .saturating_sub(self.chargebacks)
+            .saturating_sub(self.captures)
+    }
+}
+
+impl fmt::Display for Flows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "opening {} + deposits {} - withdrawals {} - chargebacks {} - captures {} = {}",
+            self.opening,
+            self.deposits,
+            self.withdrawals,
+            self.chargebacks,
+            self.captures,
+            self.expected()
+        )
+    }
+}
+
+/// Checker of the invariants of the accounts of an engine.
+///
+/// The accounts existing when the checker starts are taken as opening balances, and every
+/// transaction processed afterwards must be observed with the entry posting its changes.
+#[derive(Debug, Clone, Default)]
+pub struct InvariantChecker {
+    flows: BTreeMap<Option<Currency>, Flows>,
+    /// Totals of each client in each currency, from the opening balances and the entries.
+    totals: BTreeMap<(ClientId, Option<Currency>), Decimal>,
+    /// Funds held by disputes that are not backed by a tracked deposit: the opening ones and
+    /// those of deposits dropped from the history.
+    untracked_held: BTreeMap<(ClientId, Option<Currency>), Decimal>,
+    locked: BTreeSet<ClientId>,
+    violations: Vec<Violation>,
+}
+
+impl InvariantChecker {
+    /// Creates a checker for an engine without accounts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the balances of `account` as opening balances.
+    pub(crate) fn open(&mut self, account: &Account) {
+        let client = account.client_id();
+        let disputes = account.open_disputes();
+        for (currency, balance) in account.currency_balances() {
+            let total = balance
+                .available
+                .saturating_add(balance.held)
+                .saturating_add(account.held_by_authorizations_in(currency));
+            let flows = self.flows.entry(currency).or_default();
+            flows.opening = flows.opening.saturating_add(total);
+            add(&mut self.totals, (client, currency), total);
+            let tracked = disputes.get(&currency).copied().unwrap_or_default();
+            add(
+                &mut self.untracked_held,
+                (client, currency),
+                balance.held - tracked,
+            );
+        }
+        if account.locked() {
+            self.locked.insert(client);
+        }
+    }
+
+    /// Follows the changes posted to `entry` by `transaction`, processed with `policy`, where
+    /// `accounts` are the accounts involved once it was processed.
+    pub(crate) fn observe<'a>(
+        &mut self,
+        transaction: &Transaction,
+        entry: &JournalEntry,
+        policy: &AccountPolicy,
+        accounts: impl IntoIterator<Item = &'a Account>,
+    ) {
+        let mut changes = BTreeMap::<(ClientId, Option<Currency>), Decimal>::new();
+        for posting in entry.postings() {
+            let client = match posting.account {
+                LedgerAccount::Available(client)
+                | LedgerAccount::Held(client)
+                | LedgerAccount::Authorized(client) => client,
+                LedgerAccount::External => {
+                    self.flow(transaction, posting.currency, posting.amount);
+                    continue;
+                }
+            };
+            add(&mut self.totals, (client, posting.currency), posting.amount);
+            add(&mut changes, (client, posting.currency), posting.amount);
+        }
+        for ((client, currency), change) in changes {
+            let allowed =
+                client == transaction.client_id() && policy.allowed_when_locked(transaction.ty());
+            if !change.is_zero() && self.locked.contains(&client) && !allowed {
+                self.violations.push(Violation::new(
+                    Invariant::LockedUnchanged,
+                    vec![client],
+                    format!(
+                        "{:?} transaction {} changed the total by {} in {}",
+                        transaction.ty(),
+                        transaction.transaction_id(),
+                        change,
+                        currency_name(currency)
+                    ),
+                ));
+            }
+        }
+        for account in accounts {
+            if account.locked() {
+                self.locked.insert(account.client_id());
+            } else {
+                self.locked.remove(&account.client_id());
+            }
+        }
+    }
+
+    /// Adds the funds moved from the external funds by `transaction` to the flows of its type,
+    /// where a negative `amount` entered the accounts.
+    fn flow(&mut self, transaction: &Transaction, currency: Option<Currency>, amount: Decimal) {
+        let flows = self.flows.entry(currency).or_default();
+        let flow = match transaction.ty() {
+            TransactionType::Deposit => {
+                flows.deposits = flows.deposits.saturating_sub(amount);
+                return;
+            }
+            TransactionType::Withdrawal => &mut flows.withdrawals,
+            TransactionType::Chargeback => &mut flows.chargebacks,
+            TransactionType::Capture => &mut flows.captures,
+            ty => {
+                self.violations.push(Violation::new(
+                    Invariant::Conservation,
+                    vec![transaction.client_id()],
+                    format!(
+                        "{:?} transaction {} moved {} out of the accounts",
+                        ty,
+                        transaction.transaction_id(),
+                        amount
+                    ),
+                ));
+                return;
+            }
+        };
+        *flow = flow.saturating_add(amount);
+    }
+
+    /// Takes the funds held by the disputes of `account` that are no longer tracked as untracked
+    /// ones, once its history was pruned. `before` are the open disputes prior to the prune.
+    pub(crate) fn pruned(
+        &mut self,
+        account: &Account,
+        before: &BTreeMap<Option<Currency>, Decimal>,
+    ) {
+        let after = account.open_disputes();
+        for (currency, disputed) in before {
+            let dropped = *disputed - after.get(currency).copied().unwrap_or_default();
+            add(
+                &mut self.untracked_held,
+                (account.client_id(), *currency),
+                dropped,
+            );
+        }
+    }
+
+    /// Checks the invariants over all the `accounts` of the engine, returning the violations
+    /// found while the transactions were processed followed by the ones found now.
+    pub fn check<'a>(&self, accounts: impl IntoIterator<Item = &'a Account>) -> Vec<Violation> {
+        let mut violations = self.violations.clone();
+        let mut totals = BTreeMap::<Option<Currency>, Decimal>::new();
+        let mut diverging = BTreeMap::<Option<Currency>, BTreeSet<ClientId>>::new();
+        let mut seen = BTreeSet::new();
+        for account in accounts {
+            let client = account.client_id();
+            let disputes = account.open_disputes();
+            for (currency, balance) in account.currency_balances() {
+                seen.insert((client, currency));
+                let held_by_authorizations = account.held_by_authorizations_in(currency);
+                if balance.held.is_sign_negative() || held_by_authorizations.is_sign_negative() {
+                    violations.push(Violation::new(
+                        Invariant::NonNegativeHeld,
+                        vec![client],
+                        format!(
+                            "held {} by disputes and {} by authorizations in {}",
+                            balance.held,
+                            held_by_authorizations,
+                            currency_name(currency)
+                        ),
+                    ));
+                }
+                let disputed = disputes
+                    .get(&currency)
+                    .copied()
+                    .unwrap_or_default()
+                    .saturating_add(get(&self.untracked_held, (client, currency)));
+                if balance.held != disputed {
+                    violations.push(Violation::new(
+                        Invariant::HeldByDisputes,
+                        vec![client],
+                        format!(
+                            "held {} by disputes in {}, but {} is under dispute",
+                            balance.held,
+                            currency_name(currency),
+                            disputed
+                        ),
+                    ));
+                }
+                let total = balance
+                    .available
+                    .saturating_add(balance.held)
+                    .saturating_add(held_by_authorizations);
+                add(&mut totals, currency, total);
+                if total != get(&self.totals, (client, currency)) {
+                    diverging.entry(currency).or_default().insert(client);
+                }
+            }
+        }
+        // Accounts that disappeared had funds that are missing now.
+        for ((client, currency), total) in self.totals.iter() {
+            if !total.is_zero() && !seen.contains(&(*client, *currency)) {
+                diverging.entry(*currency).or_default().insert(*client);
+            }
+        }
+        let currencies = self
+            .flows
+            .keys()
+            .chain(totals.keys())
+            .copied()
+            .collect::<BTreeSet<_>>();
+        for currency in currencies {
+            let flows = self.flows.get(&currency).copied().unwrap_or_default();
+            let total = get(&totals, currency);
+            let clients = diverging.remove(&currency).unwrap_or_default();
+            if total != flows.expected() || !clients.is_empty() {
+                violations.push(Violation::new(
+                    Invariant::Conservation,
+                    clients.into_iter().collect(),
+                    format!(
+                        "accounts total {} in {}, but {}",
+                        total,
+                        currency_name(currency),
+                        flows
+                    ),
+                ));
+            }
+        }
+        violations
+    }
+}
+
+fn add<K: Ord>(map: &mut BTreeMap<K, Decimal>, key: K, amount: Decimal) {
+    let value = map.entry(key).or_default();
+    *value = value.saturating_add(amount);
+}
+
+fn get<K: Ord>(map: &BTreeMap<K, Decimal>, key: K) -> Decimal {
+    map.get(&key).copied().unwrap_or_default()
+}
+
+/// Returns the name of `currency` for the violations reported.
+fn currency_name(currency: Option<Currency>) -> String {
+    currency.map_or_else(
+        || "the base currency".to_string(),
+        |currency| currency.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::Amount;
+
+    fn process(
+        checker: &mut InvariantChecker,
+        account: &mut Account,
+        ty: TransactionType,
+        tx_id: u32,
+        amount: Option<Decimal>,
+    ) {
+        let builder = Transaction::builder()
+            .ty(ty)
+            .client_id(account.client_id())
+            .transaction_id(tx_id);
+        let transaction = match amount {
+            Some(amount) => builder.amount(Amount::new(amount).unwrap()).build(),
+            None => builder.build(),
+        };
+        let policy = AccountPolicy::default();
+        let mut entry = JournalEntry::new();
+        let _ = account.process_with_journal(&transaction, &policy, &mut entry);
+        checker.observe(&transaction, &entry, &policy, [&*account]);
+    }
+
+    #[test]
+    fn test_invariants_hold() {
+        let mut checker = InvariantChecker::new();
+        let opening = Account::create_with(2, dec!(5), dec!(1), false);
+        checker.open(&opening);
+        let mut account = Account::new(1);
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Deposit,
+            1,
+            Some(dec!(10)),
+        );
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Deposit,
+            2,
+            Some(dec!(4)),
+        );
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Withdrawal,
+            3,
+            Some(dec!(3)),
+        );
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Dispute,
+            1,
+            Some(dec!(6)),
+        );
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Dispute,
+            2,
+            None,
+        );
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Chargeback,
+            2,
+            None,
+        );
+        // Rejected, as the chargeback locked the account.
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Deposit,
+            4,
+            Some(dec!(1)),
+        );
+
+        assert_eq!(account.held_by_disputes(), dec!(6));
+        assert_eq!(checker.check([&account, &opening]), vec![]);
+    }
+
+    #[test]
+    fn test_violations_name_the_clients() {
+        let mut checker = InvariantChecker::new();
+        let mut account = Account::new(1);
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Deposit,
+            1,
+            Some(dec!(10)),
+        );
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Dispute,
+            1,
+            None,
+        );
+        process(
+            &mut checker,
+            &mut account,
+            TransactionType::Chargeback,
+            1,
+            None,
+        );
+        let locked = account.clone();
+
+        // Funds that appear without a transaction, in an account held with no dispute.
+        let tampered = Account::create_with(1, dec!(1), dec!(-2), true);
+        let violations = checker.check([&tampered]);
+        let invariants = violations
+            .iter()
+            .map(|violation| (violation.invariant(), violation.clients().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            invariants,
+            [
+                (Invariant::NonNegativeHeld, vec![1]),
+                (Invariant::HeldByDisputes, vec![1]),
+                (Invariant::Conservation, vec![1]),
+            ]
+        );
+        assert_eq!(checker.check([&locked]), vec![]);
+
+        let mut entry = JournalEntry::new();
+        entry.post(
+            LedgerAccount::Available(1),
+            LedgerAccount::External,
+            None,
+            dec!(3),
+        );
+        let deposit = Transaction::builder()
+            .ty(TransactionType::Deposit)
+            .client_id(1)
+            .transaction_id(2)
+            .amount(3)
+            .build();
+        checker.observe(&deposit, &entry, &AccountPolicy::default(), [&locked]);
+        assert_eq!(
+            checker.check([&locked])[0].invariant(),
+            Invariant::LockedUnchanged
+        );
+    }
+}
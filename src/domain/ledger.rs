@@ -0,0 +1,354 @@
+//! Double-entry ledger underneath the balances of the accounts.
+//!
+//! Every change to the balances of an account is posted to a `JournalEntry` as a debit to one
+//! ledger account and a credit to another one: the available funds of a client, the ones held by
+//! disputes or by authorizations, or the external world money comes from and goes to. An account
+//! processing a transaction posts its changes first and then derives its new available and held
+//! funds from those postings. Fees are posted from the available funds of the client charged to
+//! the ones of the house account: the house is an ordinary client, reported in the summary like
+//! any other, so it has no ledger account of its own. The `Ledger` derives the balance of every
+//! ledger account from the entries recorded, so the balances of the accounts can be verified
+//! against it, and its trial balance proves the debits and credits of each currency still match.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::{Account, ClientId, Currency, TransactionError};
+
+/// Account of the ledger, debited and credited by the postings of the journal entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+    /// Available funds of a client.
+    Available(ClientId),
+    /// Funds of a client held by disputes.
+    Held(ClientId),
+    /// Funds of a client held by authorizations until they are captured, voided or expire.
+    Authorized(ClientId),
+    /// Funds outside of the accounts: deposits come from it, and withdrawals, captures and
+    /// chargebacks go to it.
+    External,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerAccount::Available(client) => write!(f, "available funds of client {}", client),
+            LedgerAccount::Held(client) => write!(f, "held funds of client {}", client),
+            LedgerAccount::Authorized(client) => {
+                write!(f, "authorized funds of client {}", client)
+            }
+            LedgerAccount::External => write!(f, "external funds"),
+        }
+    }
+}
+
+/// Posting of a journal entry: a debit to `account` if `amount` is positive, or a credit if it
+/// is negative.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Posting {
+    pub account: LedgerAccount,
+    pub currency: Option<Currency>,
+    pub amount: Decimal,
+}
+
+/// Postings of the changes a transaction made to the balances of the accounts.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct JournalEntry {
+    postings: Vec<Posting>,
+}
+
+impl JournalEntry {
+    /// Creates an entry without postings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Posts `amount` in `currency` moved from `credit` to `debit`. Negative amounts move the
+    /// funds the other way around, and zero amounts are not posted.
+    pub(crate) fn post(
+        &mut self,
+        debit: LedgerAccount,
+        credit: LedgerAccount,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) {
+        if amount.is_zero() {
+            return;
+        }
+        self.postings.push(Posting {
+            account: debit,
+            currency,
+            amount,
+        });
+        self.postings.push(Posting {
+            account: credit,
+            currency,
+            amount: -amount,
+        });
+    }
+
+    /// Adds the postings of `other` to the entry.
+    pub(crate) fn extend(&mut self, other: JournalEntry) {
+        self.postings.extend(other.postings);
+    }
+
+    /// Returns the postings of the entry.
+    pub fn postings(&self) -> &[Posting] {
+        &self.postings
+    }
+
+    /// Returns the clients whose funds the entry posts to.
+    pub fn clients(&self) -> BTreeSet<ClientId> {
+        self.postings
+            .iter()
+            .filter_map(|posting| match posting.account {
+                LedgerAccount::Available(client)
+                | LedgerAccount::Held(client)
+                | LedgerAccount::Authorized(client) => Some(client),
+                LedgerAccount::External => None,
+            })
+            .collect()
+    }
+
+    /// Returns the currencies whose debits and credits in the entry do not match.
+    fn unbalanced(&self) -> Vec<Option<Currency>> {
+        let mut sums = BTreeMap::<Option<Currency>, Decimal>::new();
+        for posting in self.postings.iter() {
+            let sum = sums.entry(posting.currency).or_default();
+            *sum = sum.saturating_add(posting.amount);
+        }
+        sums.into_iter()
+            .filter(|(_, sum)| !sum.is_zero())
+            .map(|(currency, _)| currency)
+            .collect()
+    }
+}
+
+/// Balances of the ledger accounts, derived from the journal entries recorded: the debits minus
+/// the credits of each account in each currency.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Ledger {
+    balances: BTreeMap<LedgerAccount, BTreeMap<Option<Currency>, Decimal>>,
+}
+
+impl Ledger {
+    /// Creates an empty ledger.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the postings of `entry`, which must debit as much as it credits in every
+    /// currency. An unbalanced entry is rejected without recording any of its postings.
+    pub fn record(&mut self, entry: &JournalEntry) -> Result<(), TransactionError> {
+        if let Some(currency) = entry.unbalanced().first() {
+            return Err(TransactionError::LedgerImbalance(format!(
+                "journal entry debits and credits differ in {}",
+                currency_name(*currency)
+            )));
+        }
+        let mut updated = BTreeMap::<(LedgerAccount, Option<Currency>), Decimal>::new();
+        for posting in entry.postings.iter() {
+            let key = (posting.account, posting.currency);
+            let balance = updated
+                .get(&key)
+                .copied()
+                .unwrap_or_else(|| self.balance(posting.account, posting.currency));
+            let balance = balance.checked_add(posting.amount).ok_or_else(|| {
+                TransactionError::LedgerImbalance(format!(
+                    "overflow of the {} in {}",
+                    posting.account,
+                    currency_name(posting.currency)
+                ))
+            })?;
+            updated.insert(key, balance);
+        }
+        for ((account, currency), balance) in updated {
+            self.restore(account, currency, balance);
+        }
+        Ok(())
+    }
+
+    /// Returns the balance of `account` in `currency`, or in the base currency if none.
+    pub fn balance(&self, account: LedgerAccount, currency: Option<Currency>) -> Decimal {
+        self.balances
+            .get(&account)
+            .and_then(|balances| balances.get(&currency))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the balances of every ledger account in every currency.
+    pub fn balances(
+        &self,
+    ) -> impl Iterator<Item = (LedgerAccount, Option<Currency>, Decimal)> + '_ {
+        self.balances.iter().flat_map(|(account, balances)| {
+            balances
+                .iter()
+                .map(|(currency, balance)| (*account, *currency, *balance))
+        })
+    }
+
+    /// Sets the balance of a ledger account, used when the ledger is rebuilt from storage.
+    pub(crate) fn restore(
+        &mut self,
+        account: LedgerAccount,
+        currency: Option<Currency>,
+        balance: Decimal,
+    ) {
+        self.balances
+            .entry(account)
+            .or_default()
+            .insert(currency, balance);
+    }
+
+    /// Checks that the balances of `account` in every currency are the ones derived from the
+    /// ledger.
+    pub fn verify(&self, account: &Account) -> Result<(), TransactionError> {
+        let client = account.client_id();
+        let kinds = [
+            LedgerAccount::Available(client),
+            LedgerAccount::Held(client),
+            LedgerAccount::Authorized(client),
+        ];
+        let currencies = account
+            .currency_balances()
+            .map(|(currency, _)| currency)
+            .chain(
+                kinds
+                    .iter()
+                    .filter_map(|kind| self.balances.get(kind))
+                    .flat_map(|balances| balances.keys().copied()),
+            )
+            .collect::<BTreeSet<_>>();
+        for currency in currencies {
+            let balance = account.balance(currency);
+            let authorized = account.held_by_authorizations_in(currency);
+            let in_ledger = kinds.map(|kind| self.balance(kind, currency));
+            if in_ledger != [balance.available, balance.held, authorized] {
+                return Err(TransactionError::LedgerImbalance(format!(
+                    "client {} has available {}, held {} and authorized {} in {}, but the ledger \
+                     has {}, {} and {}",
+                    client,
+                    balance.available,
+                    balance.held,
+                    authorized,
+                    currency_name(currency),
+                    in_ledger[0],
+                    in_ledger[1],
+                    in_ledger[2]
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the debit balances of the ledger add up to its credit balances in every
+    /// currency.
+    pub fn trial_balance(&self) -> Result<(), TransactionError> {
+        let mut totals = BTreeMap::<Option<Currency>, (Decimal, Decimal)>::new();
+        for (_, currency, balance) in self.balances() {
+            let (debits, credits) = totals.entry(currency).or_default();
+            if balance.is_sign_positive() {
+                *debits = debits.saturating_add(balance);
+            } else {
+                *credits = credits.saturating_sub(balance);
+            }
+        }
+        for (currency, (debits, credits)) in totals {
+            if debits != credits {
+                return Err(TransactionError::LedgerImbalance(format!(
+                    "trial balance in {} has debits {} and credits {}",
+                    currency_name(currency),
+                    debits,
+                    credits
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of `currency` for the messages of the ledger.
+fn currency_name(currency: Option<Currency>) -> String {
+    currency.map_or_else(
+        || "the base currency".to_string(),
+        |currency| currency.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{Transaction, TransactionType};
+
+    #[test]
+    fn test_record_balanced_entries() {
+        let mut ledger = Ledger::new();
+        let mut entry = JournalEntry::new();
+        entry.post(
+            LedgerAccount::Available(1),
+            LedgerAccount::External,
+            None,
+            dec!(10),
+        );
+        entry.post(
+            LedgerAccount::Held(1),
+            LedgerAccount::Available(1),
+            None,
+            dec!(4),
+        );
+        ledger.record(&entry).unwrap();
+
+        assert_eq!(ledger.balance(LedgerAccount::Available(1), None), dec!(6));
+        assert_eq!(ledger.balance(LedgerAccount::Held(1), None), dec!(4));
+        assert_eq!(ledger.balance(LedgerAccount::External, None), dec!(-10));
+        assert_eq!(entry.clients().into_iter().collect::<Vec<_>>(), [1]);
+        ledger.trial_balance().unwrap();
+
+        let mut account = Account::new(1);
+        for (ty, amount) in [
+            (TransactionType::Deposit, 10),
+            (TransactionType::Dispute, 4),
+        ] {
+            let transaction = Transaction::builder()
+                .ty(ty)
+                .client_id(1)
+                .transaction_id(1)
+                .amount(amount)
+                .build();
+            account.process(&transaction).unwrap();
+        }
+        ledger.verify(&account).unwrap();
+        assert!(matches!(
+            ledger.verify(&Account::new(1)),
+            Err(TransactionError::LedgerImbalance(_))
+        ));
+    }
+
+    #[test]
+    fn test_unbalanced_entry_is_rejected() {
+        let mut ledger = Ledger::new();
+        let entry = JournalEntry {
+            postings: vec![Posting {
+                account: LedgerAccount::Available(1),
+                currency: None,
+                amount: dec!(1),
+            }],
+        };
+
+        assert!(matches!(
+            ledger.record(&entry),
+            Err(TransactionError::LedgerImbalance(_))
+        ));
+        assert_eq!(ledger, Ledger::new());
+
+        ledger.restore(LedgerAccount::Available(1), None, dec!(1));
+        assert!(matches!(
+            ledger.trial_balance(),
+            Err(TransactionError::LedgerImbalance(_))
+        ));
+    }
+}
@@ -0,0 +1,407 @@
+//! Limits of the withdrawals of the clients.
+//!
+//! Every withdrawal, and every transfer to another client, is checked against the limits of its
+//! client before it touches the available funds: the maximum amount of a single withdrawal, and
+//! the maximum amount withdrawn in a calendar day and within a rolling window. Deposits,
+//! withdrawals and transfers are also checked against the maximum number of transactions of the
+//! client within the same window. The accounts keep their recent transactions to check the
+//! limits over time.
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+use fake::Dummy;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
+
+use crate::{ClientId, Currency, Timestamp, Transaction, TransactionError};
+
+/// Length of a calendar day, in seconds.
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Length in seconds of the window of the rolling limits, unless other one is given.
+pub const DEFAULT_WITHDRAWAL_WINDOW: u64 = SECONDS_PER_DAY;
+
+/// Limits of the withdrawals of a client. Amounts are in the currency of the withdrawals, and the
+/// days are calendar days in UTC. Transactions without a timestamp are taken as made at the time
+/// of the latest transaction of the client, so all of them fall in the same window if the input
+/// has no timestamps at all.
+///
+/// # Examples
+///
+/// ```
+/// use payment_settle_accounts::WithdrawalLimits;
+/// use rust_decimal_macros::dec;
+///
+/// let limits = WithdrawalLimits::builder()
+///     .max_amount(dec!(500))
+///     .daily_amount(dec!(1000))
+///     .max_transactions(10)
+///     .window(60 * 60)
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, TypedBuilder)]
+pub struct WithdrawalLimits {
+    /// Maximum amount of a single withdrawal.
+    #[builder(default, setter(into))]
+    max_amount: Option<Decimal>,
+    /// Maximum amount withdrawn in a calendar day.
+    #[builder(default, setter(into))]
+    daily_amount: Option<Decimal>,
+    /// Maximum amount withdrawn within the rolling window.
+    #[builder(default, setter(into))]
+    rolling_amount: Option<Decimal>,
+    /// Maximum number of deposits, withdrawals and transfers within the rolling window. Disputes
+    /// and the other transactions of the client are not counted.
+    #[builder(default, setter(into))]
+    max_transactions: Option<u32>,
+    /// Length of the rolling window in seconds, `DEFAULT_WITHDRAWAL_WINDOW` if none.
+    #[builder(default, setter(into))]
+    window: Option<u64>,
+}
+
+impl WithdrawalLimits {
+    /// Returns the limits taking the ones missing from `fallback`.
+    pub fn or(&self, fallback: &WithdrawalLimits) -> WithdrawalLimits {
+        WithdrawalLimits {
+            max_amount: self.max_amount.or(fallback.max_amount),
+            daily_amount: self.daily_amount.or(fallback.daily_amount),
+            rolling_amount: self.rolling_amount.or(fallback.rolling_amount),
+            max_transactions: self.max_transactions.or(fallback.max_transactions),
+            window: self.window.or(fallback.window),
+        }
+    }
+
+    fn window(&self) -> u64 {
+        self.window.unwrap_or(DEFAULT_WITHDRAWAL_WINDOW)
+    }
+
+    /// Returns whether any limit depends on the previous transactions.
+    fn over_time(&self) -> bool {
+        self.daily_amount.is_some()
+            || self.rolling_amount.is_some()
+            || self.max_transactions.is_some()
+    }
+
+    /// Checks the number of transactions within the window once `transaction` is made at `now`,
+    /// given the `recent` transactions of the client. It is the only limit of the deposits.
+    pub(crate) fn check_count(
+        &self,
+        transaction: &Transaction,
+        now: Timestamp,
+        recent: &[RecentTransaction],
+    ) -> Result<(), TransactionError> {
+        let window = self.window();
+        let count = recent
+            .iter()
+            .filter(|recent| now <= recent.timestamp.saturating_add(window))
+            .fold(1, |count, recent| count + recent.count as usize);
+        if let Some(max) = self.max_transactions.filter(|max| count > *max as usize) {
+            return Err(TransactionError::TransactionCountExceeded(
+                max,
+                transaction.clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks a withdrawal of `amount` in `currency` made at `now` by `transaction`, given the
+    /// `recent` transactions of the client.
+    pub(crate) fn check(
+        &self,
+        transaction: &Transaction,
+        currency: Option<Currency>,
+        amount: Decimal,
+        now: Timestamp,
+        recent: &[RecentTransaction],
+    ) -> Result<(), TransactionError> {
+        if let Some(max) = self.max_amount.filter(|max| amount > *max) {
+            return Err(TransactionError::WithdrawalAmountExceedsLimit(
+                max,
+                transaction.clone(),
+            ));
+        }
+        let window = self.window();
+        let in_window =
+            |withdrawal: &&RecentTransaction| now <= withdrawal.timestamp.saturating_add(window);
+        let withdrawn = |today: bool| {
+            recent
+                .iter()
+                .filter(|withdrawal| withdrawal.currency == currency)
+                .filter(|withdrawal| match today {
+                    true => withdrawal.timestamp / SECONDS_PER_DAY == now / SECONDS_PER_DAY,
+                    false => in_window(withdrawal),
+                })
+                .fold(amount, |total, withdrawal| {
+                    total.saturating_add(withdrawal.amount)
+                })
+        };
+        if let Some(max) = self.daily_amount.filter(|max| withdrawn(true) > *max) {
+            return Err(TransactionError::DailyWithdrawalLimitExceeded(
+                max,
+                transaction.clone(),
+            ));
+        }
+        if let Some(max) = self.rolling_amount.filter(|max| withdrawn(false) > *max) {
+            return Err(TransactionError::RollingWithdrawalLimitExceeded(
+                max,
+                transaction.clone(),
+            ));
+        }
+        self.check_count(transaction, now, recent)
+    }
+
+    /// Adds `transaction` to the `recent` transactions of the client, dropping the ones that no
+    /// longer count for any limit. A transaction made at the same time and in the same currency
+    /// as the latest one is merged into it, so the transactions without a timestamp, all taken
+    /// as made at the same time, are kept as a single one. Nothing is kept if no limit depends on
+    /// them.
+    pub(crate) fn record(
+        &self,
+        recent: &mut Vec<RecentTransaction>,
+        transaction: RecentTransaction,
+    ) {
+        if !self.over_time() {
+            recent.clear();
+            return;
+        }
+        let now = transaction.timestamp;
+        let window = self.window();
+        recent.retain(|recent| {
+            now <= recent.timestamp.saturating_add(window)
+                || recent.timestamp / SECONDS_PER_DAY == now / SECONDS_PER_DAY
+        });
+        match recent.last_mut() {
+            Some(latest)
+                if latest.timestamp == transaction.timestamp
+                    && latest.currency == transaction.currency =>
+            {
+                latest.amount = latest.amount.saturating_add(transaction.amount);
+                latest.count = latest.count.saturating_add(transaction.count);
+            }
+            _ => recent.push(transaction),
+        }
+    }
+}
+
+/// Withdrawal limits of every client, with the ones of some clients replacing part of them.
+///
+/// # Examples
+///
+/// ```
+/// use payment_settle_accounts::{WithdrawalLimitSchedule, WithdrawalLimits};
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+///
+/// let schedule = WithdrawalLimitSchedule::builder()
+///     .default(WithdrawalLimits::builder().max_amount(dec!(500)).build())
+///     .clients(BTreeMap::from([(
+///         7,
+///         WithdrawalLimits::builder().max_amount(dec!(5000)).build(),
+///     )]))
+///     .build();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, TypedBuilder)]
+pub struct WithdrawalLimitSchedule {
+    /// Limits of the clients without their own.
+    #[builder(default)]
+    default: WithdrawalLimits,
+    /// Limits of some clients. The limits they do not set are taken from `default`.
+    #[builder(default)]
+    clients: BTreeMap<ClientId, WithdrawalLimits>,
+}
+
+impl WithdrawalLimitSchedule {
+    /// Returns the limits of the withdrawals of `client`.
+    pub(crate) fn limits(&self, client: ClientId) -> WithdrawalLimits {
+        match self.clients.get(&client) {
+            Some(limits) => limits.or(&self.default),
+            None => self.default,
+        }
+    }
+}
+
+/// Deposits, withdrawals and transfers of a client made at the same time and in the same
+/// currency, kept by its account while they count for the limits over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(test, derive(Dummy))]
+pub(crate) struct RecentTransaction {
+    pub(crate) timestamp: Timestamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) currency: Option<Currency>,
+    /// Amount withdrawn, zero for deposits.
+    pub(crate) amount: Decimal,
+    /// Number of transactions.
+    #[serde(default = "one")]
+    pub(crate) count: u32,
+}
+
+impl RecentTransaction {
+    /// Returns a deposit made at `timestamp` in `currency`.
+    pub(crate) fn deposit(timestamp: Timestamp, currency: Option<Currency>) -> Self {
+        RecentTransaction {
+            timestamp,
+            currency,
+            amount: Decimal::ZERO,
+            count: 1,
+        }
+    }
+
+    /// Returns a withdrawal of `amount` made at `timestamp` in `currency`.
+    pub(crate) fn withdrawal(
+        timestamp: Timestamp,
+        currency: Option<Currency>,
+        amount: Decimal,
+    ) -> Self {
+        RecentTransaction {
+            timestamp,
+            currency,
+            amount,
+            count: 1,
+        }
+    }
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// Withdrawal limits of a client as found in a CSV file, where the empty columns are taken from
+/// the limits of every client. The window is given in hours.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawalLimitsRecord {
+    pub client: ClientId,
+    pub max_withdrawal: Option<Decimal>,
+    pub daily_withdrawals: Option<Decimal>,
+    pub rolling_withdrawals: Option<Decimal>,
+    #[serde(alias = "max_withdrawals")]
+    pub max_transactions: Option<u32>,
+    pub window_hours: Option<u64>,
+}
+
+impl TryFrom<WithdrawalLimitsRecord> for (ClientId, WithdrawalLimits) {
+    type Error = TransactionError;
+
+    /// Validates that the amounts of the limits are not negative.
+    fn try_from(record: WithdrawalLimitsRecord) -> Result<Self, Self::Error> {
+        let amounts = [
+            record.max_withdrawal,
+            record.daily_withdrawals,
+            record.rolling_withdrawals,
+        ];
+        if amounts.iter().flatten().any(Decimal::is_sign_negative) {
+            return Err(TransactionError::InvalidWithdrawalLimits(format!(
+                "Negative limit for client {}",
+                record.client
+            )));
+        }
+        let limits = WithdrawalLimits {
+            max_amount: record.max_withdrawal,
+            daily_amount: record.daily_withdrawals,
+            rolling_amount: record.rolling_withdrawals,
+            max_transactions: record.max_transactions,
+            window: record
+                .window_hours
+                .map(|hours| hours.saturating_mul(60 * 60)),
+        };
+        Ok((record.client, limits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{Amount, TransactionType};
+
+    fn withdrawal(amount: Decimal) -> Transaction {
+        Transaction::builder()
+            .ty(TransactionType::Withdrawal)
+            .client_id(1)
+            .transaction_id(1)
+            .amount(Amount::new(amount).unwrap())
+            .build()
+    }
+
+    fn check(
+        limits: &WithdrawalLimits,
+        recent: &mut Vec<RecentTransaction>,
+        amount: Decimal,
+        now: Timestamp,
+    ) -> Result<(), TransactionError> {
+        limits.check(&withdrawal(amount), None, amount, now, recent)?;
+        limits.record(recent, RecentTransaction::withdrawal(now, None, amount));
+        Ok(())
+    }
+
+    #[test]
+    fn test_withdrawal_limits() {
+        let hour = 60 * 60;
+        let limits = WithdrawalLimits::builder()
+            .max_amount(dec!(50))
+            .daily_amount(dec!(100))
+            .rolling_amount(dec!(80))
+            .max_transactions(2)
+            .window(hour)
+            .build();
+        let mut recent = Vec::new();
+        let day = SECONDS_PER_DAY;
+
+        assert!(matches!(
+            check(&limits, &mut recent, dec!(50.01), day),
+            Err(TransactionError::WithdrawalAmountExceedsLimit(_, _))
+        ));
+        check(&limits, &mut recent, dec!(50), day).unwrap();
+        assert!(matches!(
+            check(&limits, &mut recent, dec!(31), day + 10),
+            Err(TransactionError::RollingWithdrawalLimitExceeded(_, _))
+        ));
+        check(&limits, &mut recent, dec!(30), day + 10).unwrap();
+        assert!(matches!(
+            check(&limits, &mut recent, dec!(0), day + 20),
+            Err(TransactionError::TransactionCountExceeded(2, _))
+        ));
+        // Out of the rolling window, but still in the same day.
+        assert!(matches!(
+            check(&limits, &mut recent, dec!(21), day + 2 * hour),
+            Err(TransactionError::DailyWithdrawalLimitExceeded(_, _))
+        ));
+        check(&limits, &mut recent, dec!(20), day + 2 * hour).unwrap();
+        check(&limits, &mut recent, dec!(50), 2 * day).unwrap();
+        assert_eq!(recent.len(), 1);
+    }
+
+    #[test]
+    fn test_client_limits_fall_back_to_the_default() {
+        let schedule = WithdrawalLimitSchedule::builder()
+            .default(
+                WithdrawalLimits::builder()
+                    .max_amount(dec!(10))
+                    .max_transactions(2)
+                    .build(),
+            )
+            .clients(BTreeMap::from([(
+                2,
+                WithdrawalLimits::builder().max_amount(dec!(20)).build(),
+            )]))
+            .build();
+
+        assert_eq!(schedule.limits(1).max_amount, Some(dec!(10)));
+        assert_eq!(schedule.limits(2).max_amount, Some(dec!(20)));
+        assert_eq!(schedule.limits(2).max_transactions, Some(2));
+        assert_eq!(schedule.limits(2).window(), DEFAULT_WITHDRAWAL_WINDOW);
+        let record = WithdrawalLimitsRecord {
+            client: 3,
+            max_withdrawal: None,
+            daily_withdrawals: Some(dec!(-1)),
+            rolling_withdrawals: None,
+            max_transactions: None,
+            window_hours: None,
+        };
+        assert!(matches!(
+            <(ClientId, WithdrawalLimits)>::try_from(record),
+            Err(TransactionError::InvalidWithdrawalLimits(_))
+        ));
+    }
+}
@@ -1,11 +1,45 @@
 //! Module that describe domain entities and errors.
+mod amount;
+mod authorization;
+mod currency;
 mod entities;
 mod errors;
+mod exchange;
+mod fees;
+mod invariants;
+mod ledger;
+mod limits;
+mod policy;
+mod precision;
+mod retention;
+mod screening;
+mod tracker;
 
+pub use amount::Amount;
+pub(crate) use authorization::Authorization;
+pub(crate) use currency::Balance;
+pub use currency::Currency;
 pub use entities::Account;
 pub use entities::ClientId;
+pub use entities::DetailedSummary;
+pub use entities::Timestamp;
 pub use entities::Transaction;
+pub use entities::TransactionRecord;
 pub use entities::TransactionResultSummary;
 pub use entities::TransactionType;
 pub use entities::TxId;
 pub use errors::*;
+pub(crate) use exchange::AppliedRate;
+pub use exchange::{CurrencyConversion, ExchangeRate};
+pub use fees::{Fee, FeeCharge, FeeSchedule};
+pub use invariants::{Invariant, InvariantChecker, Violation};
+pub use ledger::{JournalEntry, Ledger, LedgerAccount, Posting};
+pub(crate) use limits::RecentTransaction;
+pub use limits::{
+    WithdrawalLimitSchedule, WithdrawalLimits, WithdrawalLimitsRecord, DEFAULT_WITHDRAWAL_WINDOW,
+};
+pub use policy::{AccountPolicy, NegativeBalance};
+pub use precision::{ExcessPrecision, PrecisionPolicy, RoundingMode, DEFAULT_SCALE};
+pub use retention::{PruneReport, RetentionPolicy};
+pub use screening::{ClientListRecord, ClientLists, ClientScreening, ScreeningAction};
+pub(crate) use tracker::TxTrack;
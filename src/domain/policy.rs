@@ -0,0 +1,327 @@
+//! Policies applied by the accounts when processing transactions.
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use typed_builder::TypedBuilder;
+
+use super::exchange::AppliedRate;
+use crate::{
+    ClientId, CurrencyConversion, FeeSchedule, PrecisionPolicy, Timestamp, TransactionError,
+    TransactionType, WithdrawalLimitSchedule, WithdrawalLimits,
+};
+
+/// How far the available funds of an account can go below zero when a dispute holds more than
+/// what is available, for example because the disputed deposit was already withdrawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NegativeBalance {
+    /// The dispute is rejected.
+    #[default]
+    Reject,
+    /// The available funds can go negative without limit.
+    Unlimited,
+    /// The available funds can go negative down to minus the given amount.
+    Limit(Decimal),
+}
+
+impl FromStr for NegativeBalance {
+    type Err = TransactionError;
+
+    /// Parses `reject`, `unlimited` or a non-negative limit.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(NegativeBalance::Reject),
+            "unlimited" => Ok(NegativeBalance::Unlimited),
+            _ => match s.parse::<Decimal>() {
+                Ok(limit) if !limit.is_sign_negative() => Ok(NegativeBalance::Limit(limit)),
+                _ => Err(TransactionError::InvalidTransactionAmount(format!(
+                    "Invalid negative balance limit {}",
+                    s
+                ))),
+            },
+        }
+    }
+}
+
+/// Limits and rules applied by an `Account` when processing transactions. The default policy
+/// applies no limits, a chargeback locks the account rejecting all its later transactions
+/// except administrative operations, and resolved deposits can be disputed again.
+///
+/// # Examples
+///
+/// ```
+/// use payment_settle_accounts::{AccountPolicy, TransactionType};
+/// use rust_decimal_macros::dec;
+///
+/// let policy = AccountPolicy::builder()
+///     .max_transaction_amount(dec!(10000))
+///     .max_balance(dec!(1000000))
+///     .allowed_when_locked(vec![TransactionType::Resolve, TransactionType::Chargeback])
+///     .build();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, TypedBuilder)]
+pub struct AccountPolicy {
+    /// Maximum amount of a single deposit or withdrawal.
+    #[builder(default, setter(into))]
+    max_transaction_amount: Option<Decimal>,
+    /// Maximum total balance of an account. Deposits that would exceed it are rejected.
+    #[builder(default, setter(into))]
+    max_balance: Option<Decimal>,
+    /// Whether a chargeback locks the account.
+    #[builder(default = true)]
+    lock_on_chargeback: bool,
+    /// Transaction types still processed on a locked account. Administrative operations are
+    /// always processed.
+    #[builder(default)]
+    allowed_when_locked: Vec<TransactionType>,
+    /// Whether disputes can take the available funds below zero.
+    #[builder(default)]
+    negative_balance: NegativeBalance,
+    /// Maximum number of disputes opened on a single deposit. Without it, a resolved deposit
+    /// can be disputed again any number of times.
+    #[builder(default, setter(into))]
+    max_disputes: Option<u32>,
+    /// Whether a deposit partially charged back can be disputed again for the rest of its amount.
+    #[builder(default = true)]
+    dispute_after_chargeback: bool,
+    /// Maximum time in seconds between a deposit and its disputes. It only applies when both
+    /// have a timestamp.
+    #[builder(default, setter(into))]
+    dispute_window: Option<u64>,
+    /// Time in seconds after which an authorization that was not captured or voided is released.
+    /// It only applies to authorizations with a timestamp, when a later transaction of the
+    /// account has a timestamp too.
+    #[builder(default, setter(into))]
+    authorization_expiry: Option<u64>,
+    /// Conversion of the transactions in other currencies to the base currency. Without it,
+    /// accounts keep separate balances for every currency.
+    #[builder(default, setter(into))]
+    conversion: Option<CurrencyConversion>,
+    /// Fees charged for deposits, withdrawals and chargebacks. Without it, transactions are free.
+    #[builder(default, setter(into))]
+    fees: Option<FeeSchedule>,
+    /// Limits of the withdrawals and transfers of the clients. Without it, clients can withdraw
+    /// any amount of their available funds.
+    #[builder(default, setter(into))]
+    withdrawal_limits: Option<WithdrawalLimitSchedule>,
+    /// Precision of the amounts computed by the accounts, like the fees charged.
+    #[builder(default)]
+    precision: PrecisionPolicy,
+}
+
+impl Default for AccountPolicy {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl AccountPolicy {
+    /// Returns whether a chargeback locks the account.
+    pub(crate) fn lock_on_chargeback(&self) -> bool {
+        self.lock_on_chargeback
+    }
+
+    /// Returns whether a locked account rejects every transaction but administrative operations.
+    pub(crate) fn lock_is_final(&self) -> bool {
+        self.allowed_when_locked.is_empty()
+    }
+
+    /// Returns whether transactions of type `ty` are processed on a locked account.
+    pub(crate) fn allowed_when_locked(&self, ty: &TransactionType) -> bool {
+        ty.is_administrative() || self.allowed_when_locked.contains(ty)
+    }
+
+    /// Returns whether a dispute can leave `available` funds in the account.
+    pub(crate) fn allows_available(&self, available: Decimal) -> bool {
+        match self.negative_balance {
+            _ if available >= Decimal::ZERO => true,
+            NegativeBalance::Reject => false,
+            NegativeBalance::Unlimited => true,
+            NegativeBalance::Limit(limit) => -available <= limit,
+        }
+    }
+
+    /// Returns the part of `fee` that can be taken from `available` funds without leaving them
+    /// below what the negative balance policy allows.
+    pub(crate) fn chargeable(&self, available: Decimal, fee: Decimal) -> Decimal {
+        let floor = match self.negative_balance {
+            NegativeBalance::Reject => Decimal::ZERO,
+            NegativeBalance::Unlimited => return fee,
+            NegativeBalance::Limit(limit) => -limit,
+        };
+        fee.min((available - floor).max(Decimal::ZERO))
+    }
+
+    /// Returns the maximum number of disputes if a deposit already disputed `disputes` times
+    /// cannot be disputed again.
+    pub(crate) fn exceeds_disputes(&self, disputes: u32) -> Option<u32> {
+        self.max_disputes.filter(|max| disputes >= *max)
+    }
+
+    /// Returns whether a deposit can be disputed again after a chargeback.
+    pub(crate) fn dispute_after_chargeback(&self) -> bool {
+        self.dispute_after_chargeback
+    }
+
+    /// Returns whether a dispute at `disputed` comes after the dispute window of a deposit made
+    /// at `deposited`. A dispute without a time of a deposit with one cannot be shown to be
+    /// within the window, so it is taken as expired.
+    pub(crate) fn dispute_window_expired(
+        &self,
+        deposited: Option<Timestamp>,
+        disputed: Option<Timestamp>,
+    ) -> bool {
+        match (self.dispute_window, deposited, disputed) {
+            (Some(_), Some(_), None) => true,
+            _ => elapsed_more_than(self.dispute_window, deposited, disputed),
+        }
+    }
+
+    /// Returns whether an authorization made at `authorized` expired at `now`.
+    pub(crate) fn authorization_expired(
+        &self,
+        authorized: Option<Timestamp>,
+        now: Option<Timestamp>,
+    ) -> bool {
+        elapsed_more_than(self.authorization_expiry, authorized, now)
+    }
+
+    /// Returns whether `amount` is above the maximum amount of a single transaction.
+    pub(crate) fn exceeds_transaction_amount(&self, amount: Decimal) -> bool {
+        matches!(self.max_transaction_amount, Some(max) if amount > max)
+    }
+
+    /// Returns whether `total` is above the maximum balance of an account.
+    pub(crate) fn exceeds_balance(&self, total: Decimal) -> bool {
+        matches!(self.max_balance, Some(max) if total > max)
+    }
+
+    /// Returns the conversion of the transactions in other currencies, if any.
+    pub(crate) fn conversion(&self) -> Option<&CurrencyConversion> {
+        self.conversion.as_ref()
+    }
+
+    /// Returns the fees charged for the transactions, if any.
+    pub(crate) fn fees(&self) -> Option<&FeeSchedule> {
+        self.fees.as_ref()
+    }
+
+    /// Returns the precision of the amounts computed by the accounts.
+    pub(crate) fn precision(&self) -> &PrecisionPolicy {
+        &self.precision
+    }
+
+    /// Returns the limits of the withdrawals of `client`, if any.
+    pub(crate) fn withdrawal_limits(&self, client: ClientId) -> Option<WithdrawalLimits> {
+        self.withdrawal_limits
+            .as_ref()
+            .map(|schedule| schedule.limits(client))
+    }
+
+    /// Converts `amount` to the base currency with `rate`, rounding it as the currency
+    /// conversion does, or as a part of the converted amount `remaining` of a deposit or an
+    /// authorization. Amounts converted in a previous run are rounded with the default
+    /// precision policy when there is no conversion anymore.
+    pub(crate) fn convert(
+        &self,
+        amount: Decimal,
+        rate: &AppliedRate,
+        remaining: Option<Decimal>,
+    ) -> Result<Decimal, TransactionError> {
+        let default = PrecisionPolicy::default();
+        let precision = self
+            .conversion()
+            .map_or(&default, |conversion| conversion.precision());
+        match remaining {
+            Some(remaining) => rate.convert_part(amount, remaining, precision),
+            None => rate.convert(amount, precision),
+        }
+    }
+}
+
+/// Returns whether more than `period` seconds passed from `from` to `to`, or `false` if any of
+/// them is unknown.
+fn elapsed_more_than(period: Option<u64>, from: Option<Timestamp>, to: Option<Timestamp>) -> bool {
+    match (period, from, to) {
+        (Some(period), Some(from), Some(to)) => to > from.saturating_add(period),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_allows_available() {
+        let policy = |negative_balance| {
+            AccountPolicy::builder()
+                .negative_balance(negative_balance)
+                .build()
+        };
+
+        assert!(policy(NegativeBalance::Reject).allows_available(dec!(0)));
+        assert!(!policy(NegativeBalance::Reject).allows_available(dec!(-0.0001)));
+        assert!(policy(NegativeBalance::Unlimited).allows_available(dec!(-1000000)));
+        assert!(policy(NegativeBalance::Limit(dec!(50))).allows_available(dec!(-50)));
+        assert!(!policy(NegativeBalance::Limit(dec!(50))).allows_available(dec!(-50.0001)));
+    }
+
+    #[test]
+    fn test_chargeable() {
+        let policy = |negative_balance| {
+            AccountPolicy::builder()
+                .negative_balance(negative_balance)
+                .build()
+        };
+
+        assert_eq!(
+            policy(NegativeBalance::Reject).chargeable(dec!(5), dec!(2)),
+            dec!(2)
+        );
+        assert_eq!(
+            policy(NegativeBalance::Reject).chargeable(dec!(1), dec!(2)),
+            dec!(1)
+        );
+        assert_eq!(
+            policy(NegativeBalance::Reject).chargeable(dec!(-1), dec!(2)),
+            dec!(0)
+        );
+        assert_eq!(
+            policy(NegativeBalance::Limit(dec!(0.5))).chargeable(dec!(1), dec!(2)),
+            dec!(1.5)
+        );
+        assert_eq!(
+            policy(NegativeBalance::Unlimited).chargeable(dec!(-10), dec!(2)),
+            dec!(2)
+        );
+    }
+
+    #[test]
+    fn test_dispute_window_expired() {
+        let policy = AccountPolicy::builder().dispute_window(100).build();
+
+        assert!(!policy.dispute_window_expired(Some(1000), Some(1100)));
+        assert!(policy.dispute_window_expired(Some(1000), Some(1101)));
+        assert!(!policy.dispute_window_expired(None, Some(1101)));
+        assert!(policy.dispute_window_expired(Some(1000), None));
+        assert!(!policy.dispute_window_expired(None, None));
+        assert!(!AccountPolicy::default().dispute_window_expired(Some(1000), None));
+        assert!(!AccountPolicy::default().dispute_window_expired(Some(0), Some(u64::MAX)));
+    }
+
+    #[test]
+    fn test_negative_balance_from_str() {
+        assert_eq!(
+            "unlimited".parse::<NegativeBalance>().unwrap(),
+            NegativeBalance::Unlimited
+        );
+        assert_eq!(
+            "25.5".parse::<NegativeBalance>().unwrap(),
+            NegativeBalance::Limit(dec!(25.5))
+        );
+        assert!("-1".parse::<NegativeBalance>().is_err());
+        assert!("always".parse::<NegativeBalance>().is_err());
+    }
+}
@@ -0,0 +1,182 @@
+//! Precision policy for the amounts of the transactions.
+//!
+//! Amounts are expected with at most four decimal places. The policy decides what happens with
+//! amounts that have more: they can be rejected, rounded with a given rounding mode or truncated.
+use std::str::FromStr;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use typed_builder::TypedBuilder;
+
+use crate::TransactionError;
+
+/// Number of decimal places of amounts and balances.
+pub const DEFAULT_SCALE: u32 = 4;
+
+/// Rounding mode used when rounding amounts with too many decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Midpoints are rounded to the nearest even digit, also known as banker's rounding.
+    HalfEven,
+    /// Midpoints are rounded away from zero.
+    HalfUp,
+    /// Midpoints are rounded towards zero.
+    HalfDown,
+}
+
+impl From<RoundingMode> for RoundingStrategy {
+    fn from(mode: RoundingMode) -> Self {
+        match mode {
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::HalfDown => RoundingStrategy::MidpointTowardZero,
+        }
+    }
+}
+
+/// What to do with amounts that have more decimal places than the configured scale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExcessPrecision {
+    /// Rejects the amount with an error.
+    Reject,
+    /// Rounds the amount to the scale with the given rounding mode.
+    Round(RoundingMode),
+    /// Drops the extra decimal places.
+    Truncate,
+}
+
+impl Default for ExcessPrecision {
+    fn default() -> Self {
+        ExcessPrecision::Round(RoundingMode::HalfEven)
+    }
+}
+
+impl FromStr for ExcessPrecision {
+    type Err = TransactionError;
+
+    /// Parses `reject`, `truncate`, `round` (banker's rounding), `round-half-even`,
+    /// `round-half-up` or `round-half-down`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(ExcessPrecision::Reject),
+            "truncate" => Ok(ExcessPrecision::Truncate),
+            "round" | "round-half-even" => Ok(ExcessPrecision::Round(RoundingMode::HalfEven)),
+            "round-half-up" => Ok(ExcessPrecision::Round(RoundingMode::HalfUp)),
+            "round-half-down" => Ok(ExcessPrecision::Round(RoundingMode::HalfDown)),
+            _ => Err(TransactionError::InvalidTransactionAmount(format!(
+                "Invalid precision mode {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Policy applied to the amounts of the transactions when they are parsed.
+///
+/// # Examples
+///
+/// ```
+/// use payment_settle_accounts::{ExcessPrecision, PrecisionPolicy};
+///
+/// let policy = PrecisionPolicy::builder()
+///     .excess(ExcessPrecision::Reject)
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TypedBuilder)]
+pub struct PrecisionPolicy {
+    /// Maximum number of decimal places of an amount.
+    #[builder(default = DEFAULT_SCALE)]
+    scale: u32,
+    /// What to do with amounts that have more decimal places than `scale`.
+    #[builder(default)]
+    excess: ExcessPrecision,
+}
+
+impl Default for PrecisionPolicy {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl PrecisionPolicy {
+    /// Returns the maximum number of decimal places of an amount.
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Applies the policy to `amount`, returning it with at most `scale` decimal places.
+    pub fn apply(&self, amount: Decimal) -> Result<Decimal, TransactionError> {
+        if amount.scale() <= self.scale {
+            return Ok(amount);
+        }
+        match self.excess {
+            ExcessPrecision::Reject => Err(TransactionError::InvalidTransactionAmount(format!(
+                "Amount {} has more than {} decimal places",
+                amount, self.scale
+            ))),
+            ExcessPrecision::Round(mode) => {
+                Ok(amount.round_dp_with_strategy(self.scale, mode.into()))
+            }
+            ExcessPrecision::Truncate => {
+                Ok(amount.round_dp_with_strategy(self.scale, RoundingStrategy::ToZero))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn policy(excess: ExcessPrecision) -> PrecisionPolicy {
+        PrecisionPolicy::builder().excess(excess).build()
+    }
+
+    #[test]
+    fn test_apply_within_scale() {
+        assert_eq!(
+            policy(ExcessPrecision::Reject).apply(dec!(1.2345)).unwrap(),
+            dec!(1.2345)
+        );
+    }
+
+    #[test]
+    fn test_apply_reject() {
+        assert!(matches!(
+            policy(ExcessPrecision::Reject).apply(dec!(1.23455)),
+            Err(TransactionError::InvalidTransactionAmount(_))
+        ));
+    }
+
+    #[test]
+    fn test_apply_round_and_truncate() {
+        let half_even = policy(ExcessPrecision::Round(RoundingMode::HalfEven));
+        let half_up = policy(ExcessPrecision::Round(RoundingMode::HalfUp));
+        let half_down = policy(ExcessPrecision::Round(RoundingMode::HalfDown));
+        let truncate = policy(ExcessPrecision::Truncate);
+
+        assert_eq!(half_even.apply(dec!(1.00005)).unwrap(), dec!(1.0000));
+        assert_eq!(half_even.apply(dec!(1.00015)).unwrap(), dec!(1.0002));
+        assert_eq!(half_up.apply(dec!(1.00005)).unwrap(), dec!(1.0001));
+        assert_eq!(half_down.apply(dec!(1.00015)).unwrap(), dec!(1.0001));
+        assert_eq!(truncate.apply(dec!(1.00019)).unwrap(), dec!(1.0001));
+    }
+
+    #[test]
+    fn test_excess_precision_from_str() {
+        assert_eq!(
+            "round".parse::<ExcessPrecision>().unwrap(),
+            ExcessPrecision::Round(RoundingMode::HalfEven)
+        );
+        assert_eq!(
+            "round-half-up".parse::<ExcessPrecision>().unwrap(),
+            ExcessPrecision::Round(RoundingMode::HalfUp)
+        );
+        assert_eq!(
+            "reject".parse::<ExcessPrecision>().unwrap(),
+            ExcessPrecision::Reject
+        );
+        assert!("ceil".parse::<ExcessPrecision>().is_err());
+    }
+}
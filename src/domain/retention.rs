@@ -0,0 +1,106 @@
+//! Retention policies for the history of deposits tracked by the accounts.
+//!
+//! Accounts keep every deposit in case it is disputed later, but some of them can no longer
+//! change any balance: deposits of locked accounts, deposits already charged back and deposits
+//! older than the dispute window. A `RetentionPolicy` tells which of them can be dropped.
+use std::ops::AddAssign;
+
+use typed_builder::TypedBuilder;
+
+use crate::TxId;
+
+/// Policy deciding which tracked deposits are dropped when the history is pruned.
+///
+/// # Examples
+///
+/// ```
+/// use payment_settle_accounts::RetentionPolicy;
+///
+/// let policy = RetentionPolicy::builder()
+///     .locked_accounts(true)
+///     .dispute_window(1_000_000)
+///     .build();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, TypedBuilder)]
+pub struct RetentionPolicy {
+    /// Drops the whole history of locked accounts, which reject every transaction. If an account
+    /// is unlocked afterwards, its deposits before the prune can no longer be disputed or resolved,
+    /// and disputes left open keep their funds held. Engines
+    /// ignore it when their `AccountPolicy` still processes some transactions on locked accounts.
+    #[builder(default)]
+    locked_accounts: bool,
+    /// Drops deposits that were already charged back.
+    #[builder(default)]
+    charged_back: bool,
+    /// Drops deposits whose transaction id is more than this number of ids behind the latest
+    /// transaction seen. Deposits under dispute are always kept. Once dropped, a deposit is
+    /// treated as unknown, so disputes referencing it are rejected, but its id is kept to reject
+    /// duplicates.
+    #[builder(default, setter(into))]
+    dispute_window: Option<TxId>,
+}
+
+impl RetentionPolicy {
+    /// Returns whether the policy drops anything at all.
+    pub fn is_enabled(&self) -> bool {
+        self.locked_accounts || self.charged_back || self.dispute_window.is_some()
+    }
+
+    pub(crate) fn locked_accounts(&self) -> bool {
+        self.locked_accounts
+    }
+
+    /// Returns the same policy without dropping the history of locked accounts.
+    pub(crate) fn keeping_locked_accounts(&self) -> Self {
+        Self {
+            locked_accounts: false,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn charged_back(&self) -> bool {
+        self.charged_back
+    }
+
+    /// Returns the lowest transaction id still inside the dispute window, given the latest
+    /// transaction id seen.
+    pub(crate) fn window_start(&self, latest: TxId) -> Option<TxId> {
+        self.dispute_window
+            .map(|window| latest.saturating_sub(window))
+    }
+}
+
+/// Report of the tracking data dropped by a prune.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PruneReport {
+    /// Number of accounts that dropped at least one deposit.
+    pub accounts: usize,
+    /// Number of tracked deposits dropped.
+    pub entries: usize,
+    /// Bytes of memory given back.
+    pub bytes: usize,
+}
+
+impl AddAssign for PruneReport {
+    fn add_assign(&mut self, other: Self) {
+        self.accounts += other.accounts;
+        self.entries += other.entries;
+        self.bytes += other.bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_start() {
+        let policy = RetentionPolicy::builder().dispute_window(10).build();
+
+        assert_eq!(policy.window_start(100), Some(90));
+        assert_eq!(policy.window_start(5), Some(0));
+        assert_eq!(RetentionPolicy::default().window_start(100), None);
+        assert!(policy.is_enabled());
+        assert!(!RetentionPolicy::default().is_enabled());
+    }
+}
@@ -0,0 +1,226 @@
+//! Screening of the clients against the lists given by compliance.
+//!
+//! Clients in the blocklist, or missing from the allowlist when there is one, cannot move funds:
+//! their transactions, and transfers to them, are rejected or quarantined before they reach the
+//! accounts. The lists are shared by every clone of a `ClientScreening`, so they can be replaced
+//! while the transactions are being processed.
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use serde::Deserialize;
+use typed_builder::TypedBuilder;
+
+use crate::{ClientId, Transaction, TransactionError};
+
+/// Blocklist and allowlist of the clients.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::BTreeSet;
+/// use payment_settle_accounts::ClientLists;
+///
+/// let lists = ClientLists::builder()
+///     .blocked(BTreeSet::from([3, 7]))
+///     .build();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, TypedBuilder)]
+pub struct ClientLists {
+    /// Clients whose transactions are screened out.
+    #[builder(default)]
+    blocked: BTreeSet<ClientId>,
+    /// Only clients whose transactions are let through, or none to let every client through.
+    #[builder(default, setter(into))]
+    allowed: Option<BTreeSet<ClientId>>,
+}
+
+impl ClientLists {
+    /// Returns the number of clients in the blocklist and in the allowlist, if any.
+    pub fn len(&self) -> (usize, Option<usize>) {
+        (self.blocked.len(), self.allowed.as_ref().map(BTreeSet::len))
+    }
+
+    /// Returns whether the lists let every client through.
+    pub fn is_empty(&self) -> bool {
+        self.blocked.is_empty() && self.allowed.is_none()
+    }
+
+    /// Checks the client of `transaction` and the destination of a transfer, returning the error
+    /// of the first one screened out. Administrative operations move no funds, so they are let
+    /// through to keep freezing or closing the accounts of the clients screened out.
+    fn check(&self, transaction: &Transaction) -> Result<(), TransactionError> {
+        if transaction.ty().is_administrative() {
+            return Ok(());
+        }
+        for client in std::iter::once(transaction.client_id()).chain(transaction.destination()) {
+            if self.blocked.contains(&client) {
+                return Err(TransactionError::ClientBlocked(client, transaction.clone()));
+            }
+            if let Some(allowed) = &self.allowed {
+                if !allowed.contains(&client) {
+                    return Err(TransactionError::ClientNotAllowed(
+                        client,
+                        transaction.clone(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the engines do with the transactions of the clients screened out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScreeningAction {
+    /// The transactions are rejected like any other invalid transaction.
+    #[default]
+    Reject,
+    /// The transactions are set aside in the quarantine of the engine, to be reviewed later.
+    Quarantine,
+}
+
+impl FromStr for ScreeningAction {
+    type Err = TransactionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(ScreeningAction::Reject),
+            "quarantine" => Ok(ScreeningAction::Quarantine),
+            _ => Err(TransactionError::InvalidClientList(format!(
+                "unknown action {}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Screening of the transactions against some `ClientLists`. Clones share the same lists, so
+/// the lists updated through one of them apply to the engines holding the others from their next
+/// transaction on.
+#[derive(Debug, Clone, Default)]
+pub struct ClientScreening {
+    lists: Arc<RwLock<ClientLists>>,
+    action: ScreeningAction,
+}
+
+impl ClientScreening {
+    /// Creates a screening against `lists` taking `action` on the transactions screened out.
+    pub fn new(lists: ClientLists, action: ScreeningAction) -> Self {
+        Self {
+            lists: Arc::new(RwLock::new(lists)),
+            action,
+        }
+    }
+
+    /// Returns what is done with the transactions screened out.
+    pub fn action(&self) -> ScreeningAction {
+        self.action
+    }
+
+    /// Returns a copy of the current lists.
+    pub fn lists(&self) -> Result<ClientLists, TransactionError> {
+        Ok(self.lists.read()?.clone())
+    }
+
+    /// Replaces the lists, without interrupting the transactions being processed.
+    pub fn update(&self, lists: ClientLists) -> Result<(), TransactionError> {
+        *self.lists.write()? = lists;
+        Ok(())
+    }
+
+    /// Screens `transaction`, returning the error of the client screened out, if any, inside the
+    /// error of the lists themselves.
+    pub(crate) fn screen(
+        &self,
+        transaction: &Transaction,
+    ) -> Result<Result<(), TransactionError>, TransactionError> {
+        Ok(self.lists.read()?.check(transaction))
+    }
+}
+
+/// Record of a client list file: the id of a client, followed by any other columns.
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+pub struct ClientListRecord {
+    pub client: ClientId,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TransactionType;
+
+    fn transaction(client: ClientId, destination: Option<ClientId>) -> Transaction {
+        match destination {
+            Some(destination) => Transaction::builder()
+                .ty(TransactionType::Transfer)
+                .client_id(client)
+                .transaction_id(1)
+                .amount(1)
+                .destination(destination)
+                .build(),
+            None => Transaction::builder()
+                .ty(TransactionType::Deposit)
+                .client_id(client)
+                .transaction_id(1)
+                .amount(1)
+                .build(),
+        }
+    }
+
+    #[test]
+    fn test_screen_blocked_and_allowed_clients() {
+        let screening = ClientScreening::new(
+            ClientLists::builder()
+                .blocked(BTreeSet::from([2]))
+                .allowed(BTreeSet::from([1, 2, 3]))
+                .build(),
+            ScreeningAction::Reject,
+        );
+
+        assert!(screening.screen(&transaction(1, None)).unwrap().is_ok());
+        assert!(screening.screen(&transaction(1, Some(3))).unwrap().is_ok());
+        assert!(matches!(
+            screening.screen(&transaction(2, None)).unwrap(),
+            Err(TransactionError::ClientBlocked(2, _))
+        ));
+        assert!(matches!(
+            screening.screen(&transaction(1, Some(2))).unwrap(),
+            Err(TransactionError::ClientBlocked(2, _))
+        ));
+        assert!(matches!(
+            screening.screen(&transaction(4, None)).unwrap(),
+            Err(TransactionError::ClientNotAllowed(4, _))
+        ));
+        let freeze = Transaction::builder()
+            .ty(TransactionType::Freeze)
+            .client_id(2)
+            .transaction_id(2)
+            .operator("alice")
+            .reason("Sanctioned")
+            .build();
+        assert!(screening.screen(&freeze).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_update_is_shared_by_clones() {
+        let screening = ClientScreening::default();
+        let engine_side = screening.clone();
+        assert!(engine_side.screen(&transaction(5, None)).unwrap().is_ok());
+
+        screening
+            .update(ClientLists::builder().blocked(BTreeSet::from([5])).build())
+            .unwrap();
+
+        assert!(matches!(
+            engine_side.screen(&transaction(5, None)).unwrap(),
+            Err(TransactionError::ClientBlocked(5, _))
+        ));
+        assert_eq!(engine_side.lists().unwrap().len(), (1, None));
+        assert_eq!(
+            "quarantine".parse::<ScreeningAction>().unwrap(),
+            ScreeningAction::Quarantine
+        );
+        assert!("ignore".parse::<ScreeningAction>().is_err());
+    }
+}
@@ -0,0 +1,711 @@
+//! Compact storage of the deposits tracked by an account.
+//!
+//! Deposits are stored in columns sorted by transaction id instead of a `HashMap`, which removes
+//! the per-entry overhead of the hash table: transaction ids and amounts are kept in two
+//! parallel vectors and the dispute status is packed in bit sets. Lookups are binary searches
+//! and, since transaction ids usually grow over time, inserts are mostly appends. Disputed
+//! deposits are rare, so their dispute histories are kept apart in a sparse map, and so are the
+//! currencies of the deposits not made in the base currency and the rates of the deposits
+//! converted to it.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+#[cfg(test)]
+use fake::{Dummy, Faker};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::exchange::AppliedRate;
+use crate::{Currency, Timestamp, TxId};
+
+/// State of a tracked deposit in the dispute lifecycle.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+enum TxStatus {
+    /// Never disputed.
+    Depoit,
+    /// A dispute is open.
+    BeingDisputed,
+    /// The last dispute was closed by a resolve.
+    Resolved,
+    /// The last dispute was closed by a chargeback.
+    ChargedBack,
+}
+
+/// History of the disputes of a deposit: the portions of its amount under dispute and charged
+/// back, and how many disputes, resolves and chargebacks it had.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug, Default)]
+pub(crate) struct DisputeHistory {
+    disputed: Decimal,
+    charged_back: Decimal,
+    disputes: u32,
+    resolves: u32,
+    chargebacks: u32,
+}
+
+impl DisputeHistory {
+    /// Returns the number of disputes opened. Partial disputes added to an open dispute are
+    /// part of it.
+    pub(crate) fn disputes(&self) -> u32 {
+        self.disputes
+    }
+
+    /// Returns the number of chargebacks.
+    pub(crate) fn chargebacks(&self) -> u32 {
+        self.chargebacks
+    }
+}
+
+/// Tracking record of a deposit kept by an `Account` in order to resolve later disputes.
+///
+/// A deposit starts with no dispute. A dispute opens it, and resolves and chargebacks close it
+/// once nothing is under dispute anymore. Closed deposits can be disputed again for the amount
+/// that was not charged back. The history is only kept for deposits that were disputed, since
+/// most deposits never are.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub(crate) struct TxTrack {
+    amount: Decimal,
+    status: TxStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    history: Option<DisputeHistory>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    applied_rate: Option<AppliedRate>,
+}
+
+impl TxTrack {
+    pub(crate) fn new(amount: Decimal) -> Self {
+        Self {
+            amount,
+            status: TxStatus::Depoit,
+            history: None,
+            timestamp: None,
+            currency: None,
+            applied_rate: None,
+        }
+    }
+
+    /// Rebuilds a tracking record from its persisted parts.
+    pub(crate) fn restore(amount: Decimal, disputed: bool) -> Self {
+        Self {
+            amount,
+            status: if disputed {
+                TxStatus::BeingDisputed
+            } else {
+                TxStatus::Depoit
+            },
+            history: None,
+            timestamp: None,
+            currency: None,
+            applied_rate: None,
+        }
+    }
+
+    /// Returns the same record marked as charged back.
+    pub(crate) fn into_charged_back(self) -> Self {
+        Self {
+            status: TxStatus::ChargedBack,
+            ..self
+        }
+    }
+
+    /// Returns the same record with the time of the deposit.
+    pub(crate) fn with_timestamp(self, timestamp: Option<Timestamp>) -> Self {
+        Self { timestamp, ..self }
+    }
+
+    /// Returns the same record with the currency of the deposit, or none for the base currency.
+    pub(crate) fn with_currency(self, currency: Option<Currency>) -> Self {
+        Self { currency, ..self }
+    }
+
+    /// Returns the same record with the rate applied to convert the deposit to the base
+    /// currency, if it was converted.
+    pub(crate) fn with_applied_rate(self, applied_rate: Option<AppliedRate>) -> Self {
+        Self {
+            applied_rate,
+            ..self
+        }
+    }
+
+    /// Returns the same record with the dispute `history` persisted apart from the status. A
+    /// record with a history that is neither disputed nor charged back was resolved.
+    pub(crate) fn with_history(self, history: DisputeHistory) -> Self {
+        Self {
+            status: match self.status {
+                TxStatus::Depoit => TxStatus::Resolved,
+                status => status,
+            },
+            history: Some(history),
+            ..self
+        }
+    }
+
+    /// Returns the dispute history. Records persisted without one had at most a single dispute
+    /// of the whole amount, so it is implied by the status.
+    pub(crate) fn history(&self) -> DisputeHistory {
+        self.history.unwrap_or_else(|| {
+            let whole = |status| {
+                if self.status == status {
+                    self.amount
+                } else {
+                    Decimal::ZERO
+                }
+            };
+            DisputeHistory {
+                disputed: whole(TxStatus::BeingDisputed),
+                charged_back: whole(TxStatus::ChargedBack),
+                disputes: u32::from(self.status != TxStatus::Depoit),
+                resolves: u32::from(self.status == TxStatus::Resolved),
+                chargebacks: u32::from(self.status == TxStatus::ChargedBack),
+            }
+        })
+    }
+
+    /// Returns whether the deposit has an open dispute.
+    pub(crate) fn being_disputed(&self) -> bool {
+        self.status == TxStatus::BeingDisputed
+    }
+
+    /// Returns whether the last dispute of the deposit was closed by a chargeback.
+    pub(crate) fn charged_back(&self) -> bool {
+        self.status == TxStatus::ChargedBack
+    }
+
+    pub(crate) fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    /// Returns the time of the deposit, if the input has it.
+    pub(crate) fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    /// Returns the currency of the deposit, or none for the base currency.
+    pub(crate) fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Returns the rate applied to convert the deposit to the base currency, if any.
+    pub(crate) fn applied_rate(&self) -> Option<AppliedRate> {
+        self.applied_rate
+    }
+
+    /// Returns the portion of the amount under dispute.
+    pub(crate) fn disputed(&self) -> Decimal {
+        self.history().disputed
+    }
+
+    /// Returns the portion of the amount that can still be disputed.
+    pub(crate) fn undisputed(&self) -> Decimal {
+        let history = self.history();
+        self.amount - history.disputed - history.charged_back
+    }
+
+    /// Returns the record after disputing `amount`, which must not exceed `undisputed`. It
+    /// opens a new dispute unless one is already open.
+    pub(crate) fn dispute(&self, amount: Decimal) -> Self {
+        let history = self.history();
+        Self {
+            status: TxStatus::BeingDisputed,
+            history: Some(DisputeHistory {
+                disputed: history.disputed + amount,
+                disputes: history.disputes + u32::from(!self.being_disputed()),
+                ..history
+            }),
+            ..*self
+        }
+    }
+
+    /// Returns the record after resolving `amount` of the dispute, which must not exceed
+    /// `disputed`.
+    pub(crate) fn resolve(&self, amount: Decimal) -> Self {
+        let history = self.history();
+        self.settle(
+            DisputeHistory {
+                disputed: history.disputed - amount,
+                resolves: history.resolves + 1,
+                ..history
+            },
+            TxStatus::Resolved,
+        )
+    }
+
+    /// Returns the record after charging back `amount` of the dispute, which must not exceed
+    /// `disputed`.
+    pub(crate) fn chargeback(&self, amount: Decimal) -> Self {
+        let history = self.history();
+        self.settle(
+            DisputeHistory {
+                disputed: history.disputed - amount,
+                charged_back: history.charged_back + amount,
+                chargebacks: history.chargebacks + 1,
+                ..history
+            },
+            TxStatus::ChargedBack,
+        )
+    }
+
+    /// Builds the record after closing part of a dispute. The dispute stays open while some
+    /// amount is disputed, otherwise the deposit takes the `closed` status.
+    fn settle(&self, history: DisputeHistory, closed: TxStatus) -> Self {
+        Self {
+            status: if history.disputed > Decimal::ZERO {
+                TxStatus::BeingDisputed
+            } else {
+                closed
+            },
+            history: Some(history),
+            ..*self
+        }
+    }
+}
+
+/// Vector of bits packed in 64 bits words.
+#[derive(PartialEq, Clone, Default)]
+struct BitVec {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl BitVec {
+    fn with_capacity(bits: usize) -> Self {
+        Self {
+            words: Vec::with_capacity(bits / 64 + 1),
+            len: 0,
+        }
+    }
+
+    /// Creates a vector of `bits` cleared bits.
+    fn cleared(bits: usize) -> Self {
+        Self {
+            words: vec![0; bits / 64 + 1],
+            len: bits,
+        }
+    }
+
+    fn get(&self, index: usize) -> bool {
+        (self.words[index / 64] >> (index % 64)) & 1 == 1
+    }
+
+    fn set(&mut self, index: usize, value: bool) {
+        let mask = 1 << (index % 64);
+        if value {
+            self.words[index / 64] |= mask;
+        } else {
+            self.words[index / 64] &= !mask;
+        }
+    }
+
+    /// Inserts a bit at `index`, shifting all the bits after it.
+    fn insert(&mut self, index: usize, value: bool) {
+        if self.len == self.words.len() * 64 {
+            self.words.push(0);
+        }
+        let (word, bit) = (index / 64, index % 64);
+        for i in (word + 1..self.words.len()).rev() {
+            self.words[i] = (self.words[i] << 1) | (self.words[i - 1] >> 63);
+        }
+        let low = (1 << bit) - 1;
+        let current = self.words[word];
+        self.words[word] = (current & low) | ((current & !low) << 1) | ((value as u64) << bit);
+        self.len += 1;
+    }
+}
+
+/// Deposits tracked by an account, stored in columns sorted by transaction id.
+///
+/// The timestamps column stays empty until a deposit with a timestamp is tracked, so inputs
+/// without timestamps do not pay for it. From then on, a bit set tells the deposits with a
+/// timestamp apart from the ones without.
+#[derive(PartialEq, Clone, Default)]
+pub(crate) struct TxTracker {
+    ids: Vec<TxId>,
+    amounts: Vec<Decimal>,
+    timestamps: Vec<Timestamp>,
+    timed: BitVec,
+    disputed: BitVec,
+    charged_back: BitVec,
+    histories: BTreeMap<TxId, DisputeHistory>,
+    currencies: BTreeMap<TxId, Currency>,
+    rates: BTreeMap<TxId, AppliedRate>,
+}
+
+impl fmt::Debug for TxTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl TxTracker {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub(crate) fn contains(&self, tx_id: TxId) -> bool {
+        self.ids.binary_search(&tx_id).is_ok()
+    }
+
+    pub(crate) fn get(&self, tx_id: TxId) -> Option<TxTrack> {
+        self.ids
+            .binary_search(&tx_id)
+            .ok()
+            .map(|index| self.at(index))
+    }
+
+    /// Tracks a deposit, replacing any previous record with the same transaction id.
+    pub(crate) fn insert(&mut self, tx_id: TxId, track: TxTrack) {
+        match track.history {
+            Some(history) => self.histories.insert(tx_id, history),
+            None => self.histories.remove(&tx_id),
+        };
+        match track.currency {
+            Some(currency) => self.currencies.insert(tx_id, currency),
+            None => self.currencies.remove(&tx_id),
+        };
+        match track.applied_rate {
+            Some(rate) => self.rates.insert(tx_id, rate),
+            None => self.rates.remove(&tx_id),
+        };
+        let has_timestamps = !self.timestamps.is_empty() || track.timestamp.is_some();
+        if has_timestamps && self.timestamps.is_empty() {
+            self.timestamps = vec![0; self.len()];
+            self.timed = BitVec::cleared(self.len());
+        }
+        let timestamp = track.timestamp.unwrap_or_default();
+        match self.ids.binary_search(&tx_id) {
+            Ok(index) => {
+                self.amounts[index] = track.amount;
+                if has_timestamps {
+                    self.timestamps[index] = timestamp;
+                    self.timed.set(index, track.timestamp.is_some());
+                }
+                self.disputed.set(index, track.being_disputed());
+                self.charged_back.set(index, track.charged_back());
+            }
+            Err(index) => {
+                self.ids.insert(index, tx_id);
+                self.amounts.insert(index, track.amount);
+                if has_timestamps {
+                    self.timestamps.insert(index, timestamp);
+                    self.timed.insert(index, track.timestamp.is_some());
+                }
+                self.disputed.insert(index, track.being_disputed());
+                self.charged_back.insert(index, track.charged_back());
+            }
+        }
+    }
+
+    /// Keeps only the deposits for which `keep` returns `true` and returns the number of removed
+    /// deposits. The columns are rebuilt with the exact capacity needed, so the memory of the
+    /// removed deposits is given back.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(TxId, &TxTrack) -> bool) -> usize {
+        let kept = self
+            .iter()
+            .filter(|(tx_id, track)| keep(*tx_id, track))
+            .collect::<Vec<_>>();
+        let removed = self.len() - kept.len();
+        if removed > 0 {
+            let mut tracker = TxTracker {
+                ids: Vec::with_capacity(kept.len()),
+                amounts: Vec::with_capacity(kept.len()),
+                timestamps: Vec::new(),
+                timed: BitVec::default(),
+                disputed: BitVec::with_capacity(kept.len()),
+                charged_back: BitVec::with_capacity(kept.len()),
+                histories: BTreeMap::new(),
+                currencies: BTreeMap::new(),
+                rates: BTreeMap::new(),
+            };
+            for (tx_id, track) in kept {
+                tracker.insert(tx_id, track);
+            }
+            *self = tracker;
+        }
+        removed
+    }
+
+    /// Returns the highest tracked transaction id.
+    pub(crate) fn last_id(&self) -> Option<TxId> {
+        self.ids.last().copied()
+    }
+
+    /// Returns the number of bytes allocated on the heap by the columns. The size of the
+    /// histories, currencies and rates is approximated by the size of their entries.
+    pub(crate) fn heap_size(&self) -> usize {
+        self.ids.capacity() * std::mem::size_of::<TxId>()
+            + self.amounts.capacity() * std::mem::size_of::<Decimal>()
+            + self.timestamps.capacity() * std::mem::size_of::<Timestamp>()
+            + (self.timed.words.capacity()
+                + self.disputed.words.capacity()
+                + self.charged_back.words.capacity())
+                * std::mem::size_of::<u64>()
+            + self.histories.len() * std::mem::size_of::<(TxId, DisputeHistory)>()
+            + self.currencies.len() * std::mem::size_of::<(TxId, Currency)>()
+            + self.rates.len() * std::mem::size_of::<(TxId, AppliedRate)>()
+    }
+
+    /// Removes the deposits of `ids` not being disputed and returns them.
+    pub(crate) fn evict_undisputed(&mut self, ids: &BTreeSet<TxId>) -> Vec<(TxId, TxTrack)> {
+        let mut evicted = Vec::new();
+        let mut kept = TxTracker::new();
+        for (tx_id, track) in self.iter() {
+            if ids.contains(&tx_id) && !track.being_disputed() {
+                evicted.push((tx_id, track));
+            } else {
+                kept.insert(tx_id, track);
+            }
+        }
+        *self = kept;
+        evicted
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (TxId, TxTrack)> + '_ {
+        (0..self.len()).map(|index| (self.ids[index], self.at(index)))
+    }
+
+    fn at(&self, index: usize) -> TxTrack {
+        let mut track = TxTrack::restore(self.amounts[index], self.disputed.get(index));
+        if self.charged_back.get(index) {
+            track = track.into_charged_back();
+        }
+        let timestamp = self
+            .timestamps
+            .get(index)
+            .copied()
+            .filter(|_| self.timed.get(index));
+        let track = track
+            .with_timestamp(timestamp)
+            .with_currency(self.currencies.get(&self.ids[index]).copied())
+            .with_applied_rate(self.rates.get(&self.ids[index]).copied());
+        match self.histories.get(&self.ids[index]) {
+            Some(history) => track.with_history(*history),
+            None => track,
+        }
+    }
+}
+
+/// Serialized as a map from transaction id to `TxTrack`, which keeps snapshots readable and
+/// independent from the layout in memory.
+impl Serialize for TxTracker {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for TxTracker {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tracks = HashMap::<TxId, TxTrack>::deserialize(deserializer)?;
+        let mut tracks = tracks.into_iter().collect::<Vec<_>>();
+        tracks.sort_by_key(|(tx_id, _)| *tx_id);
+        let mut tracker = TxTracker::new();
+        for (tx_id, track) in tracks {
+            tracker.insert(tx_id, track);
+        }
+        Ok(tracker)
+    }
+}
+
+#[cfg(test)]
+impl Dummy<Faker> for TxTracker {
+    fn dummy_with_rng<R: fake::Rng + ?Sized>(_: &Faker, _: &mut R) -> Self {
+        TxTracker::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut tracker = TxTracker::new();
+        // Out of order ids to insert in the middle of the columns.
+        for tx_id in [5, 1, 3, 2, 4] {
+            tracker.insert(tx_id, TxTrack::restore(tx_id.into(), tx_id % 2 == 0));
+        }
+
+        assert_eq!(tracker.len(), 5);
+        assert_eq!(tracker.get(2), Some(TxTrack::restore(dec!(2), true)));
+        assert_eq!(tracker.get(3), Some(TxTrack::restore(dec!(3), false)));
+        assert_eq!(tracker.get(6), None);
+        assert_eq!(
+            tracker.iter().map(|(tx_id, _)| tx_id).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_evict_undisputed() {
+        let mut tracker = TxTracker::new();
+        for tx_id in 1..=5 {
+            tracker.insert(tx_id, TxTrack::restore(dec!(1), tx_id == 2));
+        }
+
+        let evicted = tracker.evict_undisputed(&BTreeSet::from([1, 2, 3]));
+
+        assert_eq!(
+            evicted.iter().map(|(tx_id, _)| *tx_id).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+        assert_eq!(
+            tracker.iter().map(|(tx_id, _)| tx_id).collect::<Vec<_>>(),
+            vec![2, 4, 5]
+        );
+    }
+
+    #[test]
+    fn test_dispute_lifecycle() {
+        let track = TxTrack::new(dec!(10));
+
+        let disputed = track.dispute(track.undisputed());
+        assert!(disputed.being_disputed());
+        assert_eq!(
+            disputed.history(),
+            TxTrack::restore(dec!(10), true).history()
+        );
+
+        let resolved = disputed.resolve(dec!(10));
+        assert_eq!(resolved.status, TxStatus::Resolved);
+        assert_eq!(resolved.undisputed(), dec!(10));
+
+        let charged_back = resolved.dispute(dec!(10)).chargeback(dec!(10));
+        assert!(charged_back.charged_back());
+        assert_eq!(charged_back.undisputed(), dec!(0));
+        let history = charged_back.history();
+        assert_eq!(
+            (history.disputes, history.resolves, history.chargebacks),
+            (2, 1, 1)
+        );
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let track = TxTrack::new(dec!(10)).dispute(dec!(3)).dispute(dec!(2));
+        assert!(track.being_disputed());
+        assert_eq!(track.disputed(), dec!(5));
+        assert_eq!(track.undisputed(), dec!(5));
+        assert_eq!(track.history().disputes(), 1);
+
+        let track = track.chargeback(dec!(4));
+        assert!(track.being_disputed());
+        assert_eq!(track.disputed(), dec!(1));
+        assert_eq!(track.history().charged_back, dec!(4));
+
+        let track = track.resolve(dec!(1));
+        assert_eq!(track.status, TxStatus::Resolved);
+        assert_eq!(track.undisputed(), dec!(6));
+
+        let track = track.dispute(dec!(6)).chargeback(dec!(6));
+        assert!(track.charged_back());
+        assert_eq!(track.history().charged_back, dec!(10));
+        assert_eq!(track.history().disputes(), 2);
+    }
+
+    #[test]
+    fn test_insert_keeps_history() {
+        let mut tracker = TxTracker::new();
+        let partial = TxTrack::new(dec!(10)).dispute(dec!(4));
+        tracker.insert(1, partial);
+        tracker.insert(2, TxTrack::new(dec!(1)));
+
+        assert_eq!(tracker.get(1), Some(partial));
+        assert_eq!(tracker.get(1).unwrap().disputed(), dec!(4));
+        assert_eq!(tracker.get(2), Some(TxTrack::new(dec!(1))));
+
+        let resolved = partial.resolve(dec!(4));
+        tracker.insert(1, resolved);
+        assert_eq!(tracker.get(1), Some(resolved));
+        assert_eq!(tracker.histories.len(), 1);
+    }
+
+    #[test]
+    fn test_timestamps_column() {
+        let mut tracker = TxTracker::new();
+        tracker.insert(5, TxTrack::new(dec!(1)).with_timestamp(Some(50)));
+        assert_eq!(tracker.get(5).unwrap().timestamp(), Some(50));
+
+        let mut tracker = TxTracker::new();
+        tracker.insert(1, TxTrack::new(dec!(1)));
+        assert!(tracker.timestamps.is_empty());
+
+        tracker.insert(3, TxTrack::new(dec!(1)).with_timestamp(Some(30)));
+        tracker.insert(2, TxTrack::new(dec!(1)));
+
+        assert_eq!(tracker.timestamps.len(), 3);
+        assert_eq!(tracker.get(1).unwrap().timestamp(), None);
+        assert_eq!(tracker.get(2).unwrap().timestamp(), None);
+        assert_eq!(tracker.get(3).unwrap().timestamp(), Some(30));
+
+        // Every timestamp is valid, so none of them marks the deposits without one.
+        tracker.insert(
+            4,
+            TxTrack::new(dec!(1)).with_timestamp(Some(Timestamp::MAX)),
+        );
+        tracker.insert(0, TxTrack::new(dec!(1)).with_timestamp(Some(0)));
+        assert_eq!(tracker.get(4).unwrap().timestamp(), Some(Timestamp::MAX));
+        assert_eq!(tracker.get(0).unwrap().timestamp(), Some(0));
+        assert_eq!(tracker.get(1).unwrap().timestamp(), None);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut tracker = TxTracker::new();
+        for tx_id in 1..=100 {
+            tracker.insert(tx_id, TxTrack::restore(dec!(1), tx_id == 10));
+        }
+        let before = tracker.heap_size();
+
+        let removed = tracker.retain(|tx_id, track| tx_id > 90 || track.being_disputed());
+
+        assert_eq!(removed, 89);
+        assert_eq!(
+            tracker.iter().map(|(tx_id, _)| tx_id).collect::<Vec<_>>(),
+            vec![10, 91, 92, 93, 94, 95, 96, 97, 98, 99, 100]
+        );
+        assert!(tracker.get(10).unwrap().being_disputed());
+        assert!(tracker.heap_size() < before);
+    }
+
+    #[test]
+    fn test_status_bits_across_words() {
+        let mut tracker = TxTracker::new();
+        let mut expected = Vec::new();
+        for tx_id in (0..300).rev() {
+            let disputed = tx_id % 3 == 0 || tx_id % 7 == 0;
+            tracker.insert(tx_id, TxTrack::restore(Decimal::ZERO, disputed));
+            expected.push((tx_id, disputed));
+        }
+        expected.sort();
+
+        let obtained = tracker
+            .iter()
+            .map(|(tx_id, track)| (tx_id, track.being_disputed()))
+            .collect::<Vec<_>>();
+        assert_eq!(obtained, expected);
+    }
+
+    #[test]
+    fn test_serialize_as_map() {
+        let mut tracker = TxTracker::new();
+        tracker.insert(2, TxTrack::restore(dec!(1.5), true));
+        tracker.insert(1, TxTrack::new(dec!(3)));
+
+        let json = serde_json::to_string(&tracker).unwrap();
+        let obtained: TxTracker = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(
+            json,
+            r#"{"1":{"amount":"3","status":"Depoit"},"2":{"amount":"1.5","status":"BeingDisputed"}}"#
+        );
+        assert_eq!(obtained, tracker);
+    }
+}
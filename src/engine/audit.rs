@@ -0,0 +1,130 @@
+//! Audit trail of the administrative operations.
+//!
+//! Every administrative operation processed by an engine, whether it was applied or rejected, is
+//! recorded together with its operator and reason. The memory engine appends the records to a
+//! file as JSON lines, while the SQLite engine stores them in its database.
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+use crate::domain::ClientId;
+use crate::domain::Transaction;
+use crate::domain::TransactionError;
+use crate::domain::TransactionType;
+use crate::domain::TxId;
+
+/// Record of an administrative operation in the audit trail.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct AuditRecord {
+    /// The client of the account.
+    pub client: ClientId,
+    /// The transaction id of the operation.
+    pub tx: TxId,
+    /// The type of the operation.
+    #[serde(rename = "type")]
+    pub ty: TransactionType,
+    /// The operator who requested the operation.
+    pub operator: String,
+    /// The reason given for the operation.
+    pub reason: String,
+    /// The error that rejected the operation, or `None` if it was applied.
+    pub rejection: Option<String>,
+}
+
+impl AuditRecord {
+    /// Creates the record of `transaction` given the result of processing it.
+    pub(crate) fn new(transaction: &Transaction, result: &Result<(), TransactionError>) -> Self {
+        let record = Self {
+            client: transaction.client_id(),
+            tx: transaction.transaction_id(),
+            ty: transaction.ty().clone(),
+            operator: transaction.operator().unwrap_or_default().to_string(),
+            reason: transaction.reason().unwrap_or_default().to_string(),
+            rejection: result.as_ref().err().map(|e| e.to_string()),
+        };
+        info!(
+            "Administrative operation {:?} on client {} by {}: {} ({})",
+            record.ty,
+            record.client,
+            record.operator,
+            record.reason,
+            record.rejection.as_deref().unwrap_or("applied")
+        );
+        record
+    }
+}
+
+/// Append-only file with the audit trail.
+pub(crate) struct AuditLog {
+    file: File,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuditLog").finish()
+    }
+}
+
+impl AuditLog {
+    /// Opens the audit trail at `path`, creating it if it does not exist. Records are appended
+    /// after the existing ones.
+    pub(crate) fn open(path: &str) -> Result<Self, TransactionError> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Appends `record` and synchronizes it to disk.
+    pub(crate) fn append(&mut self, record: &AuditRecord) -> Result<(), TransactionError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Reads all the records of the audit trail at `path`.
+pub fn read_audit_log(path: &str) -> Result<Vec<AuditRecord>, TransactionError> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_read() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let unlock = Transaction::builder()
+            .ty(TransactionType::Unlock)
+            .client_id(1)
+            .transaction_id(7)
+            .operator("alice")
+            .reason("Chargeback reverted by the bank")
+            .build();
+        let records = vec![
+            AuditRecord::new(&unlock, &Ok(())),
+            AuditRecord::new(
+                &unlock,
+                &Err(TransactionError::AccountClosed(unlock.clone())),
+            ),
+        ];
+
+        let mut log = AuditLog::open(path).unwrap();
+        for record in records.iter() {
+            log.append(record).unwrap();
+        }
+        let result = read_audit_log(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(result, records);
+        assert_eq!(result[0].operator, "alice");
+        assert!(result[1].rejection.is_some());
+    }
+}
@@ -1,15 +1,32 @@
 //! Memory implementation of the payment engine.
-use log::warn;
+use log::{info, warn};
 use std::collections::HashMap;
 use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::RwLock;
 
+use super::audit::{AuditLog, AuditRecord};
+use super::quarantine::{QuarantineLog, QuarantineRecord};
+use super::snapshot;
+use super::spill::{HistorySpill, HistoryStats};
+use super::wal::{FsyncPolicy, WalEntry, WriteAheadLog};
 use super::PaymentEngine;
 use crate::domain::Account;
+use crate::domain::AccountPolicy;
 use crate::domain::ClientId;
+use crate::domain::ClientScreening;
+use crate::domain::InvariantChecker;
+use crate::domain::JournalEntry;
+use crate::domain::Ledger;
+use crate::domain::PruneReport;
+use crate::domain::RetentionPolicy;
+use crate::domain::ScreeningAction;
 use crate::domain::Transaction;
 use crate::domain::TransactionError;
+use crate::domain::TxId;
+use crate::domain::Violation;
 use crate::TransactionResultSummary;
 
 /// This storage will contain the current state of the client's account.
@@ -18,9 +35,31 @@ type TxByClientId = HashMap<ClientId, RwLock<Account>>;
 /// A thread-safe payment engine that stores transaction information in memory.
 /// State is protected by a `RwLock` to allow concurrent reads and exclusive writes in order
 /// to speed up the processing of transactions.
+/// Optionally, accepted transactions can be recorded in a `WriteAheadLog` in order to recover the
+/// state after a crash, and the history of deposits can be spilled to disk with a `HistorySpill`
+/// in order to bound the memory used, and the history no longer needed can be pruned following a
+/// `RetentionPolicy`. The state of the accounts can be saved to a snapshot, also periodically
+/// while the transactions are processed.
+/// Every change to the balances is recorded in a double-entry `Ledger`, and the accounts changed
+/// by a transaction are verified against it. Optionally, the system-wide invariants of the
+/// accounts can be checked with an `InvariantChecker`, and the transactions can be screened
+/// against the lists of a `ClientScreening`, setting aside the ones screened out in a quarantine.
 #[derive(Clone)]
 pub struct MemoryThreadSafePaymentEngine {
     tx_state_by_client: Arc<RwLock<TxByClientId>>,
+    ledger: Arc<Mutex<Ledger>>,
+    wal: Option<Arc<Mutex<WriteAheadLog>>>,
+    history: Option<Arc<Mutex<HistorySpill>>>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+    invariants: Option<Arc<Mutex<InvariantChecker>>>,
+    screening: Option<ClientScreening>,
+    quarantine: Option<Arc<Mutex<QuarantineLog>>>,
+    policy: AccountPolicy,
+    retention: Option<(RetentionPolicy, u64)>,
+    snapshots: Option<(String, u64)>,
+    processed: Arc<AtomicU64>,
+    latest_tx: Arc<AtomicU32>,
+    resume_from: u64,
 }
 
 impl fmt::Debug for MemoryThreadSafePaymentEngine {
@@ -42,8 +81,392 @@ impl MemoryThreadSafePaymentEngine {
     pub fn new() -> Self {
         MemoryThreadSafePaymentEngine {
             tx_state_by_client: Arc::new(RwLock::new(HashMap::new())),
+            ledger: Arc::new(Mutex::new(Ledger::new())),
+            wal: None,
+            history: None,
+            audit: None,
+            invariants: None,
+            screening: None,
+            quarantine: None,
+            policy: AccountPolicy::default(),
+            retention: None,
+            snapshots: None,
+            processed: Arc::new(AtomicU64::new(0)),
+            latest_tx: Arc::new(AtomicU32::new(0)),
+            resume_from: 0,
         }
     }
+
+    /// Creates a `MemoryThreadSafePaymentEngine` that records every accepted transaction in the
+    /// write-ahead log at `path`, applying `account_policy` to the transactions.
+    ///
+    /// If the log already contains entries from a previous run, they are replayed to rebuild the
+    /// state of the accounts and `resume_offset` returns the position of the input following
+    /// the last recovered transaction. The log must be replayed with the policy of the run that
+    /// recorded it, as the fees charged and the currency conversions depend on it. An entry that
+    /// cannot be replayed is an error, as skipping it would lose an accepted transaction.
+    pub fn with_write_ahead_log(
+        path: &str,
+        policy: FsyncPolicy,
+        account_policy: AccountPolicy,
+    ) -> Result<Self, TransactionError> {
+        let (wal, entries) = WriteAheadLog::open(path, policy)?;
+        let mut engine = Self::new().with_policy(account_policy);
+        let mut accounts = TxByClientId::new();
+        let mut ledger = Ledger::new();
+        for entry in entries.into_iter() {
+            match entry {
+                WalEntry::Transaction {
+                    offset,
+                    transaction,
+                } => {
+                    apply(&mut accounts, &mut ledger, &transaction, &engine.policy)?.map_err(
+                        |e| {
+                            TransactionError::StorageError(format!(
+                                "Cannot replay the write-ahead log entry at offset {}: {}",
+                                offset, e
+                            ))
+                        },
+                    )?;
+                    engine.resume_from = offset + 1;
+                }
+                WalEntry::Opening { account } => {
+                    ledger.record(&account.opening_entry())?;
+                    accounts.insert(account.client_id(), RwLock::new(*account));
+                }
+                WalEntry::Quarantined { offset } => engine.resume_from = offset + 1,
+            }
+        }
+        engine.processed = Arc::new(AtomicU64::new(engine.resume_from));
+        engine.tx_state_by_client = Arc::new(RwLock::new(accounts));
+        engine.ledger = Arc::new(Mutex::new(ledger));
+        engine.wal = Some(Arc::new(Mutex::new(wal)));
+        Ok(engine)
+    }
+
+    /// Creates a `MemoryThreadSafePaymentEngine` starting from the state stored in the snapshot
+    /// at `path`. The ledger starts from the balances of the accounts in the snapshot.
+    pub fn from_snapshot(path: &str) -> Result<Self, TransactionError> {
+        let mut ledger = Ledger::new();
+        let accounts = snapshot::read(path)?
+            .into_iter()
+            .map(|account| {
+                ledger.record(&account.opening_entry())?;
+                Ok((account.client_id(), RwLock::new(account)))
+            })
+            .collect::<Result<_, TransactionError>>()?;
+        let mut engine = Self::new();
+        engine.tx_state_by_client = Arc::new(RwLock::new(accounts));
+        engine.ledger = Arc::new(Mutex::new(ledger));
+        Ok(engine)
+    }
+
+    /// Checks the balances of every account against the ledger, and the trial balance of the
+    /// ledger.
+    pub fn trial_balance(&self) -> Result<(), TransactionError> {
+        let accounts = self.tx_state_by_client.read()?;
+        let ledger = self.ledger.lock()?;
+        for account in accounts.values() {
+            ledger.verify(&*account.read()?)?;
+        }
+        ledger.trial_balance()
+    }
+
+    /// Keeps at most `budget` tracked deposits in memory, spilling the cold ones to an index on
+    /// disk at `path`. Any content of `path` left by a previous run is discarded.
+    pub fn with_history_spill(
+        mut self,
+        path: &str,
+        budget: usize,
+    ) -> Result<Self, TransactionError> {
+        let mut history = HistorySpill::open(path, budget)?;
+        for account in self.tx_state_by_client.read()?.values() {
+            let account = account.read()?;
+            for tx_id in account.tracked_ids() {
+                history.queue(account.client_id(), tx_id);
+            }
+            history.tracked(0, account.tracked_len());
+        }
+        self.history = Some(Arc::new(Mutex::new(history)));
+        Ok(self)
+    }
+
+    /// Records the administrative operations processed from now on in the audit trail at `path`.
+    pub fn with_audit_log(mut self, path: &str) -> Result<Self, TransactionError> {
+        self.audit = Some(Arc::new(Mutex::new(AuditLog::open(path)?)));
+        Ok(self)
+    }
+
+    /// Screens the transactions processed from now on against the lists of `screening`, which
+    /// can be updated through any of its clones while the engine keeps processing.
+    pub fn with_screening(mut self, screening: ClientScreening) -> Self {
+        self.screening = Some(screening);
+        self
+    }
+
+    /// Sets aside the transactions screened out in the quarantine at `path`, if the screening
+    /// quarantines them. Without a quarantine they are rejected.
+    pub fn with_quarantine_log(mut self, path: &str) -> Result<Self, TransactionError> {
+        self.quarantine = Some(Arc::new(Mutex::new(QuarantineLog::open(path)?)));
+        Ok(self)
+    }
+
+    /// Checks the invariants of the accounts over the transactions processed from now on, taking
+    /// the accounts the engine already has as opening balances.
+    pub fn with_invariant_checker(mut self) -> Result<Self, TransactionError> {
+        let mut checker = InvariantChecker::new();
+        for account in self.tx_state_by_client.read()?.values() {
+            checker.open(&*account.read()?);
+        }
+        self.invariants = Some(Arc::new(Mutex::new(checker)));
+        Ok(self)
+    }
+
+    /// Returns the violations of the invariants of the accounts, if the engine checks them.
+    pub fn check_invariants(&self) -> Result<Option<Vec<Violation>>, TransactionError> {
+        let invariants = match &self.invariants {
+            Some(invariants) => invariants,
+            None => return Ok(None),
+        };
+        let accounts = self.tx_state_by_client.read()?;
+        let accounts = accounts
+            .values()
+            .map(|account| account.read())
+            .collect::<Result<Vec<_>, _>>()?;
+        let checker = invariants.lock()?;
+        Ok(Some(
+            checker.check(accounts.iter().map(|account| &**account)),
+        ))
+    }
+
+    /// Applies the limits of `policy` to the transactions processed from now on.
+    pub fn with_policy(mut self, policy: AccountPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Prunes the history of deposits following `policy` once every `every` processed
+    /// transactions.
+    pub fn with_retention(mut self, policy: RetentionPolicy, every: u64) -> Self {
+        self.retention = Some((policy, every.max(1)));
+        self
+    }
+
+    /// Saves a snapshot of the accounts to `path` once every `every` processed transactions,
+    /// replacing the previous one.
+    pub fn with_snapshots(mut self, path: &str, every: u64) -> Self {
+        self.snapshots = Some((path.to_string(), every.max(1)));
+        self
+    }
+
+    /// Drops the tracked deposits that `policy` considers no longer needed and reports how much
+    /// memory was reclaimed. Deposits spilled to disk are not pruned.
+    pub fn prune(&self, policy: &RetentionPolicy) -> Result<PruneReport, TransactionError> {
+        let mut accounts = self.tx_state_by_client.write()?;
+        self.prune_accounts(&mut accounts, policy)
+    }
+
+    /// Returns the retention policy when the transaction processed at `offset` takes the number
+    /// of processed transactions to a multiple of the pruning interval.
+    fn prune_due(&self, offset: u64) -> Option<&RetentionPolicy> {
+        self.retention
+            .as_ref()
+            .filter(|(_, every)| offset / every != (offset + 1) / every)
+            .map(|(policy, _)| policy)
+    }
+
+    /// Saves the periodic snapshot when the transaction processed at `offset` takes the number of
+    /// processed transactions to a multiple of the snapshot interval. The storage must not be
+    /// locked by the caller, as the snapshot is taken like `save_snapshot`.
+    fn snapshot_if_due(&self, offset: u64) -> Result<(), TransactionError> {
+        match &self.snapshots {
+            Some((path, every)) if offset / every != (offset + 1) / every => {
+                self.save_snapshot(path)?;
+                info!("Saved a snapshot of {} processed transactions", offset + 1);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn prune_accounts(
+        &self,
+        accounts: &mut TxByClientId,
+        policy: &RetentionPolicy,
+    ) -> Result<PruneReport, TransactionError> {
+        let policy = if self.policy.lock_is_final() {
+            policy.clone()
+        } else {
+            policy.keeping_locked_accounts()
+        };
+        let mut history = match &self.history {
+            Some(history) => Some(history.lock()?),
+            None => None,
+        };
+        let mut invariants = match &self.invariants {
+            Some(invariants) => Some(invariants.lock()?),
+            None => None,
+        };
+        let mut accounts = accounts
+            .values_mut()
+            .map(|account| account.get_mut())
+            .collect::<Result<Vec<_>, _>>()?;
+        let latest = accounts
+            .iter()
+            .filter_map(|account| account.last_tracked())
+            .fold(self.latest_tx.load(Ordering::SeqCst), TxId::max);
+        let mut report = PruneReport::default();
+        for account in accounts.iter_mut() {
+            let tracked = account.tracked_len();
+            let disputes = account.open_disputes();
+            report += account.prune(&policy, latest);
+            if let Some(invariants) = invariants.as_mut() {
+                invariants.pruned(account, &disputes);
+            }
+            if let Some(history) = history.as_mut() {
+                history.tracked(tracked, account.tracked_len());
+            }
+        }
+        if let Some(history) = history.as_mut() {
+            let accounts = accounts
+                .iter()
+                .map(|account| (account.client_id(), &**account))
+                .collect::<HashMap<_, _>>();
+            history.retain_queued(|client_id, tx_id| {
+                accounts
+                    .get(&client_id)
+                    .is_some_and(|account| account.tracked(tx_id).is_some())
+            });
+        }
+        info!(
+            "Pruned {} tracked deposits of {} accounts, reclaimed {} bytes",
+            report.entries, report.accounts, report.bytes
+        );
+        Ok(report)
+    }
+
+    /// Returns the statistics of the history spilled to disk, if the engine spills it.
+    pub fn history_stats(&self) -> Result<Option<HistoryStats>, TransactionError> {
+        match &self.history {
+            Some(history) => Ok(Some(history.lock()?.stats())),
+            None => Ok(None),
+        }
+    }
+
+    /// Saves the current state of all the accounts to a snapshot at `path`.
+    ///
+    /// The snapshot can be taken while other threads keep processing transactions: the state is
+    /// copied while holding the lock of the storage, so it never contains a transaction applied
+    /// only partially. Deposits spilled to disk are included as well.
+    pub fn save_snapshot(&self, path: &str) -> Result<(), TransactionError> {
+        let accounts = self.tx_state_by_client.read()?;
+        let history = match &self.history {
+            Some(history) => Some(history.lock()?),
+            None => None,
+        };
+        let accounts = accounts
+            .values()
+            .map(|account| {
+                let mut account = account.read()?.clone();
+                if let Some(history) = &history {
+                    for (tx_id, track) in history.entries(account.client_id())? {
+                        account.track(tx_id, track);
+                    }
+                }
+                Ok(account)
+            })
+            .collect::<Result<Vec<_>, TransactionError>>()?;
+        snapshot::write(path, &accounts)
+    }
+}
+
+/// Creates the accounts of the clients involved in `transaction` that do not have one yet,
+/// including the house account receiving the fees of `policy` when `transaction` can be charged
+/// one.
+fn insert_accounts(accounts: &mut TxByClientId, transaction: &Transaction, policy: &AccountPolicy) {
+    let house = policy
+        .fees()
+        .filter(|fees| fees.charges(transaction.ty()))
+        .map(|fees| fees.house());
+    for client_id in std::iter::once(transaction.client_id())
+        .chain(transaction.destination())
+        .chain(house)
+    {
+        accounts
+            .entry(client_id)
+            .or_insert_with(|| RwLock::new(Account::new(client_id)));
+    }
+}
+
+/// Applies `transaction` to the accounts involved, returning whether it was accepted. Transfers
+/// are applied to both accounts or to none of them.
+fn apply(
+    accounts: &mut TxByClientId,
+    ledger: &mut Ledger,
+    transaction: &Transaction,
+    policy: &AccountPolicy,
+) -> Result<Result<(), TransactionError>, TransactionError> {
+    insert_accounts(accounts, transaction, policy);
+    let mut account = accounts[&transaction.client_id()].write()?;
+    settle(accounts, ledger, None, &mut account, transaction, policy)
+}
+
+/// Applies `transaction` to `account` and to the other accounts involved, already inserted in
+/// `accounts`: the destination of a transfer, or the house account receiving the fee charged.
+/// The changes are recorded in `ledger` and every account changed is verified against it, which
+/// is an error for the whole engine if it fails. They are followed by the `invariants` checker,
+/// if any.
+fn settle(
+    accounts: &TxByClientId,
+    ledger: &mut Ledger,
+    invariants: Option<&mut InvariantChecker>,
+    account: &mut Account,
+    transaction: &Transaction,
+    policy: &AccountPolicy,
+) -> Result<Result<(), TransactionError>, TransactionError> {
+    let mut entry = JournalEntry::new();
+    let result = match transaction.destination() {
+        Some(destination) => account.transfer_with_journal(
+            &mut *accounts[&destination].write()?,
+            transaction,
+            policy,
+            &mut entry,
+        ),
+        None => match account.process_with_journal(transaction, policy, &mut entry) {
+            Ok(Some(charge)) => {
+                // Fees are never charged to the house account, so it is not `account`.
+                if let Some(fees) = policy.fees() {
+                    accounts[&fees.house()].write()?.collect_fee(&charge);
+                }
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        },
+    };
+    ledger.record(&entry)?;
+    for client_id in entry.clients() {
+        if client_id == account.client_id() {
+            ledger.verify(account)?;
+        } else {
+            ledger.verify(&*accounts[&client_id].read()?)?;
+        }
+    }
+    if let Some(invariants) = invariants {
+        let others = entry
+            .clients()
+            .into_iter()
+            .filter(|client_id| *client_id != account.client_id())
+            .map(|client_id| accounts[&client_id].read())
+            .collect::<Result<Vec<_>, _>>()?;
+        invariants.observe(
+            transaction,
+            &entry,
+            policy,
+            std::iter::once(&*account).chain(others.iter().map(|other| &**other)),
+        );
+    }
+    Ok(result)
 }
 
 impl Default for MemoryThreadSafePaymentEngine {
@@ -77,21 +500,126 @@ impl PaymentEngine for MemoryThreadSafePaymentEngine {
     /// assert!(result.is_ok());
     /// ```
     fn process(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        if let Some(screening) = &self.screening {
+            // Screened out before creating any account, so blocked clients leave no trace.
+            if let Err(e) = screening.screen(transaction)? {
+                let offset = self.processed.fetch_add(1, Ordering::SeqCst);
+                self.latest_tx
+                    .fetch_max(transaction.transaction_id(), Ordering::SeqCst);
+                match &self.quarantine {
+                    Some(quarantine) if screening.action() == ScreeningAction::Quarantine => {
+                        info!("Quarantined: {}", e);
+                        quarantine.lock()?.append(&QuarantineRecord::new(
+                            offset,
+                            transaction,
+                            &e,
+                        ))?;
+                        // A crash before this append quarantines the transaction again on resume.
+                        if let Some(wal) = &self.wal {
+                            wal.lock()?.append_quarantined(offset)?;
+                        }
+                    }
+                    _ => warn!("{}", e),
+                }
+                if let Some(policy) = self.prune_due(offset) {
+                    self.prune_accounts(&mut *self.tx_state_by_client.write()?, policy)?;
+                }
+                return self.snapshot_if_due(offset);
+            }
+        }
         let mut transactions = self.tx_state_by_client.write()?;
-        let tx_by_client = transactions
-            .entry(transaction.client_id())
-            .or_insert_with(|| RwLock::new(Account::new(transaction.client_id())));
-        let tx_by_client = tx_by_client.get_mut()?;
-        match tx_by_client.process(transaction) {
-            Ok(_) => {}
+        let mut history = match &self.history {
+            Some(history) => Some(history.lock()?),
+            None => None,
+        };
+        insert_accounts(&mut transactions, transaction, &self.policy);
+        let mut tx_by_client = transactions[&transaction.client_id()].write()?;
+        if let Some(history) = history.as_mut() {
+            history.reload(&mut tx_by_client, transaction)?;
+        }
+        let tracked = tx_by_client.tracked_len();
+        let offset = self.processed.fetch_add(1, Ordering::SeqCst);
+        self.latest_tx
+            .fetch_max(transaction.transaction_id(), Ordering::SeqCst);
+        // The storage is locked for writing, so no other thread sees a half-applied transfer or
+        // a fee not credited to the house account yet.
+        let mut invariants = match &self.invariants {
+            Some(invariants) => Some(invariants.lock()?),
+            None => None,
+        };
+        let result = settle(
+            &transactions,
+            &mut *self.ledger.lock()?,
+            invariants.as_deref_mut(),
+            &mut tx_by_client,
+            transaction,
+            &self.policy,
+        )?;
+        drop(invariants);
+        if transaction.ty().is_administrative() {
+            let record = AuditRecord::new(transaction, &result);
+            if let Some(audit) = &self.audit {
+                audit.lock()?.append(&record)?;
+            }
+        }
+        match result {
+            Ok(_) => {
+                if let Some(wal) = &self.wal {
+                    wal.lock()?.append(offset, transaction)?;
+                }
+            }
             Err(e) => {
                 warn!("{}", e);
             }
         }
+        if let Some(history) = history.as_mut() {
+            if tx_by_client.tracked_len() > tracked {
+                history.queue(transaction.client_id(), transaction.transaction_id());
+            }
+            history.tracked(tracked, tx_by_client.tracked_len());
+        }
+        drop(tx_by_client);
+        if let Some(history) = history.as_mut().filter(|history| history.over_budget()) {
+            history.enforce_budget(&mut transactions)?;
+        }
+        drop(history);
+        if let Some(policy) = self.prune_due(offset) {
+            self.prune_accounts(&mut transactions, policy)?;
+        }
+        drop(transactions);
+        self.snapshot_if_due(offset)
+    }
+
+    /// Creates the account of a client with its opening balances. If the engine has a
+    /// write-ahead log, the account is recorded in it to be recovered after a crash.
+    fn open_account(&mut self, account: Account) -> Result<(), TransactionError> {
+        let mut accounts = self.tx_state_by_client.write()?;
+        if accounts.contains_key(&account.client_id()) {
+            return Err(TransactionError::AccountAlreadyExists(account.client_id()));
+        }
+        self.ledger.lock()?.record(&account.opening_entry())?;
+        if let Some(invariants) = &self.invariants {
+            invariants.lock()?.open(&account);
+        }
+        if let Some(wal) = &self.wal {
+            wal.lock()?.append_opening(&account)?;
+        }
+        accounts.insert(account.client_id(), RwLock::new(account));
         Ok(())
     }
 
-    /// Returns a summary of the transaction results.
+    /// Counts an invalid input record, which is processed again on resume like the rejected
+    /// transactions after the last accepted one.
+    fn skip_invalid(&mut self) -> Result<(), TransactionError> {
+        let offset = self.processed.fetch_add(1, Ordering::SeqCst);
+        if let Some(policy) = self.prune_due(offset) {
+            self.prune_accounts(&mut *self.tx_state_by_client.write()?, policy)?;
+        }
+        self.snapshot_if_due(offset)
+    }
+
+    /// Returns a summary of the transaction results, once the trial balance of the ledger
+    /// proves no funds were created or lost.
     ///
     /// # Returns
     ///
@@ -118,18 +646,31 @@ impl PaymentEngine for MemoryThreadSafePaymentEngine {
     fn summary(
         &self,
     ) -> Result<Box<dyn Iterator<Item = TransactionResultSummary>>, TransactionError> {
+        self.trial_balance()?;
         let iter: Vec<TransactionResultSummary> = self
             .tx_state_by_client
             .read()?
             .values()
-            .map(|tx| tx.read().unwrap().clone().into())
+            .flat_map(|tx| {
+                tx.read()
+                    .unwrap()
+                    .summaries(self.policy.precision().scale())
+            })
             .collect();
         Ok(Box::new(iter.into_iter()))
     }
+
+    /// Returns the offset of the input following the last transaction recovered from the
+    /// write-ahead log, or 0 if the engine has no log.
+    fn resume_offset(&self) -> Result<u64, TransactionError> {
+        Ok(self.resume_from)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
     use std::thread;
 
     use super::*;
@@ -279,4 +820,547 @@ mod tests {
 
         assert_eq!(tx_by_client.len(), 1);
     }
+
+    #[test]
+    fn test_recover_from_write_ahead_log() {
+        let path = std::env::temp_dir().join(format!("memory-recover-{}.wal", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let transactions = [
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(1)
+                .amount(Amount::new(dec!(2.5)).unwrap())
+                .ty(TransactionType::Deposit)
+                .build(),
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(2)
+                .amount(10)
+                .ty(TransactionType::Withdrawal)
+                .build(),
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(1)
+                .ty(TransactionType::Dispute)
+                .build(),
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(2)
+                .ty(TransactionType::Dispute)
+                .build(),
+        ];
+
+        let mut engine = MemoryThreadSafePaymentEngine::with_write_ahead_log(
+            path,
+            FsyncPolicy::Always,
+            AccountPolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(engine.resume_offset().unwrap(), 0);
+        engine
+            .open_account(Account::create_with(2, dec!(5), dec!(0), false))
+            .unwrap();
+        for transaction in transactions.iter() {
+            engine.process(transaction).unwrap();
+        }
+        drop(engine);
+
+        let engine = MemoryThreadSafePaymentEngine::with_write_ahead_log(
+            path,
+            FsyncPolicy::Always,
+            AccountPolicy::default(),
+        )
+        .unwrap();
+        let summary = engine.summary().unwrap().collect::<Vec<_>>();
+        std::fs::remove_file(path).unwrap();
+
+        // Rejected transactions after the last accepted one are processed again on resume.
+        assert_eq!(engine.resume_offset().unwrap(), 3);
+        assert_eq!(summary.len(), 2);
+        assert!(summary.contains(&Account::create_with(1, dec!(0), dec!(2.5), false).into()));
+        assert!(summary.contains(&Account::create_with(2, dec!(5), dec!(0), false).into()));
+    }
+
+    #[test]
+    fn test_recover_fees_from_write_ahead_log() {
+        let path = std::env::temp_dir().join(format!("memory-fees-{}.wal", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let policy = AccountPolicy::builder()
+            .fees(
+                FeeSchedule::builder()
+                    .house(0)
+                    .deposit("1%".parse::<Fee>().unwrap())
+                    .build(),
+            )
+            .build();
+        let deposit = Transaction::builder()
+            .client_id(1)
+            .transaction_id(1)
+            .amount(10)
+            .ty(TransactionType::Deposit)
+            .build();
+
+        let mut engine = MemoryThreadSafePaymentEngine::with_write_ahead_log(
+            path,
+            FsyncPolicy::Always,
+            policy.clone(),
+        )
+        .unwrap();
+        engine.process(&deposit).unwrap();
+        let expected: [TransactionResultSummary; 2] = [
+            Account::create_with(0, dec!(0.1), dec!(0), false).into(),
+            Account::create_with(1, dec!(9.9), dec!(0), false)
+                .with_fees(dec!(0.1))
+                .into(),
+        ];
+        let summary = engine.summary().unwrap().collect::<Vec<_>>();
+        assert_eq!(summary.len(), 2);
+        assert!(expected.iter().all(|account| summary.contains(account)));
+        drop(engine);
+
+        let engine =
+            MemoryThreadSafePaymentEngine::with_write_ahead_log(path, FsyncPolicy::Always, policy)
+                .unwrap();
+        let summary = engine.summary().unwrap().collect::<Vec<_>>();
+        std::fs::remove_file(path).unwrap();
+
+        // Replaying the log with the fee schedule charges the same fees again.
+        assert_eq!(summary.len(), 2);
+        assert!(expected.iter().all(|account| summary.contains(account)));
+    }
+
+    #[test]
+    fn test_ledger_divergence_fails_loudly() {
+        let mut engine = MemoryThreadSafePaymentEngine::new();
+        engine
+            .open_account(Account::create_with(2, dec!(5), dec!(0), false))
+            .unwrap();
+        let deposit = Transaction::builder()
+            .client_id(1)
+            .transaction_id(1)
+            .amount(3)
+            .ty(TransactionType::Deposit)
+            .build();
+        engine.process(&deposit).unwrap();
+        engine.trial_balance().unwrap();
+
+        // Funds that appear in an account without a journal entry.
+        engine.tx_state_by_client.read().unwrap()[&2]
+            .write()
+            .unwrap()
+            .collect_fee(&FeeCharge {
+                currency: None,
+                amount: dec!(1),
+            });
+        assert!(matches!(
+            engine.summary(),
+            Err(TransactionError::LedgerImbalance(_))
+        ));
+        let withdrawal = Transaction::builder()
+            .client_id(2)
+            .transaction_id(2)
+            .amount(1)
+            .ty(TransactionType::Withdrawal)
+            .build();
+        assert!(matches!(
+            engine.process(&withdrawal),
+            Err(TransactionError::LedgerImbalance(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut engine = MemoryThreadSafePaymentEngine::new();
+        engine
+            .open_account(Account::create_with(2, dec!(5), dec!(1), false))
+            .unwrap();
+        assert_eq!(engine.check_invariants().unwrap(), None);
+        let mut engine = engine.with_invariant_checker().unwrap();
+        for (ty, tx_id, amount) in [
+            (TransactionType::Deposit, 1, Some(10)),
+            (TransactionType::Deposit, 2, Some(4)),
+            (TransactionType::Dispute, 1, None),
+            (TransactionType::Dispute, 2, None),
+            (TransactionType::Chargeback, 2, None),
+            (TransactionType::Withdrawal, 3, Some(1)),
+        ] {
+            let builder = Transaction::builder()
+                .client_id(1)
+                .transaction_id(tx_id)
+                .ty(ty);
+            let transaction = match amount {
+                Some(amount) => builder.amount(amount).build(),
+                None => builder.build(),
+            };
+            engine.process(&transaction).unwrap();
+        }
+        assert_eq!(engine.check_invariants().unwrap(), Some(vec![]));
+
+        // The history of the locked account is dropped with the deposit still under dispute.
+        engine
+            .prune(&RetentionPolicy::builder().locked_accounts(true).build())
+            .unwrap();
+        assert_eq!(engine.check_invariants().unwrap(), Some(vec![]));
+
+        engine.tx_state_by_client.read().unwrap()[&2]
+            .write()
+            .unwrap()
+            .collect_fee(&FeeCharge {
+                currency: None,
+                amount: dec!(1),
+            });
+        let violations = engine.check_invariants().unwrap().unwrap();
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].invariant(), Invariant::Conservation);
+        assert_eq!(violations[0].clients(), [2]);
+    }
+
+    #[test]
+    fn test_open_existing_account() {
+        let mut engine = MemoryThreadSafePaymentEngine::new();
+        engine.open_account(Account::new(1)).unwrap();
+
+        let result = engine.open_account(Account::new(1));
+
+        assert!(matches!(
+            result,
+            Err(TransactionError::AccountAlreadyExists(1))
+        ));
+    }
+
+    #[test]
+    fn test_save_and_load_snapshot() {
+        let path = std::env::temp_dir().join(format!("memory-snapshot-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut engine = MemoryThreadSafePaymentEngine::new();
+        let transactions = [
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(1)
+                .amount(Amount::new(dec!(2.5)).unwrap())
+                .ty(TransactionType::Deposit)
+                .build(),
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(1)
+                .ty(TransactionType::Dispute)
+                .build(),
+        ];
+        for transaction in transactions.iter() {
+            engine.process(transaction).unwrap();
+        }
+
+        engine.save_snapshot(path).unwrap();
+        let mut restored = MemoryThreadSafePaymentEngine::from_snapshot(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // The dispute status is restored, so the deposit can be resolved after loading.
+        let resolve = Transaction::builder()
+            .client_id(1)
+            .transaction_id(1)
+            .ty(TransactionType::Resolve)
+            .build();
+        restored.process(&resolve).unwrap();
+        let summary = restored.summary().unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![Account::create_with(1, dec!(2.5), dec!(0), false).into()]
+        );
+    }
+
+    #[test]
+    fn test_periodic_snapshots() {
+        let path = std::env::temp_dir().join(format!("memory-snapshots-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut engine = MemoryThreadSafePaymentEngine::new().with_snapshots(path, 2);
+        let transaction = |ty, transaction_id, amount| {
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .amount(Amount::new(amount).unwrap())
+                .ty(ty)
+                .build()
+        };
+        let restored = || {
+            MemoryThreadSafePaymentEngine::from_snapshot(path)
+                .unwrap()
+                .summary()
+                .unwrap()
+                .collect::<Vec<_>>()
+        };
+
+        engine
+            .process(&transaction(TransactionType::Deposit, 1, dec!(5)))
+            .unwrap();
+        engine
+            .process(&transaction(TransactionType::Deposit, 2, dec!(3)))
+            .unwrap();
+        engine
+            .process(&transaction(TransactionType::Dispute, 1, dec!(5)))
+            .unwrap();
+        // The snapshot is only saved every two transactions.
+        assert_eq!(
+            restored(),
+            vec![Account::create_with(1, dec!(8), dec!(0), false).into()]
+        );
+
+        engine
+            .process(&transaction(TransactionType::Withdrawal, 3, dec!(1)))
+            .unwrap();
+        let summary = engine.summary().unwrap().collect::<Vec<_>>();
+        assert_eq!(restored(), summary);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            summary,
+            vec![Account::create_with(1, dec!(2), dec!(5), false).into()]
+        );
+    }
+
+    #[test]
+    fn test_process_with_history_spill() {
+        let path = std::env::temp_dir().join(format!("memory-spill-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut engine = MemoryThreadSafePaymentEngine::new()
+            .with_history_spill(path, 4)
+            .unwrap();
+        for transaction_id in 1..=10 {
+            let deposit = Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .amount(1)
+                .ty(TransactionType::Deposit)
+                .build();
+            engine.process(&deposit).unwrap();
+        }
+        for transaction_id in 1..=10 {
+            let dispute = Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .ty(TransactionType::Dispute)
+                .build();
+            engine.process(&dispute).unwrap();
+        }
+        let stats = engine.history_stats().unwrap().unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            engine.summary().unwrap().collect::<Vec<_>>(),
+            vec![Account::create_with(1, dec!(0), dec!(10), false).into()]
+        );
+        assert_eq!(stats.lookups, 10);
+        assert_eq!(stats.misses, 0);
+        assert!(stats.disk_hits > 0);
+        assert!(stats.memory_hit_rate() < 1.0);
+    }
+
+    #[test]
+    fn test_process_with_retention() {
+        let policy = RetentionPolicy::builder()
+            .locked_accounts(true)
+            .dispute_window(5)
+            .build();
+        let mut engine = MemoryThreadSafePaymentEngine::new().with_retention(policy.clone(), 5);
+        for transaction_id in 1..=10 {
+            let deposit = Transaction::builder()
+                .client_id((transaction_id % 2) as u16)
+                .transaction_id(transaction_id)
+                .amount(1)
+                .ty(TransactionType::Deposit)
+                .build();
+            engine.process(&deposit).unwrap();
+        }
+        for ty in [TransactionType::Dispute, TransactionType::Chargeback] {
+            let transaction = Transaction::builder()
+                .client_id(0)
+                .transaction_id(10)
+                .ty(ty)
+                .build();
+            engine.process(&transaction).unwrap();
+        }
+
+        let report = engine.prune(&policy).unwrap();
+
+        // Transactions 1 to 4 were pruned while processing, and client 0 is locked now.
+        assert_eq!(report.entries, 3);
+        assert_eq!(report.accounts, 1);
+        assert!(report.bytes > 0);
+        let accounts = engine.tx_state_by_client.read().unwrap();
+        let tracked = |client_id| accounts[&client_id].read().unwrap().tracked_len();
+        assert_eq!(tracked(0), 0);
+        assert_eq!(tracked(1), 3);
+    }
+
+    #[test]
+    fn test_process_rejects_overflow_and_keeps_running() {
+        let policy = AccountPolicy::builder().max_balance(Decimal::MAX).build();
+        let mut engine = MemoryThreadSafePaymentEngine::new().with_policy(policy);
+        for (transaction_id, amount) in [(1, Decimal::MAX), (2, dec!(1)), (3, dec!(0))] {
+            let deposit = Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .amount(Amount::new(amount).unwrap())
+                .ty(TransactionType::Deposit)
+                .build();
+            assert!(engine.process(&deposit).is_ok());
+        }
+
+        assert_eq!(
+            engine.summary().unwrap().collect::<Vec<_>>(),
+            vec![Account::create_with(1, Decimal::MAX, dec!(0), false).into()]
+        );
+    }
+
+    #[test]
+    fn test_prune_keeps_locked_accounts_still_processing() {
+        let policy = AccountPolicy::builder()
+            .allowed_when_locked(vec![TransactionType::Resolve])
+            .build();
+        let mut engine = MemoryThreadSafePaymentEngine::new().with_policy(policy);
+        let transactions = [
+            (TransactionType::Deposit, 1),
+            (TransactionType::Deposit, 2),
+            (TransactionType::Dispute, 1),
+            (TransactionType::Dispute, 2),
+            (TransactionType::Chargeback, 1),
+        ];
+        for (ty, transaction_id) in transactions {
+            let builder = Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .ty(ty.clone());
+            let transaction = if ty == TransactionType::Deposit {
+                builder.amount(1).build()
+            } else {
+                builder.build()
+            };
+            engine.process(&transaction).unwrap();
+        }
+
+        let retention = RetentionPolicy::builder().locked_accounts(true).build();
+        let report = engine.prune(&retention).unwrap();
+
+        assert_eq!(report.entries, 0);
+        let resolve = Transaction::builder()
+            .client_id(1)
+            .transaction_id(2)
+            .ty(TransactionType::Resolve)
+            .build();
+        engine.process(&resolve).unwrap();
+        assert_eq!(
+            engine.summary().unwrap().collect::<Vec<_>>(),
+            vec![Account::create_with(1, dec!(1), dec!(0), true).into()]
+        );
+    }
+
+    #[test]
+    fn test_screened_transactions_are_quarantined() {
+        let path =
+            std::env::temp_dir().join(format!("memory-quarantine-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let screening = ClientScreening::new(
+            ClientLists::builder()
+                .blocked(std::collections::BTreeSet::from([2]))
+                .build(),
+            ScreeningAction::Quarantine,
+        );
+        let mut engine = MemoryThreadSafePaymentEngine::new()
+            .with_screening(screening.clone())
+            .with_quarantine_log(path)
+            .unwrap();
+        let deposit = |client_id, transaction_id| {
+            Transaction::builder()
+                .client_id(client_id)
+                .transaction_id(transaction_id)
+                .amount(5)
+                .ty(TransactionType::Deposit)
+                .build()
+        };
+        let transfer = Transaction::builder()
+            .client_id(1)
+            .transaction_id(3)
+            .amount(2)
+            .ty(TransactionType::Transfer)
+            .destination(2)
+            .build();
+
+        engine.process(&deposit(1, 1)).unwrap();
+        engine.process(&deposit(2, 2)).unwrap();
+        engine.process(&transfer).unwrap();
+        assert_eq!(
+            engine.summary().unwrap().collect::<Vec<_>>(),
+            vec![Account::create_with(1, dec!(5), dec!(0), false).into()]
+        );
+
+        // Lists updated while the engine keeps processing.
+        screening.update(ClientLists::default()).unwrap();
+        engine.process(&deposit(2, 4)).unwrap();
+        let quarantined = read_quarantine_log(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(engine.summary().unwrap().count(), 2);
+        assert_eq!(
+            quarantined
+                .iter()
+                .map(|record| (record.offset, record.transaction.clone()))
+                .collect::<Vec<_>>(),
+            vec![(1, deposit(2, 2)), (2, transfer)]
+        );
+        assert!(quarantined[0].reason.contains("blocklist"));
+    }
+
+    #[test]
+    fn test_quarantined_transactions_are_not_replayed_on_resume() {
+        let dir = std::env::temp_dir();
+        let wal = dir.join(format!(
+            "memory-quarantine-resume-{}.wal",
+            std::process::id()
+        ));
+        let log = dir.join(format!(
+            "memory-quarantine-resume-{}.log",
+            std::process::id()
+        ));
+        let (wal, log) = (wal.to_str().unwrap(), log.to_str().unwrap());
+        let _ = std::fs::remove_file(wal);
+        let _ = std::fs::remove_file(log);
+        let open = || {
+            MemoryThreadSafePaymentEngine::with_write_ahead_log(
+                wal,
+                FsyncPolicy::Always,
+                AccountPolicy::default(),
+            )
+            .unwrap()
+            .with_screening(ClientScreening::new(
+                ClientLists::builder()
+                    .blocked(std::collections::BTreeSet::from([2]))
+                    .build(),
+                ScreeningAction::Quarantine,
+            ))
+            .with_quarantine_log(log)
+            .unwrap()
+        };
+        let deposit = |client_id, transaction_id| {
+            Transaction::builder()
+                .client_id(client_id)
+                .transaction_id(transaction_id)
+                .amount(5)
+                .ty(TransactionType::Deposit)
+                .build()
+        };
+        let mut engine = open();
+        engine.process(&deposit(1, 1)).unwrap();
+        engine.process(&deposit(2, 2)).unwrap();
+        assert_eq!(engine.latest_tx.load(Ordering::SeqCst), 2);
+        drop(engine);
+
+        let engine = open();
+        let quarantined = read_quarantine_log(log).unwrap();
+        std::fs::remove_file(wal).unwrap();
+        std::fs::remove_file(log).unwrap();
+
+        assert_eq!(engine.resume_offset().unwrap(), 2);
+        assert_eq!(quarantined.len(), 1);
+    }
 }
@@ -1,10 +1,16 @@
 //! Contains the `PaymentEngine` trait definition.
+mod audit;
 mod memory;
+mod quarantine;
+mod snapshot;
+mod spill;
+mod sqlite;
+mod wal;
 
 #[cfg(test)]
 use mockall::{automock, predicate::*};
 
-use crate::{Transaction, TransactionError, TransactionResultSummary};
+use crate::{Account, Transaction, TransactionError, TransactionResultSummary};
 
 /// Trait representing a payment engine. `PaymentEngine` is responsible for processing transactions
 /// one by one and keeping track of them in a `TransactionResult` per Client Account.
@@ -22,6 +28,35 @@ pub trait PaymentEngine {
     /// a `TransactionError` if an error occurred during processing.
     fn process(&mut self, transaction: &Transaction) -> Result<(), TransactionError>;
 
+    /// Seed the engine with an account holding prior balances, before processing transactions.
+    ///
+    /// # Arguments
+    ///
+    /// * `account` - The account with its opening balances.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` if the account was created, or an `Err` containing a `TransactionError`
+    /// if the engine already has an account for the same client. Engines that cannot be seeded
+    /// reject every account.
+    fn open_account(&mut self, account: Account) -> Result<(), TransactionError> {
+        Err(TransactionError::InvalidOpeningBalance(format!(
+            "The engine cannot open the account of client {} with opening balances",
+            account.client_id()
+        )))
+    }
+
+    /// Count an input record rejected before reaching the engine because it is invalid.
+    ///
+    /// # Returns
+    ///
+    /// Returns `Ok(())` once the record is counted, so the offsets of the following records
+    /// still match their position in the input. Engines that do not keep state between runs
+    /// have nothing to count.
+    fn skip_invalid(&mut self) -> Result<(), TransactionError> {
+        Ok(())
+    }
+
     /// Get a summary of the processed transactions.
     ///
     /// # Returns
@@ -30,6 +65,21 @@ pub trait PaymentEngine {
     fn summary(
         &self,
     ) -> Result<Box<dyn Iterator<Item = TransactionResultSummary>>, TransactionError>;
+
+    /// Number of input records already applied by a previous run of the engine.
+    ///
+    /// # Returns
+    ///
+    /// Returns the offset from which the input should be resumed. Engines that do not keep state
+    /// between runs start always from the beginning.
+    fn resume_offset(&self) -> Result<u64, TransactionError> {
+        Ok(0)
+    }
 }
 
+pub use audit::{read_audit_log, AuditRecord};
 pub use memory::MemoryThreadSafePaymentEngine;
+pub use quarantine::{read_quarantine_log, QuarantineRecord};
+pub use spill::HistoryStats;
+pub use sqlite::SqlitePaymentEngine;
+pub use wal::FsyncPolicy;
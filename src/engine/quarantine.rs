@@ -0,0 +1,73 @@
+//! Quarantine of the transactions of the clients screened out.
+//!
+//! Transactions of clients in the blocklist, or missing from the allowlist, can be set aside
+//! instead of rejected, so they can be reviewed and released once the lists are cleared. The
+//! memory engine appends them to a file as JSON lines, while the SQLite engine stores them in its
+//! database.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+
+use crate::domain::Transaction;
+use crate::domain::TransactionError;
+
+/// Record of a transaction set aside in the quarantine.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct QuarantineRecord {
+    /// The position of the transaction in the input.
+    pub offset: u64,
+    /// The transaction set aside, to be processed again once released.
+    pub transaction: Transaction,
+    /// The error of the screening that set the transaction aside.
+    pub reason: String,
+}
+
+impl QuarantineRecord {
+    /// Creates the record of `transaction`, found at `offset` of the input and screened out with
+    /// `error`.
+    pub(crate) fn new(offset: u64, transaction: &Transaction, error: &TransactionError) -> Self {
+        Self {
+            offset,
+            transaction: transaction.clone(),
+            reason: error.to_string(),
+        }
+    }
+}
+
+/// Append-only file with the quarantined transactions.
+pub(crate) struct QuarantineLog {
+    file: File,
+}
+
+impl fmt::Debug for QuarantineLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuarantineLog").finish()
+    }
+}
+
+impl QuarantineLog {
+    /// Opens the quarantine at `path`, creating it if it does not exist. Records are appended
+    /// after the existing ones.
+    pub(crate) fn open(path: &str) -> Result<Self, TransactionError> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Appends `record` and synchronizes it to disk.
+    pub(crate) fn append(&mut self, record: &QuarantineRecord) -> Result<(), TransactionError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Reads all the records of the quarantine at `path`.
+pub fn read_quarantine_log(path: &str) -> Result<Vec<QuarantineRecord>, TransactionError> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
@@ -0,0 +1,141 @@
+//! Versioned snapshots of the state of the accounts.
+//!
+//! A snapshot file starts with a JSON header line containing the format version, the number of
+//! accounts and a checksum of the rest of the file, followed by the accounts serialized as JSON.
+//! The checksum allows detecting truncated or modified snapshots before loading them.
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+
+use crate::domain::Account;
+use crate::domain::TransactionError;
+
+/// Version of the snapshot format written by this build.
+pub(crate) const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotHeader {
+    version: u32,
+    accounts: usize,
+    checksum: String,
+}
+
+/// FNV-1a hash of the snapshot body.
+fn checksum(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Writes `accounts` to a snapshot at `path`. The file is written next to the destination first
+/// and then renamed, so an existing snapshot is never left half overwritten.
+pub(crate) fn write(path: &str, accounts: &[Account]) -> Result<(), TransactionError> {
+    let body = serde_json::to_vec(accounts)?;
+    let header = SnapshotHeader {
+        version: SNAPSHOT_VERSION,
+        accounts: accounts.len(),
+        checksum: checksum(&body),
+    };
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, &header)?;
+    file.write_all(b"\n")?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Reads the accounts stored in the snapshot at `path`, validating its version and checksum.
+pub(crate) fn read(path: &str) -> Result<Vec<Account>, TransactionError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let header: SnapshotHeader = serde_json::from_str(&line)
+        .map_err(|e| TransactionError::InvalidSnapshot(format!("{}: {}", path, e)))?;
+    if header.version != SNAPSHOT_VERSION {
+        return Err(TransactionError::InvalidSnapshot(format!(
+            "{}: unsupported version {}, expected {}",
+            path, header.version, SNAPSHOT_VERSION
+        )));
+    }
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    if checksum(&body) != header.checksum {
+        return Err(TransactionError::InvalidSnapshot(format!(
+            "{}: checksum mismatch",
+            path
+        )));
+    }
+    let accounts: Vec<Account> = serde_json::from_slice(&body)
+        .map_err(|e| TransactionError::InvalidSnapshot(format!("{}: {}", path, e)))?;
+    if accounts.len() != header.accounts {
+        return Err(TransactionError::InvalidSnapshot(format!(
+            "{}: expected {} accounts but found {}",
+            path,
+            header.accounts,
+            accounts.len()
+        )));
+    }
+    Ok(accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    fn temp_snapshot(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.snapshot", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let path = temp_snapshot("snapshot-roundtrip");
+        let accounts = vec![
+            Account::create_with(1, dec!(1.5), dec!(2), false),
+            Account::create_with(2, dec!(0), dec!(0), true),
+        ];
+
+        write(&path, &accounts).unwrap();
+        let result = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(result, accounts);
+    }
+
+    #[test]
+    fn test_read_modified_snapshot() {
+        let path = temp_snapshot("snapshot-modified");
+        write(&path, &[Account::create_with(1, dec!(1.5), dec!(2), false)]).unwrap();
+        let content = fs::read_to_string(&path).unwrap().replace("1.5", "9.5");
+        fs::write(&path, content).unwrap();
+
+        let result = read(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(
+            matches!(result, Err(TransactionError::InvalidSnapshot(msg)) if msg.contains("checksum"))
+        );
+    }
+
+    #[test]
+    fn test_read_unsupported_version() {
+        let path = temp_snapshot("snapshot-version");
+        fs::write(
+            &path,
+            "{\"version\":0,\"accounts\":0,\"checksum\":\"\"}\n[]",
+        )
+        .unwrap();
+
+        let result = read(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(
+            matches!(result, Err(TransactionError::InvalidSnapshot(msg)) if msg.contains("version"))
+        );
+    }
+}
@@ -0,0 +1,410 @@
+//! On-disk spill of the history of deposits tracked by the accounts.
+//!
+//! Every deposit is tracked in memory in case it is disputed later, so the memory used by an
+//! engine grows with the number of transactions instead of the number of clients. When a
+//! `HistorySpill` is configured, the engine keeps at most a given number of tracked deposits in
+//! memory and moves the cold ones to an index on disk. They are looked up again only when a
+//! dispute, resolve or chargeback references a deposit that is not in memory, or when a
+//! deposit, withdrawal, transfer or authorization reuses its id, so it is detected as a
+//! duplicate. Only ids up to the highest one spilled for the client can be on disk, so the ids of
+//! new transactions above it, the usual case when ids grow with the input, never touch the disk.
+//! The ids tracked in memory are queued in the order they were tracked, so the coldest ones are
+//! spilled first without scanning every account.
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::sync::RwLock;
+
+use crate::domain::Account;
+use crate::domain::ClientId;
+use crate::domain::Transaction;
+use crate::domain::TransactionError;
+use crate::domain::TransactionType;
+use crate::domain::TxId;
+use crate::domain::TxTrack;
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = OFF;
+    PRAGMA synchronous = OFF;
+    DROP TABLE IF EXISTS spilled_deposits;
+    CREATE TABLE spilled_deposits (
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        track TEXT NOT NULL,
+        PRIMARY KEY (client, tx)
+    ) WITHOUT ROWID;
+";
+
+/// Statistics of the lookups of tracked deposits in a `HistorySpill`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryStats {
+    /// Number of disputes, resolves and chargebacks looked up.
+    pub lookups: u64,
+    /// Lookups whose deposit was found in memory.
+    pub memory_hits: u64,
+    /// Lookups whose deposit was found on disk and loaded back into memory.
+    pub disk_hits: u64,
+    /// Lookups whose deposit was not found anywhere.
+    pub misses: u64,
+    /// Queries of the index on disk, both for the deposits looked up and for the ids of new
+    /// deposits, withdrawals, transfers and authorizations checked for duplicates.
+    pub disk_lookups: u64,
+    /// Number of tracked deposits moved from memory to disk.
+    pub spilled: u64,
+    /// Number of tracked deposits currently in memory.
+    pub in_memory: usize,
+}
+
+impl HistoryStats {
+    /// Ratio of lookups served from memory over the lookups whose deposit exists.
+    pub fn memory_hit_rate(&self) -> f64 {
+        let found = self.memory_hits + self.disk_hits;
+        if found == 0 {
+            return 1.0;
+        }
+        self.memory_hits as f64 / found as f64
+    }
+}
+
+/// Index on disk of the tracked deposits evicted from memory.
+pub(crate) struct HistorySpill {
+    conn: Connection,
+    budget: usize,
+    stats: HistoryStats,
+    /// Clients and ids of the deposits tracked in memory, oldest first. Ids pruned from the
+    /// accounts are dropped from the queue once they are popped, or when the accounts are pruned.
+    queue: VecDeque<(ClientId, TxId)>,
+    /// Highest id spilled to disk for each client. Ids above it are never looked up on disk.
+    watermarks: HashMap<ClientId, TxId>,
+}
+
+impl fmt::Debug for HistorySpill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HistorySpill")
+            .field("budget", &self.budget)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl HistorySpill {
+    /// Creates the index at `path`, discarding any content left by a previous run. At most
+    /// `budget` tracked deposits are kept in memory.
+    pub(crate) fn open(path: &str, budget: usize) -> Result<Self, TransactionError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn,
+            budget,
+            stats: HistoryStats::default(),
+            queue: VecDeque::new(),
+            watermarks: HashMap::new(),
+        })
+    }
+
+    pub(crate) fn stats(&self) -> HistoryStats {
+        self.stats.clone()
+    }
+
+    /// Loads back into `account` the deposit referenced by `transaction` if it is a dispute,
+    /// resolve or chargeback and the deposit was spilled to disk. A deposit spilled with the id
+    /// of a new deposit, withdrawal, transfer or authorization is loaded back too, so the account
+    /// rejects the transaction as a duplicate.
+    pub(crate) fn reload(
+        &mut self,
+        account: &mut Account,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        let tx_id = transaction.transaction_id();
+        match transaction.ty() {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {}
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Transfer
+            | TransactionType::Authorize => {
+                if account.tracked(tx_id).is_none() {
+                    if let Some(track) = self.take(account.client_id(), tx_id)? {
+                        account.track(tx_id, track);
+                    }
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        }
+        self.stats.lookups += 1;
+        if account.tracked(tx_id).is_some() {
+            self.stats.memory_hits += 1;
+            return Ok(());
+        }
+        match self.take(account.client_id(), tx_id)? {
+            Some(track) => {
+                self.stats.disk_hits += 1;
+                account.track(tx_id, track);
+            }
+            None => self.stats.misses += 1,
+        }
+        Ok(())
+    }
+
+    /// Records that the number of deposits tracked in memory by an account went from `before` to
+    /// `after`.
+    pub(crate) fn tracked(&mut self, before: usize, after: usize) {
+        self.stats.in_memory = (self.stats.in_memory + after).saturating_sub(before);
+    }
+
+    /// Queues a deposit just tracked in memory by the account of `client_id`.
+    pub(crate) fn queue(&mut self, client_id: ClientId, tx_id: TxId) {
+        self.queue.push_back((client_id, tx_id));
+    }
+
+    /// Drops from the queue the deposits no longer tracked in memory, such as the ones pruned.
+    pub(crate) fn retain_queued(&mut self, mut tracked: impl FnMut(ClientId, TxId) -> bool) {
+        self.queue
+            .retain(|(client_id, tx_id)| tracked(*client_id, *tx_id));
+    }
+
+    /// Returns whether there are more deposits tracked in memory than the budget allows.
+    pub(crate) fn over_budget(&self) -> bool {
+        self.stats.in_memory > self.budget
+    }
+
+    /// Moves the oldest undisputed deposits of `accounts` to disk until only half of the budget
+    /// is used, if the budget is exceeded. Only the accounts of the deposits taken from the queue
+    /// are visited, and the deposits being disputed are queued again.
+    pub(crate) fn enforce_budget(
+        &mut self,
+        accounts: &mut HashMap<ClientId, RwLock<Account>>,
+    ) -> Result<(), TransactionError> {
+        if !self.over_budget() {
+            return Ok(());
+        }
+        let target = self.budget / 2;
+        let db_tx = self.conn.transaction()?;
+        // Every queued deposit is visited at most once, in case all of them are disputed.
+        let mut unvisited = self.queue.len();
+        while self.stats.in_memory > target && unvisited > 0 {
+            let batch = (self.stats.in_memory - target).min(unvisited);
+            unvisited -= batch;
+            let mut ids = BTreeMap::<ClientId, BTreeSet<TxId>>::new();
+            for (client_id, tx_id) in self.queue.drain(..batch) {
+                ids.entry(client_id).or_default().insert(tx_id);
+            }
+            for (client_id, ids) in ids {
+                let Some(account) = accounts.get_mut(&client_id) else {
+                    continue;
+                };
+                let account = account.get_mut()?;
+                let evicted = account.evict_undisputed(&ids);
+                for (tx_id, track) in evicted.iter() {
+                    let watermark = self.watermarks.entry(client_id).or_default();
+                    *watermark = (*watermark).max(*tx_id);
+                    db_tx.execute(
+                        "INSERT OR REPLACE INTO spilled_deposits (client, tx, track) VALUES (?1, ?2, ?3)",
+                        params![client_id, tx_id, serde_json::to_string(track)?],
+                    )?;
+                }
+                for tx_id in ids {
+                    if account.tracked(tx_id).is_some() {
+                        self.queue.push_back((client_id, tx_id));
+                    }
+                }
+                self.stats.in_memory -= evicted.len();
+                self.stats.spilled += evicted.len() as u64;
+            }
+        }
+        db_tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns all the deposits of `client_id` stored on disk, without removing them.
+    pub(crate) fn entries(
+        &self,
+        client_id: ClientId,
+    ) -> Result<Vec<(TxId, TxTrack)>, TransactionError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT tx, track FROM spilled_deposits WHERE client = ?1")?;
+        let rows = stmt
+            .query_map(params![client_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(TxId, String)>, _>>()?;
+        rows.into_iter()
+            .map(|(tx_id, track)| Ok((tx_id, serde_json::from_str(&track)?)))
+            .collect()
+    }
+
+    /// Removes from disk and returns the deposit `tx_id` of `client_id`, if it was spilled. The
+    /// disk is only queried for ids up to the watermark of the client.
+    fn take(
+        &mut self,
+        client_id: ClientId,
+        tx_id: TxId,
+    ) -> Result<Option<TxTrack>, TransactionError> {
+        if self
+            .watermarks
+            .get(&client_id)
+            .is_none_or(|watermark| tx_id > *watermark)
+        {
+            return Ok(None);
+        }
+        self.stats.disk_lookups += 1;
+        let track: Option<String> = self
+            .conn
+            .query_row(
+                "DELETE FROM spilled_deposits WHERE client = ?1 AND tx = ?2 RETURNING track",
+                params![client_id, tx_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match track {
+            Some(track) => {
+                self.stats.in_memory += 1;
+                self.queue.push_back((client_id, tx_id));
+                Ok(Some(serde_json::from_str(&track)?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+
+    fn temp_spill(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.spill", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn deposit(client_id: ClientId, transaction_id: TxId) -> Transaction {
+        Transaction::builder()
+            .ty(TransactionType::Deposit)
+            .client_id(client_id)
+            .transaction_id(transaction_id)
+            .amount(1)
+            .build()
+    }
+
+    fn dispute(client_id: ClientId, transaction_id: TxId) -> Transaction {
+        Transaction::builder()
+            .ty(TransactionType::Dispute)
+            .client_id(client_id)
+            .transaction_id(transaction_id)
+            .build()
+    }
+
+    fn accounts(accounts: Vec<Account>) -> HashMap<ClientId, RwLock<Account>> {
+        accounts
+            .into_iter()
+            .map(|account| (account.client_id(), RwLock::new(account)))
+            .collect()
+    }
+
+    #[test]
+    fn test_spill_and_reload() {
+        let path = temp_spill("spill-reload");
+        let mut spill = HistorySpill::open(&path, 2).unwrap();
+        let mut account = Account::new(1);
+        for tx_id in 1..=4 {
+            account.process(&deposit(1, tx_id)).unwrap();
+            spill.queue(1, tx_id);
+        }
+        spill.tracked(0, 4);
+        let mut accounts = accounts(vec![account]);
+
+        spill.enforce_budget(&mut accounts).unwrap();
+        let account = accounts.get_mut(&1).unwrap().get_mut().unwrap();
+        assert_eq!(account.tracked_len(), 1);
+        assert_eq!(spill.entries(1).unwrap().len(), 3);
+
+        let spilled = spill.entries(1).unwrap()[0].0;
+        spill.reload(account, &dispute(1, spilled)).unwrap();
+        spill.reload(account, &dispute(1, spilled)).unwrap();
+        spill.reload(account, &dispute(1, 10)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(account.tracked(spilled).is_some());
+        assert_eq!(
+            spill.stats(),
+            HistoryStats {
+                lookups: 3,
+                memory_hits: 1,
+                disk_hits: 1,
+                misses: 1,
+                disk_lookups: 1,
+                spilled: 3,
+                in_memory: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn test_only_ids_up_to_the_watermark_are_looked_up_on_disk() {
+        let path = temp_spill("spill-watermark");
+        let mut spill = HistorySpill::open(&path, 0).unwrap();
+        let mut account = Account::new(1);
+        for tx_id in [2, 4] {
+            account.process(&deposit(1, tx_id)).unwrap();
+            spill.queue(1, tx_id);
+        }
+        spill.tracked(0, 2);
+        let mut accounts = accounts(vec![account]);
+        spill.enforce_budget(&mut accounts).unwrap();
+        let account = accounts.get_mut(&1).unwrap().get_mut().unwrap();
+
+        for tx_id in [3, 5, 6] {
+            spill.reload(account, &deposit(1, tx_id)).unwrap();
+        }
+        spill.reload(account, &dispute(1, 7)).unwrap();
+        // Nothing of client 2 was spilled.
+        spill.reload(&mut Account::new(2), &deposit(2, 1)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(spill.stats().disk_lookups, 1);
+        assert_eq!(spill.stats().misses, 1);
+    }
+
+    #[test]
+    fn test_disputed_deposits_are_not_spilled() {
+        let path = temp_spill("spill-disputed");
+        let mut spill = HistorySpill::open(&path, 0).unwrap();
+        let mut account = Account::new(1);
+        account.process(&deposit(1, 1)).unwrap();
+        account.process(&dispute(1, 1)).unwrap();
+        spill.queue(1, 1);
+        spill.tracked(0, 1);
+        let mut accounts = accounts(vec![account]);
+
+        spill.enforce_budget(&mut accounts).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(accounts[&1].read().unwrap().tracked_len(), 1);
+        assert_eq!(spill.stats().spilled, 0);
+        assert_eq!(spill.queue, [(1, 1)]);
+    }
+
+    #[test]
+    fn test_duplicate_of_spilled_deposit_is_rejected() {
+        let path = temp_spill("spill-duplicate");
+        let mut spill = HistorySpill::open(&path, 0).unwrap();
+        let mut account = Account::new(1);
+        account.process(&deposit(1, 1)).unwrap();
+        spill.queue(1, 1);
+        spill.tracked(0, 1);
+        let mut accounts = accounts(vec![account]);
+        spill.enforce_budget(&mut accounts).unwrap();
+        let account = accounts.get_mut(&1).unwrap().get_mut().unwrap();
+        assert_eq!(account.tracked_len(), 0);
+
+        spill.reload(account, &deposit(1, 1)).unwrap();
+        let result = account.process(&deposit(1, 1));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            result,
+            Err(TransactionError::DuplicateTransaction(_))
+        ));
+        assert_eq!(account.available(), Decimal::ONE);
+    }
+}
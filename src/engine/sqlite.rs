@@ -0,0 +1,1282 @@
+//! SQLite implementation of the payment engine.
+//!
+//! Accounts and the deposits tracked for disputes are persisted in a local database file, so
+//! balances survive process restarts. Each transaction is applied inside a database transaction
+//! together with the number of input records processed so far, which allows resuming an
+//! interrupted run from the exact record where it stopped. The balances of the double-entry
+//! ledger are stored too, and only the ones of the accounts involved are loaded to verify them.
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use rust_decimal::Decimal;
+use std::fmt;
+use std::str::FromStr;
+
+use super::audit::AuditRecord;
+use super::quarantine::QuarantineRecord;
+use super::PaymentEngine;
+use crate::domain::Account;
+use crate::domain::AccountPolicy;
+use crate::domain::AppliedRate;
+use crate::domain::Authorization;
+use crate::domain::Balance;
+use crate::domain::ClientId;
+use crate::domain::ClientScreening;
+use crate::domain::Currency;
+use crate::domain::JournalEntry;
+use crate::domain::Ledger;
+use crate::domain::LedgerAccount;
+use crate::domain::RecentTransaction;
+use crate::domain::ScreeningAction;
+use crate::domain::Timestamp;
+use crate::domain::Transaction;
+use crate::domain::TransactionError;
+use crate::domain::TxId;
+use crate::domain::TxTrack;
+use crate::TransactionResultSummary;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        client INTEGER PRIMARY KEY,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        locked INTEGER NOT NULL,
+        frozen INTEGER NOT NULL DEFAULT 0,
+        closed INTEGER NOT NULL DEFAULT 0,
+        fees TEXT NOT NULL DEFAULT '0'
+    );
+    CREATE TABLE IF NOT EXISTS tracked_deposits (
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        amount TEXT NOT NULL,
+        disputed INTEGER NOT NULL,
+        charged_back INTEGER NOT NULL DEFAULT 0,
+        history TEXT,
+        timestamp INTEGER,
+        currency TEXT,
+        applied_rate TEXT,
+        PRIMARY KEY (client, tx)
+    );
+    CREATE TABLE IF NOT EXISTS authorizations (
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        amount TEXT NOT NULL,
+        timestamp INTEGER,
+        currency TEXT,
+        applied_rate TEXT,
+        PRIMARY KEY (client, tx)
+    );
+    CREATE TABLE IF NOT EXISTS balances (
+        client INTEGER NOT NULL,
+        currency TEXT NOT NULL,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        fees TEXT NOT NULL DEFAULT '0',
+        PRIMARY KEY (client, currency)
+    );
+    CREATE TABLE IF NOT EXISTS recent_withdrawals (
+        client INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        currency TEXT,
+        amount TEXT NOT NULL,
+        count INTEGER NOT NULL DEFAULT 1
+    );
+    CREATE INDEX IF NOT EXISTS recent_withdrawals_client ON recent_withdrawals (client);
+    CREATE TABLE IF NOT EXISTS retired_ids (
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        PRIMARY KEY (client, tx)
+    );
+    CREATE TABLE IF NOT EXISTS applied_rates (
+        client INTEGER NOT NULL,
+        tx INTEGER NOT NULL,
+        applied_rate TEXT NOT NULL,
+        PRIMARY KEY (client, tx)
+    );
+    CREATE TABLE IF NOT EXISTS ledger (
+        account TEXT NOT NULL,
+        currency TEXT NOT NULL DEFAULT '',
+        balance TEXT NOT NULL,
+        PRIMARY KEY (account, currency)
+    );
+    CREATE TABLE IF NOT EXISTS progress (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        processed INTEGER NOT NULL
+    );
+    INSERT OR IGNORE INTO progress (id, processed) VALUES (0, 0);
+    CREATE TABLE IF NOT EXISTS audit_trail (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        record TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS quarantine (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        record TEXT NOT NULL
+    );
+";
+
+/// Columns added to the tables after they were first created, with their definitions. The
+/// tables of a database written by an earlier version are left as they are by the schema, so
+/// the columns they lack are added when the database is opened.
+const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+    ("accounts", "frozen", "INTEGER NOT NULL DEFAULT 0"),
+    ("accounts", "closed", "INTEGER NOT NULL DEFAULT 0"),
+    ("accounts", "fees", "TEXT NOT NULL DEFAULT '0'"),
+    (
+        "tracked_deposits",
+        "charged_back",
+        "INTEGER NOT NULL DEFAULT 0",
+    ),
+    ("tracked_deposits", "history", "TEXT"),
+    ("tracked_deposits", "timestamp", "INTEGER"),
+    ("tracked_deposits", "currency", "TEXT"),
+    ("tracked_deposits", "applied_rate", "TEXT"),
+    ("authorizations", "currency", "TEXT"),
+    ("authorizations", "applied_rate", "TEXT"),
+    ("balances", "fees", "TEXT NOT NULL DEFAULT '0'"),
+    ("recent_withdrawals", "count", "INTEGER NOT NULL DEFAULT 1"),
+];
+
+/// A payment engine that persists the state of the client's accounts in a SQLite database.
+/// Administrative operations are recorded in an audit trail stored in the same database, and so
+/// are the transactions set aside in the quarantine by a `ClientScreening`.
+pub struct SqlitePaymentEngine {
+    conn: Connection,
+    policy: AccountPolicy,
+    screening: Option<ClientScreening>,
+}
+
+impl fmt::Debug for SqlitePaymentEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqlitePaymentEngine").finish()
+    }
+}
+
+impl SqlitePaymentEngine {
+    /// Opens or creates the database at `path`, resuming any state left by a previous run.
+    pub fn open(path: &str) -> Result<Self, TransactionError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Creates an engine backed by a database that only lives in memory. Useful for testing.
+    pub fn open_in_memory() -> Result<Self, TransactionError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Self, TransactionError> {
+        conn.execute_batch(SCHEMA)?;
+        add_missing_columns(&conn)?;
+        seed_ledger(&mut conn)?;
+        Ok(Self {
+            conn,
+            policy: AccountPolicy::default(),
+            screening: None,
+        })
+    }
+
+    /// Applies the limits of `policy` to the transactions processed from now on.
+    pub fn with_policy(mut self, policy: AccountPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Screens the transactions processed from now on against the lists of `screening`, which
+    /// can be updated through any of its clones while the engine keeps processing.
+    pub fn with_screening(mut self, screening: ClientScreening) -> Self {
+        self.screening = Some(screening);
+        self
+    }
+
+    /// Returns all the transactions set aside in the quarantine, in the order they were
+    /// processed.
+    pub fn quarantined(&self) -> Result<Vec<QuarantineRecord>, TransactionError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT record FROM quarantine ORDER BY id")?;
+        let rows = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        rows.iter()
+            .map(|record| Ok(serde_json::from_str(record)?))
+            .collect()
+    }
+
+    /// Returns all the records of the audit trail, in the order they were processed.
+    pub fn audit_trail(&self) -> Result<Vec<AuditRecord>, TransactionError> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT record FROM audit_trail ORDER BY id")?;
+        let rows = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        rows.iter()
+            .map(|record| Ok(serde_json::from_str(record)?))
+            .collect()
+    }
+
+    /// Forgets how many input records were processed, keeping the balances of the accounts.
+    /// It should be used when a new input is going to be processed on top of the stored state.
+    pub fn reset_progress(&mut self) -> Result<(), TransactionError> {
+        self.conn
+            .execute("UPDATE progress SET processed = 0 WHERE id = 0", [])?;
+        Ok(())
+    }
+}
+
+fn parse_decimal(value: String) -> Result<Decimal, TransactionError> {
+    Decimal::from_str(&value).map_err(|e| TransactionError::StorageError(e.to_string()))
+}
+
+fn parse_currency(value: Option<String>) -> Result<Option<Currency>, TransactionError> {
+    value
+        .map(|value| Currency::from_str(&value))
+        .transpose()
+        .map_err(|e| TransactionError::StorageError(e.to_string()))
+}
+
+/// Returns the key of a ledger account in the ledger table.
+fn ledger_key(account: LedgerAccount) -> String {
+    match account {
+        LedgerAccount::Available(client_id) => format!("available:{}", client_id),
+        LedgerAccount::Held(client_id) => format!("held:{}", client_id),
+        LedgerAccount::Authorized(client_id) => format!("authorized:{}", client_id),
+        LedgerAccount::External => "external".to_string(),
+    }
+}
+
+fn parse_ledger_key(key: &str) -> Result<LedgerAccount, TransactionError> {
+    let invalid = || TransactionError::StorageError(format!("Invalid ledger account {}", key));
+    match key.split_once(':') {
+        Some(("available", client_id)) => Ok(LedgerAccount::Available(
+            client_id.parse().map_err(|_| invalid())?,
+        )),
+        Some(("held", client_id)) => Ok(LedgerAccount::Held(
+            client_id.parse().map_err(|_| invalid())?,
+        )),
+        Some(("authorized", client_id)) => Ok(LedgerAccount::Authorized(
+            client_id.parse().map_err(|_| invalid())?,
+        )),
+        None if key == "external" => Ok(LedgerAccount::External),
+        _ => Err(invalid()),
+    }
+}
+
+/// Loads the balances of the ledger accounts of `clients` and of the external funds, or of
+/// every ledger account if `clients` is none.
+fn load_ledger(
+    conn: &Connection,
+    clients: Option<&[ClientId]>,
+) -> Result<Ledger, TransactionError> {
+    let rows = match clients {
+        Some(clients) => {
+            let mut stmt =
+                conn.prepare("SELECT account, currency, balance FROM ledger WHERE account = ?1")?;
+            let mut rows = Vec::new();
+            let accounts = clients
+                .iter()
+                .flat_map(|client| {
+                    [
+                        LedgerAccount::Available(*client),
+                        LedgerAccount::Held(*client),
+                        LedgerAccount::Authorized(*client),
+                    ]
+                })
+                .chain(std::iter::once(LedgerAccount::External));
+            for account in accounts {
+                rows.extend(
+                    stmt.query_map(params![ledger_key(account)], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                    })?
+                    .collect::<Result<Vec<(String, String, String)>, _>>()?,
+                );
+            }
+            rows
+        }
+        None => conn
+            .prepare("SELECT account, currency, balance FROM ledger")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<Vec<(String, String, String)>, _>>()?,
+    };
+    let mut ledger = Ledger::new();
+    for (account, currency, balance) in rows {
+        let currency = parse_currency(Some(currency).filter(|currency| !currency.is_empty()))?;
+        ledger.restore(
+            parse_ledger_key(&account)?,
+            currency,
+            parse_decimal(balance)?,
+        );
+    }
+    Ok(ledger)
+}
+
+/// Records `entry` in `ledger` and stores the balances of the ledger accounts it posts to.
+fn record_entry(
+    conn: &Connection,
+    ledger: &mut Ledger,
+    entry: &JournalEntry,
+) -> Result<(), TransactionError> {
+    ledger.record(entry)?;
+    for posting in entry.postings() {
+        conn.execute(
+            "INSERT INTO ledger (account, currency, balance) VALUES (?1, ?2, ?3)
+             ON CONFLICT (account, currency) DO UPDATE SET balance = excluded.balance",
+            params![
+                ledger_key(posting.account),
+                posting
+                    .currency
+                    .map(|currency| currency.to_string())
+                    .unwrap_or_default(),
+                ledger
+                    .balance(posting.account, posting.currency)
+                    .to_string()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds the `ADDED_COLUMNS` missing from the tables of a database written by an earlier version.
+fn add_missing_columns(conn: &Connection) -> Result<(), TransactionError> {
+    let mut stmt = conn.prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?;
+    for (table, column, definition) in ADDED_COLUMNS {
+        if !stmt.exists(params![table, column])? {
+            info!("Adding column {} to table {}", column, table);
+            conn.execute(
+                &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+                [],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Seeds an empty ledger with the opening balances of the accounts already stored, as found in
+/// databases written before the ledger was kept.
+fn seed_ledger(conn: &mut Connection) -> Result<(), TransactionError> {
+    let db_tx = conn.transaction()?;
+    let entries: i64 = db_tx.query_row("SELECT COUNT(*) FROM ledger", [], |row| row.get(0))?;
+    if entries > 0 {
+        return Ok(());
+    }
+    let clients = db_tx
+        .prepare("SELECT client FROM accounts ORDER BY client")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<ClientId>, _>>()?;
+    if clients.is_empty() {
+        return Ok(());
+    }
+    let mut ledger = Ledger::new();
+    for client in &clients {
+        if let Some(account) = load_account(&db_tx, *client)? {
+            record_entry(&db_tx, &mut ledger, &account.opening_entry())?;
+        }
+    }
+    db_tx.commit()?;
+    info!("Seeded the ledger from {} stored accounts", clients.len());
+    Ok(())
+}
+
+fn load_account(
+    conn: &Connection,
+    client_id: ClientId,
+) -> Result<Option<Account>, TransactionError> {
+    let row = conn
+        .query_row(
+            "SELECT available, held, locked, frozen, closed, fees FROM accounts WHERE client = ?1",
+            params![client_id],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                ))
+            },
+        )
+        .optional()?;
+    row.map(|(available, held, locked, frozen, closed, fees)| {
+        let mut account = Account::create_with(
+            client_id,
+            parse_decimal(available)?,
+            parse_decimal(held)?,
+            locked,
+        )
+        .with_status(frozen, closed)
+        .with_fees(parse_decimal(fees)?);
+        restore_details(conn, &mut account)?;
+        Ok(account)
+    })
+    .transpose()
+}
+
+fn parse_applied_rate(value: Option<String>) -> Result<Option<AppliedRate>, TransactionError> {
+    value
+        .map(|value| serde_json::from_str(&value))
+        .transpose()
+        .map_err(TransactionError::from)
+}
+
+/// Restores the open authorizations of `account`, its balances in other currencies than the
+/// base one and its withdrawals counting for the withdrawal limits.
+fn restore_details(conn: &Connection, account: &mut Account) -> Result<(), TransactionError> {
+    let mut stmt = conn.prepare(
+        "SELECT tx, amount, timestamp, currency, applied_rate FROM authorizations
+         WHERE client = ?1",
+    )?;
+    let rows = stmt
+        .query_map(params![account.client_id()], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?
+        .collect::<Result<
+            Vec<(
+                TxId,
+                String,
+                Option<Timestamp>,
+                Option<String>,
+                Option<String>,
+            )>,
+            _,
+        >>()?;
+    for (tx_id, amount, timestamp, currency, applied_rate) in rows {
+        let authorization = Authorization::new(parse_decimal(amount)?, timestamp)
+            .with_currency(parse_currency(currency)?)
+            .with_applied_rate(parse_applied_rate(applied_rate)?);
+        account.restore_authorization(tx_id, authorization);
+    }
+    let mut stmt =
+        conn.prepare("SELECT currency, available, held, fees FROM balances WHERE client = ?1")?;
+    let rows = stmt
+        .query_map(params![account.client_id()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<Vec<(String, String, String, String)>, _>>()?;
+    for (currency, available, held, fees) in rows {
+        let balance = Balance {
+            available: parse_decimal(available)?,
+            held: parse_decimal(held)?,
+            fees: parse_decimal(fees)?,
+        };
+        if let Some(currency) = parse_currency(Some(currency))? {
+            account.restore_balance(currency, balance);
+        }
+    }
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, currency, amount, count FROM recent_withdrawals
+         WHERE client = ?1 ORDER BY rowid",
+    )?;
+    let rows = stmt
+        .query_map(params![account.client_id()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<Vec<(Timestamp, Option<String>, String, u32)>, _>>()?;
+    for (timestamp, currency, amount, count) in rows {
+        account.restore_recent(RecentTransaction {
+            timestamp,
+            currency: parse_currency(currency)?,
+            amount: parse_decimal(amount)?,
+            count,
+        });
+    }
+    Ok(())
+}
+
+/// Columns of a row of the tracked deposits: amount, disputed, charged back, history, timestamp,
+/// currency and applied rate.
+type TrackRow = (
+    String,
+    bool,
+    bool,
+    Option<String>,
+    Option<Timestamp>,
+    Option<String>,
+    Option<String>,
+);
+
+/// Returns whether the transaction `tx_id` of `client_id` is no longer tracked but still
+/// rejected as a duplicate.
+fn is_retired(
+    conn: &Connection,
+    client_id: ClientId,
+    tx_id: TxId,
+) -> Result<bool, TransactionError> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM retired_ids WHERE client = ?1 AND tx = ?2",
+            params![client_id, tx_id],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+fn load_track(
+    conn: &Connection,
+    client_id: ClientId,
+    tx_id: TxId,
+) -> Result<Option<TxTrack>, TransactionError> {
+    let row = conn
+        .query_row(
+            "SELECT amount, disputed, charged_back, history, timestamp, currency, applied_rate
+             FROM tracked_deposits WHERE client = ?1 AND tx = ?2",
+            params![client_id, tx_id],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                ))
+            },
+        )
+        .optional()?;
+    row.map(
+        |(amount, disputed, charged_back, history, timestamp, currency, applied_rate): TrackRow| {
+            let mut track = TxTrack::restore(parse_decimal(amount)?, disputed)
+                .with_timestamp(timestamp)
+                .with_currency(parse_currency(currency)?)
+                .with_applied_rate(parse_applied_rate(applied_rate)?);
+            if charged_back {
+                track = track.into_charged_back();
+            }
+            if let Some(history) = history {
+                track = track.with_history(serde_json::from_str(&history)?);
+            }
+            Ok(track)
+        },
+    )
+    .transpose()
+}
+
+fn store_account(conn: &Connection, account: &Account) -> Result<(), TransactionError> {
+    conn.execute(
+        "INSERT INTO accounts (client, available, held, locked, frozen, closed, fees)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (client) DO UPDATE SET
+            available = excluded.available, held = excluded.held, locked = excluded.locked,
+            frozen = excluded.frozen, closed = excluded.closed, fees = excluded.fees",
+        params![
+            account.client_id(),
+            account.available().to_string(),
+            account.held_by_disputes().to_string(),
+            account.locked(),
+            account.frozen(),
+            account.closed(),
+            account.fees().to_string()
+        ],
+    )?;
+    conn.execute(
+        "DELETE FROM authorizations WHERE client = ?1",
+        params![account.client_id()],
+    )?;
+    for (tx_id, authorization) in account.authorizations() {
+        conn.execute(
+            "INSERT INTO authorizations (client, tx, amount, timestamp, currency, applied_rate)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                account.client_id(),
+                tx_id,
+                authorization.amount().to_string(),
+                authorization.timestamp(),
+                authorization
+                    .currency()
+                    .map(|currency| currency.to_string()),
+                authorization
+                    .applied_rate()
+                    .map(|rate| serde_json::to_string(&rate))
+                    .transpose()?
+            ],
+        )?;
+    }
+    // Only the retired ids loaded or added by the transaction are in the account.
+    for tx_id in account.retired_ids() {
+        conn.execute(
+            "INSERT OR IGNORE INTO retired_ids (client, tx) VALUES (?1, ?2)",
+            params![account.client_id(), tx_id],
+        )?;
+    }
+    // The rates of the withdrawals and transfers are never loaded, so these are the new ones.
+    for (tx_id, rate) in account.applied_rates() {
+        conn.execute(
+            "INSERT OR IGNORE INTO applied_rates (client, tx, applied_rate) VALUES (?1, ?2, ?3)",
+            params![account.client_id(), tx_id, serde_json::to_string(rate)?],
+        )?;
+    }
+    for (currency, balance) in account.balances() {
+        conn.execute(
+            "INSERT INTO balances (client, currency, available, held, fees)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (client, currency) DO UPDATE SET
+                available = excluded.available, held = excluded.held, fees = excluded.fees",
+            params![
+                account.client_id(),
+                currency.to_string(),
+                balance.available.to_string(),
+                balance.held.to_string(),
+                balance.fees.to_string()
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Replaces the recent transactions stored for the account with the ones it has now, in the
+/// `recent_withdrawals` table. They only change when the account deposits or withdraws, so they
+/// are not written by `store_account`.
+fn store_recent_transactions(conn: &Connection, account: &Account) -> Result<(), TransactionError> {
+    conn.execute(
+        "DELETE FROM recent_withdrawals WHERE client = ?1",
+        params![account.client_id()],
+    )?;
+    for recent in account.recent_transactions() {
+        conn.execute(
+            "INSERT INTO recent_withdrawals (client, timestamp, currency, amount, count)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                account.client_id(),
+                recent.timestamp,
+                recent.currency.map(|currency| currency.to_string()),
+                recent.amount.to_string(),
+                recent.count
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn store_track(
+    conn: &Connection,
+    client_id: ClientId,
+    tx_id: TxId,
+    track: &TxTrack,
+) -> Result<(), TransactionError> {
+    // Deposits never disputed have no history.
+    let history = track.history();
+    let history = if history.disputes() > 0 {
+        Some(serde_json::to_string(&history)?)
+    } else {
+        None
+    };
+    conn.execute(
+        "INSERT INTO tracked_deposits
+            (client, tx, amount, disputed, charged_back, history, timestamp, currency,
+             applied_rate)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT (client, tx) DO UPDATE SET
+            amount = excluded.amount, disputed = excluded.disputed,
+            charged_back = excluded.charged_back, history = excluded.history,
+            timestamp = excluded.timestamp, currency = excluded.currency,
+            applied_rate = excluded.applied_rate",
+        params![
+            client_id,
+            tx_id,
+            track.amount().to_string(),
+            track.being_disputed(),
+            track.charged_back(),
+            history,
+            track.timestamp(),
+            track.currency().map(|currency| currency.to_string()),
+            track
+                .applied_rate()
+                .map(|rate| serde_json::to_string(&rate))
+                .transpose()?
+        ],
+    )?;
+    Ok(())
+}
+
+impl PaymentEngine for SqlitePaymentEngine {
+    /// Processes the given transaction.
+    ///
+    /// Only the account of the client and the tracked deposit referenced by the transaction are
+    /// loaded from the database. The changes are written back atomically together with the
+    /// progress of the input, so a crash never leaves a transaction half applied.
+    fn process(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let client_id = transaction.client_id();
+        let tx_id = transaction.transaction_id();
+        let db_tx = self.conn.transaction()?;
+        if let Some(screening) = &self.screening {
+            // Screened out before loading any account, so blocked clients leave no trace.
+            if let Err(e) = screening.screen(transaction)? {
+                if screening.action() == ScreeningAction::Quarantine {
+                    info!("Quarantined: {}", e);
+                    let offset: i64 = db_tx.query_row(
+                        "SELECT processed FROM progress WHERE id = 0",
+                        [],
+                        |row| row.get(0),
+                    )?;
+                    db_tx.execute(
+                        "INSERT INTO quarantine (record) VALUES (?1)",
+                        params![serde_json::to_string(&QuarantineRecord::new(
+                            offset as u64,
+                            transaction,
+                            &e
+                        ))?],
+                    )?;
+                } else {
+                    warn!("{}", e);
+                }
+                db_tx.execute(
+                    "UPDATE progress SET processed = processed + 1 WHERE id = 0",
+                    [],
+                )?;
+                db_tx.commit()?;
+                return Ok(());
+            }
+        }
+        let mut account =
+            load_account(&db_tx, client_id)?.unwrap_or_else(|| Account::new(client_id));
+        if let Some(track) = load_track(&db_tx, client_id, tx_id)? {
+            account.track(tx_id, track);
+        }
+        if is_retired(&db_tx, client_id, tx_id)? {
+            account.restore_retired(tx_id);
+        }
+        let recent_transactions = account.recent_transactions().to_vec();
+        // Both sides of a transfer are stored within the same database transaction.
+        let mut destination = match transaction.destination() {
+            Some(destination) => Some(
+                load_account(&db_tx, destination)?.unwrap_or_else(|| Account::new(destination)),
+            ),
+            None => None,
+        };
+        // So is the house account receiving the fees, only loaded when a fee is charged.
+        let mut house = None;
+        let mut entry = JournalEntry::new();
+        let result = match destination.as_mut() {
+            Some(destination) => {
+                account.transfer_with_journal(destination, transaction, &self.policy, &mut entry)
+            }
+            None => match account.process_with_journal(transaction, &self.policy, &mut entry) {
+                // Fees are never charged to the house account, so it is not `account`.
+                Ok(Some(charge)) => {
+                    if let Some(fees) = self.policy.fees() {
+                        let mut account = load_account(&db_tx, fees.house())?
+                            .unwrap_or_else(|| Account::new(fees.house()));
+                        account.collect_fee(&charge);
+                        house = Some(account);
+                    }
+                    Ok(())
+                }
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            },
+        };
+        if transaction.ty().is_administrative() {
+            db_tx.execute(
+                "INSERT INTO audit_trail (record) VALUES (?1)",
+                params![serde_json::to_string(&AuditRecord::new(
+                    transaction,
+                    &result
+                ))?],
+            )?;
+        }
+        match result {
+            Ok(_) => {}
+            Err(e) => {
+                warn!("{}", e);
+            }
+        }
+        // Every account changed is verified against the ledger before storing it, so a
+        // divergence is an error for the whole engine.
+        let accounts = std::iter::once(&account)
+            .chain(destination.iter())
+            .chain(house.iter())
+            .collect::<Vec<_>>();
+        let clients = accounts
+            .iter()
+            .map(|account| account.client_id())
+            .collect::<Vec<_>>();
+        let mut ledger = load_ledger(&db_tx, Some(&clients))?;
+        record_entry(&db_tx, &mut ledger, &entry)?;
+        for account in accounts {
+            ledger.verify(account)?;
+            store_account(&db_tx, account)?;
+        }
+        // Only the account of the client counts the transaction for its limits, and only if it
+        // deposits or withdraws.
+        if account.recent_transactions() != recent_transactions.as_slice() {
+            store_recent_transactions(&db_tx, &account)?;
+        }
+        if let Some(track) = account.tracked(tx_id) {
+            store_track(&db_tx, client_id, tx_id, &track)?;
+        }
+        db_tx.execute(
+            "UPDATE progress SET processed = processed + 1 WHERE id = 0",
+            [],
+        )?;
+        db_tx.commit()?;
+        Ok(())
+    }
+
+    /// Inserts the account of a client with its opening balances.
+    fn open_account(&mut self, account: Account) -> Result<(), TransactionError> {
+        let db_tx = self.conn.transaction()?;
+        if load_account(&db_tx, account.client_id())?.is_some() {
+            return Err(TransactionError::AccountAlreadyExists(account.client_id()));
+        }
+        let mut ledger = load_ledger(&db_tx, Some(&[account.client_id()]))?;
+        record_entry(&db_tx, &mut ledger, &account.opening_entry())?;
+        store_account(&db_tx, &account)?;
+        store_recent_transactions(&db_tx, &account)?;
+        db_tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns a summary of all the accounts stored in the database, once they are verified
+    /// against the ledger and its trial balance proves no funds were created or lost.
+    fn summary(
+        &self,
+    ) -> Result<Box<dyn Iterator<Item = TransactionResultSummary>>, TransactionError> {
+        let mut stmt = self.conn.prepare(
+            "SELECT client, available, held, locked, frozen, closed, fees FROM accounts \
+             ORDER BY client",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                ))
+            })?
+            .collect::<Result<Vec<(ClientId, String, String, bool, bool, bool, String)>, _>>()?;
+        let ledger = load_ledger(&self.conn, None)?;
+        let iter = rows
+            .into_iter()
+            .map(
+                |(client_id, available, held, locked, frozen, closed, fees)| {
+                    let mut account = Account::create_with(
+                        client_id,
+                        parse_decimal(available)?,
+                        parse_decimal(held)?,
+                        locked,
+                    )
+                    .with_status(frozen, closed)
+                    .with_fees(parse_decimal(fees)?);
+                    restore_details(&self.conn, &mut account)?;
+                    ledger.verify(&account)?;
+                    Ok(account.summaries(self.policy.precision().scale()))
+                },
+            )
+            .collect::<Result<Vec<Vec<TransactionResultSummary>>, TransactionError>>()?;
+        ledger.trial_balance()?;
+        Ok(Box::new(iter.into_iter().flatten()))
+    }
+
+    /// Counts an invalid input record in the progress of the input.
+    fn skip_invalid(&mut self) -> Result<(), TransactionError> {
+        self.conn.execute(
+            "UPDATE progress SET processed = processed + 1 WHERE id = 0",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Returns the number of input records applied by this and any previous run.
+    fn resume_offset(&self) -> Result<u64, TransactionError> {
+        let processed: i64 =
+            self.conn
+                .query_row("SELECT processed FROM progress WHERE id = 0", [], |row| {
+                    row.get(0)
+                })?;
+        Ok(processed as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn test_process_keeps_state_between_transactions() {
+        let mut engine = SqlitePaymentEngine::open_in_memory().unwrap();
+        let transactions = [
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(1)
+                .amount(Amount::new(dec!(10.5)).unwrap())
+                .ty(TransactionType::Deposit)
+                .build(),
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(1)
+                .ty(TransactionType::Dispute)
+                .build(),
+            Transaction::builder()
+                .client_id(2)
+                .transaction_id(2)
+                .amount(3)
+                .ty(TransactionType::Withdrawal)
+                .build(),
+        ];
+        for transaction in transactions.iter() {
+            engine.process(transaction).unwrap();
+        }
+
+        let summary = engine.summary().unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                Account::create_with(1, dec!(0), dec!(10.5), false).into(),
+                Account::create_with(2, dec!(0), dec!(0), false).into(),
+            ]
+        );
+        assert_eq!(engine.resume_offset().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_reset_progress_keeps_balances() {
+        let mut engine = SqlitePaymentEngine::open_in_memory().unwrap();
+        let deposit = Transaction::builder()
+            .client_id(1)
+            .transaction_id(1)
+            .amount(1)
+            .ty(TransactionType::Deposit)
+            .build();
+        engine.process(&deposit).unwrap();
+
+        engine.reset_progress().unwrap();
+
+        assert_eq!(engine.resume_offset().unwrap(), 0);
+        assert_eq!(engine.summary().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_ledger_divergence_fails_loudly() {
+        let mut engine = SqlitePaymentEngine::open_in_memory().unwrap();
+        engine
+            .open_account(Account::create_with(1, dec!(5), dec!(1), false))
+            .unwrap();
+        let withdrawal = |tx_id| {
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(tx_id)
+                .amount(2)
+                .ty(TransactionType::Withdrawal)
+                .build()
+        };
+        engine.process(&withdrawal(1)).unwrap();
+        assert_eq!(engine.summary().unwrap().count(), 1);
+
+        engine
+            .conn
+            .execute("UPDATE accounts SET available = '4' WHERE client = 1", [])
+            .unwrap();
+        assert!(matches!(
+            engine.summary(),
+            Err(TransactionError::LedgerImbalance(_))
+        ));
+        assert!(matches!(
+            engine.process(&withdrawal(2)),
+            Err(TransactionError::LedgerImbalance(_))
+        ));
+    }
+
+    #[test]
+    fn test_ledger_is_seeded_from_stored_accounts() {
+        let path =
+            std::env::temp_dir().join(format!("sqlite-seed-ledger-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        {
+            let mut engine = SqlitePaymentEngine::open(path).unwrap();
+            engine
+                .open_account(Account::create_with(1, dec!(5), dec!(1), false))
+                .unwrap();
+            engine
+                .open_account(Account::create_with(2, dec!(3), dec!(0), false))
+                .unwrap();
+            // Databases written before the ledger was kept have no ledger balances.
+            engine.conn.execute("DELETE FROM ledger", []).unwrap();
+        }
+
+        let mut engine = SqlitePaymentEngine::open(path).unwrap();
+        let withdrawal = Transaction::builder()
+            .client_id(1)
+            .transaction_id(1)
+            .amount(2)
+            .ty(TransactionType::Withdrawal)
+            .build();
+        engine.process(&withdrawal).unwrap();
+
+        assert_eq!(engine.summary().unwrap().count(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_columns_are_added() {
+        let conn = Connection::open_in_memory().unwrap();
+        // Tables as created by the first version of the engine.
+        conn.execute_batch(
+            "CREATE TABLE accounts (
+                client INTEGER PRIMARY KEY,
+                available TEXT NOT NULL,
+                held TEXT NOT NULL,
+                locked INTEGER NOT NULL
+            );
+            CREATE TABLE tracked_deposits (
+                client INTEGER NOT NULL,
+                tx INTEGER NOT NULL,
+                amount TEXT NOT NULL,
+                disputed INTEGER NOT NULL,
+                PRIMARY KEY (client, tx)
+            );
+            INSERT INTO accounts VALUES (1, '10', '0', 0);
+            INSERT INTO tracked_deposits VALUES (1, 1, '10', 0);",
+        )
+        .unwrap();
+        let mut engine = SqlitePaymentEngine::init(conn).unwrap();
+
+        engine
+            .process(
+                &Transaction::builder()
+                    .ty(TransactionType::Dispute)
+                    .client_id(1)
+                    .transaction_id(1)
+                    .amount(4)
+                    .build(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            engine.summary().unwrap().collect::<Vec<_>>(),
+            vec![Account::create_with(1, dec!(6), dec!(4), false).into()]
+        );
+        // Opening the database again finds every column.
+        SqlitePaymentEngine::init(engine.conn).unwrap();
+    }
+
+    #[test]
+    fn test_open_account() {
+        let mut engine = SqlitePaymentEngine::open_in_memory().unwrap();
+        engine
+            .open_account(Account::create_with(1, dec!(5), dec!(1), false))
+            .unwrap();
+        let withdrawal = Transaction::builder()
+            .client_id(1)
+            .transaction_id(1)
+            .amount(2)
+            .ty(TransactionType::Withdrawal)
+            .build();
+        engine.process(&withdrawal).unwrap();
+
+        let result = engine.open_account(Account::new(1));
+
+        assert!(matches!(
+            result,
+            Err(TransactionError::AccountAlreadyExists(1))
+        ));
+        assert_eq!(
+            engine.summary().unwrap().collect::<Vec<_>>(),
+            vec![Account::create_with(1, dec!(3), dec!(1), false).into()]
+        );
+    }
+
+    #[test]
+    fn test_settled_authorization_ids_cannot_be_reused() {
+        let mut engine = SqlitePaymentEngine::open_in_memory().unwrap();
+        let transaction = |ty, transaction_id, amount: Option<u32>| {
+            let transaction = Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .ty(ty);
+            match amount {
+                Some(amount) => transaction.amount(amount).build(),
+                None => transaction.build(),
+            }
+        };
+        engine
+            .process(&transaction(TransactionType::Deposit, 1, Some(10)))
+            .unwrap();
+        engine
+            .process(&transaction(TransactionType::Authorize, 2, Some(4)))
+            .unwrap();
+        engine
+            .process(&transaction(TransactionType::Capture, 2, None))
+            .unwrap();
+
+        engine
+            .process(&transaction(TransactionType::Authorize, 2, Some(4)))
+            .unwrap();
+
+        assert_eq!(
+            engine.summary().unwrap().collect::<Vec<_>>(),
+            vec![Account::create_with(1, dec!(6), dec!(0), false).into()]
+        );
+    }
+
+    #[test]
+    fn test_house_account_is_only_stored_when_charged() {
+        let policy = AccountPolicy::builder()
+            .fees(
+                FeeSchedule::builder()
+                    .house(0)
+                    .withdrawal("1".parse::<Fee>().unwrap())
+                    .build(),
+            )
+            .build();
+        let mut engine = SqlitePaymentEngine::open_in_memory()
+            .unwrap()
+            .with_policy(policy);
+        let transaction = |ty, transaction_id, amount| {
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .amount(amount)
+                .ty(ty)
+                .build()
+        };
+
+        engine
+            .process(&transaction(TransactionType::Deposit, 1, 5))
+            .unwrap();
+        engine
+            .process(&transaction(TransactionType::Withdrawal, 2, 10))
+            .unwrap();
+        assert_eq!(engine.summary().unwrap().count(), 1);
+
+        engine
+            .process(&transaction(TransactionType::Withdrawal, 3, 2))
+            .unwrap();
+        assert_eq!(
+            engine.summary().unwrap().collect::<Vec<_>>(),
+            vec![
+                Account::create_with(0, dec!(1), dec!(0), false).into(),
+                Account::create_with(1, dec!(2), dec!(0), false)
+                    .with_fees(dec!(1))
+                    .into(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_applied_rates_of_withdrawals_are_stored() {
+        let conversion = CurrencyConversion::new(
+            "EUR".parse().unwrap(),
+            vec![ExchangeRate {
+                from: "GBP".parse().unwrap(),
+                to: "EUR".parse().unwrap(),
+                rate: dec!(1.2),
+                valid_from: None,
+                valid_until: None,
+            }],
+            PrecisionPolicy::default(),
+        )
+        .unwrap();
+        let mut engine = SqlitePaymentEngine::open_in_memory()
+            .unwrap()
+            .with_policy(AccountPolicy::builder().conversion(conversion).build());
+        let transaction = |ty, transaction_id| {
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .amount(5)
+                .currency("GBP".parse().unwrap())
+                .ty(ty)
+                .build()
+        };
+
+        engine
+            .process(&transaction(TransactionType::Deposit, 1))
+            .unwrap();
+        engine
+            .process(&transaction(TransactionType::Withdrawal, 2))
+            .unwrap();
+
+        let rate: String = engine
+            .conn
+            .query_row(
+                "SELECT applied_rate FROM applied_rates WHERE client = 1 AND tx = 2",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<AppliedRate>(&rate).unwrap().rate,
+            dec!(1.2)
+        );
+    }
+
+    #[test]
+    fn test_recent_transactions_are_only_stored_when_changed() {
+        let policy = AccountPolicy::builder()
+            .withdrawal_limits(
+                WithdrawalLimitSchedule::builder()
+                    .default(WithdrawalLimits::builder().max_transactions(5).build())
+                    .build(),
+            )
+            .build();
+        let mut engine = SqlitePaymentEngine::open_in_memory()
+            .unwrap()
+            .with_policy(policy);
+        engine
+            .conn
+            .execute_batch(
+                "CREATE TABLE rewrites (client INTEGER NOT NULL);
+                 CREATE TRIGGER count_rewrites AFTER DELETE ON recent_withdrawals
+                 BEGIN INSERT INTO rewrites (client) VALUES (old.client); END;",
+            )
+            .unwrap();
+        let transaction = |ty, transaction_id| {
+            Transaction::builder()
+                .client_id(1)
+                .transaction_id(transaction_id)
+                .amount(1)
+                .timestamp(100)
+                .ty(ty)
+                .build()
+        };
+        let rewrites = |engine: &SqlitePaymentEngine| -> i64 {
+            engine
+                .conn
+                .query_row("SELECT COUNT(*) FROM rewrites", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        engine
+            .process(&transaction(TransactionType::Deposit, 1))
+            .unwrap();
+        assert_eq!(rewrites(&engine), 0);
+        engine
+            .process(&transaction(TransactionType::Withdrawal, 2))
+            .unwrap();
+        assert_eq!(rewrites(&engine), 1);
+
+        engine
+            .process(&transaction(TransactionType::Deposit, 3))
+            .unwrap();
+        engine
+            .process(&transaction(TransactionType::Dispute, 3))
+            .unwrap();
+        engine
+            .process(&transaction(TransactionType::Resolve, 3))
+            .unwrap();
+        assert_eq!(rewrites(&engine), 2);
+        // The transactions made at the same time are stored as a single row.
+        let stored: (i64, u32) = engine
+            .conn
+            .query_row(
+                "SELECT COUNT(*), SUM(count) FROM recent_withdrawals WHERE client = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(stored, (1, 3));
+    }
+}
@@ -0,0 +1,306 @@
+//! Append-only write-ahead log of accepted transactions.
+//!
+//! Every transaction accepted by an engine is appended to the log as a JSON line together with
+//! the offset of the record in the input. On startup the log is replayed to rebuild the state of
+//! the engine, and the offset of the last entry tells the pipeline where to resume the input.
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::str::FromStr;
+
+use crate::domain::Account;
+use crate::domain::Transaction;
+use crate::domain::TransactionError;
+
+/// Policy used to decide when the log is synchronized to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Synchronize after every appended entry. Nothing accepted is lost on a power failure.
+    Always,
+    /// Synchronize once every given number of appended entries.
+    Every(usize),
+    /// Never synchronize explicitly and let the operating system flush its buffers.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = TransactionError;
+
+    /// Parses `always`, `never` or a number of entries between synchronizations.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            n => n
+                .parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .map(FsyncPolicy::Every)
+                .ok_or_else(|| {
+                    TransactionError::StorageError(format!("Invalid fsync policy {}", s))
+                }),
+        }
+    }
+}
+
+/// An entry of the log, tagged with its kind so that an entry which cannot be rebuilt is an
+/// error instead of being mistaken for another kind.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum WalEntry {
+    /// An accepted transaction and its position in the input.
+    Transaction {
+        offset: u64,
+        transaction: Transaction,
+    },
+    /// An account seeded with opening balances.
+    Opening { account: Box<Account> },
+    /// The position in the input of a transaction set aside in the quarantine, so it is not
+    /// quarantined again when resuming.
+    Quarantined { offset: u64 },
+}
+
+/// Append-only log of accepted transactions.
+pub struct WriteAheadLog {
+    file: File,
+    policy: FsyncPolicy,
+    unsynced: usize,
+}
+
+impl fmt::Debug for WriteAheadLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteAheadLog")
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl WriteAheadLog {
+    /// Opens the log at `path`, creating it if it does not exist, and returns it together with
+    /// the entries already stored.
+    ///
+    /// A partially written entry at the end of the log, left by a crash in the middle of an
+    /// append, is discarded and truncated. Any other malformed entry, including a complete last
+    /// one, is reported as an error.
+    pub(crate) fn open(
+        path: &str,
+        policy: FsyncPolicy,
+    ) -> Result<(Self, Vec<WalEntry>), TransactionError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut entries = Vec::new();
+        let mut valid_len = 0;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            let complete = line.ends_with('\n');
+            match serde_json::from_str::<WalEntry>(line.trim_end()) {
+                Ok(entry) if complete => {
+                    entries.push(entry);
+                    valid_len += read as u64;
+                }
+                result => {
+                    let mut rest = String::new();
+                    if complete || reader.read_line(&mut rest)? > 0 {
+                        return Err(result.err().map_or_else(
+                            || TransactionError::StorageError("Corrupted write-ahead log".into()),
+                            |e| e.into(),
+                        ));
+                    }
+                    warn!(
+                        "Discarding incomplete entry at the end of the write-ahead log {}",
+                        path
+                    );
+                    file.set_len(valid_len)?;
+                    break;
+                }
+            }
+        }
+        Ok((
+            Self {
+                file,
+                policy,
+                unsynced: 0,
+            },
+            entries,
+        ))
+    }
+
+    /// Appends an accepted transaction found at `offset` in the input.
+    pub(crate) fn append(
+        &mut self,
+        offset: u64,
+        transaction: &Transaction,
+    ) -> Result<(), TransactionError> {
+        self.write(&WalEntry::Transaction {
+            offset,
+            transaction: transaction.clone(),
+        })
+    }
+
+    /// Appends the position of a transaction found at `offset` in the input and set aside in the
+    /// quarantine.
+    pub(crate) fn append_quarantined(&mut self, offset: u64) -> Result<(), TransactionError> {
+        self.write(&WalEntry::Quarantined { offset })
+    }
+
+    /// Appends an account seeded with opening balances.
+    pub(crate) fn append_opening(&mut self, account: &Account) -> Result<(), TransactionError> {
+        self.write(&WalEntry::Opening {
+            account: Box::new(account.clone()),
+        })
+    }
+
+    fn write(&mut self, entry: &WalEntry) -> Result<(), TransactionError> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.unsynced += 1;
+        match self.policy {
+            FsyncPolicy::Always => self.sync(),
+            FsyncPolicy::Every(n) if self.unsynced >= n => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Forces the synchronization of all the appended entries to disk.
+    pub fn sync(&mut self) -> Result<(), TransactionError> {
+        self.file.sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+}
+
+impl Drop for WriteAheadLog {
+    fn drop(&mut self) {
+        if self.unsynced > 0 && self.policy != FsyncPolicy::Never {
+            if let Err(e) = self.sync() {
+                warn!("Error synchronizing write-ahead log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::TransactionType;
+
+    fn temp_log(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.wal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_string()
+    }
+
+    fn deposit(transaction_id: u32) -> Transaction {
+        Transaction::builder()
+            .ty(TransactionType::Deposit)
+            .client_id(1)
+            .transaction_id(transaction_id)
+            .amount(1)
+            .build()
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let path = temp_log("wal-append");
+        let (mut wal, entries) = WriteAheadLog::open(&path, FsyncPolicy::Always).unwrap();
+        assert!(entries.is_empty());
+        wal.append(0, &deposit(1)).unwrap();
+        wal.append_opening(&Account::new(2)).unwrap();
+        wal.append(2, &deposit(3)).unwrap();
+        drop(wal);
+
+        let (_, entries) = WriteAheadLog::open(&path, FsyncPolicy::Always).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            entries,
+            vec![
+                WalEntry::Transaction {
+                    offset: 0,
+                    transaction: deposit(1)
+                },
+                WalEntry::Opening {
+                    account: Box::new(Account::new(2))
+                },
+                WalEntry::Transaction {
+                    offset: 2,
+                    transaction: deposit(3)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incomplete_last_entry_is_truncated() {
+        let path = temp_log("wal-torn");
+        let (mut wal, _) = WriteAheadLog::open(&path, FsyncPolicy::Every(10)).unwrap();
+        wal.append(0, &deposit(1)).unwrap();
+        drop(wal);
+        let valid_len = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"kind\":\"transaction\",\"offset\":1,\"transac")
+            .unwrap();
+
+        let (_, entries) = WriteAheadLog::open(&path, FsyncPolicy::Never).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(len, valid_len);
+    }
+
+    #[test]
+    fn test_corrupted_entry_in_the_middle_is_an_error() {
+        let path = temp_log("wal-corrupted");
+        std::fs::write(&path, "garbage\n{}\n").unwrap();
+
+        let result = WriteAheadLog::open(&path, FsyncPolicy::Never);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(TransactionError::StorageError(_))));
+    }
+
+    #[test]
+    fn test_transaction_that_cannot_be_rebuilt_is_an_error() {
+        let path = temp_log("wal-invalid");
+        std::fs::write(
+            &path,
+            "{\"kind\":\"transaction\",\"offset\":0,\"transaction\":\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"-1\"}}\n",
+        )
+        .unwrap();
+
+        let result = WriteAheadLog::open(&path, FsyncPolicy::Never);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(TransactionError::StorageError(_))));
+    }
+
+    #[test]
+    fn test_fsync_policy_from_str() {
+        assert_eq!(
+            "always".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Always
+        );
+        assert_eq!("never".parse::<FsyncPolicy>().unwrap(), FsyncPolicy::Never);
+        assert_eq!(
+            "100".parse::<FsyncPolicy>().unwrap(),
+            FsyncPolicy::Every(100)
+        );
+        assert!("0".parse::<FsyncPolicy>().is_err());
+    }
+}
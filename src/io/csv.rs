@@ -5,11 +5,17 @@ use std::fs::File;
 use std::io::{BufReader, BufWriter, Stdout};
 
 use crate::domain::TransactionError;
-use crate::{Transaction, TransactionResultSummary};
+use crate::{
+    Account, ClientId, ClientListRecord, Currency, DetailedSummary, ExchangeRate, PrecisionPolicy,
+    Transaction, TransactionRecord, TransactionResultSummary, WithdrawalLimits,
+    WithdrawalLimitsRecord,
+};
 
 /// `CSVTransactionReader` is a wrapper around `csv::Reader`.
 pub struct CSVTransactionReader {
     reader: csv::Reader<BufReader<File>>,
+    precision: PrecisionPolicy,
+    base_currency: Option<Currency>,
 }
 
 /// Implement `Debug` for `CSVTransactionReader` hiding details
@@ -21,7 +27,9 @@ impl fmt::Debug for CSVTransactionReader {
 
 /// `CSVReaderIter` is a wrapper around `csv::DeserializeRecordsIter`.
 pub struct CSVReaderIter<'a> {
-    iter: csv::DeserializeRecordsIter<'a, BufReader<File>, Transaction>,
+    iter: csv::DeserializeRecordsIter<'a, BufReader<File>, TransactionRecord>,
+    precision: PrecisionPolicy,
+    base_currency: Option<Currency>,
 }
 
 /// Implement Debug for `CSVReaderIter` hiding details
@@ -37,18 +45,45 @@ impl Iterator for CSVReaderIter<'_> {
 
     /// Advances the iterator and returns the next value.
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|r| r.map_err(|e| e.into()))
+        self.iter.next().map(|r| {
+            r.map_err(TransactionError::from)
+                .and_then(Transaction::try_from)
+                .and_then(|transaction| transaction.with_precision(&self.precision))
+                .and_then(|transaction| transaction.with_base_currency(self.base_currency))
+        })
     }
 }
 
 /// `CSVTransactionReader` has a function to return an iter due to lifetimes.
 impl CSVTransactionReader {
-    /// Returns an iterator over the transactions in the CSV file.
+    /// Returns an iterator over the transactions in the CSV file, with their amounts adjusted to
+    /// the precision policy of the reader. Records with a negative amount, without the amount
+    /// required by their type or with an amount their type does not expect are returned as
+    /// errors, and so are records with a currency when the reader has no base currency. Those
+    /// errors are invalid records, see `TransactionError::is_invalid_record`, and the following
+    /// records can still be read.
     pub fn iter(&mut self) -> CSVReaderIter<'_> {
         CSVReaderIter {
             iter: self.reader.deserialize(),
+            precision: self.precision,
+            base_currency: self.base_currency,
         }
     }
+
+    /// Applies `precision` to the amounts of the transactions read. By default amounts with more
+    /// than four decimal places are rounded with banker's rounding.
+    pub fn with_precision(mut self, precision: PrecisionPolicy) -> Self {
+        self.precision = precision;
+        self
+    }
+
+    /// Reads the transactions without a currency as made in `base`, which also accepts
+    /// transactions in other currencies. By default there is no base currency and transactions
+    /// with a currency are rejected.
+    pub fn with_base_currency(mut self, base: Option<Currency>) -> Self {
+        self.base_currency = base;
+        self
+    }
 }
 
 impl<'a> CSVTransactionReader {
@@ -61,13 +96,164 @@ impl<'a> CSVTransactionReader {
             .trim(csv::Trim::All)
             .flexible(true)
             .from_reader(reader);
-        CSVTransactionReader { reader: rdr }
+        CSVTransactionReader {
+            reader: rdr,
+            precision: PrecisionPolicy::default(),
+            base_currency: None,
+        }
+    }
+}
+
+/// `CSVAccountReader` reads accounts with opening balances from a CSV file with the same format
+/// as the summary written by `CSVTransactionResultStdoutWriter`.
+pub struct CSVAccountReader {
+    reader: csv::Reader<BufReader<File>>,
+}
+
+/// Implement `Debug` for `CSVAccountReader` hiding details
+impl fmt::Debug for CSVAccountReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CSVAccountReader")
+    }
+}
+
+impl CSVAccountReader {
+    /// Creates a new `CSVAccountReader` with the given filename.
+    pub fn new(filename: &str) -> Result<Self, TransactionError> {
+        let file = File::open(filename)
+            .map_err(|e| TransactionError::InvalidOpeningBalance(format!("{}: {}", filename, e)))?;
+        let rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(BufReader::new(file));
+        Ok(CSVAccountReader { reader: rdr })
+    }
+
+    /// Returns an iterator over the accounts in the CSV file, validating each opening balance.
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<Account, TransactionError>> + '_ {
+        self.reader
+            .deserialize::<TransactionResultSummary>()
+            .map(|r| {
+                r.map_err(TransactionError::from)
+                    .and_then(Account::try_from)
+            })
+    }
+}
+
+/// `CSVExchangeRateReader` reads the exchange rates used to convert transactions from a CSV file
+/// with the columns `from, to, rate, valid_from, valid_until`. The bounds of the range of time
+/// of a rate are timestamps in seconds since the Unix epoch, and can be left empty.
+pub struct CSVExchangeRateReader {
+    reader: csv::Reader<BufReader<File>>,
+}
+
+/// Implement `Debug` for `CSVExchangeRateReader` hiding details
+impl fmt::Debug for CSVExchangeRateReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CSVExchangeRateReader")
+    }
+}
+
+impl CSVExchangeRateReader {
+    /// Creates a new `CSVExchangeRateReader` with the given filename.
+    pub fn new(filename: &str) -> Result<Self, TransactionError> {
+        let file = File::open(filename)
+            .map_err(|e| TransactionError::InvalidExchangeRate(format!("{}: {}", filename, e)))?;
+        let rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(BufReader::new(file));
+        Ok(CSVExchangeRateReader { reader: rdr })
+    }
+
+    /// Returns an iterator over the exchange rates in the CSV file.
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<ExchangeRate, TransactionError>> + '_ {
+        self.reader
+            .deserialize::<ExchangeRate>()
+            .map(|r| r.map_err(TransactionError::from))
+    }
+}
+
+/// `CSVWithdrawalLimitsReader` reads the withdrawal limits of some clients from a CSV file with
+/// the columns `client, max_withdrawal, daily_withdrawals, rolling_withdrawals, max_transactions,
+/// window_hours`. The empty columns are taken from the limits of every client.
+pub struct CSVWithdrawalLimitsReader {
+    reader: csv::Reader<BufReader<File>>,
+}
+
+/// Implement `Debug` for `CSVWithdrawalLimitsReader` hiding details
+impl fmt::Debug for CSVWithdrawalLimitsReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CSVWithdrawalLimitsReader")
+    }
+}
+
+impl CSVWithdrawalLimitsReader {
+    /// Creates a new `CSVWithdrawalLimitsReader` with the given filename.
+    pub fn new(filename: &str) -> Result<Self, TransactionError> {
+        let file = File::open(filename).map_err(|e| {
+            TransactionError::InvalidWithdrawalLimits(format!("{}: {}", filename, e))
+        })?;
+        let rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(BufReader::new(file));
+        Ok(CSVWithdrawalLimitsReader { reader: rdr })
+    }
+
+    /// Returns an iterator over the limits of the clients in the CSV file, validating each of
+    /// them.
+    pub fn iter(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(ClientId, WithdrawalLimits), TransactionError>> + '_ {
+        self.reader
+            .deserialize::<WithdrawalLimitsRecord>()
+            .map(|r| {
+                r.map_err(TransactionError::from)
+                    .and_then(TryFrom::try_from)
+            })
+    }
+}
+
+/// `CSVClientListReader` reads a blocklist or an allowlist of clients from a CSV file with a
+/// `client` column. Other columns, such as the reason a client is listed, are ignored.
+pub struct CSVClientListReader {
+    reader: csv::Reader<BufReader<File>>,
+}
+
+/// Implement `Debug` for `CSVClientListReader` hiding details
+impl fmt::Debug for CSVClientListReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CSVClientListReader")
+    }
+}
+
+impl CSVClientListReader {
+    /// Creates a new `CSVClientListReader` with the given filename.
+    pub fn new(filename: &str) -> Result<Self, TransactionError> {
+        let file = File::open(filename)
+            .map_err(|e| TransactionError::InvalidClientList(format!("{}: {}", filename, e)))?;
+        let rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(BufReader::new(file));
+        Ok(CSVClientListReader { reader: rdr })
+    }
+
+    /// Returns an iterator over the clients in the CSV file.
+    pub fn iter(&mut self) -> impl Iterator<Item = Result<ClientId, TransactionError>> + '_ {
+        self.reader.deserialize::<ClientListRecord>().map(|r| {
+            r.map(|record| record.client)
+                .map_err(TransactionError::from)
+        })
     }
 }
 
 /// `CSVTransactionResultStdoutWriter` is a wrapper around `csv::Writer` using stdout.
 pub struct CSVTransactionResultStdoutWriter {
     writer: csv::Writer<BufWriter<Stdout>>,
+    detailed: bool,
+    base_currency: Option<Currency>,
 }
 
 impl fmt::Debug for CSVTransactionResultStdoutWriter {
@@ -82,15 +268,41 @@ impl CSVTransactionResultStdoutWriter {
     pub fn new() -> Self {
         Self {
             writer: csv::Writer::from_writer(BufWriter::new(std::io::stdout())),
+            detailed: false,
+            base_currency: None,
         }
     }
 
+    /// Writes a detailed summary, with additional `frozen` and `closed` columns for the
+    /// administrative status of the account and a `negative` column telling whether the
+    /// available funds of the account are below zero.
+    pub fn with_detailed_summary(mut self, detailed: bool) -> Self {
+        self.detailed = detailed;
+        self
+    }
+
+    /// Writes an additional `currency` column, with the balances in the base currency labelled
+    /// as `base`. Without a base currency every balance is in the base one and the column is
+    /// left out.
+    pub fn with_base_currency(mut self, base: Option<Currency>) -> Self {
+        self.base_currency = base;
+        self
+    }
+
     /// Writes the transaction result to the CSV writer.
     pub fn write<T>(&mut self, result: T) -> Result<(), TransactionError>
     where
         T: Into<TransactionResultSummary>,
     {
-        self.writer.serialize(result.into())?;
+        let mut result = result.into();
+        if let Some(base) = self.base_currency {
+            result = result.with_base_currency(base);
+        }
+        if self.detailed {
+            self.writer.serialize(DetailedSummary::from(result))?;
+        } else {
+            self.writer.serialize(result)?;
+        }
         Ok(())
     }
 }
@@ -105,7 +317,7 @@ impl Default for CSVTransactionResultStdoutWriter {
 mod tests {
     use rust_decimal_macros::dec;
 
-    use crate::TransactionType;
+    use crate::{Amount, TransactionType};
 
     use super::*;
 
@@ -125,7 +337,7 @@ mod tests {
                 .ty(TransactionType::Withdrawal)
                 .client_id(1_u16)
                 .transaction_id(4_u32)
-                .amount(dec!(1.5))
+                .amount(Amount::new(dec!(1.5)).unwrap())
                 .build(),
             Transaction::builder()
                 .ty(TransactionType::Dispute)
@@ -157,4 +369,90 @@ mod tests {
             .to_string()
             .contains("Error parsing CSV file"));
     }
+
+    #[test]
+    fn test_csv_reader_with_precision() {
+        let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_excess_precision.csv");
+        let amounts = csv_reader
+            .iter()
+            .map(|transaction| transaction.unwrap().amount().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(amounts, vec![dec!(1.0), dec!(2.0000)]);
+
+        let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_excess_precision.csv")
+            .with_precision(
+                PrecisionPolicy::builder()
+                    .excess(crate::ExcessPrecision::Reject)
+                    .build(),
+            );
+        let result = csv_reader.iter().collect::<Result<Vec<Transaction>, _>>();
+        assert!(matches!(
+            result,
+            Err(TransactionError::InvalidTransactionAmount(_))
+        ));
+    }
+
+    #[test]
+    fn test_csv_reader_negative_amount() {
+        let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_negative_amount.csv");
+        let result = csv_reader.iter().collect::<Vec<_>>();
+        assert!(result[0].is_ok());
+        assert!(matches!(
+            result[1],
+            Err(TransactionError::InvalidTransactionAmount(_))
+        ));
+    }
+
+    #[test]
+    fn test_csv_account_reader() {
+        let mut csv_reader = CSVAccountReader::new("tests/data/opening_balances.csv").unwrap();
+        let result = csv_reader.iter().collect::<Result<Vec<Account>, _>>();
+        let expected = vec![
+            Account::create_with(1, dec!(10), dec!(0), false),
+            Account::create_with(2, dec!(1.5), dec!(0.5), true),
+        ];
+        assert_eq!(result.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_csv_client_list_reader() {
+        let mut csv_reader = CSVClientListReader::new("tests/data/blocklist.csv").unwrap();
+        let result = csv_reader.iter().collect::<Result<Vec<_>, _>>();
+        assert_eq!(result.unwrap(), vec![3, 5]);
+        assert!(matches!(
+            CSVClientListReader::new("tests/data/missing.csv"),
+            Err(TransactionError::InvalidClientList(_))
+        ));
+    }
+
+    #[test]
+    fn test_csv_withdrawal_limits_reader() {
+        let mut csv_reader =
+            CSVWithdrawalLimitsReader::new("tests/data/withdrawal_limits.csv").unwrap();
+        let result = csv_reader.iter().collect::<Result<Vec<_>, _>>();
+        let expected = vec![
+            (1, WithdrawalLimits::builder().max_amount(dec!(55)).build()),
+            (
+                2,
+                WithdrawalLimits::builder()
+                    .daily_amount(dec!(100))
+                    .rolling_amount(dec!(80))
+                    .max_transactions(2)
+                    .window(60 * 60)
+                    .build(),
+            ),
+        ];
+        assert_eq!(result.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_csv_account_reader_inconsistent_total() {
+        let mut csv_reader =
+            CSVAccountReader::new("tests/data/opening_balances_inconsistent.csv").unwrap();
+        let result = csv_reader.iter().collect::<Result<Vec<Account>, _>>();
+        assert!(matches!(
+            result,
+            Err(TransactionError::InvalidOpeningBalance(_))
+        ));
+    }
 }
@@ -3,8 +3,12 @@ use mockall::{automock, predicate::*};
 
 mod csv;
 
+pub use csv::CSVAccountReader;
+pub use csv::CSVClientListReader;
+pub use csv::CSVExchangeRateReader;
 pub use csv::CSVTransactionReader;
 pub use csv::CSVTransactionResultStdoutWriter;
+pub use csv::CSVWithdrawalLimitsReader;
 
 use crate::Transaction;
 use crate::TransactionError;
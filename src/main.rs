@@ -1,21 +1,495 @@
 use env_logger::Env;
-use payment_settle_accounts::TransactionPipelineBuilder;
+use log::info;
+use payment_settle_accounts::{
+    load_client_lists, load_currency_conversion, load_withdrawal_limits, seed_opening_balances,
+    AccountPolicy, CSVOptions, ClientListWatcher, ClientScreening, Currency, FeeSchedule,
+    FsyncPolicy, MemoryThreadSafePaymentEngine, PrecisionPolicy, RetentionPolicy, ScreeningAction,
+    SqlitePaymentEngine, TransactionPipelineBuilder, WithdrawalLimitSchedule, WithdrawalLimits,
+    DEFAULT_SCALE,
+};
+use rust_decimal::Decimal;
 use std::env;
+use std::time::Duration;
+
+const SECONDS_PER_HOUR: u64 = 60 * 60;
+const SECONDS_PER_DAY: u64 = 24 * SECONDS_PER_HOUR;
+
+const USAGE: &str = "<csv-complete-filename> [--db <sqlite-file> [--resume]] \
+                     [--wal <log-file> [--fsync always|never|<entries>]] \
+                     [--load-snapshot <file>] [--save-snapshot <file> [--snapshot-every <transactions>]] \
+                     [--opening-balances <csv-file>] \
+                     [--history-spill <file> [--history-budget <deposits>]] \
+                     [--prune-locked] [--prune-charged-back] [--prune-after-tx-ids <tx-ids>] \
+                     [--prune-every <transactions>] \
+                     [--max-amount <amount>] [--max-balance <amount>] \
+                     [--scale <decimals>] \
+                     [--precision reject|truncate|round|round-half-up|round-half-down] \
+                     [--audit-log <file>] \
+                     [--no-lock-on-chargeback] [--allow-when-locked <type>,...] \
+                     [--negative-balance reject|unlimited|<limit>] [--detailed-summary] \
+                     [--max-disputes <count>] [--no-dispute-after-chargeback] \
+                     [--dispute-days <days>] [--authorization-days <days>] \
+                     [--base-currency <code> [--fx-rates <csv-file>] \
+                     [--fx-rounding truncate|round|round-half-up|round-half-down|reject]] \
+                     [--house-account <client> [--deposit-fee <fee>] [--withdrawal-fee <fee>] \
+                     [--chargeback-fee <fee>]] [--verify] \
+                     [--max-withdrawal <amount>] [--daily-withdrawals <amount>] \
+                     [--rolling-withdrawals <amount>] [--max-transactions <count>] \
+                     [--withdrawal-window-hours <hours>] [--client-limits <csv-file>] \
+                     [--blocklist <csv-file>] [--allowlist <csv-file>] \
+                     [--blocked-clients reject|quarantine] [--quarantine-log <file>] \
+                     [--reload-lists-every <seconds>]
+
+--verify checks the invariants of the accounts, and is only available without --db.
+--history-budget counts the tracked deposits kept in memory, not bytes.
+--prune-after-tx-ids drops the history of deposits from memory by transaction id distance; to reject
+late disputes by time, use --dispute-days.
+--dispute-days rejects disputes by the days since their deposit; to drop old deposits from memory,
+use --prune-after-tx-ids.
+Accounts with a negative balance are only reported with --detailed-summary.";
+
+/// Default number of tracked deposits kept in memory when the history is spilled to disk.
+const DEFAULT_HISTORY_BUDGET: usize = 10_000_000;
+
+/// Default number of processed transactions between two prunes of the history.
+const DEFAULT_PRUNE_EVERY: u64 = 1_000_000;
+
+/// Default number of seconds between two checks of the client lists for changes.
+const DEFAULT_RELOAD_LISTS_EVERY: u64 = 10;
+
+/// Command line options of the program.
+struct Options {
+    filename: String,
+    database: Option<String>,
+    resume: bool,
+    wal: Option<String>,
+    fsync: FsyncPolicy,
+    load_snapshot: Option<String>,
+    save_snapshot: Option<String>,
+    snapshot_every: Option<u64>,
+    opening_balances: Option<String>,
+    history_spill: Option<String>,
+    history_budget: usize,
+    retention: RetentionPolicy,
+    prune_every: u64,
+    policy: AccountPolicy,
+    precision: PrecisionPolicy,
+    audit_log: Option<String>,
+    detailed_summary: bool,
+    base_currency: Option<Currency>,
+    verify: bool,
+    screening: Option<ClientScreening>,
+    blocklist: Option<String>,
+    allowlist: Option<String>,
+    quarantine_log: Option<String>,
+    reload_lists_every: Duration,
+}
+
+fn parse_options() -> anyhow::Result<Options> {
+    let mut args = env::args();
+    let program = args.next().unwrap_or_default();
+    let usage = || anyhow::format_err!("Usage: {} {}", program, USAGE);
+    let mut filename = None;
+    let mut database = None;
+    let mut resume = false;
+    let mut wal = None;
+    let mut fsync = None;
+    let mut load_snapshot = None;
+    let mut save_snapshot = None;
+    let mut snapshot_every = None;
+    let mut opening_balances = None;
+    let mut history_spill = None;
+    let mut history_budget = None;
+    let mut prune_locked = false;
+    let mut prune_charged_back = false;
+    let mut prune_after_tx_ids = None;
+    let mut prune_every = None;
+    let mut max_amount = None;
+    let mut max_balance = None;
+    let mut scale = None;
+    let mut excess_precision = None;
+    let mut audit_log = None;
+    let mut lock_on_chargeback = true;
+    let mut allowed_when_locked = Vec::new();
+    let mut negative_balance = None;
+    let mut detailed_summary = false;
+    let mut max_disputes = None;
+    let mut dispute_after_chargeback = true;
+    let mut dispute_days: Option<u64> = None;
+    let mut authorization_days: Option<u64> = None;
+    let mut base_currency = None;
+    let mut fx_rates = None;
+    let mut fx_rounding = None;
+    let mut house_account = None;
+    let mut deposit_fee = None;
+    let mut withdrawal_fee = None;
+    let mut chargeback_fee = None;
+    let mut verify = false;
+    let mut max_withdrawal = None;
+    let mut daily_withdrawals = None;
+    let mut rolling_withdrawals = None;
+    let mut max_transactions = None;
+    let mut withdrawal_window_hours: Option<u64> = None;
+    let mut client_limits = None;
+    let mut blocklist = None;
+    let mut allowlist = None;
+    let mut blocked_clients = None;
+    let mut quarantine_log = None;
+    let mut reload_lists_every = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--db" => database = Some(args.next().ok_or_else(usage)?),
+            "--resume" => resume = true,
+            "--wal" => wal = Some(args.next().ok_or_else(usage)?),
+            "--fsync" => fsync = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--load-snapshot" => load_snapshot = Some(args.next().ok_or_else(usage)?),
+            "--save-snapshot" => save_snapshot = Some(args.next().ok_or_else(usage)?),
+            "--snapshot-every" => snapshot_every = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--opening-balances" => opening_balances = Some(args.next().ok_or_else(usage)?),
+            "--history-spill" => history_spill = Some(args.next().ok_or_else(usage)?),
+            "--history-budget" => {
+                history_budget = Some(args.next().ok_or_else(usage)?.parse()?);
+            }
+            "--prune-locked" => prune_locked = true,
+            "--prune-charged-back" => prune_charged_back = true,
+            "--prune-after-tx-ids" => {
+                prune_after_tx_ids = Some(args.next().ok_or_else(usage)?.parse()?)
+            }
+            "--prune-every" => prune_every = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--max-amount" => max_amount = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--max-balance" => max_balance = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--scale" => scale = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--precision" => excess_precision = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--audit-log" => audit_log = Some(args.next().ok_or_else(usage)?),
+            "--no-lock-on-chargeback" => lock_on_chargeback = false,
+            "--negative-balance" => {
+                negative_balance = Some(args.next().ok_or_else(usage)?.parse()?)
+            }
+            "--detailed-summary" => detailed_summary = true,
+            "--max-disputes" => max_disputes = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--no-dispute-after-chargeback" => dispute_after_chargeback = false,
+            "--dispute-days" => dispute_days = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--authorization-days" => {
+                authorization_days = Some(args.next().ok_or_else(usage)?.parse()?);
+            }
+            "--base-currency" => base_currency = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--fx-rates" => fx_rates = Some(args.next().ok_or_else(usage)?),
+            "--fx-rounding" => fx_rounding = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--house-account" => house_account = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--deposit-fee" => deposit_fee = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--withdrawal-fee" => withdrawal_fee = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--chargeback-fee" => chargeback_fee = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--verify" => verify = true,
+            "--max-withdrawal" => max_withdrawal = Some(args.next().ok_or_else(usage)?.parse()?),
+            "--daily-withdrawals" => {
+                daily_withdrawals = Some(args.next().ok_or_else(usage)?.parse()?);
+            }
+            "--rolling-withdrawals" => {
+                rolling_withdrawals = Some(args.next().ok_or_else(usage)?.parse()?);
+            }
+            "--max-transactions" => {
+                max_transactions = Some(args.next().ok_or_else(usage)?.parse()?)
+            }
+            "--withdrawal-window-hours" => {
+                withdrawal_window_hours = Some(args.next().ok_or_else(usage)?.parse()?);
+            }
+            "--client-limits" => client_limits = Some(args.next().ok_or_else(usage)?),
+            "--blocklist" => blocklist = Some(args.next().ok_or_else(usage)?),
+            "--allowlist" => allowlist = Some(args.next().ok_or_else(usage)?),
+            "--blocked-clients" => {
+                blocked_clients = Some(args.next().ok_or_else(usage)?.parse()?);
+            }
+            "--quarantine-log" => quarantine_log = Some(args.next().ok_or_else(usage)?),
+            "--reload-lists-every" => {
+                reload_lists_every = Some(args.next().ok_or_else(usage)?.parse()?);
+            }
+            "--allow-when-locked" => {
+                allowed_when_locked = args
+                    .next()
+                    .ok_or_else(usage)?
+                    .split(',')
+                    .map(|ty| ty.trim().parse())
+                    .collect::<Result<_, _>>()?;
+            }
+            _ if filename.is_none() && !arg.starts_with("--") => filename = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+    if (resume && database.is_none())
+        || (fsync.is_some() && wal.is_none())
+        || (database.is_some() && wal.is_some())
+        || (database.is_some() && (load_snapshot.is_some() || save_snapshot.is_some()))
+        || (wal.is_some() && load_snapshot.is_some())
+        || (snapshot_every.is_some() && save_snapshot.is_none())
+        || (database.is_some() && history_spill.is_some())
+        || (history_budget.is_some() && history_spill.is_none())
+        || (database.is_some() && audit_log.is_some())
+        || (fx_rates.is_some() && base_currency.is_none())
+        || (fx_rounding.is_some() && fx_rates.is_none())
+        || (house_account.is_some()
+            == (deposit_fee.is_none() && withdrawal_fee.is_none() && chargeback_fee.is_none()))
+        || [max_withdrawal, daily_withdrawals, rolling_withdrawals]
+            .iter()
+            .flatten()
+            .any(|amount: &Decimal| amount.is_sign_negative())
+    {
+        return Err(usage());
+    }
+    if database.is_some() && verify {
+        return Err(anyhow::format_err!(
+            "--verify is only available with the memory engine, not with --db"
+        ));
+    }
+    let listed = blocklist.is_some() || allowlist.is_some();
+    let action = blocked_clients.unwrap_or_default();
+    if (!listed && (blocked_clients.is_some() || reload_lists_every.is_some()))
+        || (database.is_some() && quarantine_log.is_some())
+        || (quarantine_log.is_some() && action != ScreeningAction::Quarantine)
+        || (database.is_none() && action == ScreeningAction::Quarantine && quarantine_log.is_none())
+    {
+        return Err(usage());
+    }
+    let retention = RetentionPolicy::builder()
+        .locked_accounts(prune_locked)
+        .charged_back(prune_charged_back)
+        .dispute_window(prune_after_tx_ids)
+        .build();
+    if (database.is_some() && retention.is_enabled())
+        || (prune_every.is_some() && !retention.is_enabled())
+    {
+        return Err(usage());
+    }
+    let scale = scale.unwrap_or(DEFAULT_SCALE);
+    let precision = PrecisionPolicy::builder()
+        .scale(scale)
+        .excess(excess_precision.unwrap_or_default())
+        .build();
+    let conversion = match (fx_rates, base_currency) {
+        (Some(fx_rates), Some(base)) => {
+            let precision = PrecisionPolicy::builder()
+                .scale(scale)
+                .excess(fx_rounding.unwrap_or_default())
+                .build();
+            Some(
+                load_currency_conversion(fx_rates.as_str(), base, precision)
+                    .map_err(|e| anyhow::anyhow!("Error loading exchange rates: {}", e))?,
+            )
+        }
+        _ => None,
+    };
+    let fees = house_account.map(|house| {
+        FeeSchedule::builder()
+            .house(house)
+            .deposit(deposit_fee)
+            .withdrawal(withdrawal_fee)
+            .chargeback(chargeback_fee)
+            .build()
+    });
+    let default_limits = WithdrawalLimits::builder()
+        .max_amount(max_withdrawal)
+        .daily_amount(daily_withdrawals)
+        .rolling_amount(rolling_withdrawals)
+        .max_transactions(max_transactions)
+        .window(withdrawal_window_hours.map(|hours| hours.saturating_mul(SECONDS_PER_HOUR)))
+        .build();
+    let withdrawal_limits = match client_limits {
+        Some(client_limits) => Some(
+            load_withdrawal_limits(client_limits.as_str(), default_limits)
+                .map_err(|e| anyhow::anyhow!("Error loading withdrawal limits: {}", e))?,
+        ),
+        None if default_limits != WithdrawalLimits::default() => Some(
+            WithdrawalLimitSchedule::builder()
+                .default(default_limits)
+                .build(),
+        ),
+        None => None,
+    };
+    let screening = if listed {
+        let lists = load_client_lists(blocklist.as_deref(), allowlist.as_deref())
+            .map_err(|e| anyhow::anyhow!("Error loading client lists: {}", e))?;
+        Some(ClientScreening::new(lists, action))
+    } else {
+        None
+    };
+    Ok(Options {
+        filename: filename.ok_or_else(usage)?,
+        database,
+        resume,
+        wal,
+        fsync: fsync.unwrap_or(FsyncPolicy::Always),
+        load_snapshot,
+        save_snapshot,
+        snapshot_every,
+        opening_balances,
+        history_spill,
+        history_budget: history_budget.unwrap_or(DEFAULT_HISTORY_BUDGET),
+        retention,
+        prune_every: prune_every.unwrap_or(DEFAULT_PRUNE_EVERY),
+        policy: AccountPolicy::builder()
+            .max_transaction_amount(max_amount)
+            .max_balance(max_balance)
+            .lock_on_chargeback(lock_on_chargeback)
+            .allowed_when_locked(allowed_when_locked)
+            .negative_balance(negative_balance.unwrap_or_default())
+            .max_disputes(max_disputes)
+            .dispute_after_chargeback(dispute_after_chargeback)
+            .dispute_window(dispute_days.map(|days| days.saturating_mul(SECONDS_PER_DAY)))
+            .authorization_expiry(
+                authorization_days.map(|days| days.saturating_mul(SECONDS_PER_DAY)),
+            )
+            .conversion(conversion)
+            .fees(fees)
+            .withdrawal_limits(withdrawal_limits)
+            .precision(precision)
+            .build(),
+        precision,
+        audit_log,
+        detailed_summary,
+        base_currency,
+        verify,
+        screening,
+        blocklist,
+        allowlist,
+        quarantine_log,
+        reload_lists_every: Duration::from_secs(
+            reload_lists_every.unwrap_or(DEFAULT_RELOAD_LISTS_EVERY),
+        ),
+    })
+}
 
 fn main() -> anyhow::Result<()> {
-    let filename = match env::args().nth(1) {
-        Some(filename) => Ok(filename),
-        None => Err(anyhow::format_err!(
-            "Usage: {} <csv-complete-filename>",
-            env::args().next().unwrap(),
-        )),
-    }?;
+    let options = parse_options()?;
 
     env_logger::Builder::from_env(Env::default().default_filter_or("error")).init();
 
-    let mut program = TransactionPipelineBuilder::csv_pipeline(filename.as_str());
+    let filename = options.filename.as_str();
+    let csv_options = CSVOptions::builder()
+        .precision(options.precision)
+        .detailed_summary(options.detailed_summary)
+        .base_currency(options.base_currency)
+        .build();
+    // The lists are reloaded while the pipeline runs, until the watcher is dropped on exit.
+    let _watcher = options.screening.as_ref().map(|screening| {
+        ClientListWatcher::spawn(
+            screening.clone(),
+            options.blocklist.clone(),
+            options.allowlist.clone(),
+            options.reload_lists_every,
+        )
+    });
+    if let Some(database) = options.database {
+        let mut engine = SqlitePaymentEngine::open(database.as_str())
+            .map_err(|e| anyhow::anyhow!("Error opening database: {}", e))?
+            .with_policy(options.policy);
+        if let Some(screening) = options.screening {
+            engine = engine.with_screening(screening);
+        }
+        if !options.resume {
+            engine.reset_progress()?;
+            if let Some(opening) = options.opening_balances {
+                seed_opening_balances(&mut engine, opening.as_str())
+                    .map_err(|e| anyhow::anyhow!("Error loading opening balances: {}", e))?;
+            }
+        }
+        let mut program =
+            TransactionPipelineBuilder::csv_pipeline_with_options(filename, engine, csv_options);
+        program
+            .run()
+            .map_err(|e| anyhow::anyhow!("Error running transaction pipeline: {}", e))?;
+        return Ok(());
+    }
+
+    let mut engine = match (options.wal, options.load_snapshot) {
+        (Some(wal), _) => {
+            // The log is replayed with the policy it was recorded with, as fees and currency
+            // conversions depend on it.
+            MemoryThreadSafePaymentEngine::with_write_ahead_log(
+                wal.as_str(),
+                options.fsync,
+                options.policy.clone(),
+            )
+            .map_err(|e| anyhow::anyhow!("Error recovering write-ahead log: {}", e))?
+        }
+        (None, Some(snapshot)) => MemoryThreadSafePaymentEngine::from_snapshot(snapshot.as_str())
+            .map_err(|e| anyhow::anyhow!("Error loading snapshot: {}", e))?,
+        (None, None) => MemoryThreadSafePaymentEngine::new(),
+    };
+    if let Some(history_spill) = options.history_spill {
+        engine = engine
+            .with_history_spill(history_spill.as_str(), options.history_budget)
+            .map_err(|e| anyhow::anyhow!("Error opening history spill: {}", e))?;
+    }
+    engine = engine.with_policy(options.policy);
+    if let Some(screening) = options.screening {
+        engine = engine.with_screening(screening);
+    }
+    if let Some(quarantine_log) = options.quarantine_log {
+        engine = engine
+            .with_quarantine_log(quarantine_log.as_str())
+            .map_err(|e| anyhow::anyhow!("Error opening quarantine log: {}", e))?;
+    }
+    if let Some(audit_log) = options.audit_log {
+        engine = engine
+            .with_audit_log(audit_log.as_str())
+            .map_err(|e| anyhow::anyhow!("Error opening audit log: {}", e))?;
+    }
+    if options.retention.is_enabled() {
+        engine = engine.with_retention(options.retention.clone(), options.prune_every);
+    }
+    if let (Some(snapshot), Some(every)) = (&options.save_snapshot, options.snapshot_every) {
+        engine = engine.with_snapshots(snapshot.as_str(), every);
+    }
+    if options.verify {
+        // Accounts recovered from a log or loaded from a snapshot are taken as opening balances.
+        engine = engine.with_invariant_checker()?;
+    }
+    if let Some(opening) = options.opening_balances {
+        // Opening balances applied by a previous run are already recovered from the log, so
+        // only the accounts still missing are opened.
+        seed_opening_balances(&mut engine, opening.as_str())
+            .map_err(|e| anyhow::anyhow!("Error loading opening balances: {}", e))?;
+    }
+    let mut program = TransactionPipelineBuilder::csv_pipeline_with_options(
+        filename,
+        engine.clone(),
+        csv_options,
+    );
     program
         .run()
         .map_err(|e| anyhow::anyhow!("Error running transaction pipeline: {}", e))?;
+    if let Some(violations) = engine.check_invariants()? {
+        for violation in violations.iter() {
+            eprintln!("{}", violation);
+        }
+        if !violations.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Found {} violations of the invariants",
+                violations.len()
+            ));
+        }
+        info!("Invariants of the accounts verified");
+    }
+    if let Some(stats) = engine.history_stats()? {
+        info!(
+            "History lookups: {}, memory hits: {}, disk hits: {}, misses: {}, disk lookups: {}, \
+             spilled: {}, memory hit rate: {:.4}",
+            stats.lookups,
+            stats.memory_hits,
+            stats.disk_hits,
+            stats.misses,
+            stats.disk_lookups,
+            stats.spilled,
+            stats.memory_hit_rate()
+        );
+    }
+    if let Some(snapshot) = options.save_snapshot {
+        // Pruning before saving keeps the snapshot as small as the history that still matters.
+        if options.retention.is_enabled() {
+            engine.prune(&options.retention)?;
+        }
+        engine
+            .save_snapshot(snapshot.as_str())
+            .map_err(|e| anyhow::anyhow!("Error saving snapshot: {}", e))?;
+    }
     Ok(())
 }
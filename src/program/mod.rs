@@ -43,9 +43,19 @@
 //!     sink: TCPSink { listener: TcpListener::bind("127.0.0.1:8081").unwrap() },
 //! });
 //! ```
+use log::{info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use typed_builder::TypedBuilder;
+
 use crate::{
-    CSVTransactionReader, CSVTransactionResultStdoutWriter, MemoryThreadSafePaymentEngine,
-    PaymentEngine, Sink, Source, TransactionError,
+    CSVAccountReader, CSVClientListReader, CSVExchangeRateReader, CSVTransactionReader,
+    CSVTransactionResultStdoutWriter, CSVWithdrawalLimitsReader, ClientLists, ClientScreening,
+    Currency, CurrencyConversion, MemoryThreadSafePaymentEngine, PaymentEngine, PrecisionPolicy,
+    Sink, Source, SqlitePaymentEngine, TransactionError, WithdrawalLimitSchedule, WithdrawalLimits,
 };
 
 /// Represents a transaction pipeline, consisting of a source, filter, and sink.
@@ -56,6 +66,21 @@ pub struct TransactionPipeline<S, F, K> {
     sink: K,
 }
 
+/// Options of the CSV files read and written by a pipeline.
+#[derive(Debug, Clone, Default, TypedBuilder)]
+pub struct CSVOptions {
+    /// Precision policy applied to the amounts of the transactions read.
+    #[builder(default)]
+    precision: PrecisionPolicy,
+    /// Whether the summary includes additional details, such as accounts with a negative balance.
+    #[builder(default)]
+    detailed_summary: bool,
+    /// Currency of the transactions without one, required to process transactions in several
+    /// currencies.
+    #[builder(default)]
+    base_currency: Option<Currency>,
+}
+
 /// Builder for constructing a transaction pipeline.
 #[derive(Debug)]
 pub struct TransactionPipelineBuilder {}
@@ -71,12 +96,282 @@ impl TransactionPipelineBuilder {
     ///
     /// A box containing the constructed pipeline.
     pub fn csv_pipeline(filename: &str) -> Box<dyn Pipeline> {
+        Self::csv_pipeline_with(filename, MemoryThreadSafePaymentEngine::new())
+    }
+
+    /// Constructs a CSV transaction pipeline processing the transactions with the given engine.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The name of the CSV file to read data from.
+    /// * `engine` - The `PaymentEngine` used to process the transactions.
+    ///
+    /// # Returns
+    ///
+    /// A box containing the constructed pipeline.
+    pub fn csv_pipeline_with<F>(filename: &str, engine: F) -> Box<dyn Pipeline>
+    where
+        F: PaymentEngine + 'static,
+    {
+        Self::csv_pipeline_with_options(filename, engine, CSVOptions::default())
+    }
+
+    /// Constructs a CSV transaction pipeline processing the transactions with the given engine
+    /// and reading and writing the CSV files with the given options.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The name of the CSV file to read data from.
+    /// * `engine` - The `PaymentEngine` used to process the transactions.
+    /// * `options` - The `CSVOptions` used to read the transactions and write the summary.
+    ///
+    /// # Returns
+    ///
+    /// A box containing the constructed pipeline.
+    pub fn csv_pipeline_with_options<F>(
+        filename: &str,
+        engine: F,
+        options: CSVOptions,
+    ) -> Box<dyn Pipeline>
+    where
+        F: PaymentEngine + 'static,
+    {
         Box::new(TransactionPipeline {
-            source: CSVTransactionReader::new(filename),
-            filter: MemoryThreadSafePaymentEngine::new(),
-            sink: CSVTransactionResultStdoutWriter::new(),
+            source: CSVTransactionReader::new(filename)
+                .with_precision(options.precision)
+                .with_base_currency(options.base_currency),
+            filter: engine,
+            sink: CSVTransactionResultStdoutWriter::new()
+                .with_detailed_summary(options.detailed_summary)
+                .with_base_currency(options.base_currency),
         })
     }
+
+    /// Constructs a CSV transaction pipeline processing the transactions with the given engine,
+    /// applying `precision` to the amounts read.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The name of the CSV file to read data from.
+    /// * `engine` - The `PaymentEngine` used to process the transactions.
+    /// * `precision` - The `PrecisionPolicy` applied to the amounts when they are parsed.
+    ///
+    /// # Returns
+    ///
+    /// A box containing the constructed pipeline.
+    #[deprecated(note = "use `csv_pipeline_with_options` with the precision in `CSVOptions`")]
+    pub fn csv_pipeline_with_precision<F>(
+        filename: &str,
+        engine: F,
+        precision: PrecisionPolicy,
+    ) -> Box<dyn Pipeline>
+    where
+        F: PaymentEngine + 'static,
+    {
+        Self::csv_pipeline_with_options(
+            filename,
+            engine,
+            CSVOptions::builder().precision(precision).build(),
+        )
+    }
+
+    /// Constructs a CSV transaction pipeline whose accounts are persisted in a SQLite database.
+    ///
+    /// # Arguments
+    ///
+    /// * `filename` - The name of the CSV file to read data from.
+    /// * `database` - The path of the SQLite database file holding the accounts.
+    /// * `resume` - Whether the CSV file is the same input of a previous interrupted run, in
+    ///   which case the records already applied are skipped.
+    ///
+    /// # Returns
+    ///
+    /// A box containing the constructed pipeline or an error if the database cannot be opened.
+    pub fn sqlite_pipeline(
+        filename: &str,
+        database: &str,
+        resume: bool,
+    ) -> Result<Box<dyn Pipeline>, TransactionError> {
+        let mut engine = SqlitePaymentEngine::open(database)?;
+        if !resume {
+            engine.reset_progress()?;
+        }
+        Ok(Self::csv_pipeline_with(filename, engine))
+    }
+}
+
+/// Seeds `engine` with the opening balances read from the CSV file `filename`, which has the same
+/// format as the summary written by the pipeline.
+///
+/// Clients that already have an account in `engine` are skipped, so seeding again after an
+/// interrupted run only opens the accounts still missing. A client listed twice in the file is
+/// still an error.
+///
+/// # Returns
+///
+/// The number of accounts created, or the first error found validating the opening balances.
+pub fn seed_opening_balances<F>(engine: &mut F, filename: &str) -> Result<usize, TransactionError>
+where
+    F: PaymentEngine,
+{
+    let mut reader = CSVAccountReader::new(filename)?;
+    let mut listed = BTreeSet::new();
+    let mut opened = 0;
+    for account in reader.iter() {
+        let account = account?;
+        let client_id = account.client_id();
+        match engine.open_account(account) {
+            Ok(()) => opened += 1,
+            Err(TransactionError::AccountAlreadyExists(_)) if !listed.contains(&client_id) => {
+                info!(
+                    "Skipping the opening balance of existing client {}",
+                    client_id
+                );
+            }
+            Err(e) => return Err(e),
+        }
+        listed.insert(client_id);
+    }
+    info!("Opened {} accounts from {}", opened, filename);
+    Ok(opened)
+}
+
+/// Loads the exchange rates to `base` from the CSV file `filename`, converting the amounts with
+/// the given `precision` policy.
+///
+/// # Returns
+///
+/// The conversion to apply to the transactions in other currencies, or the first error found
+/// reading or validating the rates.
+pub fn load_currency_conversion(
+    filename: &str,
+    base: Currency,
+    precision: PrecisionPolicy,
+) -> Result<CurrencyConversion, TransactionError> {
+    let mut reader = CSVExchangeRateReader::new(filename)?;
+    let rates = reader.iter().collect::<Result<Vec<_>, _>>()?;
+    info!("Loaded {} exchange rates from {}", rates.len(), filename);
+    CurrencyConversion::new(base, rates, precision)
+}
+
+/// Loads the withdrawal limits of some clients from the CSV file `filename`, taking the limits
+/// they do not set from `default`, the limits of every client.
+///
+/// # Returns
+///
+/// The limits of the withdrawals of every client, or the first error found reading or
+/// validating them.
+pub fn load_withdrawal_limits(
+    filename: &str,
+    default: WithdrawalLimits,
+) -> Result<WithdrawalLimitSchedule, TransactionError> {
+    let mut reader = CSVWithdrawalLimitsReader::new(filename)?;
+    let clients = reader.iter().collect::<Result<BTreeMap<_, _>, _>>()?;
+    info!(
+        "Loaded the withdrawal limits of {} clients from {}",
+        clients.len(),
+        filename
+    );
+    Ok(WithdrawalLimitSchedule::builder()
+        .default(default)
+        .clients(clients)
+        .build())
+}
+
+/// Loads the blocklist and the allowlist of the clients from the CSV files `blocklist` and
+/// `allowlist`, if given. Without an allowlist every client not blocked is let through.
+///
+/// # Returns
+///
+/// The lists of the clients, or the first error found reading them.
+pub fn load_client_lists(
+    blocklist: Option<&str>,
+    allowlist: Option<&str>,
+) -> Result<ClientLists, TransactionError> {
+    let read = |filename: &str| -> Result<BTreeSet<_>, TransactionError> {
+        CSVClientListReader::new(filename)?.iter().collect()
+    };
+    let blocked = blocklist.map(read).transpose()?.unwrap_or_default();
+    let allowed = allowlist.map(read).transpose()?;
+    let lists = ClientLists::builder()
+        .blocked(blocked)
+        .allowed(allowed)
+        .build();
+    let (blocked, allowed) = lists.len();
+    info!(
+        "Loaded {} blocked clients and {} allowed clients",
+        blocked,
+        allowed.map_or_else(|| "all".to_string(), |allowed| allowed.to_string())
+    );
+    Ok(lists)
+}
+
+/// Watches the CSV files of the client lists from a background thread, reloading them into a
+/// `ClientScreening` whenever any of them changes, so long-running ingestion picks the new lists
+/// up without restarting the pipeline. Lists that cannot be read, for instance while they are
+/// being rewritten, are retried on the next check and the previous ones are kept meanwhile.
+/// The thread is stopped when the watcher is dropped.
+#[derive(Debug)]
+pub struct ClientListWatcher {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ClientListWatcher {
+    /// Starts watching `blocklist` and `allowlist`, checking them every `interval`.
+    pub fn spawn(
+        screening: ClientScreening,
+        blocklist: Option<String>,
+        allowlist: Option<String>,
+        interval: Duration,
+    ) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        // Taken before spawning, so changes made as soon as this returns are not missed.
+        let mut loaded = file_versions(&[blocklist.as_deref(), allowlist.as_deref()]);
+        let handle = thread::spawn(move || {
+            let files = [blocklist.as_deref(), allowlist.as_deref()];
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let current = file_versions(&files);
+                if current == loaded {
+                    continue;
+                }
+                match load_client_lists(files[0], files[1])
+                    .and_then(|lists| screening.update(lists))
+                {
+                    Ok(()) => {
+                        info!("Reloaded the client lists");
+                        loaded = current;
+                    }
+                    Err(e) => warn!("Error reloading the client lists: {}", e),
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for ClientListWatcher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Returns the modification time and the length of each of `files`, to find out whether any of
+/// them changed.
+fn file_versions(files: &[Option<&str>]) -> Vec<Option<(SystemTime, u64)>> {
+    files
+        .iter()
+        .map(|file| {
+            let metadata = fs::metadata((*file)?).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect()
 }
 
 /// Trait for defining a pipeline.
@@ -97,9 +392,21 @@ where
 {
     fn run(&mut self) -> Result<(), TransactionError> {
         let reader = self.source.read()?;
-        for record in reader {
-            let record = record?;
-            self.filter.process(&record)?;
+        let offset = self.filter.resume_offset()?;
+        if offset > 0 {
+            info!("Resuming input after {} records already processed", offset);
+        }
+        for record in reader.skip(offset as usize) {
+            match record {
+                Ok(record) => self.filter.process(&record)?,
+                // Invalid records are rejected like invalid transactions, without stopping the
+                // run. Malformed input is still an error.
+                Err(e) if e.is_invalid_record() => {
+                    warn!("{}", e);
+                    self.filter.skip_invalid()?;
+                }
+                Err(e) => return Err(e),
+            }
         }
         let results = self.filter.summary()?;
         for record in results {
@@ -126,6 +433,36 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_client_list_watcher_reloads_changed_lists() {
+        let path = std::env::temp_dir().join(format!("blocklist-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, "client\n3\n").unwrap();
+        let lists = load_client_lists(Some(&path), Some("tests/data/allowlist.csv")).unwrap();
+        assert_eq!(lists.len(), (1, Some(3)));
+        let screening = ClientScreening::new(lists, Default::default());
+
+        let watcher = ClientListWatcher::spawn(
+            screening.clone(),
+            Some(path.clone()),
+            Some("tests/data/allowlist.csv".to_string()),
+            Duration::from_millis(10),
+        );
+        std::fs::write(&path, "client\n3\n4\n5\n").unwrap();
+        let mut reloaded = false;
+        for _ in 0..500 {
+            if screening.lists().unwrap().len() == (3, Some(3)) {
+                reloaded = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(watcher);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(reloaded);
+    }
+
     #[test]
     fn test_run_success() {
         let mut source_mock = MockSourceMocked::new();
@@ -141,6 +478,7 @@ mod tests {
             .return_once(|| Ok(Box::new(returned.into_iter().map(Ok))));
 
         // Set expectations for filter mock
+        filter_mock.expect_resume_offset().returning(|| Ok(0));
         filter_mock.expect_process().times(3).returning(|_| Ok(()));
         let returned = fake::vec![TransactionResultSummary; 2];
         filter_mock.expect_summary().times(1).return_once(|| {
@@ -201,6 +539,7 @@ mod tests {
             .return_once(|| Ok(Box::new(returned.into_iter().map(Ok))));
 
         // Set expectations for filter mock
+        filter_mock.expect_resume_offset().returning(|| Ok(0));
         filter_mock.expect_process().times(1).returning(|_| {
             let tx = Faker.fake();
             Err(TransactionError::InsufficientFunds(tx))
@@ -233,6 +572,7 @@ mod tests {
             .return_once(|| Ok(Box::new(returned.into_iter().map(Ok))));
 
         // Set expectations for filter mock
+        filter_mock.expect_resume_offset().returning(|| Ok(0));
         filter_mock.expect_process().times(3).returning(|_| Ok(()));
         filter_mock.expect_summary().times(1).return_once(|| {
             Err(TransactionError::SyncError(
@@ -267,6 +607,7 @@ mod tests {
             .return_once(|| Ok(Box::new(returned.into_iter().map(Ok))));
 
         // Set expectations for filter mock
+        filter_mock.expect_resume_offset().returning(|| Ok(0));
         filter_mock.expect_process().times(3).returning(|_| Ok(()));
         let returned = fake::vec![TransactionResultSummary; 2];
         filter_mock.expect_summary().times(1).return_once(|| {
@@ -289,4 +630,80 @@ mod tests {
 
         assert!(transaction_pipeline.run().is_err());
     }
+
+    #[test]
+    fn test_run_skips_records_already_processed() {
+        let mut source_mock = MockSourceMocked::new();
+        let mut filter_mock = MockPaymentEngine::new();
+        let mut sink_mock = MockSink::new();
+
+        let returned = fake::vec![Transaction; 5];
+
+        // Set expectations for source mock
+        source_mock
+            .expect_read()
+            .times(1)
+            .return_once(|| Ok(Box::new(returned.into_iter().map(Ok))));
+
+        // Set expectations for filter mock
+        filter_mock.expect_resume_offset().returning(|| Ok(3));
+        filter_mock.expect_process().times(2).returning(|_| Ok(()));
+        filter_mock.expect_summary().times(1).return_once(|| {
+            Ok(Box::new(std::iter::empty()) as Box<dyn Iterator<Item = TransactionResultSummary>>)
+        });
+
+        // Set expectations for sink mock
+        sink_mock.expect_write().never();
+
+        let mut transaction_pipeline = Box::new(TransactionPipeline {
+            source: source_mock,
+            filter: filter_mock,
+            sink: sink_mock,
+        }) as Box<dyn Pipeline>;
+
+        assert!(transaction_pipeline.run().is_ok());
+    }
+
+    #[test]
+    fn test_run_rejects_invalid_records() {
+        let mut source_mock = MockSourceMocked::new();
+        let mut filter_mock = MockPaymentEngine::new();
+        let mut sink_mock = MockSink::new();
+
+        let returned = vec![
+            Ok(Faker.fake::<Transaction>()),
+            Err(TransactionError::InvalidTransactionAmount(
+                "Invalid amount".to_string(),
+            )),
+            Ok(Faker.fake::<Transaction>()),
+        ];
+
+        // Set expectations for source mock
+        source_mock
+            .expect_read()
+            .times(1)
+            .return_once(|| Ok(Box::new(returned.into_iter())));
+
+        // Set expectations for filter mock
+        filter_mock.expect_resume_offset().returning(|| Ok(0));
+        filter_mock.expect_process().times(2).returning(|_| Ok(()));
+        filter_mock
+            .expect_skip_invalid()
+            .times(1)
+            .returning(|| Ok(()));
+        filter_mock.expect_summary().times(1).return_once(|| {
+            Ok(Box::new(std::iter::empty()) as Box<dyn Iterator<Item = TransactionResultSummary>>)
+        });
+
+        // Set expectations for sink mock
+        sink_mock.expect_write().never();
+
+        let mut transaction_pipeline = Box::new(TransactionPipeline {
+            source: source_mock,
+            filter: filter_mock,
+            sink: sink_mock,
+        }) as Box<dyn Pipeline>;
+
+        assert!(transaction_pipeline.run().is_ok());
+    }
 }
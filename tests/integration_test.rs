@@ -30,6 +30,7 @@ fn test_process_with_correct_results_with_chargebacks() {
     assert!(result.contains(&expected));
 }
 
+#[allow(clippy::useless_vec)]
 #[test]
 fn test_process_with_correct_results_with_chargebacks_and_disputes() {
     let mut csv_reader =
@@ -49,3 +50,669 @@ fn test_process_with_correct_results_with_chargebacks_and_disputes() {
         assert!(expected.contains(obtained));
     });
 }
+
+fn sqlite_summary(filename: &str) -> Vec<TransactionResultSummary> {
+    let mut csv_reader = CSVTransactionReader::new(filename);
+    let mut engine = SqlitePaymentEngine::open_in_memory().unwrap();
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    engine.summary().unwrap().collect::<Vec<_>>()
+}
+
+#[test]
+fn test_sqlite_process_with_correct_results() {
+    let result = sqlite_summary("tests/data/tx_test_ok.csv");
+    assert_eq!(result.len(), 2);
+
+    let expected: TransactionResultSummary =
+        Account::create_with(1_u16, dec!(0.4688), dec!(0), false).into();
+    assert!(result.contains(&expected));
+}
+
+#[test]
+fn test_sqlite_process_with_correct_results_with_chargebacks() {
+    let result = sqlite_summary("tests/data/tx_test_with_charge_back.csv");
+    assert_eq!(result.len(), 2);
+
+    let expected = Account::create_with(1_u16, dec!(0.5), dec!(0), true).into();
+    assert!(result.contains(&expected));
+}
+
+#[test]
+fn test_sqlite_process_with_correct_results_with_chargebacks_and_disputes() {
+    let result = sqlite_summary("tests/data/tx_tests_ok_with_dispute_and_chargebacks.csv");
+    assert_eq!(result.len(), 2);
+
+    let expected = [
+        Account::create_with(1_u16, dec!(80), dec!(0), false).into(),
+        Account::create_with(2_u16, dec!(80), dec!(0), false).into(),
+    ];
+    result.iter().for_each(|obtained| {
+        assert!(expected.contains(obtained));
+    });
+}
+
+#[test]
+fn test_process_partial_disputes() {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_partial_disputes.csv");
+    let mut engine = MemoryThreadSafePaymentEngine::new();
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    let result = engine.summary().unwrap().collect::<Vec<_>>();
+    let expected: [TransactionResultSummary; 2] = [
+        Account::create_with(1_u16, dec!(4), dec!(4.5), true).into(),
+        // The last dispute exceeds the 6 left to dispute and is rejected.
+        Account::create_with(2_u16, dec!(6), dec!(2), false).into(),
+    ];
+    assert_eq!(result.len(), 2);
+    result.iter().for_each(|obtained| {
+        assert!(expected.contains(obtained));
+    });
+
+    let sqlite_result = sqlite_summary("tests/data/tx_tests_partial_disputes.csv");
+    assert_eq!(sqlite_result.len(), 2);
+    sqlite_result.iter().for_each(|obtained| {
+        assert!(expected.contains(obtained));
+    });
+}
+
+#[test]
+fn test_process_transfers() {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_transfers.csv");
+    let mut engine = MemoryThreadSafePaymentEngine::new();
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    let result = engine.summary().unwrap().collect::<Vec<_>>();
+    // The underfunded transfer and the transfer to the locked client 3 are rejected.
+    let expected: [TransactionResultSummary; 4] = [
+        Account::create_with(1_u16, dec!(5), dec!(0), false).into(),
+        Account::create_with(2_u16, dec!(9), dec!(0), false).into(),
+        Account::create_with(3_u16, dec!(0), dec!(0), true).into(),
+        Account::create_with(4_u16, dec!(1), dec!(0), false).into(),
+    ];
+    assert_eq!(result.len(), 4);
+    result.iter().for_each(|obtained| {
+        assert!(expected.contains(obtained));
+    });
+
+    let sqlite_result = sqlite_summary("tests/data/tx_tests_transfers.csv");
+    assert_eq!(sqlite_result.len(), 4);
+    sqlite_result.iter().for_each(|obtained| {
+        assert!(expected.contains(obtained));
+    });
+}
+
+#[test]
+fn test_process_with_dispute_window() {
+    let policy = AccountPolicy::builder()
+        .dispute_window(120 * 24 * 60 * 60)
+        .build();
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_timestamps.csv");
+    let mut engine = MemoryThreadSafePaymentEngine::new().with_policy(policy.clone());
+    let mut sqlite_engine = SqlitePaymentEngine::open_in_memory()
+        .unwrap()
+        .with_policy(policy);
+    for record in csv_reader.iter() {
+        let record = record.unwrap();
+        engine.process(&record).unwrap();
+        sqlite_engine.process(&record).unwrap();
+    }
+
+    // The dispute of tx 2 arrives 127 days after the deposit and is rejected.
+    let expected = || Account::create_with(1_u16, dec!(5), dec!(12), false).into();
+    assert_eq!(
+        engine.summary().unwrap().collect::<Vec<_>>(),
+        vec![expected()]
+    );
+    assert_eq!(
+        sqlite_engine.summary().unwrap().collect::<Vec<_>>(),
+        vec![expected()]
+    );
+}
+
+#[test]
+fn test_process_authorizations() {
+    let policy = AccountPolicy::builder()
+        .authorization_expiry(7 * 24 * 60 * 60)
+        .build();
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_authorizations.csv");
+    let mut engine = MemoryThreadSafePaymentEngine::new().with_policy(policy.clone());
+    let mut sqlite_engine = SqlitePaymentEngine::open_in_memory()
+        .unwrap()
+        .with_policy(policy);
+    for record in csv_reader.iter() {
+        let record = record.unwrap();
+        engine.process(&record).unwrap();
+        sqlite_engine.process(&record).unwrap();
+    }
+
+    let mut summary = engine
+        .summary()
+        .unwrap()
+        .map(|summary| serde_json::to_value(DetailedSummary::from(summary)).unwrap())
+        .collect::<Vec<_>>();
+    summary.sort_by_key(|summary| summary["client"].as_u64());
+    let sqlite_summary = sqlite_engine
+        .summary()
+        .unwrap()
+        .map(|summary| serde_json::to_value(DetailedSummary::from(summary)).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(summary, sqlite_summary);
+    assert_eq!(summary.len(), 2);
+
+    // Authorization 4 is still open, and authorization 6 expired before its capture.
+    assert_eq!(summary[0]["available"], "45.0000");
+    assert_eq!(summary[0]["held"], "20.0000");
+    assert_eq!(summary[0]["held_disputes"], "0.0000");
+    assert_eq!(summary[0]["held_authorizations"], "20.0000");
+    assert_eq!(summary[0]["total"], "65.0000");
+    assert_eq!(summary[1]["available"], "51.0000");
+    assert_eq!(summary[1]["held"], "0.0000");
+}
+
+#[test]
+fn test_sqlite_resume_after_restart() {
+    let database = std::env::temp_dir().join(format!("payments-resume-{}.db", std::process::id()));
+    let database = database.to_str().unwrap();
+    let _ = std::fs::remove_file(database);
+
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_test_with_charge_back.csv");
+    let records = csv_reader.iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+    let (first_run, _) = records.split_at(records.len() / 2);
+
+    let mut engine = SqlitePaymentEngine::open(database).unwrap();
+    for record in first_run {
+        engine.process(record).unwrap();
+    }
+    drop(engine);
+
+    let mut engine = SqlitePaymentEngine::open(database).unwrap();
+    let offset = engine.resume_offset().unwrap() as usize;
+    assert_eq!(offset, first_run.len());
+    for record in records.iter().skip(offset) {
+        engine.process(record).unwrap();
+    }
+    let result = engine.summary().unwrap().collect::<Vec<_>>();
+    std::fs::remove_file(database).unwrap();
+
+    let expected = Account::create_with(1_u16, dec!(0.5), dec!(0), true).into();
+    assert!(result.contains(&expected));
+}
+
+#[test]
+fn test_pipeline_rejects_invalid_rows_and_carries_on() {
+    let database = std::env::temp_dir().join(format!("payments-invalid-{}.db", std::process::id()));
+    let database = database.to_str().unwrap();
+    let _ = std::fs::remove_file(database);
+
+    TransactionPipelineBuilder::sqlite_pipeline(
+        "tests/data/tx_tests_invalid_rows.csv",
+        database,
+        false,
+    )
+    .unwrap()
+    .run()
+    .unwrap();
+    let engine = SqlitePaymentEngine::open(database).unwrap();
+    let result = engine.summary().unwrap().collect::<Vec<_>>();
+    let offset = engine.resume_offset().unwrap();
+    drop(engine);
+    std::fs::remove_file(database).unwrap();
+
+    // The invalid rows still count for the progress of the input.
+    assert_eq!(offset, 6);
+    assert_eq!(
+        result,
+        vec![Account::create_with(1_u16, dec!(8.5), dec!(0), false).into()]
+    );
+}
+
+#[test]
+fn test_process_with_opening_balances() {
+    let mut engine = MemoryThreadSafePaymentEngine::new();
+    let opened = seed_opening_balances(&mut engine, "tests/data/opening_balances.csv").unwrap();
+    assert_eq!(opened, 2);
+
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_test_ok.csv");
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    let result = engine.summary().unwrap().collect::<Vec<_>>();
+
+    let expected: TransactionResultSummary =
+        Account::create_with(1_u16, dec!(10.4688), dec!(0), false).into();
+    assert!(result.contains(&expected));
+}
+
+#[test]
+fn test_seed_opening_balances_again() {
+    let mut engine = MemoryThreadSafePaymentEngine::new();
+    engine
+        .open_account(Account::create_with(2_u16, dec!(1.5), dec!(0.5), true))
+        .unwrap();
+
+    // Only the accounts missing are opened, as when resuming an interrupted seed.
+    let opened = seed_opening_balances(&mut engine, "tests/data/opening_balances.csv").unwrap();
+    assert_eq!(opened, 1);
+    let opened = seed_opening_balances(&mut engine, "tests/data/opening_balances.csv").unwrap();
+    assert_eq!(opened, 0);
+    assert_eq!(engine.summary().unwrap().count(), 2);
+
+    let result = seed_opening_balances(
+        &mut MemoryThreadSafePaymentEngine::new(),
+        "tests/data/opening_balances_duplicated.csv",
+    );
+    assert!(matches!(
+        result,
+        Err(TransactionError::AccountAlreadyExists(1))
+    ));
+}
+
+#[test]
+fn test_process_with_inconsistent_opening_balances() {
+    let mut engine = MemoryThreadSafePaymentEngine::new();
+    let result = seed_opening_balances(&mut engine, "tests/data/opening_balances_inconsistent.csv");
+    assert!(matches!(
+        result,
+        Err(TransactionError::InvalidOpeningBalance(_))
+    ));
+}
+
+fn administrative_statuses(
+    summary: impl Iterator<Item = TransactionResultSummary>,
+) -> Vec<(String, bool, bool)> {
+    let mut summary = summary
+        .map(|summary| serde_json::to_value(DetailedSummary::from(summary)).unwrap())
+        .collect::<Vec<_>>();
+    summary.sort_by_key(|summary| summary["client"].as_u64());
+    summary
+        .iter()
+        .map(|summary| {
+            (
+                summary["available"].as_str().unwrap().to_string(),
+                summary["frozen"].as_bool().unwrap(),
+                summary["closed"].as_bool().unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_process_administrative_operations_with_audit_trail() {
+    let path = std::env::temp_dir().join(format!("integration-audit-{}.log", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_admin.csv");
+    let mut engine = MemoryThreadSafePaymentEngine::new()
+        .with_audit_log(path)
+        .unwrap();
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    let result = administrative_statuses(engine.summary().unwrap());
+    let audit = read_audit_log(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(
+        result,
+        [
+            ("0.0000".to_string(), false, true),
+            ("0.0000".to_string(), true, false),
+        ]
+    );
+    assert_eq!(
+        audit
+            .iter()
+            .map(|record| (
+                record.tx,
+                record.operator.as_str(),
+                record.rejection.is_none()
+            ))
+            .collect::<Vec<_>>(),
+        vec![(3, "alice", true), (5, "bob", true), (8, "alice", true)]
+    );
+}
+
+#[test]
+fn test_sqlite_process_administrative_operations_with_audit_trail() {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_admin.csv");
+    let mut engine = SqlitePaymentEngine::open_in_memory().unwrap();
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    let result = administrative_statuses(engine.summary().unwrap());
+    let audit = engine.audit_trail().unwrap();
+
+    assert_eq!(
+        result,
+        [
+            ("0.0000".to_string(), false, true),
+            ("0.0000".to_string(), true, false),
+        ]
+    );
+    assert_eq!(audit.len(), 3);
+    assert_eq!(audit[1].ty, TransactionType::Freeze);
+    assert_eq!(audit[1].reason, "Suspicious activity");
+}
+
+#[test]
+fn test_process_disputes_with_negative_balance() {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_negative_balance.csv");
+    let policy = AccountPolicy::builder()
+        .negative_balance(NegativeBalance::Limit(dec!(5)))
+        .build();
+    let mut engine = MemoryThreadSafePaymentEngine::new().with_policy(policy);
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    let result = engine.summary().unwrap().collect::<Vec<_>>();
+    assert_eq!(result.len(), 2);
+
+    // The dispute on client 1 would take its available funds to -8, beyond the limit.
+    let expected: [TransactionResultSummary; 2] = [
+        Account::create_with(1_u16, dec!(2), dec!(0), false).into(),
+        Account::create_with(2_u16, dec!(-5), dec!(5), false).into(),
+    ];
+    result.iter().for_each(|obtained| {
+        assert!(expected.contains(obtained));
+    });
+    assert_eq!(result.iter().filter(|s| s.negative()).count(), 1);
+}
+
+fn currencies_summary<F: PaymentEngine>(mut engine: F) -> Vec<TransactionResultSummary> {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_currencies.csv")
+        .with_base_currency(Some("EUR".parse().unwrap()));
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    engine.summary().unwrap().collect::<Vec<_>>()
+}
+
+#[test]
+fn test_process_with_several_currencies() {
+    let results = [
+        currencies_summary(MemoryThreadSafePaymentEngine::new()),
+        currencies_summary(SqlitePaymentEngine::open_in_memory().unwrap()),
+    ];
+    for result in results {
+        // The dispute in USD does not match the deposit and is rejected.
+        let expected: TransactionResultSummary =
+            Account::create_with(1_u16, dec!(2), dec!(10), false).into();
+        assert!(result.contains(&expected));
+        let mut currencies = result
+            .iter()
+            .filter_map(|summary| summary.currency())
+            .map(|currency| currency.to_string())
+            .collect::<Vec<_>>();
+        currencies.sort();
+        assert_eq!(currencies, vec!["GBP", "USD"]);
+        assert_eq!(result.len(), 3);
+    }
+}
+
+#[test]
+fn test_process_currency_without_base_currency() {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_currencies.csv");
+    let result = csv_reader.iter().collect::<Result<Vec<_>, _>>();
+    assert!(matches!(result, Err(TransactionError::InvalidCurrency(_))));
+}
+
+fn conversion_policy() -> AccountPolicy {
+    let conversion = load_currency_conversion(
+        "tests/data/exchange_rates.csv",
+        "EUR".parse().unwrap(),
+        PrecisionPolicy::default(),
+    )
+    .unwrap();
+    AccountPolicy::builder().conversion(conversion).build()
+}
+
+fn conversion_summary<F: PaymentEngine>(mut engine: F) -> Vec<TransactionResultSummary> {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_conversion.csv")
+        .with_base_currency(Some("EUR".parse().unwrap()));
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    engine.summary().unwrap().collect::<Vec<_>>()
+}
+
+#[test]
+fn test_process_with_currency_conversion() {
+    let results = [
+        conversion_summary(MemoryThreadSafePaymentEngine::new().with_policy(conversion_policy())),
+        conversion_summary(
+            SqlitePaymentEngine::open_in_memory()
+                .unwrap()
+                .with_policy(conversion_policy()),
+        ),
+    ];
+    for result in results {
+        // The withdrawal is converted at the new rate and the dispute at the one of its deposit,
+        // while the deposit in JPY is rejected without a rate.
+        let expected: [TransactionResultSummary; 2] = [
+            Account::create_with(1_u16, dec!(8.8), dec!(6), false).into(),
+            Account::create_with(2_u16, dec!(2.7), dec!(0), false).into(),
+        ];
+        assert_eq!(result.len(), 2);
+        result.iter().for_each(|obtained| {
+            assert!(expected.contains(obtained));
+        });
+    }
+}
+
+fn fees_policy() -> AccountPolicy {
+    let fees = FeeSchedule::builder()
+        .house(0)
+        .deposit("1%".parse::<Fee>().unwrap())
+        .withdrawal("0.5".parse::<Fee>().unwrap())
+        .chargeback("15".parse::<Fee>().unwrap())
+        .build();
+    AccountPolicy::builder().fees(fees).build()
+}
+
+fn fees_summary<F: PaymentEngine>(mut engine: F) -> Vec<serde_json::Value> {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_fees.csv");
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    let mut summary = engine
+        .summary()
+        .unwrap()
+        .map(|summary| serde_json::to_value(DetailedSummary::from(summary)).unwrap())
+        .collect::<Vec<_>>();
+    summary.sort_by_key(|summary| summary["client"].as_u64());
+    summary
+}
+
+#[test]
+fn test_process_with_fees() {
+    let summary = fees_summary(MemoryThreadSafePaymentEngine::new().with_policy(fees_policy()));
+    let sqlite_summary = fees_summary(
+        SqlitePaymentEngine::open_in_memory()
+            .unwrap()
+            .with_policy(fees_policy()),
+    );
+    assert_eq!(summary, sqlite_summary);
+    assert_eq!(summary.len(), 4);
+
+    // The second withdrawal cannot pay its fee, the chargeback fee is reduced to the available
+    // funds of client 2 and the transfer is free.
+    let balances = summary
+        .iter()
+        .map(|summary| {
+            (
+                summary["available"].as_str().unwrap(),
+                summary["fees"].as_str().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        balances,
+        [
+            ("11.5000", "0.0000"),
+            ("38.5000", "1.5000"),
+            ("0.0000", "10.0000"),
+            ("10.0000", "0.0000"),
+        ]
+    );
+    assert_eq!(summary[2]["locked"], true);
+}
+
+#[test]
+fn test_check_invariants_of_every_scenario() {
+    let authorizations = AccountPolicy::builder()
+        .authorization_expiry(7 * 24 * 60 * 60)
+        .build();
+    let negative_balance = AccountPolicy::builder()
+        .negative_balance(NegativeBalance::Limit(dec!(5)))
+        .build();
+    let scenarios = [
+        (
+            "tests/data/tx_tests_ok_with_dispute_and_chargebacks.csv",
+            None,
+            AccountPolicy::default(),
+        ),
+        (
+            "tests/data/tx_tests_partial_disputes.csv",
+            None,
+            AccountPolicy::default(),
+        ),
+        (
+            "tests/data/tx_tests_transfers.csv",
+            None,
+            AccountPolicy::default(),
+        ),
+        (
+            "tests/data/tx_tests_admin.csv",
+            None,
+            AccountPolicy::default(),
+        ),
+        (
+            "tests/data/tx_tests_authorizations.csv",
+            None,
+            authorizations,
+        ),
+        (
+            "tests/data/tx_tests_negative_balance.csv",
+            None,
+            negative_balance,
+        ),
+        (
+            "tests/data/tx_tests_currencies.csv",
+            Some("EUR"),
+            AccountPolicy::default(),
+        ),
+        (
+            "tests/data/tx_tests_conversion.csv",
+            Some("EUR"),
+            conversion_policy(),
+        ),
+        ("tests/data/tx_tests_fees.csv", None, fees_policy()),
+    ];
+    for (filename, base_currency, policy) in scenarios {
+        let mut engine = MemoryThreadSafePaymentEngine::new()
+            .with_policy(policy)
+            .with_invariant_checker()
+            .unwrap();
+        seed_opening_balances(&mut engine, "tests/data/opening_balances.csv").unwrap();
+        let mut csv_reader = CSVTransactionReader::new(filename)
+            .with_base_currency(base_currency.map(|currency| currency.parse().unwrap()));
+        for record in csv_reader.iter() {
+            engine.process(&record.unwrap()).unwrap();
+        }
+        assert_eq!(
+            engine.check_invariants().unwrap(),
+            Some(vec![]),
+            "{}",
+            filename
+        );
+    }
+}
+
+fn withdrawal_limits_summary<F: PaymentEngine>(mut engine: F) -> Vec<TransactionResultSummary> {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_withdrawal_limits.csv");
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    engine.summary().unwrap().collect::<Vec<_>>()
+}
+
+#[test]
+fn test_process_with_withdrawal_limits() {
+    let policy = || {
+        let limits = load_withdrawal_limits(
+            "tests/data/withdrawal_limits.csv",
+            WithdrawalLimits::builder().max_amount(dec!(50)).build(),
+        )
+        .unwrap();
+        AccountPolicy::builder().withdrawal_limits(limits).build()
+    };
+    let results = [
+        withdrawal_limits_summary(MemoryThreadSafePaymentEngine::new().with_policy(policy())),
+        withdrawal_limits_summary(
+            SqlitePaymentEngine::open_in_memory()
+                .unwrap()
+                .with_policy(policy()),
+        ),
+    ];
+    for result in results {
+        // Client 1 cannot withdraw more than 55 at once, and client 2, whose deposit is out of
+        // the window, hits its rolling, count and daily limits in turn.
+        assert_eq!(result.len(), 3);
+        let expected: [TransactionResultSummary; 3] = [
+            Account::create_with(1_u16, dec!(100), dec!(0), false).into(),
+            Account::create_with(2_u16, dec!(160), dec!(0), false).into(),
+            Account::create_with(3_u16, dec!(0), dec!(0), false).into(),
+        ];
+        for expected in expected.iter() {
+            assert!(result.contains(expected));
+        }
+    }
+}
+
+fn screened_summary<F: PaymentEngine>(mut engine: F) -> (F, Vec<TransactionResultSummary>) {
+    let mut csv_reader = CSVTransactionReader::new("tests/data/tx_tests_client_lists.csv");
+    for record in csv_reader.iter() {
+        engine.process(&record.unwrap()).unwrap();
+    }
+    let summary = engine.summary().unwrap().collect::<Vec<_>>();
+    (engine, summary)
+}
+
+#[test]
+fn test_process_with_client_lists() {
+    let screening = |action| {
+        let lists = load_client_lists(Some("tests/data/blocklist.csv"), None).unwrap();
+        ClientScreening::new(lists, action)
+    };
+    let (_, memory) = screened_summary(
+        MemoryThreadSafePaymentEngine::new().with_screening(screening(ScreeningAction::Reject)),
+    );
+    let (sqlite, summary) = screened_summary(
+        SqlitePaymentEngine::open_in_memory()
+            .unwrap()
+            .with_screening(screening(ScreeningAction::Quarantine)),
+    );
+
+    // Blocked clients get no account, and transfers to them are not applied either.
+    for result in [memory, summary] {
+        assert_eq!(result.len(), 2);
+        let expected: [TransactionResultSummary; 2] = [
+            Account::create_with(1_u16, dec!(6), dec!(0), false).into(),
+            Account::create_with(2_u16, dec!(14), dec!(0), false).into(),
+        ];
+        for expected in expected.iter() {
+            assert!(result.contains(expected));
+        }
+    }
+    let quarantined = sqlite
+        .quarantined()
+        .unwrap()
+        .iter()
+        .map(|record| (record.offset, record.transaction.transaction_id()))
+        .collect::<Vec<_>>();
+    assert_eq!(quarantined, [(1, 2), (3, 4), (5, 6)]);
+    assert_eq!(sqlite.resume_offset().unwrap(), 6);
+}